@@ -1,12 +1,18 @@
 //! Experimental build tool for cargo
 
-use pwasm_utils::{build, logger, BuildError, SourceTarget, TargetRuntime};
+use pwasm_utils::{
+	build_with_config,
+	cli_args::{ArgSpec, CommandSpec, KEEP_SECTIONS, STACK_LIMIT, STRIP},
+	inject_gas_and_stack_limiter, inject_gas_counter_with_config, logger, peephole,
+	remove_dead_code, rules, serialize_to_file, stack_height, strip_custom_sections, BuildConfig,
+	BuildError, CombinedError, GasConfig, SourceInput, TargetRuntime, EMSCRIPTEN_TRIPLET,
+	UNKNOWN_TRIPLET,
+};
 
-mod source;
+mod size;
 
-use std::{fs, io, path::PathBuf};
+use std::{fs, io};
 
-use clap::{crate_version, App, Arg};
 use parity_wasm::elements;
 
 #[derive(Debug)]
@@ -16,6 +22,9 @@ pub enum Error {
 	Decoding(elements::Error, String),
 	Encoding(elements::Error),
 	Build(BuildError),
+	Instrumentation(CombinedError),
+	TooLarge { size: usize, limit: usize },
+	TooMuchGrowth { size: usize, baseline: usize, max_growth: f64 },
 }
 
 impl std::fmt::Display for Error {
@@ -35,28 +44,25 @@ impl std::fmt::Display for Error {
 				err
 			),
 			Build(err) => write!(f, "Build error: {}", err),
+			Instrumentation(err) => write!(f, "Instrumentation error: {}", err),
+			TooLarge { size, limit } =>
+				write!(f, "Final wasm is {} bytes which exceeds the limit of {} bytes", size, limit),
+			TooMuchGrowth { size, baseline, max_growth } => write!(
+				f,
+				"Final wasm is {} bytes which is more than {}% bigger than the baseline of {} bytes",
+				size, max_growth, baseline
+			),
 		}
 	}
 }
 
-pub fn wasm_path(input: &source::SourceInput) -> String {
-	let mut path = PathBuf::from(input.target_dir());
-	path.push(format!("{}.wasm", input.final_name()));
-	path.to_string_lossy().to_string()
+pub fn wasm_path(input: &SourceInput) -> String {
+	input.final_path().to_string_lossy().to_string()
 }
 
-pub fn process_output(input: &source::SourceInput) -> Result<(), Error> {
-	let mut cargo_path = PathBuf::from(input.target_dir());
-	let wasm_name = input.bin_name().to_string().replace("-", "_");
-	cargo_path.push(match input.target() {
-		SourceTarget::Emscripten => source::EMSCRIPTEN_TRIPLET,
-		SourceTarget::Unknown => source::UNKNOWN_TRIPLET,
-	});
-	cargo_path.push("release");
-	cargo_path.push(format!("{}.wasm", wasm_name));
-
-	let mut target_path = PathBuf::from(input.target_dir());
-	target_path.push(format!("{}.wasm", input.final_name()));
+pub fn process_output(input: &SourceInput) -> Result<(), Error> {
+	let cargo_path = input.artifact_path();
+	let target_path = input.final_path();
 	fs::copy(cargo_path.as_path(), target_path.as_path()).map_err(|io| {
 		Error::FailedToCopy(format!(
 			"Failed to copy '{}' to '{}': {}",
@@ -72,75 +78,127 @@ pub fn process_output(input: &source::SourceInput) -> Result<(), Error> {
 fn do_main() -> Result<(), Error> {
 	logger::init();
 
-	let matches = App::new("wasm-build")
-		.version(crate_version!())
-		.arg(Arg::with_name("target")
-			.index(1)
-			.required(true)
-			.help("Cargo target directory"))
-		.arg(Arg::with_name("wasm")
-			.index(2)
-			.required(true)
-			.help("Wasm binary name"))
-		.arg(Arg::with_name("target-runtime")
-			.help("What runtime we are compiling to")
-			.long("target-runtime")
-			.takes_value(true)
-			.default_value("pwasm")
-			.possible_values(&["substrate", "pwasm"]))
-		.arg(Arg::with_name("skip_optimization")
-			.help("Skip symbol optimization step producing final wasm")
-			.long("skip-optimization"))
-		.arg(Arg::with_name("enforce_stack_adjustment")
-			.help("Enforce stack size adjustment (used for old wasm32-unknown-unknown)")
-			.long("enforce-stack-adjustment"))
-		.arg(Arg::with_name("runtime_type")
-			.help("Injects RUNTIME_TYPE global export")
-			.takes_value(true)
-			.long("runtime-type"))
-		.arg(Arg::with_name("runtime_version")
-			.help("Injects RUNTIME_VERSION global export")
-			.takes_value(true)
-			.long("runtime-version"))
-		.arg(Arg::with_name("source_target")
-			.help("Cargo target type kind ('wasm32-unknown-unknown' or 'wasm32-unknown-emscripten'")
-			.takes_value(true)
-			.long("target"))
-		.arg(Arg::with_name("final_name")
-			.help("Final wasm binary name")
-			.takes_value(true)
-			.long("final"))
-		.arg(Arg::with_name("save_raw")
-			.help("Save intermediate raw bytecode to path")
-			.takes_value(true)
-			.long("save-raw"))
-		.arg(Arg::with_name("shrink_stack")
-			.help("Shrinks the new stack size for wasm32-unknown-unknown")
-			.takes_value(true)
-			.long("shrink-stack"))
-		.arg(Arg::with_name("public_api")
-			.help("Preserves specific imports in the library")
-			.takes_value(true)
-			.long("public-api"))
-
+	let args = [
+		ArgSpec::positional("target", 1, "Cargo target directory"),
+		ArgSpec::positional("wasm", 2, "Wasm binary name"),
+		ArgSpec::option(
+			"target-runtime",
+			"target-runtime",
+			"runtime",
+			"What runtime we are compiling to",
+		)
+		.with_default_value("pwasm")
+		.with_possible_values(&["substrate", "pwasm"]),
+		ArgSpec::flag(
+			"skip_optimization",
+			"skip-optimization",
+			"Skip symbol optimization step producing final wasm",
+		),
+		ArgSpec::flag(
+			"peephole",
+			"peephole",
+			"Replace instruction sequences by shorter equivalent ones",
+		),
+		ArgSpec::flag(
+			"remove_dead_code",
+			"remove-dead-code",
+			"Remove the code which can't be executed, after the instrumentation",
+		),
+		ArgSpec::flag("gas", "gas", "Meter the code with gas imported from env"),
+		ArgSpec::option(
+			"gas_schedule",
+			"gas-schedule",
+			"file",
+			"JSON encoded schedule to meter the code with instead of the default one",
+		)
+		.with_requires("gas"),
+		STACK_LIMIT,
+		ArgSpec::flag(
+			"enforce_stack_adjustment",
+			"enforce-stack-adjustment",
+			"Enforce stack size adjustment (used for old wasm32-unknown-unknown)",
+		),
+		ArgSpec::flag(
+			"relocate_stack",
+			"relocate-stack",
+			"Move the adjusted stack above the data segments it overlaps instead of warning",
+		)
+		.with_requires("enforce_stack_adjustment"),
+		ArgSpec::option(
+			"runtime_type",
+			"runtime-type",
+			"type",
+			"Injects RUNTIME_TYPE global export",
+		),
+		ArgSpec::option(
+			"runtime_version",
+			"runtime-version",
+			"version",
+			"Injects RUNTIME_VERSION global export",
+		),
+		ArgSpec::option(
+			"source_target",
+			"target",
+			"triplet",
+			"Cargo target type kind ('wasm32-unknown-unknown' or 'wasm32-unknown-emscripten'",
+		),
+		ArgSpec::option(
+			"artifact",
+			"artifact",
+			"path",
+			"Path of the binary built by cargo, if not in the usual place in the target directory",
+		),
+		ArgSpec::option("final_name", "final", "name", "Final wasm binary name"),
+		ArgSpec::option("save_raw", "save-raw", "path", "Save intermediate raw bytecode to path"),
+		ArgSpec::option(
+			"shrink_stack",
+			"shrink-stack",
+			"size",
+			"Shrinks the new stack size for wasm32-unknown-unknown",
+		),
+		ArgSpec::option(
+			"public_api",
+			"public-api",
+			"imports",
+			"Preserves specific imports in the library",
+		),
+		ArgSpec::option(
+			"max_size",
+			"max-size",
+			"bytes",
+			"Fail if the final wasm is bigger than the given number of bytes",
+		),
+		ArgSpec::option(
+			"baseline",
+			"baseline",
+			"path",
+			"Previous build of the wasm to print a per-function size diff against",
+		),
+		ArgSpec::option(
+			"max_growth",
+			"max-growth",
+			"percentage",
+			"Fail if the final wasm grows by more than the given percentage versus --baseline",
+		)
+		.with_requires("baseline"),
+		STRIP,
+		KEEP_SECTIONS,
+	];
+	let matches = CommandSpec::new("wasm-build", "Post-process a contract built by cargo", &args)
 		.get_matches();
 
 	let target_dir = matches.value_of("target").expect("is required; qed");
 	let wasm_binary = matches.value_of("wasm").expect("is required; qed");
 
-	let mut source_input = source::SourceInput::new(target_dir, wasm_binary);
+	let mut source_input = SourceInput::new(target_dir, wasm_binary);
 
-	let source_target_val = matches.value_of("source_target").unwrap_or(source::EMSCRIPTEN_TRIPLET);
-	if source_target_val == source::UNKNOWN_TRIPLET {
+	let source_target_val = matches.value_of("source_target").unwrap_or(EMSCRIPTEN_TRIPLET);
+	if source_target_val == UNKNOWN_TRIPLET {
 		source_input = source_input.unknown()
-	} else if source_target_val == source::EMSCRIPTEN_TRIPLET {
+	} else if source_target_val == EMSCRIPTEN_TRIPLET {
 		source_input = source_input.emscripten()
 	} else {
-		eprintln!(
-			"--target can be: '{}' or '{}'",
-			source::EMSCRIPTEN_TRIPLET,
-			source::UNKNOWN_TRIPLET
-		);
+		eprintln!("--target can be: '{}' or '{}'", EMSCRIPTEN_TRIPLET, UNKNOWN_TRIPLET);
 		::std::process::exit(1);
 	}
 
@@ -148,13 +206,58 @@ fn do_main() -> Result<(), Error> {
 		source_input = source_input.with_final(final_name);
 	}
 
+	if let Some(artifact) = matches.value_of("artifact") {
+		source_input = source_input.with_artifact(artifact);
+	}
+
 	process_output(&source_input)?;
 
 	let path = wasm_path(&source_input);
 
-	let module =
+	let mut module =
 		parity_wasm::deserialize_file(&path).map_err(|e| Error::Decoding(e, path.to_string()))?;
 
+	if matches.is_present("peephole") {
+		peephole(&mut module);
+	}
+
+	// The instrumentation is applied before the constructor is split off and the code packed
+	// into it. The optimization keeps the gas import and the thunks, which are used.
+	let gas_rules = match matches.value_of("gas_schedule") {
+		Some(path) => Some(
+			rules::Set::from_reader(fs::File::open(path).map_err(Error::Io)?).map_err(Error::Io)?,
+		),
+		None if matches.is_present("gas") => Some(rules::Set::default()),
+		None => None,
+	};
+	let stack_config = matches.value_of("stack_limit").map(|limit| {
+		stack_height::Config::new(limit.parse().expect("Stack limit is not a valid u32"))
+	});
+	let gas_config = GasConfig::new("env");
+	module = match (gas_rules, stack_config) {
+		(Some(rules), Some(stack_config)) =>
+			inject_gas_and_stack_limiter(module, &rules, &gas_config, &stack_config)
+				.map_err(Error::Instrumentation)?,
+		(Some(rules), None) => inject_gas_counter_with_config(module, &rules, &gas_config)
+			.map_err(|err| Error::Instrumentation(err.into()))?,
+		(None, Some(stack_config)) =>
+			stack_height::inject_limiter_with_config(module, &stack_config)
+				.map_err(|err| Error::Instrumentation(err.into()))?,
+		(None, None) => module,
+	};
+
+	if matches.is_present("remove_dead_code") {
+		remove_dead_code(&mut module);
+	}
+
+	if matches.is_present("strip") {
+		let keep: Vec<_> = matches
+			.value_of("keep_sections")
+			.map(|val| val.split(',').collect())
+			.unwrap_or_default();
+		strip_custom_sections(&mut module, &keep);
+	}
+
 	let runtime_type_version = if let (Some(runtime_type), Some(runtime_version)) =
 		(matches.value_of("runtime_type"), matches.value_of("runtime_version"))
 	{
@@ -185,7 +288,11 @@ fn do_main() -> Result<(), Error> {
 		_ => unreachable!("all possible values are enumerated in clap config; qed"),
 	};
 
-	let (module, ctor_module) = build(
+	let mut build_config = BuildConfig::new();
+	if matches.is_present("relocate_stack") {
+		build_config = build_config.with_relocated_stack();
+	}
+	let (module, ctor_module) = build_with_config(
 		module,
 		source_input.target(),
 		runtime_type_version,
@@ -198,17 +305,45 @@ fn do_main() -> Result<(), Error> {
 			.expect("New stack size is not valid u32"),
 		matches.is_present("skip_optimization"),
 		&target_runtime,
+		&build_config,
 	)
 	.map_err(Error::Build)?;
 
 	if let Some(save_raw_path) = matches.value_of("save_raw") {
-		parity_wasm::serialize_to_file(save_raw_path, module.clone()).map_err(Error::Encoding)?;
+		serialize_to_file(save_raw_path, module.clone()).map_err(Error::Encoding)?;
 	}
 
-	if let Some(ctor_module) = ctor_module {
-		parity_wasm::serialize_to_file(&path, ctor_module).map_err(Error::Encoding)?;
-	} else {
-		parity_wasm::serialize_to_file(&path, module).map_err(Error::Encoding)?;
+	let final_module = ctor_module.unwrap_or(module);
+	// The sizes are taken before the module is consumed by the streaming serializer.
+	let baseline = matches
+		.value_of("baseline")
+		.map(|baseline_path| (baseline_path, size::function_sizes(&final_module)));
+	let final_size = serialize_to_file(&path, final_module).map_err(Error::Encoding)?;
+
+	if let Some((baseline_path, final_sizes)) = baseline {
+		let baseline_bytes = fs::read(baseline_path).map_err(Error::Io)?;
+		let baseline: elements::Module = parity_wasm::deserialize_buffer(&baseline_bytes)
+			.map_err(|e| Error::Decoding(e, baseline_path.to_string()))?;
+
+		size::print_size_diff(&size::size_diff(&size::function_sizes(&baseline), &final_sizes));
+
+		if let Some(max_growth) = matches.value_of("max_growth") {
+			let max_growth: f64 = max_growth.parse().expect("--max-growth should be a percentage");
+			if size::growth_percent(baseline_bytes.len(), final_size) > max_growth {
+				return Err(Error::TooMuchGrowth {
+					size: final_size,
+					baseline: baseline_bytes.len(),
+					max_growth,
+				})
+			}
+		}
+	}
+
+	if let Some(max_size) = matches.value_of("max_size") {
+		let limit: usize = max_size.parse().expect("--max-size should be a positive integer");
+		if final_size > limit {
+			return Err(Error::TooLarge { size: final_size, limit })
+		}
 	}
 
 	Ok(())
@@ -226,7 +361,7 @@ mod tests {
 	use std::fs;
 	use tempdir::TempDir;
 
-	use super::{process_output, source::SourceInput};
+	use super::{process_output, SourceInput};
 
 	#[test]
 	fn processes_cargo_output() {
@@ -0,0 +1,104 @@
+//! Size gate of the final artifact
+
+use std::collections::BTreeMap;
+
+use parity_wasm::elements::{self, Serialize};
+
+/// Serialized size of each function body, keyed by the export name of the function
+/// or by `func[<index>]` if it isn't exported.
+pub fn function_sizes(module: &elements::Module) -> BTreeMap<String, usize> {
+	let imported = module.import_count(elements::ImportCountType::Function);
+	let exports: BTreeMap<usize, &str> = module
+		.export_section()
+		.map(|es| es.entries())
+		.unwrap_or(&[])
+		.iter()
+		.filter_map(|entry| match entry.internal() {
+			elements::Internal::Function(idx) => Some((*idx as usize, entry.field())),
+			_ => None,
+		})
+		.collect();
+
+	module
+		.code_section()
+		.map(|cs| cs.bodies())
+		.unwrap_or(&[])
+		.iter()
+		.enumerate()
+		.map(|(defined_idx, body)| {
+			let func_idx = imported + defined_idx;
+			let label = exports
+				.get(&func_idx)
+				.map(|name| name.to_string())
+				.unwrap_or_else(|| format!("func[{}]", func_idx));
+			let mut bytes = Vec::new();
+			body.clone()
+				.serialize(&mut bytes)
+				.expect("serializing into a vec can't fail; qed");
+			(label, bytes.len())
+		})
+		.collect()
+}
+
+/// Functions whose size differs between `baseline` and `current`, with the biggest changes first.
+///
+/// Functions missing on one side are reported with a size of 0.
+pub fn size_diff(
+	baseline: &BTreeMap<String, usize>,
+	current: &BTreeMap<String, usize>,
+) -> Vec<(String, usize, usize)> {
+	let mut diff: Vec<_> = baseline
+		.keys()
+		.chain(current.keys().filter(|label| !baseline.contains_key(*label)))
+		.map(|label| {
+			let old = baseline.get(label).cloned().unwrap_or(0);
+			let new = current.get(label).cloned().unwrap_or(0);
+			(label.clone(), old, new)
+		})
+		.filter(|(_, old, new)| old != new)
+		.collect();
+	diff.sort_by_key(|(_, old, new)| std::cmp::Reverse((*new as i64 - *old as i64).abs()));
+	diff
+}
+
+/// Print per-function size changes to stderr.
+pub fn print_size_diff(diff: &[(String, usize, usize)]) {
+	for (label, old, new) in diff {
+		eprintln!("{}: {} -> {} ({:+})", label, old, new, *new as i64 - *old as i64);
+	}
+}
+
+/// Growth of `size` relative to `baseline` in percents.
+pub fn growth_percent(baseline: usize, size: usize) -> f64 {
+	if baseline == 0 {
+		return if size == 0 { 0.0 } else { f64::INFINITY }
+	}
+	(size as f64 - baseline as f64) * 100.0 / baseline as f64
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn diff_reports_changed_functions_only() {
+		let baseline: BTreeMap<_, _> =
+			vec![("call".to_string(), 10), ("func[1]".to_string(), 5), ("gone".to_string(), 3)]
+				.into_iter()
+				.collect();
+		let current: BTreeMap<_, _> =
+			vec![("call".to_string(), 30), ("func[1]".to_string(), 5), ("new".to_string(), 1)]
+				.into_iter()
+				.collect();
+
+		assert_eq!(
+			size_diff(&baseline, &current),
+			vec![
+				("call".to_string(), 10, 30),
+				("gone".to_string(), 3, 0),
+				("new".to_string(), 0, 1)
+			]
+		);
+		assert_eq!(growth_percent(200, 210), 5.0);
+	}
+}
@@ -1,6 +1,9 @@
-use clap::{App, Arg};
 use parity_wasm::elements;
-use pwasm_utils::logger;
+use pwasm_utils::{
+	check_mutable_globals,
+	cli_args::{ArgSpec, CommandSpec, ALLOW_MUTABLE_EXPORT, INPUT, MAX_MEMORY_GROWTH},
+	logger, max_memory_growth, normalize, MutableGlobalsPolicy,
+};
 
 fn fail(msg: &str) -> ! {
 	eprintln!("{}", msg);
@@ -38,15 +41,57 @@ const ALLOWED_IMPORTS: &[&str] = &[
 fn main() {
 	logger::init();
 
-	let matches = App::new("wasm-check")
-		.arg(Arg::with_name("input").index(1).required(true).help("Input WASM file"))
-		.get_matches();
+	let args = [
+		INPUT,
+		ALLOW_MUTABLE_EXPORT,
+		MAX_MEMORY_GROWTH,
+		ArgSpec::flag(
+			"canonical",
+			"canonical",
+			"Reject integers which aren't encoded with the minimal number of bytes",
+		),
+	];
+	let matches = CommandSpec::new(
+		"wasm-check",
+		"Check that a contract can run on the Parity runtime",
+		&args,
+	)
+	.get_matches();
 
 	let input = matches.value_of("input").expect("is required; qed");
 
+	if matches.is_present("canonical") {
+		let bytes = std::fs::read(input).expect("Failed to read the input module");
+		let normalized = normalize(&bytes).expect("Input module deserialization failed");
+		if let Some(offset) = normalized.first_difference {
+			fail(&format!("The module isn't encoded canonically at offset {:#x}", offset));
+		}
+	}
+
 	let module =
 		parity_wasm::deserialize_file(&input).expect("Input module deserialization failed");
 
+	let policy = matches
+		.values_of("allow_mutable_export")
+		.into_iter()
+		.flatten()
+		.fold(MutableGlobalsPolicy::new(), |policy, field| policy.with_export(field));
+	if let Err(violations) = check_mutable_globals(&module, &policy) {
+		fail(&format!("{}. Parity runtime does not support mutable globals", violations[0]));
+	}
+
+	if let Some(max_pages) = matches.value_of("max_memory_growth") {
+		let max_pages: u32 = max_pages.parse().expect("Invalid number of pages");
+		for (field, growth) in max_memory_growth(&module) {
+			if !growth.within(max_pages) {
+				fail(&format!(
+					"Export '{}' may grow the memory by {}, at most {} pages are allowed",
+					field, growth, max_pages
+				));
+			}
+		}
+	}
+
 	for section in module.sections() {
 		match section {
 			elements::Section::Import(import_section) => {
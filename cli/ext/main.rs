@@ -12,5 +12,5 @@ fn main() {
 		vec!["_free", "_malloc", "_memcpy", "_memset", "_memmove"],
 	);
 
-	parity_wasm::serialize_to_file(&args[2], module).expect("Module to serialize ok");
+	pwasm_utils::serialize_to_file(&args[2], module).expect("Module to serialize ok");
 }
@@ -1,21 +1,29 @@
 use pwasm_utils::{self as utils, logger};
-use std::env;
+use std::{env, fs};
 
 fn main() {
 	logger::init();
 
 	let args = env::args().collect::<Vec<_>>();
-	if args.len() != 3 {
-		println!("Usage: {} input_file.wasm output_file.wasm", args[0]);
+	if args.len() != 3 && args.len() != 4 {
+		println!("Usage: {} input_file.wasm output_file.wasm [schedule.json]", args[0]);
 		return
 	}
 
+	let rules = match args.get(3) {
+		Some(path) => {
+			let file = fs::File::open(path).expect("Schedule file to exist");
+			utils::rules::Set::from_reader(file).expect("Schedule to be valid")
+		},
+		None => utils::rules::Set::default(),
+	};
+
 	// Loading module
 	let module =
 		parity_wasm::deserialize_file(&args[1]).expect("Module deserialization to succeed");
 
-	let result = utils::inject_gas_counter(module, &utils::rules::Set::default(), "env")
-		.expect("Failed to inject gas. Some forbidden opcodes?");
+	let result = utils::inject_gas_counter(module, &rules, "env")
+		.unwrap_or_else(|err| panic!("Failed to inject gas: {}", err));
 
-	parity_wasm::serialize_to_file(&args[2], result).expect("Module serialization to succeed")
+	utils::serialize_to_file(&args[2], result).expect("Module serialization to succeed");
 }
@@ -1,15 +1,26 @@
-use clap::{App, Arg};
-use pwasm_utils::{self as utils, logger};
+use pwasm_utils::{
+	self as utils,
+	cli_args::{ArgSpec, CommandSpec, INPUT, OUTPUT},
+	logger,
+};
 
 fn main() {
 	logger::init();
 
 	let target_runtime = utils::TargetRuntime::pwasm();
 
-	let matches = App::new("wasm-pack")
-		.arg(Arg::with_name("input").index(1).required(true).help("Input WASM file"))
-		.arg(Arg::with_name("output").index(2).required(true).help("Output WASM file"))
-		.get_matches();
+	let args = [
+		INPUT,
+		OUTPUT,
+		ArgSpec::flag(
+			"share_data",
+			"share-data",
+			"Copy the data segments of the code from the constructor instead of embedding them \
+			 twice",
+		),
+	];
+	let matches =
+		CommandSpec::new("wasm-pack", "Pack a contract into its constructor", &args).get_matches();
 
 	let input = matches.value_of("input").expect("is required; qed");
 	let output = matches.value_of("output").expect("is required; qed");
@@ -19,13 +30,22 @@ fn main() {
 	let ctor_module = module.clone();
 	let raw_module = parity_wasm::serialize(module).expect("Serialization failed");
 
+	let mut config = utils::PackConfig::new();
+	if matches.is_present("share_data") {
+		config = config.with_shared_data();
+	}
+
 	// Invoke packer
-	let mut result_module =
-		utils::pack_instance(raw_module, ctor_module, &utils::TargetRuntime::pwasm())
-			.expect("Packing failed");
+	let mut result_module = utils::pack_instance_with_config(
+		raw_module,
+		ctor_module,
+		&utils::TargetRuntime::pwasm(),
+		&config,
+	)
+	.expect("Packing failed");
 	// Optimize constructor, since it does not need everything
 	utils::optimize(&mut result_module, vec![target_runtime.symbols().call])
 		.expect("Optimization failed");
 
-	parity_wasm::serialize_to_file(&output, result_module).expect("Serialization failed");
+	pwasm_utils::serialize_to_file(&output, result_module).expect("Serialization failed");
 }
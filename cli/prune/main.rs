@@ -1,26 +1,35 @@
-use clap::{App, Arg};
-use pwasm_utils::{self as utils, logger};
+use pwasm_utils::{
+	self as utils,
+	cli_args::{ArgSpec, CommandSpec, INPUT, KEEP_SECTIONS, OUTPUT, STRIP},
+	logger,
+};
 
 fn main() {
 	logger::init();
 
 	let target_runtime = utils::TargetRuntime::pwasm();
+	let exports_help = format!(
+		"Comma-separated list of exports to keep, `*` and `?` match like in globs. Prefix a \
+		 name with `func:`, `global:`, `memory:` or `table:` to match only that kind of export. \
+		 Default: '{}'",
+		target_runtime.symbols().call
+	);
 
-	let matches = App::new("wasm-prune")
-		.arg(Arg::with_name("input").index(1).required(true).help("Input WASM file"))
-		.arg(Arg::with_name("output").index(2).required(true).help("Output WASM file"))
-		.arg(
-			Arg::with_name("exports")
-				.long("exports")
-				.short("e")
-				.takes_value(true)
-				.value_name("functions")
-				.help(&format!(
-					"Comma-separated list of exported functions to keep. Default: '{}'",
-					target_runtime.symbols().call
-				)),
-		)
-		.get_matches();
+	let args = [
+		INPUT,
+		OUTPUT,
+		ArgSpec::option("exports", "exports", "functions", &exports_help).with_short("e"),
+		ArgSpec::flag(
+			"drop_unused_data",
+			"drop-unused-data",
+			"Also remove the data segments no constant address points into",
+		),
+		STRIP,
+		KEEP_SECTIONS,
+	];
+	let matches =
+		CommandSpec::new("wasm-prune", "Remove the code and data a contract doesn't use", &args)
+			.get_matches();
 
 	let exports = matches
 		.value_of("exports")
@@ -36,7 +45,38 @@ fn main() {
 	// Invoke optimizer
 	//   Contract is supposed to have only these functions as public api
 	//   All other symbols not usable by this list is optimized away
-	utils::optimize(&mut module, exports).expect("Optimizer failed");
+	let mut config = utils::OptimizerConfig::new();
+	if matches.is_present("drop_unused_data") {
+		config = config.with_unused_data_elimination();
+	}
+	let report =
+		utils::optimize_with_config(&mut module, exports, &config).expect("Optimizer failed");
+	eprintln!(
+		"Removed {} functions, {} imports, {} globals, {} types and {} data segments, saving {} \
+		 bytes",
+		report.functions.len(),
+		report.imports.len(),
+		report.globals.len(),
+		report.types.len(),
+		report.data_segments.len(),
+		report.size_reduction,
+	);
+	for function in &report.functions {
+		log::info!("Removed function {} ({:?})", function.func_idx, function.name);
+	}
+	for import in &report.imports {
+		log::info!("Removed import {}.{}", import.module, import.field);
+	}
 
-	parity_wasm::serialize_to_file(&output, module).expect("Serialization failed");
+	if matches.is_present("strip") {
+		let keep: Vec<_> = matches
+			.value_of("keep_sections")
+			.map(|val| val.split(',').collect())
+			.unwrap_or_default();
+		for section in utils::strip_custom_sections(&mut module, &keep) {
+			log::info!("Removed custom section {}", section);
+		}
+	}
+
+	pwasm_utils::serialize_to_file(&output, module).expect("Serialization failed");
 }
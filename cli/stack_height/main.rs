@@ -20,5 +20,5 @@ fn main() {
 	let result =
 		stack_height::inject_limiter(module, 1024).expect("Failed to inject stack height counter");
 
-	parity_wasm::serialize_to_file(&output_file, result).expect("Module serialization to succeed")
+	pwasm_utils::serialize_to_file(&output_file, result).expect("Module serialization to succeed");
 }
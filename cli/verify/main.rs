@@ -0,0 +1,142 @@
+//! Checks to run on an instrumented artifact before accepting it.
+
+use pwasm_utils::{
+	check_mutable_globals, check_structure,
+	cli_args::{ArgSpec, CommandSpec, ALLOW_MUTABLE_EXPORT, MAX_MEMORY_GROWTH, STACK_LIMIT},
+	logger, max_memory_growth, rules, stack_height, verify_gas_counter, MutableGlobalsPolicy,
+};
+use std::fs;
+
+/// Contents of the file passed with `--policy`.
+///
+/// The command line flags add to the policy: the exports allowed by both are accepted, and
+/// `--max-memory-growth` takes precedence over `max_memory_growth`.
+#[derive(Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Policy {
+	/// Exports which may expose a mutable global.
+	allow_mutable_exports: Vec<String>,
+	/// Number of pages an export may grow the memory by.
+	max_memory_growth: Option<u32>,
+}
+
+fn main() {
+	logger::init();
+
+	let args = [
+		ArgSpec::positional("input", 1, "Instrumented WASM file"),
+		ArgSpec::option(
+			"rules",
+			"rules",
+			"file",
+			"JSON encoded schedule the gas metering has to match",
+		),
+		ArgSpec::option(
+			"gas_module",
+			"gas-module",
+			"name",
+			"Module the gas function is imported from",
+		)
+		.with_default_value("env"),
+		ArgSpec::option(
+			"gas_field",
+			"gas-field",
+			"name",
+			"Name the gas function is imported under",
+		)
+		.with_default_value("gas"),
+		ArgSpec::option(
+			"policy",
+			"policy",
+			"file",
+			"JSON encoded policy with the allowed mutable exports and the maximal memory growth",
+		),
+		STACK_LIMIT,
+		ALLOW_MUTABLE_EXPORT,
+		MAX_MEMORY_GROWTH,
+	];
+	let matches = CommandSpec::new(
+		"wasm-verify",
+		"Check an instrumented contract before accepting it",
+		&args,
+	)
+	.get_matches();
+
+	let input = matches.value_of("input").expect("is required; qed");
+
+	let module = match parity_wasm::deserialize_file(input) {
+		Ok(module) => module,
+		Err(err) => {
+			eprintln!("Invalid module: {}", err);
+			std::process::exit(1)
+		},
+	};
+
+	if let Err(errors) = check_structure(&module) {
+		for error in &errors {
+			eprintln!("Validation: {}", error);
+		}
+		std::process::exit(1)
+	}
+
+	let file_policy: Policy = match matches.value_of("policy") {
+		Some(path) => {
+			let policy = fs::read(path).expect("Policy file to exist");
+			serde_json::from_slice(&policy).expect("Policy to be valid")
+		},
+		None => Policy::default(),
+	};
+
+	let mut failures = Vec::new();
+
+	if let Some(path) = matches.value_of("rules") {
+		let file = fs::File::open(path).expect("Schedule file to exist");
+		let rules = rules::Set::from_reader(file).expect("Schedule to be valid");
+		let gas_import = (
+			matches.value_of("gas_module").expect("has a default; qed"),
+			matches.value_of("gas_field").expect("has a default; qed"),
+		);
+		if let Err(mismatches) = verify_gas_counter(&module, &rules, gas_import) {
+			failures.extend(mismatches.iter().map(|m| format!("Gas metering: {}", m)));
+		}
+	}
+
+	if let Some(stack_limit) = matches.value_of("stack_limit") {
+		let stack_limit = stack_limit.parse().expect("Invalid stack limit");
+		if let Err(mismatches) = stack_height::verify(&module, stack_limit) {
+			failures.extend(mismatches.iter().map(|m| format!("Stack height limiter: {}", m)));
+		}
+	}
+
+	let policy = file_policy
+		.allow_mutable_exports
+		.iter()
+		.map(String::as_str)
+		.chain(matches.values_of("allow_mutable_export").into_iter().flatten())
+		.fold(MutableGlobalsPolicy::new(), |policy, field| policy.with_export(field));
+	if let Err(violations) = check_mutable_globals(&module, &policy) {
+		failures.extend(violations.iter().map(|v| format!("Policy: {}", v)));
+	}
+
+	let max_pages = matches
+		.value_of("max_memory_growth")
+		.map(|max_pages| max_pages.parse().expect("Invalid number of pages"))
+		.or(file_policy.max_memory_growth);
+	if let Some(max_pages) = max_pages {
+		for (field, growth) in max_memory_growth(&module) {
+			if !growth.within(max_pages) {
+				failures.push(format!(
+					"Policy: export '{}' may grow the memory by {}, at most {} pages are allowed",
+					field, growth, max_pages
+				));
+			}
+		}
+	}
+
+	for failure in &failures {
+		eprintln!("{}", failure);
+	}
+	if !failures.is_empty() {
+		std::process::exit(1)
+	}
+}
@@ -0,0 +1,21 @@
+;; Returns the 15th Fibonacci number as a little endian i32, computed recursively.
+(module
+	(import "env" "ret" (func $ret (param i32 i32)))
+	(memory 1)
+	(func $fib (param $n i32) (result i32)
+		(if (result i32) (i32.lt_u (local.get $n) (i32.const 2))
+			(then (local.get $n))
+			(else
+				(i32.add
+					(call $fib (i32.sub (local.get $n) (i32.const 1)))
+					(call $fib (i32.sub (local.get $n) (i32.const 2)))
+				)
+			)
+		)
+	)
+	(func (export "deploy"))
+	(func (export "call")
+		(i32.store (i32.const 0) (call $fib (i32.const 15)))
+		(call $ret (i32.const 0) (i32.const 4))
+	)
+)
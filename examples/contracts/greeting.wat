@@ -0,0 +1,15 @@
+;; Returns a greeting kept in a data segment, which the constructor stores before the code.
+(module
+	(import "env" "ret" (func $ret (param i32 i32)))
+	(memory 1)
+	(data (i32.const 16) "Hello, world!")
+	(func (export "deploy")
+		;; The constructor checks that its data is in place.
+		(if (i32.ne (i32.load8_u (i32.const 16)) (i32.const 72))
+			(then unreachable)
+		)
+	)
+	(func (export "call")
+		(call $ret (i32.const 16) (i32.const 13))
+	)
+)
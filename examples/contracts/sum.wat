@@ -0,0 +1,21 @@
+;; Returns the sum of 1..=10 as a little endian i32.
+(module
+	(import "env" "ret" (func $ret (param i32 i32)))
+	(memory 1)
+	(func (export "deploy"))
+	(func (export "call")
+		(local $i i32)
+		(local $sum i32)
+		(local.set $i (i32.const 10))
+		(block $done
+			(loop $next
+				(br_if $done (i32.eqz (local.get $i)))
+				(local.set $sum (i32.add (local.get $sum) (local.get $i)))
+				(local.set $i (i32.sub (local.get $i) (i32.const 1)))
+				(br $next)
+			)
+		)
+		(i32.store (i32.const 0) (local.get $sum))
+		(call $ret (i32.const 0) (i32.const 4))
+	)
+)
@@ -0,0 +1,74 @@
+//! Static analyses of modules guiding the optimization of their sources.
+
+use crate::std::{collections::BTreeSet as Set, vec::Vec};
+
+use parity_wasm::elements::{self, Instruction, Internal};
+
+/// Functions which are only ever called through the table.
+///
+/// These are the defined functions in a table element segment which no `call` instruction refers
+/// to and which are neither exported nor the start function. Each of them gets a thunk from the
+/// stack height limiter, so devirtualizing the calls to them in the source, e.g. by LTO, reduces
+/// the instrumentation overhead.
+///
+/// Returns the function indices in ascending order.
+pub fn indirect_only_functions(module: &elements::Module) -> Vec<u32> {
+	let func_imports = module.import_count(elements::ImportCountType::Function) as u32;
+
+	let mut referenced = Set::new();
+	for body in module.code_section().map(|section| section.bodies()).unwrap_or(&[]) {
+		for instruction in body.code().elements() {
+			if let Instruction::Call(callee) = instruction {
+				referenced.insert(*callee);
+			}
+		}
+	}
+	for entry in module.export_section().map(|section| section.entries()).unwrap_or(&[]) {
+		if let Internal::Function(func_idx) = entry.internal() {
+			referenced.insert(*func_idx);
+		}
+	}
+	referenced.extend(module.start_section());
+
+	module
+		.elements_section()
+		.map(|section| section.entries())
+		.unwrap_or(&[])
+		.iter()
+		.flat_map(|segment| segment.members().iter().copied())
+		.filter(|func_idx| *func_idx >= func_imports && !referenced.contains(func_idx))
+		.collect::<Set<_>>()
+		.into_iter()
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	#[test]
+	fn indirect_only() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "f" (func $imported))
+	(table 5 funcref)
+	(elem (i32.const 0) $imported $direct $indirect $exported $indirect)
+	(func $direct)
+	(func $indirect)
+	(func $exported (export "call")
+		(call $direct)
+		(call_indirect (i32.const 2))
+	)
+)
+"#,
+		);
+
+		assert_eq!(indirect_only_functions(&module), vec![2]);
+	}
+}
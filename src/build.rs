@@ -1,19 +1,26 @@
 use super::{
-	externalize_mem, inject_runtime_type, optimize, pack_instance, shrink_unknown_stack, std::fmt,
-	ununderscore_funcs, OptimizerError, PackingError, TargetRuntime,
+	externalize_mem, find_shadow_stack, inject_runtime_type, optimize, pack_instance,
+	relocate_stack, shrink_unknown_stack, stack_overlaps,
+	std::{fmt, string::String, vec::Vec},
+	ununderscore_funcs, OptimizerError, PackingError, RuntimeTypeError, StackRegionError,
+	TargetRuntime,
 };
+use log::{info, warn};
 use parity_wasm::elements;
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
 	Encoding(elements::Error),
 	Packing(PackingError),
-	Optimizer,
+	Optimizer(OptimizerError),
+	RuntimeType(RuntimeTypeError),
+	StackRelocation(StackRegionError),
 }
 
 impl From<OptimizerError> for Error {
-	fn from(_err: OptimizerError) -> Self {
-		Error::Optimizer
+	fn from(err: OptimizerError) -> Self {
+		Error::Optimizer(err)
 	}
 }
 
@@ -23,6 +30,18 @@ impl From<PackingError> for Error {
 	}
 }
 
+impl From<RuntimeTypeError> for Error {
+	fn from(err: RuntimeTypeError) -> Self {
+		Error::RuntimeType(err)
+	}
+}
+
+impl From<StackRegionError> for Error {
+	fn from(err: StackRegionError) -> Self {
+		Error::StackRelocation(err)
+	}
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum SourceTarget {
 	Emscripten,
@@ -34,12 +53,98 @@ impl fmt::Display for Error {
 		use self::Error::*;
 		match self {
 			Encoding(err) => write!(f, "Encoding error ({})", err),
-			Optimizer => write!(f, "Optimization error due to missing export section. Pointed wrong file?"),
+			Optimizer(OptimizerError::NoExportSection) => write!(f, "Optimization error due to missing export section. Pointed wrong file?"),
+			Optimizer(err) => write!(f, "Optimization error: {}", err),
 			Packing(e) => write!(f, "Packing failed due to module structure error: {}. Sure used correct libraries for building contracts?", e),
+			RuntimeType(e) => write!(f, "Runtime type injection failed: {}", e),
+			StackRelocation(e) => write!(f, "Relocating the stack failed: {}", e),
+		}
+	}
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			Error::Encoding(err) => Some(err),
+			Error::Packing(err) => Some(err),
+			Error::Optimizer(err) => Some(err),
+			Error::RuntimeType(err) => Some(err),
+			Error::StackRelocation(err) => Some(err),
+		}
+	}
+}
+
+/// Change made by [`normalize_memory`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemoryFix {
+	/// The memory import was renamed from `module::field`.
+	RenamedImport { module: String, field: String },
+	/// The export `field` of the imported memory was removed.
+	RemovedExport { field: String },
+}
+
+impl fmt::Display for MemoryFix {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			MemoryFix::RenamedImport { module, field } =>
+				write!(f, "Renamed the memory import {}::{}", module, field),
+			MemoryFix::RemovedExport { field } =>
+				write!(f, "Removed the export '{}' of the imported memory", field),
 		}
 	}
 }
 
+/// Make the memory import of `module` match the name expected by `target_runtime`.
+///
+/// The runtime provides the memory under [`TargetRuntime::memory_import`], so an imported memory
+/// of a different name is renamed, and exports of the imported memory are removed. Modules
+/// defining their memory are left unchanged.
+///
+/// Returns the changes made.
+pub fn normalize_memory(
+	module: &mut elements::Module,
+	target_runtime: &TargetRuntime,
+) -> Vec<MemoryFix> {
+	let (memory_module, memory_field) = target_runtime.memory_import();
+	let mut fixes = Vec::new();
+
+	let mut imports_memory = false;
+	if let Some(section) = module.import_section_mut() {
+		for entry in section.entries_mut() {
+			if !matches!(entry.external(), elements::External::Memory(_)) {
+				continue
+			}
+			imports_memory = true;
+			if entry.module() != memory_module || entry.field() != memory_field {
+				fixes.push(MemoryFix::RenamedImport {
+					module: entry.module().into(),
+					field: entry.field().into(),
+				});
+				*entry = elements::ImportEntry::new(
+					memory_module.into(),
+					memory_field.into(),
+					*entry.external(),
+				);
+			}
+		}
+	}
+
+	if imports_memory {
+		if let Some(section) = module.export_section_mut() {
+			section.entries_mut().retain(|entry| match entry.internal() {
+				elements::Internal::Memory(_) => {
+					fixes.push(MemoryFix::RemovedExport { field: entry.field().into() });
+					false
+				},
+				_ => true,
+			});
+		}
+	}
+
+	fixes
+}
+
 fn has_ctor(module: &elements::Module, target_runtime: &TargetRuntime) -> bool {
 	if let Some(section) = module.export_section() {
 		section.entries().iter().any(|e| target_runtime.symbols().create == e.field())
@@ -48,8 +153,53 @@ fn has_ctor(module: &elements::Module, target_runtime: &TargetRuntime) -> bool {
 	}
 }
 
+/// Options of [`build_with_config`] besides the arguments of [`build`].
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+	relocate_overlapped_stack: bool,
+}
+
+impl Config {
+	/// New configuration which builds like [`build`].
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Move the adjusted stack above the data segments it overlaps, see
+	/// [`crate::relocate_stack`], instead of warning about them.
+	pub fn with_relocated_stack(mut self) -> Self {
+		self.relocate_overlapped_stack = true;
+		self
+	}
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn build(
+	module: elements::Module,
+	source_target: SourceTarget,
+	runtime_type_version: Option<([u8; 4], u32)>,
+	public_api_entries: &[&str],
+	enforce_stack_adjustment: bool,
+	stack_size: u32,
+	skip_optimization: bool,
+	target_runtime: &TargetRuntime,
+) -> Result<(elements::Module, Option<elements::Module>), Error> {
+	build_with_config(
+		module,
+		source_target,
+		runtime_type_version,
+		public_api_entries,
+		enforce_stack_adjustment,
+		stack_size,
+		skip_optimization,
+		target_runtime,
+		&Config::new(),
+	)
+}
+
+/// Like [`build`], with the additional options of `config`.
+#[allow(clippy::too_many_arguments)]
+pub fn build_with_config(
 	mut module: elements::Module,
 	source_target: SourceTarget,
 	runtime_type_version: Option<([u8; 4], u32)>,
@@ -58,6 +208,7 @@ pub fn build(
 	stack_size: u32,
 	skip_optimization: bool,
 	target_runtime: &TargetRuntime,
+	config: &Config,
 ) -> Result<(elements::Module, Option<elements::Module>), Error> {
 	if let SourceTarget::Emscripten = source_target {
 		module = ununderscore_funcs(module);
@@ -67,9 +218,24 @@ pub fn build(
 		// 49152 is 48kb!
 		if enforce_stack_adjustment {
 			assert!(stack_size <= 1024 * 1024);
-			let (new_module, new_stack_top) =
+			let (new_module, mut new_stack_top) =
 				shrink_unknown_stack(module, 1024 * 1024 - stack_size);
 			module = new_module;
+			// The shrunk stack may reach down into the data.
+			if let Some(stack) = find_shadow_stack(&module) {
+				let overlaps = stack_overlaps(&module, &stack, stack_size);
+				if config.relocate_overlapped_stack && !overlaps.is_empty() {
+					new_stack_top = relocate_stack(&mut module, &stack, stack_size)?.top;
+					info!(
+						"Relocated the stack above the data, its top is now {:#x}",
+						new_stack_top
+					);
+				} else {
+					for overlap in overlaps {
+						warn!("{}", overlap);
+					}
+				}
+			}
 			let mut stack_top_page = new_stack_top / 65536;
 			if new_stack_top % 65536 > 0 {
 				stack_top_page += 1
@@ -80,9 +246,13 @@ pub fn build(
 		}
 	}
 
+	for fix in normalize_memory(&mut module, target_runtime) {
+		info!("{}", fix);
+	}
+
 	if let Some(runtime_type_version) = runtime_type_version {
 		let (runtime_type, runtime_version) = runtime_type_version;
-		module = inject_runtime_type(module, runtime_type, runtime_version);
+		module = inject_runtime_type(module, runtime_type, runtime_version, true)?;
 	}
 
 	let mut ctor_module = module.clone();
@@ -117,3 +287,61 @@ pub fn build(
 
 	Ok((module, Some(ctor_module)))
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	#[test]
+	fn memory_normalization() {
+		let mut module = parse_wat(
+			r#"
+(module
+	(import "seal0" "mem" (memory 1 16))
+	(func (export "call"))
+	(export "mem" (memory 0))
+)
+"#,
+		);
+
+		assert_eq!(
+			normalize_memory(&mut module, &TargetRuntime::pwasm()),
+			vec![
+				MemoryFix::RenamedImport { module: "seal0".into(), field: "mem".into() },
+				MemoryFix::RemovedExport { field: "mem".into() },
+			]
+		);
+		let import = &module.import_section().unwrap().entries()[0];
+		assert_eq!((import.module(), import.field()), ("env", "memory"));
+		let exports = module.export_section().unwrap().entries();
+		assert_eq!(exports.len(), 1);
+		assert_eq!(exports[0].field(), "call");
+
+		assert!(normalize_memory(&mut module, &TargetRuntime::pwasm()).is_empty());
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn error_source() {
+		use std::error::Error as _;
+
+		let err = build(
+			parse_wat("(module)"),
+			SourceTarget::Emscripten,
+			None,
+			&[],
+			false,
+			0,
+			false,
+			&TargetRuntime::pwasm(),
+		)
+		.unwrap_err();
+		assert!(matches!(err, Error::Optimizer(OptimizerError::NoExportSection)));
+		assert_eq!(err.source().unwrap().to_string(), "No export section in the module");
+	}
+}
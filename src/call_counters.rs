@@ -0,0 +1,242 @@
+//! Counting of the calls a module makes to each imported function.
+
+use crate::std::{fmt, mem, string::String, vec::Vec};
+
+use byteorder::{ByteOrder, LittleEndian};
+use parity_wasm::elements::{self, Instruction};
+
+/// Size of a single counter in bytes.
+const COUNTER_SIZE: u32 = 8;
+/// Size of a memory page in bytes.
+const PAGE_SIZE: u64 = 65536;
+
+#[derive(Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+	/// The module neither defines nor imports a memory.
+	NoMemory,
+	/// The counters don't fit into the initial memory at the requested offset.
+	OutOfBounds { offset: u32, size: u32 },
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match *self {
+			Error::NoMemory => write!(f, "Module has no memory to keep the call counters in"),
+			Error::OutOfBounds { offset, size } => write!(
+				f,
+				"Call counters of {} bytes at offset {} exceed the initial memory",
+				size, offset
+			),
+		}
+	}
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+/// Location of the counters injected by [`inject_call_counters`].
+///
+/// Each imported function has a little endian `u64` counter, the counters are laid out in the
+/// order of the function imports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallCounters {
+	offset: u32,
+	imports: Vec<(String, String)>,
+}
+
+/// Number of calls made to an imported function.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostCallCount {
+	pub module: String,
+	pub field: String,
+	pub calls: u64,
+}
+
+impl CallCounters {
+	/// Offset of the counters in the memory.
+	pub fn offset(&self) -> u32 {
+		self.offset
+	}
+
+	/// Size of the memory region occupied by the counters in bytes.
+	pub fn size(&self) -> u32 {
+		self.imports.len() as u32 * COUNTER_SIZE
+	}
+
+	/// Read the counters from the memory of an instance of the instrumented module.
+	///
+	/// Returns `None` if the memory doesn't contain the counters.
+	pub fn read_profile(&self, memory: &[u8]) -> Option<Vec<HostCallCount>> {
+		let region = memory.get(self.offset as usize..)?.get(..self.size() as usize)?;
+		Some(
+			self.imports
+				.iter()
+				.zip(region.chunks_exact(COUNTER_SIZE as usize))
+				.map(|((module, field), counter)| HostCallCount {
+					module: module.clone(),
+					field: field.clone(),
+					calls: LittleEndian::read_u64(counter),
+				})
+				.collect(),
+		)
+	}
+}
+
+/// Minimal size of the memory of the module in bytes.
+fn initial_memory_size(module: &elements::Module) -> Option<u64> {
+	let limits =
+		match module.memory_section().and_then(|section| section.entries().first()) {
+			Some(memory) => *memory.limits(),
+			None => module.import_section()?.entries().iter().find_map(|entry| {
+				match entry.external() {
+					elements::External::Memory(memory) => Some(*memory.limits()),
+					_ => None,
+				}
+			})?,
+		};
+	Some(u64::from(limits.initial()) * PAGE_SIZE)
+}
+
+/// Count the calls to each imported function in a region of the memory.
+///
+/// Before every `call` of an imported function, the counter of that function at `offset` is
+/// incremented. The counters must fit into the initial memory and the region must not be used by
+/// the module otherwise, e.g. by reserving it in the linker. It is not initialized, so the
+/// memory is expected to be zeroed there. Calls through a table are not counted.
+///
+/// The returned [`CallCounters`] reads the profile out of the memory after execution.
+pub fn inject_call_counters(
+	mut module: elements::Module,
+	offset: u32,
+) -> Result<(elements::Module, CallCounters), Error> {
+	let imports: Vec<(String, String)> = module
+		.import_section()
+		.map(|section| section.entries())
+		.unwrap_or(&[])
+		.iter()
+		.filter(|entry| matches!(entry.external(), elements::External::Function(_)))
+		.map(|entry| (entry.module().into(), entry.field().into()))
+		.collect();
+	let counters = CallCounters { offset, imports };
+
+	let memory_size = initial_memory_size(&module).ok_or(Error::NoMemory)?;
+	if u64::from(offset) + u64::from(counters.size()) > memory_size {
+		return Err(Error::OutOfBounds { offset, size: counters.size() })
+	}
+
+	let func_imports = counters.imports.len() as u32;
+	if let Some(code_section) = module.code_section_mut() {
+		for body in code_section.bodies_mut() {
+			let code = body.code_mut().elements_mut();
+			let calls = code
+				.iter()
+				.filter(
+					|instruction| matches!(instruction, Instruction::Call(f) if *f < func_imports),
+				)
+				.count();
+			if calls == 0 {
+				continue
+			}
+
+			let original = mem::replace(code, Vec::with_capacity(code.len() + 6 * calls));
+			for instruction in original {
+				if let Instruction::Call(func_idx) = instruction {
+					if func_idx < func_imports {
+						// The bounds check above guarantees that this doesn't overflow.
+						let counter = offset + func_idx * COUNTER_SIZE;
+						code.extend([
+							Instruction::I32Const(0),
+							Instruction::I32Const(0),
+							Instruction::I64Load(3, counter),
+							Instruction::I64Const(1),
+							Instruction::I64Add,
+							Instruction::I64Store(3, counter),
+						]);
+					}
+				}
+				code.push(instruction);
+			}
+		}
+	}
+
+	Ok((module, counters))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	#[test]
+	fn counts_import_calls() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "foo" (func $foo))
+	(import "env" "bar" (func $bar (param i32)))
+	(memory 1)
+	(func $local)
+	(func (export "call")
+		(call $foo)
+		(call $bar (i32.const 1))
+		(call $local)
+	)
+)
+"#,
+		);
+
+		let (module, counters) = inject_call_counters(module, 1024).unwrap();
+		assert_eq!(counters.size(), 16);
+		assert_eq!(
+			module.code_section().unwrap().bodies()[1].code().elements(),
+			&[
+				Instruction::I32Const(0),
+				Instruction::I32Const(0),
+				Instruction::I64Load(3, 1024),
+				Instruction::I64Const(1),
+				Instruction::I64Add,
+				Instruction::I64Store(3, 1024),
+				Instruction::Call(0),
+				Instruction::I32Const(1),
+				Instruction::I32Const(0),
+				Instruction::I32Const(0),
+				Instruction::I64Load(3, 1032),
+				Instruction::I64Const(1),
+				Instruction::I64Add,
+				Instruction::I64Store(3, 1032),
+				Instruction::Call(1),
+				Instruction::Call(2),
+				Instruction::End,
+			]
+		);
+
+		let mut memory = vec![0u8; 1040];
+		LittleEndian::write_u64(&mut memory[1024..], 3);
+		LittleEndian::write_u64(&mut memory[1032..], 1);
+		assert_eq!(
+			counters.read_profile(&memory),
+			Some(vec![
+				HostCallCount { module: "env".into(), field: "foo".into(), calls: 3 },
+				HostCallCount { module: "env".into(), field: "bar".into(), calls: 1 },
+			])
+		);
+		assert_eq!(counters.read_profile(&memory[..1039]), None);
+	}
+
+	#[test]
+	fn region_out_of_bounds() {
+		let module = parse_wat(r#"(module (import "env" "foo" (func)) (memory 1))"#);
+		assert_eq!(
+			inject_call_counters(module, 65530).unwrap_err(),
+			Error::OutOfBounds { offset: 65530, size: 8 }
+		);
+
+		let module = parse_wat(r#"(module (import "env" "foo" (func)))"#);
+		assert_eq!(inject_call_counters(module, 0).unwrap_err(), Error::NoMemory);
+	}
+}
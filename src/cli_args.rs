@@ -0,0 +1,373 @@
+//! Declarative specifications of the arguments of the command line tools.
+//!
+//! The tools describe their arguments with [`ArgSpec`], which is turned into the clap
+//! application as well as into the man page, so both stay in sync. Every tool gets the
+//! `--generate-completions <shell>` and `--generate-manpage` options, and the arguments shared
+//! between the tools are defined once here.
+
+use clap::{App, Arg, ArgMatches, Shell};
+use std::{io, process};
+
+/// Name of the argument requesting the completion script.
+pub const COMPLETIONS_ARG: &str = "generate_completions";
+
+/// Name of the argument requesting the man page.
+pub const MANPAGE_ARG: &str = "generate_manpage";
+
+/// How an argument is given on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgKind<'a> {
+	/// The positional argument at the index, starting at 1. Required unless a completion script
+	/// or the man page is requested.
+	Positional(u64),
+	/// An option without a value.
+	Flag,
+	/// An option with a value of the given name.
+	Value(&'a str),
+	/// An option with a value of the given name which may be given several times.
+	Values(&'a str),
+}
+
+/// Specification of a command line argument.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArgSpec<'a> {
+	name: &'a str,
+	long: Option<&'a str>,
+	short: Option<&'a str>,
+	kind: ArgKind<'a>,
+	help: &'a str,
+	default_value: Option<&'a str>,
+	possible_values: &'a [&'a str],
+	requires: Option<&'a str>,
+}
+
+impl<'a> ArgSpec<'a> {
+	/// The positional argument `name` at `index`, starting at 1.
+	pub const fn positional(name: &'a str, index: u64, help: &'a str) -> Self {
+		Self::new(name, None, ArgKind::Positional(index), help)
+	}
+
+	/// The flag `--long`.
+	pub const fn flag(name: &'a str, long: &'a str, help: &'a str) -> Self {
+		Self::new(name, Some(long), ArgKind::Flag, help)
+	}
+
+	/// The option `--long <value_name>`.
+	pub const fn option(name: &'a str, long: &'a str, value_name: &'a str, help: &'a str) -> Self {
+		Self::new(name, Some(long), ArgKind::Value(value_name), help)
+	}
+
+	/// The option `--long <value_name>` which may be given several times.
+	pub const fn repeated(
+		name: &'a str,
+		long: &'a str,
+		value_name: &'a str,
+		help: &'a str,
+	) -> Self {
+		Self::new(name, Some(long), ArgKind::Values(value_name), help)
+	}
+
+	const fn new(name: &'a str, long: Option<&'a str>, kind: ArgKind<'a>, help: &'a str) -> Self {
+		ArgSpec {
+			name,
+			long,
+			short: None,
+			kind,
+			help,
+			default_value: None,
+			possible_values: &[],
+			requires: None,
+		}
+	}
+
+	/// Accept `-short` as well.
+	pub const fn with_short(mut self, short: &'a str) -> Self {
+		self.short = Some(short);
+		self
+	}
+
+	/// Use `value` if the option isn't given.
+	pub const fn with_default_value(mut self, value: &'a str) -> Self {
+		self.default_value = Some(value);
+		self
+	}
+
+	/// Only accept the given values.
+	pub const fn with_possible_values(mut self, values: &'a [&'a str]) -> Self {
+		self.possible_values = values;
+		self
+	}
+
+	/// Only accept the argument along with the argument `name`.
+	pub const fn with_requires(mut self, name: &'a str) -> Self {
+		self.requires = Some(name);
+		self
+	}
+
+	/// Name the tool looks the argument up by.
+	pub fn name(&self) -> &'a str {
+		self.name
+	}
+
+	fn arg<'b>(&self) -> Arg<'a, 'b>
+	where
+		'a: 'b,
+	{
+		let mut arg = Arg::with_name(self.name).help(self.help);
+		if let Some(long) = self.long {
+			arg = arg.long(long);
+		}
+		if let Some(short) = self.short {
+			arg = arg.short(short);
+		}
+		arg = match self.kind {
+			ArgKind::Positional(index) =>
+				arg.index(index).required_unless_one(&[COMPLETIONS_ARG, MANPAGE_ARG]),
+			ArgKind::Flag => arg,
+			ArgKind::Value(value_name) => arg.takes_value(true).value_name(value_name),
+			ArgKind::Values(value_name) =>
+				arg.takes_value(true).value_name(value_name).multiple(true).number_of_values(1),
+		};
+		if let Some(value) = self.default_value {
+			arg = arg.default_value(value);
+		}
+		if !self.possible_values.is_empty() {
+			arg = arg.possible_values(self.possible_values);
+		}
+		if let Some(requires) = self.requires {
+			arg = arg.requires(requires);
+		}
+		arg
+	}
+}
+
+/// The input module, shared by the tools reading a single module.
+pub const INPUT: ArgSpec<'static> = ArgSpec::positional("input", 1, "Input WASM file");
+
+/// The output module, shared by the tools writing a single module.
+pub const OUTPUT: ArgSpec<'static> = ArgSpec::positional("output", 2, "Output WASM file");
+
+/// Exports of mutable globals allowed by the policy checks.
+pub const ALLOW_MUTABLE_EXPORT: ArgSpec<'static> = ArgSpec::repeated(
+	"allow_mutable_export",
+	"allow-mutable-export",
+	"name",
+	"Allow exporting a mutable global under this name",
+);
+
+/// Limit of the memory growth checked by the policy checks.
+pub const MAX_MEMORY_GROWTH: ArgSpec<'static> = ArgSpec::option(
+	"max_memory_growth",
+	"max-memory-growth",
+	"pages",
+	"Maximum number of pages an exported function may grow the memory by",
+);
+
+/// Stack limit to instrument or verify the module with.
+pub const STACK_LIMIT: ArgSpec<'static> = ArgSpec::option(
+	"stack_limit",
+	"stack-limit",
+	"limit",
+	"Stack limit the stack height limiter enforces",
+);
+
+/// Stripping of the custom sections.
+pub const STRIP: ArgSpec<'static> =
+	ArgSpec::flag("strip", "strip", "Remove the name section and all other custom sections");
+
+/// Custom sections kept by [`STRIP`].
+pub const KEEP_SECTIONS: ArgSpec<'static> = ArgSpec::option(
+	"keep_sections",
+	"keep-sections",
+	"sections",
+	"Comma-separated list of custom sections to keep when stripping",
+)
+.with_requires("strip");
+
+/// Specification of a command line tool.
+#[derive(Debug, Clone, Copy)]
+pub struct CommandSpec<'a> {
+	name: &'a str,
+	about: &'a str,
+	args: &'a [ArgSpec<'a>],
+}
+
+impl<'a> CommandSpec<'a> {
+	/// The tool `name` described by `about` and taking `args`.
+	pub const fn new(name: &'a str, about: &'a str, args: &'a [ArgSpec<'a>]) -> Self {
+		CommandSpec { name, about, args }
+	}
+
+	/// The clap application of the tool, including the options generating the completion script
+	/// and the man page.
+	pub fn app<'b>(&self) -> App<'a, 'b>
+	where
+		'a: 'b,
+	{
+		App::new(self.name)
+			.version(env!("CARGO_PKG_VERSION"))
+			.about(self.about)
+			.args(&self.args.iter().map(ArgSpec::arg).collect::<Vec<_>>())
+			.arg(
+				Arg::with_name(COMPLETIONS_ARG)
+					.long("generate-completions")
+					.takes_value(true)
+					.value_name("shell")
+					.possible_values(&Shell::variants())
+					.help("Print the completion script for the shell and exit"),
+			)
+			.arg(
+				Arg::with_name(MANPAGE_ARG)
+					.long("generate-manpage")
+					.help("Print the man page in roff format and exit"),
+			)
+	}
+
+	/// Parse the command line.
+	///
+	/// Prints the completion script or the man page and exits if either is requested.
+	pub fn get_matches(&self) -> ArgMatches<'a> {
+		let mut app = self.app();
+		let matches = app.clone().get_matches();
+		if let Some(shell) = matches.value_of(COMPLETIONS_ARG) {
+			let shell = shell.parse::<Shell>().expect("possible values are checked by clap; qed");
+			app.gen_completions_to(self.name, shell, &mut io::stdout());
+			process::exit(0);
+		}
+		if matches.is_present(MANPAGE_ARG) {
+			print!("{}", self.manpage());
+			process::exit(0);
+		}
+		matches
+	}
+
+	/// The man page of the tool in roff format.
+	pub fn manpage(&self) -> String {
+		let name = roff_escape(self.name);
+		let about = roff_escape(self.about);
+		let mut page = format!(
+			".TH {} 1 \"\" \"pwasm\\-utils {}\"\n",
+			name.to_uppercase(),
+			env!("CARGO_PKG_VERSION")
+		);
+		page.push_str(&format!(".SH NAME\n{} \\- {}\n", name, about));
+		page.push_str(&format!(".SH SYNOPSIS\n.B {}\n[OPTIONS]", name));
+		let mut positionals: Vec<_> = self
+			.args
+			.iter()
+			.filter_map(|arg| match arg.kind {
+				ArgKind::Positional(index) => Some((index, arg.name)),
+				_ => None,
+			})
+			.collect();
+		positionals.sort_unstable();
+		for (_, name) in positionals {
+			page.push_str(&format!(" \\fI{}\\fR", roff_escape(name)));
+		}
+		page.push_str(&format!("\n.SH DESCRIPTION\n{}.\n.SH OPTIONS\n", about));
+
+		let shells = Shell::variants().join(", ");
+		let generators = [
+			(
+				"\\fB\\-\\-generate\\-completions\\fR \\fIshell\\fR".into(),
+				format!(
+					"Print the completion script for the shell and exit. Possible values: {}.",
+					shells
+				),
+			),
+			(
+				"\\fB\\-\\-generate\\-manpage\\fR".into(),
+				"Print the man page in roff format and exit.".into(),
+			),
+			("\\fB\\-h\\fR, \\fB\\-\\-help\\fR".into(), "Print help information.".into()),
+			("\\fB\\-V\\fR, \\fB\\-\\-version\\fR".into(), "Print version information.".into()),
+		];
+		let options = self.args.iter().map(|arg| (synopsis(arg), description(arg)));
+		for (synopsis, description) in options.chain(generators) {
+			page.push_str(&format!(".TP\n{}\n{}\n", synopsis, roff_escape(&description)));
+		}
+		page
+	}
+}
+
+/// How the argument is written in the options of the man page, with roff font changes.
+fn synopsis(arg: &ArgSpec) -> String {
+	let mut names = Vec::new();
+	if let Some(short) = arg.short {
+		names.push(format!("\\fB\\-{}\\fR", roff_escape(short)));
+	}
+	if let Some(long) = arg.long {
+		names.push(format!("\\fB\\-\\-{}\\fR", roff_escape(long)));
+	}
+	let mut synopsis = names.join(", ");
+	match arg.kind {
+		ArgKind::Positional(_) => synopsis = format!("\\fI{}\\fR", roff_escape(arg.name)),
+		ArgKind::Flag => {},
+		ArgKind::Value(value_name) =>
+			synopsis.push_str(&format!(" \\fI{}\\fR", roff_escape(value_name))),
+		ArgKind::Values(value_name) =>
+			synopsis.push_str(&format!(" \\fI{}\\fR ...", roff_escape(value_name))),
+	}
+	synopsis
+}
+
+/// The help of the argument along with its possible and default values.
+fn description(arg: &ArgSpec) -> String {
+	let mut description = String::from(arg.help);
+	if !arg.possible_values.is_empty() {
+		description.push_str(&format!(" Possible values: {}.", arg.possible_values.join(", ")));
+	}
+	if let Some(value) = arg.default_value {
+		description.push_str(&format!(" Default: {}.", value));
+	}
+	description
+}
+
+/// Escape the characters roff interprets within text.
+fn roff_escape(text: &str) -> String {
+	let escaped = text.replace('\\', "\\e").replace('-', "\\-");
+	// A line starting with a dot or a quote is a request.
+	if escaped.starts_with('.') || escaped.starts_with('\'') {
+		format!("\\&{}", escaped)
+	} else {
+		escaped
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn manpage() {
+		let args = [
+			INPUT,
+			ArgSpec::option("exports", "exports", "functions", "Exports to keep").with_short("e"),
+			ALLOW_MUTABLE_EXPORT,
+			ArgSpec::option("runtime", "target-runtime", "runtime", "Runtime")
+				.with_default_value("pwasm")
+				.with_possible_values(&["substrate", "pwasm"]),
+		];
+		let spec = CommandSpec::new("wasm-tool", "Process a module", &args);
+
+		let page = spec.manpage();
+		assert!(page.starts_with(".TH WASM\\-TOOL 1 "));
+		assert!(page.contains("\n.SH NAME\nwasm\\-tool \\- Process a module\n"));
+		assert!(page.contains("\n.B wasm\\-tool\n[OPTIONS] \\fIinput\\fR\n"));
+		assert!(page.contains("\n.TP\n\\fB\\-e\\fR, \\fB\\-\\-exports\\fR \\fIfunctions\\fR\n"));
+		assert!(page.contains(
+			"\\fB\\-\\-allow\\-mutable\\-export\\fR \\fIname\\fR ...\nAllow exporting a mutable \
+			 global under this name\n"
+		));
+		assert!(page.contains("Runtime Possible values: substrate, pwasm. Default: pwasm.\n"));
+
+		let matches = spec
+			.app()
+			.get_matches_from_safe(["wasm-tool", "in.wasm", "-e", "call"])
+			.expect("the arguments are valid");
+		assert_eq!(matches.value_of("exports"), Some("call"));
+		assert_eq!(matches.value_of("runtime"), Some("pwasm"));
+		assert!(spec.app().get_matches_from_safe(["wasm-tool", "--generate-manpage"]).is_ok());
+		assert!(spec.app().get_matches_from_safe(["wasm-tool"]).is_err());
+	}
+}
@@ -0,0 +1,183 @@
+//! Generation of Rust source scaffolding the host side of a module.
+
+use std::{collections::BTreeMap, fmt::Write};
+
+use parity_wasm::elements::{self, External, Type, ValueType};
+
+const KEYWORDS: &[&str] = &[
+	"as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern",
+	"false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub",
+	"ref", "return", "self", "Self", "static", "struct", "super", "trait", "true", "type",
+	"unsafe", "use", "where", "while",
+];
+
+/// Rust identifier for an arbitrary import name.
+fn identifier(name: &str) -> String {
+	let mut ident: String =
+		name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+	if ident.is_empty() || ident.starts_with(|c: char| c.is_ascii_digit()) {
+		ident.insert(0, '_');
+	}
+	if KEYWORDS.contains(&ident.as_str()) {
+		ident.push('_');
+	}
+	ident
+}
+
+fn rust_type(value_type: &ValueType) -> &'static str {
+	match value_type {
+		ValueType::I32 => "i32",
+		ValueType::I64 => "i64",
+		ValueType::F32 => "f32",
+		ValueType::F64 => "f64",
+		#[cfg(feature = "simd")]
+		ValueType::V128 => "u128",
+	}
+}
+
+/// Generate a trait with a method for each function the module imports, and a stub implementing
+/// it by panicking.
+///
+/// The trait is named `trait_name` and the stub `<trait_name>Stub`. Methods are named after the
+/// imported fields, qualified by the module name if several modules export the same field. The
+/// generated code is meant as a starting point for a test harness of the module.
+pub fn host_shim(module: &elements::Module, trait_name: &str) -> String {
+	let types = module.type_section().map(|section| section.types()).unwrap_or(&[]);
+	let imports = module
+		.import_section()
+		.map(|section| section.entries())
+		.unwrap_or(&[])
+		.iter()
+		.filter_map(|entry| match entry.external() {
+			External::Function(type_idx) => {
+				let Type::Function(ty) = types.get(*type_idx as usize)?;
+				Some((entry.module(), entry.field(), ty))
+			},
+			_ => None,
+		})
+		.collect::<Vec<_>>();
+
+	let mut field_count = BTreeMap::new();
+	for (_, field, _) in &imports {
+		*field_count.entry(identifier(field)).or_insert(0) += 1;
+	}
+
+	let methods = imports
+		.iter()
+		.map(|(module, field, ty)| {
+			let name = match identifier(field) {
+				name if field_count[&name] > 1 => identifier(&format!("{}_{}", module, field)),
+				name => name,
+			};
+			let params = ty
+				.params()
+				.iter()
+				.enumerate()
+				.map(|(idx, param)| format!(", arg{}: {}", idx, rust_type(param)))
+				.collect::<String>();
+			let results = match ty.results() {
+				[] => String::new(),
+				[result] => format!(" -> {}", rust_type(result)),
+				results => format!(
+					" -> ({})",
+					results.iter().map(rust_type).collect::<Vec<_>>().join(", ")
+				),
+			};
+			(
+				format!("{}::{}", module, field),
+				format!("fn {}(&mut self{}){}", name, params, results),
+			)
+		})
+		.collect::<Vec<_>>();
+
+	let mut source = String::new();
+	// Writing to a `String` doesn't fail.
+	let _ = writeln!(source, "/// Host functions imported by the module.");
+	let _ = writeln!(source, "pub trait {} {{", trait_name);
+	for (import, signature) in &methods {
+		let _ = writeln!(source, "\t/// `{}`", import);
+		let _ = writeln!(source, "\t{};", signature);
+	}
+	let _ = writeln!(source, "}}\n");
+	let _ = writeln!(source, "/// Implementation of [`{}`] panicking on every call.", trait_name);
+	let _ = writeln!(source, "pub struct {}Stub;\n", trait_name);
+	let _ = writeln!(source, "impl {} for {}Stub {{", trait_name, trait_name);
+	for (idx, (import, signature)) in methods.iter().enumerate() {
+		if idx > 0 {
+			let _ = writeln!(source);
+		}
+		// The arguments are unused.
+		let _ = writeln!(source, "\t{} {{", signature.replace(", arg", ", _arg"));
+		let _ = writeln!(source, "\t\tunimplemented!(\"{}\")", import.escape_default());
+		let _ = writeln!(source, "\t}}");
+	}
+	let _ = writeln!(source, "}}");
+	source
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use indoc::indoc;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	#[test]
+	fn trait_and_stub() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "memory" (memory 1))
+	(import "seal0" "call" (func (param i32 i64) (result i32)))
+	(import "seal1" "call" (func (param i32)))
+	(import "env" "type" (func (result f64)))
+	(import "env" "ext.log" (func))
+)
+"#,
+		);
+
+		assert_eq!(
+			host_shim(&module, "Env"),
+			indoc!(
+				"
+				/// Host functions imported by the module.
+				pub trait Env {
+					/// `seal0::call`
+					fn seal0_call(&mut self, arg0: i32, arg1: i64) -> i32;
+					/// `seal1::call`
+					fn seal1_call(&mut self, arg0: i32);
+					/// `env::type`
+					fn type_(&mut self) -> f64;
+					/// `env::ext.log`
+					fn ext_log(&mut self);
+				}
+
+				/// Implementation of [`Env`] panicking on every call.
+				pub struct EnvStub;
+
+				impl Env for EnvStub {
+					fn seal0_call(&mut self, _arg0: i32, _arg1: i64) -> i32 {
+						unimplemented!(\"seal0::call\")
+					}
+
+					fn seal1_call(&mut self, _arg0: i32) {
+						unimplemented!(\"seal1::call\")
+					}
+
+					fn type_(&mut self) -> f64 {
+						unimplemented!(\"env::type\")
+					}
+
+					fn ext_log(&mut self) {
+						unimplemented!(\"env::ext.log\")
+					}
+				}
+				"
+			)
+			.trim_start()
+		);
+	}
+}
@@ -0,0 +1,128 @@
+//! Gas metering and stack height limiting in a single pass.
+
+use crate::std::fmt;
+
+use crate::{gas, rules::Rules, stack_height};
+use parity_wasm::elements;
+
+/// Failure of [`inject_gas_and_stack_limiter`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+	Gas(gas::Error),
+	StackHeight(stack_height::Error),
+}
+
+impl From<gas::Error> for Error {
+	fn from(err: gas::Error) -> Self {
+		Error::Gas(err)
+	}
+}
+
+impl From<stack_height::Error> for Error {
+	fn from(err: stack_height::Error) -> Self {
+		Error::StackHeight(err)
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			Error::Gas(err) => write!(f, "Gas metering failed: {}", err),
+			Error::StackHeight(err) => write!(f, "Stack height limiting failed: {}", err),
+		}
+	}
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			Error::Gas(err) => Some(err),
+			Error::StackHeight(err) => Some(err),
+		}
+	}
+}
+
+/// Meter the module with gas and limit its stack height, rewriting every function body once.
+///
+/// This is equivalent to [`gas::inject_gas_counter_with_config`] followed by
+/// [`stack_height::inject_limiter_with_config`], with the following differences:
+///
+/// - the stack costs are computed from the bodies before metering, so the code charging gas
+///   doesn't count towards the stack height;
+/// - the calls of the functions appended by the metering, e.g. the memory grow counter, aren't
+///   wrapped with stack height checks. These functions don't call any other function.
+///
+/// Like in the sequential application, the stack height checks and the thunks aren't charged
+/// for.
+pub fn inject_gas_and_stack_limiter<R: Rules>(
+	mut module: elements::Module,
+	rules: &R,
+	gas_config: &gas::Config,
+	stack_config: &stack_height::Config,
+) -> Result<elements::Module, Error> {
+	if stack_height::check_static_bound(&mut module, stack_config)? {
+		let (module, _) = gas::instrument(module, rules, gas_config, |_| Ok::<_, Error>(()))?;
+		return Ok(module)
+	}
+	let mut stack = stack_height::Context::new(&mut module, stack_config)?;
+	if let Some(func_idx) = gas_config.inserted_import(&module) {
+		stack.insert_function(func_idx);
+	}
+	if let Some(global_idx) = gas_config.inserted_global(&module) {
+		stack.insert_global(global_idx);
+	}
+
+	let (module, _) = gas::instrument(module, rules, gas_config, |body| {
+		stack.instrument_body(body).map_err(Error::StackHeight)
+	})?;
+
+	Ok(stack.finish(module, stack_config)?)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::rules;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	#[test]
+	fn matches_sequential_passes() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "ext" (func $ext))
+	(func $callee (param i32) (result i32)
+		(i32.add (local.get 0) (i32.const 1))
+	)
+	(func (export "call") (param i32) (result i32)
+		(call $ext)
+		(call $callee (local.get 0))
+	)
+)
+"#,
+		);
+		let rules = rules::Set::default();
+		let gas_config = gas::Config::new("env");
+		let stack_config = stack_height::Config::new(1024);
+
+		let combined =
+			inject_gas_and_stack_limiter(module.clone(), &rules, &gas_config, &stack_config)
+				.unwrap();
+		let sequential = stack_height::inject_limiter_with_config(
+			gas::inject_gas_counter_with_config(module, &rules, &gas_config).unwrap(),
+			&stack_config,
+		)
+		.unwrap();
+
+		// Metering doesn't increase the maximal height of these bodies, so the stack costs agree.
+		assert_eq!(combined, sequential);
+		assert_eq!(stack_height::verify(&combined, 1024), Ok(()));
+		assert_eq!(gas::verify(&combined, &rules, "env"), Ok(()));
+	}
+}
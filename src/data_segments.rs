@@ -0,0 +1,179 @@
+//! Detection of the data segments the code can't refer to.
+
+use crate::std::{ops::Range, vec::Vec};
+
+use byteorder::{ByteOrder, LittleEndian};
+use parity_wasm::elements::{self, Instruction};
+
+/// Offset of a memory access from its address operand.
+pub(crate) fn memory_offset(instruction: &Instruction) -> Option<u32> {
+	use parity_wasm::elements::Instruction::*;
+
+	match instruction {
+		I32Load(_, offset) |
+		I64Load(_, offset) |
+		F32Load(_, offset) |
+		F64Load(_, offset) |
+		I32Load8S(_, offset) |
+		I32Load8U(_, offset) |
+		I32Load16S(_, offset) |
+		I32Load16U(_, offset) |
+		I64Load8S(_, offset) |
+		I64Load8U(_, offset) |
+		I64Load16S(_, offset) |
+		I64Load16U(_, offset) |
+		I64Load32S(_, offset) |
+		I64Load32U(_, offset) |
+		I32Store(_, offset) |
+		I64Store(_, offset) |
+		F32Store(_, offset) |
+		F64Store(_, offset) |
+		I32Store8(_, offset) |
+		I32Store16(_, offset) |
+		I64Store8(_, offset) |
+		I64Store16(_, offset) |
+		I64Store32(_, offset) => Some(*offset),
+		_ => None,
+	}
+}
+
+/// Push the constants of `code` which may be addresses to `addresses`.
+///
+/// Returns `false` if the code may access the memory in ways which aren't understood.
+fn push_addresses(code: &[Instruction], addresses: &mut Vec<u32>) -> bool {
+	let mut previous: Option<&Instruction> = None;
+	for instruction in code {
+		match instruction {
+			Instruction::I32Const(value) => addresses.push(*value as u32),
+			Instruction::I64Const(value) => addresses.extend(u32::try_from(*value as u64).ok()),
+			// The accesses of these proposals aren't decoded.
+			#[cfg(feature = "simd")]
+			Instruction::Simd(_) => return false,
+			#[cfg(feature = "atomics")]
+			Instruction::Atomics(_) => return false,
+			_ =>
+				if let Some(offset) = memory_offset(instruction) {
+					addresses.push(offset);
+					if let Some(Instruction::I32Const(address)) = previous {
+						addresses.push((*address as u32).wrapping_add(offset));
+					}
+				},
+		}
+		previous = Some(instruction);
+	}
+	true
+}
+
+/// Indices of the data segments no address known to the module points into, in ascending order.
+///
+/// Code compiled from Rust or C reaches data only through pointers derived from the address of
+/// the data they point to, which is a constant somewhere in the module. The constants are taken
+/// from the code, the initializers of globals and element segments, the offsets of the memory
+/// accesses, and all little endian 32 bit words in the segments which are used. A segment is used
+/// if any of them points into it or right past its end. Code computing the addresses of data in a
+/// segment from the address of data in another one breaks this assumption.
+///
+/// Returns no segments if some segment is passive or placed at an offset which isn't constant.
+pub fn unused_data_segments(module: &elements::Module) -> Vec<usize> {
+	let segments = module.data_section().map_or(&[][..], |section| section.entries());
+	let mut ranges: Vec<Range<u32>> = Vec::with_capacity(segments.len());
+	for segment in segments {
+		let code = segment.offset().as_ref().map(|offset| offset.code());
+		match code {
+			Some([Instruction::I32Const(offset), Instruction::End]) => {
+				let start = *offset as u32;
+				ranges.push(start..start.saturating_add(segment.value().len() as u32));
+			},
+			_ => return Vec::new(),
+		}
+	}
+
+	let mut addresses = Vec::new();
+	let bodies = module.code_section().map_or(&[][..], |section| section.bodies());
+	let globals = module.global_section().map_or(&[][..], |section| section.entries());
+	let elements = module.elements_section().map_or(&[][..], |section| section.entries());
+	let codes = bodies
+		.iter()
+		.map(|body| body.code().elements())
+		.chain(globals.iter().map(|global| global.init_expr().code()))
+		.chain(elements.iter().filter_map(|segment| Some(segment.offset().as_ref()?.code())));
+	for code in codes {
+		if !push_addresses(code, &mut addresses) {
+			return Vec::new()
+		}
+	}
+
+	let mut used = vec![false; segments.len()];
+	while let Some(address) = addresses.pop() {
+		for (idx, range) in ranges.iter().enumerate() {
+			if used[idx] || address < range.start || address > range.end {
+				continue
+			}
+			used[idx] = true;
+			let value = segments[idx].value();
+			addresses
+				.extend((4..=value.len()).map(|end| LittleEndian::read_u32(&value[end - 4..end])));
+		}
+	}
+	used.iter()
+		.enumerate()
+		.filter(|(_, used)| !**used)
+		.map(|(idx, _)| idx)
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	#[test]
+	fn unused_segments() {
+		let module = parse_wat(
+			r#"
+(module
+	(memory 1)
+	(global $ptr i32 (i32.const 0x100))
+	;; Used by the global.
+	(data (i32.const 0x100) "\00\02\00\00")
+	;; Used by the pointer stored in the previous segment.
+	(data (i32.const 0x200) "abcd")
+	;; Used by the offset of the load.
+	(data (i32.const 0x300) "abcd")
+	;; Used by the offset of the store.
+	(data (i32.const 0x400) "abcd")
+	;; Unused.
+	(data (i32.const 0x500) "abcd")
+	;; A pointer past the end of the segment counts.
+	(data (i32.const 0x600) "abcd")
+	(func (export "call") (param i32) (result i32)
+		(i32.store offset=0x400 (i32.const 2) (i32.const 0x604))
+		(i32.load offset=0x300 (local.get 0))
+	)
+)
+"#,
+		);
+
+		assert_eq!(unused_data_segments(&module), vec![4]);
+	}
+
+	#[test]
+	fn unknown_offset() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "base" (global i32))
+	(memory 1)
+	(data (i32.const 0) "abcd")
+	(data (global.get 0) "abcd")
+)
+"#,
+		);
+
+		assert!(unused_data_segments(&module).is_empty());
+	}
+}
@@ -0,0 +1,280 @@
+//! Removal of the code which can't be executed within a function.
+
+use crate::std::{mem, vec::Vec};
+
+use crate::stack_effect::{function_stack_effects, resolve_func_type};
+use parity_wasm::elements::{self, BlockType, Instruction};
+
+/// Whether control never passes from the instruction to the next one.
+fn is_transfer(instruction: &Instruction) -> bool {
+	matches!(
+		instruction,
+		Instruction::Unreachable |
+			Instruction::Br(_) |
+			Instruction::BrTable(_) |
+			Instruction::Return
+	)
+}
+
+/// Remove the instructions following an unconditional transfer of control up to the end of the
+/// enclosing block, or its `else`.
+///
+/// Returns the number of removed instructions.
+fn remove_unreachable(code: &mut Vec<Instruction>) -> usize {
+	let original = mem::take(code);
+	let len = original.len();
+	// Nesting depth within the unreachable code, if the code is unreachable.
+	let mut unreachable: Option<u32> = None;
+	for instruction in original {
+		match (unreachable, &instruction) {
+			(None, _) => {
+				if is_transfer(&instruction) {
+					unreachable = Some(0);
+				}
+				code.push(instruction);
+			},
+			(Some(0), Instruction::End | Instruction::Else) => {
+				unreachable = None;
+				code.push(instruction);
+			},
+			(Some(depth), Instruction::Block(_) | Instruction::Loop(_) | Instruction::If(_)) =>
+				unreachable = Some(depth + 1),
+			(Some(depth), Instruction::End) => unreachable = Some(depth - 1),
+			(Some(_), _) => {},
+		}
+	}
+	len - code.len()
+}
+
+/// Positions of the `br 0` instructions in the body of the defined function `func_idx` which
+/// branch to the `end` or `else` right after them with exactly the values the block results in.
+///
+/// Falling through to the end has the same effect, so they can be removed. A `br 0` leaving
+/// further values on the stack can't be removed, since a block can only end with its results.
+fn redundant_branches(module: &elements::Module, func_idx: u32) -> Vec<usize> {
+	let func_imports = module.import_count(elements::ImportCountType::Function) as u32;
+	let (instructions, signature) = match (
+		function_stack_effects(module, func_idx),
+		resolve_func_type(func_imports + func_idx, module),
+	) {
+		(Ok(instructions), Ok(signature)) => (instructions, signature),
+		_ => return Vec::new(),
+	};
+	let code = module.code_section().expect("the function has a body; qed").bodies()
+		[func_idx as usize]
+		.code()
+		.elements();
+	// The height of the value stack within each open block, its arity and whether it is a loop.
+	let mut frames = vec![(0u32, signature.results().len() as u32, false)];
+	let mut redundant = Vec::new();
+	for (pos, item) in instructions.enumerate() {
+		let (instruction, effect) = match item {
+			Ok(item) => item,
+			Err(_) => return Vec::new(),
+		};
+		let frame = match frames.last_mut() {
+			Some(frame) => frame,
+			None => return Vec::new(),
+		};
+		if let Instruction::Br(0) = instruction {
+			let (height, arity, is_loop) = *frame;
+			let next = code.get(pos + 1);
+			if !is_loop &&
+				height == arity &&
+				matches!(next, Some(Instruction::End | Instruction::Else))
+			{
+				redundant.push(pos);
+			}
+		}
+		frame.0 = frame.0.saturating_sub(effect.pops) + effect.pushes;
+		match instruction {
+			Instruction::Block(ty) | Instruction::Loop(ty) | Instruction::If(ty) => {
+				let arity = if *ty == BlockType::NoResult { 0 } else { 1 };
+				frames.push((0, arity, matches!(instruction, Instruction::Loop(_))));
+			},
+			Instruction::Else => frame.0 = 0,
+			Instruction::End => {
+				let (_, arity, _) = frames.pop().expect("the frame was found above; qed");
+				if let Some(parent) = frames.last_mut() {
+					parent.0 += arity;
+				}
+			},
+			_ => {},
+		}
+	}
+	redundant
+}
+
+/// Remove the code of the function bodies of `module` which can't be executed.
+///
+/// The instructions following an `unreachable`, `br`, `br_table` or `return` up to the end of the
+/// enclosing block, or its `else`, are removed, along with the blocks among them. A `br 0` right
+/// before the `end` of a block which isn't a loop, or before the `else` of an `if`, is removed if
+/// the stack holds exactly the results of the block, since falling through has the same effect.
+///
+/// No instruction which can be executed is removed, so the pass can run after the instrumentation.
+/// The charges injected by the gas metering are left as they are, even if they include the costs
+/// of removed instructions.
+///
+/// Returns the number of removed instructions.
+pub fn remove_dead_code(module: &mut elements::Module) -> usize {
+	let mut removed = 0;
+	let bodies = module.code_section_mut().map_or(&mut [][..], |section| section.bodies_mut());
+	for body in bodies.iter_mut() {
+		removed += remove_unreachable(body.code_mut().elements_mut());
+	}
+
+	let func_count = module.code_section().map_or(0, |section| section.bodies().len());
+	let redundant: Vec<Vec<usize>> = (0..func_count as u32)
+		.map(|func_idx| redundant_branches(module, func_idx))
+		.collect();
+	let bodies = module.code_section_mut().map_or(&mut [][..], |section| section.bodies_mut());
+	for (body, redundant) in bodies.iter_mut().zip(redundant) {
+		let mut pos = 0;
+		body.code_mut().elements_mut().retain(|_| {
+			pos += 1;
+			!redundant.contains(&(pos - 1))
+		});
+		removed += redundant.len();
+	}
+	removed
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use parity_wasm::elements::Instruction::*;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	fn validate_module(module: &elements::Module) {
+		let binary = elements::serialize(module.clone()).expect("Failed to serialize");
+		wabt::Module::read_binary(&binary, &Default::default())
+			.expect("Wabt failed to read final binary")
+			.validate()
+			.expect("Invalid module");
+	}
+
+	fn code(module: &elements::Module, func_idx: usize) -> &[Instruction] {
+		module.code_section().unwrap().bodies()[func_idx].code().elements()
+	}
+
+	#[test]
+	fn unreachable_code() {
+		let mut module = parse_wat(
+			r#"
+(module
+	(func (param i32) (result i32)
+		(if (local.get 0)
+			(then
+				(return (i32.const 1))
+				(block (drop (i32.const 2)))
+				(nop)
+			)
+			(else
+				(unreachable)
+				(drop (i32.const 3))
+			)
+		)
+		(block
+			(br_table 0 0 (local.get 0))
+			(nop)
+		)
+		(i32.const 4)
+		(return)
+		(drop)
+		(i32.const 5)
+	)
+)
+"#,
+		);
+
+		assert_eq!(remove_dead_code(&mut module), 10);
+		assert_eq!(
+			code(&module, 0),
+			&[
+				GetLocal(0),
+				If(BlockType::NoResult),
+				I32Const(1),
+				Return,
+				Else,
+				Unreachable,
+				End,
+				Block(BlockType::NoResult),
+				GetLocal(0),
+				BrTable(Box::new(elements::BrTableData { table: Box::new([0]), default: 0 })),
+				End,
+				I32Const(4),
+				Return,
+				End,
+			]
+		);
+		validate_module(&module);
+		assert_eq!(remove_dead_code(&mut module), 0);
+	}
+
+	#[test]
+	fn branches_to_end() {
+		let mut module = parse_wat(
+			r#"
+(module
+	(func (param i32) (result i32)
+		(block (result i32)
+			(i32.const 1)
+			(br 0)
+		)
+		(block (result i32)
+			(i32.const 2)
+			(i32.const 3)
+			(br 0)
+		)
+		(block
+			(loop
+				(br_if 1 (local.get 0))
+				(br 0)
+			)
+		)
+		(if (local.get 0)
+			(then (br 0))
+			(else (br 0))
+		)
+		(drop)
+		(br 0)
+	)
+)
+"#,
+		);
+
+		assert_eq!(remove_dead_code(&mut module), 4);
+		assert_eq!(
+			code(&module, 0),
+			&[
+				Block(BlockType::Value(elements::ValueType::I32)),
+				I32Const(1),
+				End,
+				Block(BlockType::Value(elements::ValueType::I32)),
+				I32Const(2),
+				I32Const(3),
+				Br(0),
+				End,
+				Block(BlockType::NoResult),
+				Loop(BlockType::NoResult),
+				GetLocal(0),
+				BrIf(1),
+				Br(0),
+				End,
+				End,
+				GetLocal(0),
+				If(BlockType::NoResult),
+				Else,
+				End,
+				Drop,
+				End,
+			]
+		);
+		validate_module(&module);
+	}
+}
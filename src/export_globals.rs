@@ -1,50 +1,48 @@
 use parity_wasm::elements;
 
-use crate::optimizer::{export_section, global_section};
+use crate::{
+	internal_globals::internal_globals,
+	sections::{get_or_insert_export_section, global_section},
+};
 
 /// Export all declared mutable globals.
 ///
 /// This will export all internal mutable globals under the name of
 /// concat(`prefix`, i) where i is the index inside the range of
 /// [0..<total number of internal mutable globals>].
+///
+/// Globals injected by instrumentation passes and marked as internal
+/// (see [`crate::mark_internal_global`]) are not exported.
 pub fn export_mutable_globals(module: &mut elements::Module, prefix: impl Into<String>) {
+	let imported_globals = module.import_count(elements::ImportCountType::Global);
+	let internal = internal_globals(module);
 	let exports = global_section(module)
 		.map(|section| {
 			section
 				.entries()
 				.iter()
 				.enumerate()
-				.filter_map(
-					|(index, global)| {
-						if global.global_type().is_mutable() {
-							Some(index)
-						} else {
-							None
-						}
-					},
-				)
+				.filter_map(|(index, global)| {
+					if global.global_type().is_mutable() &&
+						!internal.contains(&((imported_globals + index) as u32))
+					{
+						Some(index)
+					} else {
+						None
+					}
+				})
 				.collect::<Vec<_>>()
 		})
 		.unwrap_or_default();
 
-	if module.export_section().is_none() {
-		module
-			.sections_mut()
-			.push(elements::Section::Export(elements::ExportSection::default()));
-	}
-
 	let prefix: String = prefix.into();
+	let export_section = get_or_insert_export_section(module);
 	for (symbol_index, export) in exports.into_iter().enumerate() {
 		let new_entry = elements::ExportEntry::new(
 			format!("{}_{}", prefix, symbol_index),
-			elements::Internal::Global(
-				(module.import_count(elements::ImportCountType::Global) + export) as _,
-			),
+			elements::Internal::Global((imported_globals + export) as _),
 		);
-		export_section(module)
-			.expect("added above if does not exists")
-			.entries_mut()
-			.push(new_entry);
+		export_section.entries_mut().push(new_entry);
 	}
 }
 
@@ -132,4 +130,23 @@ mod tests {
 			(export "exported_internal_global_0" (global 2)))
 		"#
 	}
+
+	#[test]
+	fn skips_internal_globals() {
+		let mut module = parse_wat(
+			r#"
+		(module
+			(import "env" "global" (global $global i64))
+			(global (;0;) (mut i32) (i32.const 1))
+			(global (;1;) (mut i32) (i32.const 0)))
+		"#,
+		);
+		crate::mark_internal_global(&mut module, 1);
+
+		export_mutable_globals(&mut module, "exported_internal_global");
+
+		let exports = module.export_section().expect("export section to exist").entries();
+		assert_eq!(exports.len(), 1);
+		assert_eq!(exports[0].internal(), &elements::Internal::Global(2));
+	}
 }
@@ -3,42 +3,19 @@ use crate::std::{borrow::ToOwned, string::String, vec::Vec};
 use byteorder::{ByteOrder, LittleEndian};
 use parity_wasm::{builder, elements};
 
-use crate::optimizer::{export_section, import_section};
+use crate::{
+	sections::{export_section_mut, import_section_mut, memory_section_mut},
+	visit_function_indices, IndexSite,
+};
 
 type Insertion = (usize, u32, u32, String);
 
-pub fn update_call_index(
-	instructions: &mut elements::Instructions,
-	original_imports: usize,
-	inserts: &[Insertion],
-) {
-	use parity_wasm::elements::Instruction::*;
-	for instruction in instructions.elements_mut().iter_mut() {
-		if let Call(call_index) = instruction {
-			if let Some(pos) = inserts.iter().position(|x| x.1 == *call_index) {
-				*call_index = (original_imports + pos) as u32;
-			} else if *call_index as usize > original_imports {
-				*call_index += inserts.len() as u32;
-			}
-		}
-	}
-}
-
-pub fn memory_section(module: &mut elements::Module) -> Option<&mut elements::MemorySection> {
-	for section in module.sections_mut() {
-		if let elements::Section::Memory(sect) = section {
-			return Some(sect)
-		}
-	}
-	None
-}
-
 pub fn externalize_mem(
 	mut module: elements::Module,
 	adjust_pages: Option<u32>,
 	max_pages: u32,
 ) -> elements::Module {
-	let mut entry = memory_section(&mut module)
+	let mut entry = memory_section_mut(&mut module)
 		.expect("Memory section to exist")
 		.entries_mut()
 		.pop()
@@ -67,7 +44,7 @@ fn foreach_public_func_name<F>(mut module: elements::Module, f: F) -> elements::
 where
 	F: Fn(&mut String),
 {
-	if let Some(section) = import_section(&mut module) {
+	if let Some(section) = import_section_mut(&mut module) {
 		for entry in section.entries_mut() {
 			if let elements::External::Function(_) = *entry.external() {
 				f(entry.field_mut())
@@ -75,7 +52,7 @@ where
 		}
 	}
 
-	if let Some(section) = export_section(&mut module) {
+	if let Some(section) = export_section_mut(&mut module) {
 		for entry in section.entries_mut() {
 			if let elements::Internal::Function(_) = *entry.internal() {
 				f(entry.field_mut())
@@ -176,35 +153,14 @@ pub fn externalize(module: elements::Module, replaced_funcs: Vec<&str>) -> eleme
 	// Back to mutable access
 	let mut module = mbuilder.build();
 
-	// Third, rewire all calls to imported functions and update all other calls indices
-	for section in module.sections_mut() {
-		match section {
-			elements::Section::Code(code_section) =>
-				for func_body in code_section.bodies_mut() {
-					update_call_index(func_body.code_mut(), import_funcs_total, &replaces);
-				},
-			elements::Section::Export(export_section) => {
-				for export in export_section.entries_mut() {
-					if let elements::Internal::Function(func_index) = export.internal_mut() {
-						if *func_index >= import_funcs_total as u32 {
-							*func_index += replaces.len() as u32;
-						}
-					}
-				}
-			},
-			elements::Section::Element(elements_section) => {
-				for segment in elements_section.entries_mut() {
-					// update all indirect call addresses initial values
-					for func_index in segment.members_mut() {
-						if *func_index >= import_funcs_total as u32 {
-							*func_index += replaces.len() as u32;
-						}
-					}
-				}
-			},
+	// Third, rewire all calls to imported functions and update all other function indices
+	visit_function_indices(&mut module, |func_index, site| {
+		match replaces.iter().position(|x| x.1 == *func_index) {
+			Some(pos) if site == IndexSite::Call => *func_index = (import_funcs_total + pos) as u32,
+			_ if *func_index >= import_funcs_total as u32 => *func_index += replaces.len() as u32,
 			_ => {},
 		}
-	}
+	});
 
 	module
 }
@@ -0,0 +1,278 @@
+//! Fuel metering emitting the code wasmtime generates when it consumes fuel.
+
+use crate::std::{mem, vec::Vec};
+
+use super::Error;
+use parity_wasm::{builder, elements, elements::Instruction};
+
+/// Cost wasmtime assigns to an instruction.
+fn cost(instruction: &Instruction) -> i64 {
+	use Instruction::*;
+	match instruction {
+		Nop | Drop | Block(_) | Loop(_) | Unreachable | Return | Else | End => 0,
+		_ => 1,
+	}
+}
+
+/// Whether wasmtime adds the fuel consumed so far to the counter before the instruction.
+fn flushes(instruction: &Instruction) -> bool {
+	use Instruction::*;
+	matches!(
+		instruction,
+		Unreachable |
+			Return | Call(_) |
+			CallIndirect(..) |
+			Loop(_) | If(_) |
+			Br(_) | BrIf(_) |
+			BrTable(_) |
+			End | Else
+	)
+}
+
+/// Control frame of the body being instrumented.
+struct Frame {
+	is_loop: bool,
+	is_if: bool,
+	/// Whether the frame is entered in reachable code.
+	reachable: bool,
+	/// Whether a branch targets the end of the frame.
+	branched_to: bool,
+	/// Whether the end of the `then` arm is reachable, once the `else` is found.
+	then_reachable: Option<bool>,
+}
+
+/// Add `amount` to the counter.
+fn flush(counter: u32, amount: i64, instructions: &mut Vec<Instruction>) {
+	use Instruction::*;
+	if amount > 0 {
+		instructions.extend([GetGlobal(counter), I64Const(amount), I64Add, SetGlobal(counter)]);
+	}
+}
+
+/// Trap if the fuel is exhausted.
+fn check(counter: u32, instructions: &mut Vec<Instruction>) {
+	use Instruction::*;
+	instructions.extend([
+		GetGlobal(counter),
+		I64Const(0),
+		I64GeS,
+		If(elements::BlockType::NoResult),
+		Unreachable,
+		End,
+	]);
+}
+
+/// Record a branch to the frame at `depth`.
+fn branch(frames: &mut [Frame], depth: u32) -> Result<(), ()> {
+	let idx = frames.len().checked_sub(depth as usize + 1).ok_or(())?;
+	// A branch to a loop continues it instead of reaching its end.
+	if !frames[idx].is_loop {
+		frames[idx].branched_to = true;
+	}
+	Ok(())
+}
+
+fn inject_body(body: &mut elements::FuncBody, counter: u32) -> Result<(), ()> {
+	let original = mem::take(body.code_mut().elements_mut());
+	let instructions = body.code_mut().elements_mut();
+	let mut frames = vec![Frame {
+		is_loop: false,
+		is_if: false,
+		reachable: true,
+		branched_to: false,
+		then_reachable: None,
+	}];
+	let mut reachable = true;
+	// Even an empty function consumes fuel.
+	let mut consumed = 1;
+
+	check(counter, instructions);
+	for instruction in original {
+		if reachable {
+			consumed += cost(&instruction);
+			if flushes(&instruction) {
+				flush(counter, consumed, instructions);
+				consumed = 0;
+			}
+		}
+
+		match &instruction {
+			Instruction::Block(_) | Instruction::Loop(_) | Instruction::If(_) =>
+				frames.push(Frame {
+					is_loop: matches!(instruction, Instruction::Loop(_)),
+					is_if: matches!(instruction, Instruction::If(_)),
+					reachable,
+					branched_to: false,
+					then_reachable: None,
+				}),
+			Instruction::Else => {
+				let frame = frames.last_mut().filter(|frame| frame.is_if).ok_or(())?;
+				frame.then_reachable = Some(reachable);
+				reachable = frame.reachable;
+			},
+			Instruction::End => {
+				let frame = frames.pop().ok_or(())?;
+				let falls_through = match frame.then_reachable {
+					Some(then_reachable) => then_reachable || reachable,
+					// Without an `else`, a false condition skips to the end.
+					None => reachable || frame.is_if,
+				};
+				reachable = frame.reachable && (falls_through || frame.branched_to);
+			},
+			Instruction::Br(depth) if reachable => {
+				branch(&mut frames, *depth)?;
+				reachable = false;
+			},
+			Instruction::BrIf(depth) if reachable => branch(&mut frames, *depth)?,
+			Instruction::BrTable(table) if reachable => {
+				for depth in table.table.iter().chain(Some(&table.default)) {
+					branch(&mut frames, *depth)?;
+				}
+				reachable = false;
+			},
+			Instruction::Unreachable | Instruction::Return => reachable = false,
+			_ => {},
+		}
+
+		let is_reachable_loop = matches!(instruction, Instruction::Loop(_)) && reachable;
+		instructions.push(instruction);
+		if is_reachable_loop {
+			check(counter, instructions);
+		}
+	}
+
+	if frames.is_empty() {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+/// Meter the module with fuel the way wasmtime does when it is configured to consume fuel.
+///
+/// The fuel is kept in an injected mutable `i64` global exported as `export_name`. Like the
+/// counter of wasmtime, it holds the negated amount of fuel left: the embedder sets it to the
+/// negated fuel limit before execution and reads the consumption from it afterwards. The global
+/// is incremented and checked at the same places and by the same amounts as in code compiled by
+/// wasmtime, so the instrumented module runs out of fuel at exactly the same point. Instead of
+/// calling into the runtime, the check traps.
+///
+/// Wasmtime charges a fixed cost of 1 for every instruction except `nop`, `drop`, `block`,
+/// `loop`, `unreachable`, `return`, `else` and `end`, plus 1 on function entry, and doesn't
+/// charge for `memory.grow` or locals. Hence, unlike [`super::inject_gas_counter`], this
+/// instrumentation takes no rules.
+///
+/// The instrumented module must be run with fuel consumption disabled in wasmtime, lest it is
+/// charged twice.
+pub fn inject_wasmtime_fuel(
+	mut module: elements::Module,
+	export_name: &str,
+) -> Result<elements::Module, Error> {
+	let func_imports = module.import_count(elements::ImportCountType::Function) as u32;
+	let counter = module.globals_space() as u32;
+
+	let bodies = module.code_section_mut().map_or(&mut [][..], |section| section.bodies_mut());
+	for (idx, body) in bodies.iter_mut().enumerate() {
+		inject_body(body, counter)
+			.map_err(|_| Error::Malformed { func_idx: func_imports + idx as u32 })?;
+	}
+
+	let mut b = builder::from_module(module);
+	b.push_global(
+		builder::global()
+			.value_type()
+			.i64()
+			.mutable()
+			.init_expr(Instruction::I64Const(0))
+			.build(),
+	);
+	b.push_export(builder::export().field(export_name).internal().global(counter).build());
+	Ok(b.build())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use parity_wasm::elements::Instruction::*;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	fn body(module: &elements::Module, idx: usize) -> &[Instruction] {
+		module.code_section().unwrap().bodies()[idx].code().elements()
+	}
+
+	#[test]
+	fn wasmtime_pattern() {
+		let module = parse_wat(
+			r#"
+(module
+	(global i32 (i32.const 0))
+	(func (param i32)
+		(loop
+			(call 1)
+			(br_if 0 (local.get 0))
+		)
+		(drop (i32.const 1))
+	)
+	(func
+		(block
+			(br 0)
+			(drop (i32.const 2))
+		)
+		(nop)
+	)
+)
+"#,
+		);
+
+		let module = inject_wasmtime_fuel(module, "fuel").unwrap();
+
+		let check = [
+			GetGlobal(1),
+			I64Const(0),
+			I64GeS,
+			If(elements::BlockType::NoResult),
+			Unreachable,
+			End,
+		];
+		let flush = |amount| [GetGlobal(1), I64Const(amount), I64Add, SetGlobal(1)];
+		let expected = [
+			&check[..],
+			&flush(1),
+			&[Loop(elements::BlockType::NoResult)],
+			&check,
+			&flush(1),
+			&[Call(1), GetLocal(0)],
+			&flush(2),
+			&[BrIf(0), End, I32Const(1), Drop],
+			&flush(1),
+			&[End],
+		]
+		.concat();
+		assert_eq!(body(&module, 0), &expected[..]);
+
+		// The code after the branch is unreachable and not charged for.
+		let expected = [
+			&check[..],
+			&[Block(elements::BlockType::NoResult)],
+			&flush(2),
+			&[Br(0), I32Const(2), Drop, End, Nop, End],
+		]
+		.concat();
+		assert_eq!(body(&module, 1), &expected[..]);
+
+		let exports = module.export_section().unwrap().entries();
+		assert!(exports
+			.iter()
+			.any(|e| e.field() == "fuel" && *e.internal() == elements::Internal::Global(1)));
+
+		let binary = parity_wasm::serialize(module).expect("serialization failed");
+		wabt::Module::read_binary(&binary, &Default::default())
+			.unwrap()
+			.validate()
+			.expect("injected module to be valid");
+	}
+}
@@ -2,24 +2,714 @@
 //!
 //! The primary public interface is the `inject_gas_counter` function which transforms a given
 //! module into one that charges gas for code to be executed. See function documentation for usage
-//! and details.
+//! and details. The instrumentation can be tuned with a `Config` passed to
+//! `inject_gas_counter_with_config`. An instrumented module can be checked against a set of
+//! rules with `verify`. Alternatively, `inject_wasmtime_fuel` meters a module exactly like wasmtime
+//! consuming fuel.
 
+mod fuel;
 #[cfg(test)]
 mod validation;
+mod verify;
 
-use crate::std::{cmp::min, mem, vec::Vec};
+pub use fuel::inject_wasmtime_fuel;
+pub use verify::{verify, Mismatch};
 
-use crate::rules::Rules;
+use crate::std::{
+	cmp::min, collections::BTreeMap as Map, fmt, iter, mem, ops::Range, string::String, vec::Vec,
+};
+
+use crate::{
+	check_limits,
+	rules::{MemoryGrowCost, Rules},
+	visit_function_indices, IndexPlacement, LimitExceeded, Limits,
+};
 use parity_wasm::{builder, elements, elements::ValueType};
 
-pub fn update_call_index(instructions: &mut elements::Instructions, inserted_index: u32) {
-	use parity_wasm::elements::Instruction::*;
-	for instruction in instructions.elements_mut().iter_mut() {
-		if let Call(call_index) = instruction {
-			if *call_index >= inserted_index {
-				*call_index += 1
+/// Name of the gas metering in the section [`crate::INSTRUMENTED_SECTION`].
+pub const PASS: &str = "gas";
+
+/// The way the injected code charges gas.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Backend {
+	/// Call the gas function imported from the host, see [`GasImport`], with the amount of gas to
+	/// charge.
+	HostFunction,
+	/// Decrement an injected mutable `i64` global, exported under the given name, and trap
+	/// when the remaining gas would underflow.
+	///
+	/// The embedder sets the global to the gas limit before execution and reads the remaining
+	/// gas from it afterwards. No host function is called.
+	MutableGlobal(String),
+	/// Like [`Backend::MutableGlobal`], but the check and the decrement are inlined at every
+	/// charge instead of calling a function.
+	///
+	/// This makes the instrumented code bigger, but saves a call per metered block. The charging
+	/// function is still appended to the module to charge for `memory.grow`.
+	InlineMutableGlobal(String),
+	/// Charge from an allowance cached in an injected mutable `i64` global, exported under the
+	/// given name, and refill it by calling the gas function imported from the host when it runs
+	/// short.
+	///
+	/// The import has the signature [i64] -> [i64]. It is called with the charge the allowance
+	/// doesn't cover, must trap if less gas than that remains, and otherwise returns the amount
+	/// of gas it deducted for the module, which has to be at least the argument. Returning more,
+	/// e.g. all the remaining gas, saves the calls for the following charges. The embedder
+	/// refunds the allowance left in the global after execution.
+	BatchedHostFunction(String),
+	/// Keep the remaining gas as an `i64` at a fixed address of the linear memory, and check and
+	/// decrement it inline, trapping when it would underflow.
+	///
+	/// This needs neither imports nor exports, so it works on hosts which can't provide a gas
+	/// function. The counter is at `offset` in the memory of the module, unless `dedicated_page`
+	/// is set: then a page is appended to the memory defined by the module, which is set aside
+	/// for the counter at `offset` within the page. Its address is the initial size of the memory
+	/// before instrumentation times 64 KiB plus `offset`. The embedder writes the gas limit to the
+	/// counter before execution and reads the remaining gas from it afterwards. The code of the
+	/// module must not write to the counter.
+	LinearMemory { offset: u32, dedicated_page: bool },
+}
+
+/// How the injected code charges gas.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Charger {
+	/// Call the function with the given index.
+	Call(u32),
+	/// Check and decrement the global with the given index.
+	Inline(u32),
+	/// Check and decrement the counter at the given address of the linear memory.
+	Memory(u32),
+}
+
+impl Charger {
+	/// Number of instructions injected for a single charge.
+	fn len(self) -> usize {
+		match self {
+			Charger::Call(_) => 2,
+			Charger::Inline(_) => 10,
+			Charger::Memory(_) => 13,
+		}
+	}
+
+	fn charge(
+		self,
+		amount: u64,
+		precision: GasPrecision,
+		instructions: &mut Vec<elements::Instruction>,
+	) {
+		use parity_wasm::elements::Instruction::*;
+
+		match self {
+			Charger::Call(gas_func) => {
+				instructions.push(precision.charge(amount));
+				instructions.push(Call(gas_func));
+			},
+			Charger::Inline(gas_global) => {
+				// The global is always 64 bit wide.
+				let amount = I64Const(amount as i64);
+				instructions.extend([
+					// if gas < amount: unreachable
+					GetGlobal(gas_global),
+					amount.clone(),
+					I64LtU,
+					If(elements::BlockType::NoResult),
+					Unreachable,
+					End,
+					// gas -= amount
+					GetGlobal(gas_global),
+					amount,
+					I64Sub,
+					SetGlobal(gas_global),
+				]);
+			},
+			Charger::Memory(address) => {
+				let amount = I64Const(amount as i64);
+				let address = I32Const(address as i32);
+				instructions.extend([
+					// if gas < amount: unreachable
+					address.clone(),
+					I64Load(3, 0),
+					amount.clone(),
+					I64LtU,
+					If(elements::BlockType::NoResult),
+					Unreachable,
+					End,
+					// gas -= amount
+					address.clone(),
+					address,
+					I64Load(3, 0),
+					amount,
+					I64Sub,
+					I64Store(3, 0),
+				]);
+			},
+		}
+	}
+}
+
+/// Reason of a failed instrumentation, see [`inject_gas_counter`].
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Error {
+	/// The rules forbid the `instruction` found at `offset` in the body of the function
+	/// `func_idx`.
+	///
+	/// The function index refers to the function space of the module before instrumentation.
+	Forbidden { func_idx: u32, offset: usize, instruction: elements::Instruction },
+	/// The body of the function `func_idx` has malformed control flow.
+	Malformed { func_idx: u32 },
+	/// The cost of a metered block in the function `func_idx` doesn't fit into `u64`.
+	Overflow { func_idx: u32 },
+	/// The gas counter of [`Backend::LinearMemory`] doesn't fit into the memory of the module.
+	CounterMemory,
+	/// The module records that it is metered already, see [`Config::with_mark`].
+	AlreadyInstrumented,
+	/// The module exceeds the limits set with [`Config::with_limits`].
+	LimitExceeded(LimitExceeded),
+	/// The module imports the function `func_idx` under the gas import, but its signature isn't
+	/// the `expected` one of the backend.
+	GasImportSignature { func_idx: u32, expected: elements::FunctionType },
+	/// The gas import or global can't be placed at the `index` set with
+	/// [`Config::with_import_placement`] or [`Config::with_global_placement`].
+	InvalidPlacement { index: u32 },
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			Error::Forbidden { func_idx, offset, instruction } => write!(
+				f,
+				"Forbidden instruction `{}` at offset {} in function {}",
+				instruction, offset, func_idx
+			),
+			Error::Malformed { func_idx } => write!(f, "Function {} can't be metered", func_idx),
+			Error::Overflow { func_idx } =>
+				write!(f, "Cost of function {} overflows the gas counter", func_idx),
+			Error::CounterMemory =>
+				write!(f, "The gas counter can't be placed in the memory of the module"),
+			Error::AlreadyInstrumented => write!(f, "The module is metered already"),
+			Error::LimitExceeded(err) => write!(f, "{}", err),
+			Error::GasImportSignature { func_idx, expected } => write!(
+				f,
+				"Function {} is imported under the gas import, but its signature isn't {:?} -> {:?}",
+				func_idx,
+				expected.params(),
+				expected.results()
+			),
+			Error::InvalidPlacement { index } =>
+				write!(f, "The injected gas entity can't be placed at index {}", index),
+		}
+	}
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			Error::LimitExceeded(err) => Some(err),
+			_ => None,
+		}
+	}
+}
+
+/// Overhead of the instrumentation, see [`inject_gas_counter_with_report`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Report {
+	/// The functions defined by the module, in order.
+	pub functions: Vec<FunctionReport>,
+}
+
+/// Overhead of the instrumentation of a single function.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FunctionReport {
+	/// Index of the function in the function space of the module before instrumentation.
+	pub func_idx: u32,
+	/// Number of metered blocks charged for.
+	pub metered_blocks: usize,
+	/// Sum of the charges of the metered blocks, not counting `memory.grow`, bulk memory
+	/// operations and import surcharges.
+	pub static_cost: u64,
+	/// Number of bytes the encoded body grew by.
+	pub size_increase: usize,
+}
+
+/// Metering failure within a single function body.
+#[derive(Debug)]
+pub(crate) enum BodyError {
+	/// Position of a forbidden instruction.
+	Forbidden(usize),
+	Malformed,
+	Overflow,
+}
+
+impl From<()> for BodyError {
+	fn from(_: ()) -> Self {
+		BodyError::Malformed
+	}
+}
+
+/// Width of the gas amounts passed to the charging function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GasPrecision {
+	/// Charges are `i32` values. Metered blocks costing more than `u32::MAX` are charged in
+	/// several steps.
+	Bits32,
+	/// Charges are `i64` values, so the "gas" import has the signature [i64] -> [].
+	Bits64,
+}
+
+impl GasPrecision {
+	fn value_type(self) -> ValueType {
+		match self {
+			GasPrecision::Bits32 => ValueType::I32,
+			GasPrecision::Bits64 => ValueType::I64,
+		}
+	}
+
+	/// Maximal amount of gas a single charge of this width can take.
+	fn max_charge(self) -> u64 {
+		match self {
+			GasPrecision::Bits32 => u32::MAX.into(),
+			GasPrecision::Bits64 => u64::MAX,
+		}
+	}
+
+	fn charge(self, amount: u64) -> elements::Instruction {
+		match self {
+			GasPrecision::Bits32 => elements::Instruction::I32Const(amount as i32),
+			GasPrecision::Bits64 => elements::Instruction::I64Const(amount as i64),
+		}
+	}
+}
+
+/// Where in a function body gas is charged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChargePlacement {
+	/// Charge at the beginning of every metered block, so that exactly the executed code is
+	/// charged for unless execution traps.
+	MeteredBlocks,
+	/// Charge only at the function entry, at loop headers and after the end of control blocks
+	/// targeted by a branch.
+	///
+	/// Every other instruction is charged for together with the closest of these points it is
+	/// dominated by, so the arms of an `if` and code skipped by a branch are charged for even if
+	/// they are not executed. This overestimates the gas used in exchange for far fewer charges
+	/// in code with many small `block` and `if` constructs.
+	BranchTargets,
+	/// Charge once at the function entry for all instructions of the body.
+	///
+	/// This is an upper bound of the cost of a call as long as the function contains no loops.
+	/// Iterations of a loop after the first are not charged for, so this is only suitable for
+	/// debugging and rough accounting. [`verify`] rejects functions with loops charged this way.
+	FunctionEntry,
+}
+
+/// Module and field names the gas function is imported under.
+///
+/// A module name converts into the import of the field "gas" from that module, a pair of names
+/// into the import of the field from the module, e.g. `("seal0", "gas")`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GasImport {
+	module: String,
+	field: String,
+}
+
+impl GasImport {
+	/// Import of `field` from `module`.
+	pub fn new(module: &str, field: &str) -> Self {
+		GasImport { module: module.into(), field: field.into() }
+	}
+
+	/// Name of the module the gas function is imported from.
+	pub fn module(&self) -> &str {
+		&self.module
+	}
+
+	/// Name the gas function is imported under.
+	pub fn field(&self) -> &str {
+		&self.field
+	}
+}
+
+impl From<&str> for GasImport {
+	fn from(module: &str) -> Self {
+		GasImport::new(module, "gas")
+	}
+}
+
+impl From<(&str, &str)> for GasImport {
+	fn from((module, field): (&str, &str)) -> Self {
+		GasImport::new(module, field)
+	}
+}
+
+/// Configuration of the gas metering instrumentation.
+#[derive(Debug, Clone)]
+pub struct Config {
+	import: GasImport,
+	backend: Backend,
+	max_block_cost: u32,
+	precision: GasPrecision,
+	coalesce_charges: bool,
+	placement: ChargePlacement,
+	exempt_functions: Vec<u32>,
+	exempt_exports: Vec<String>,
+	cold_roots: Vec<String>,
+	import_costs: Vec<(String, String, u32)>,
+	import_placement: IndexPlacement,
+	global_placement: IndexPlacement,
+	mark: bool,
+	instrumentation_map: bool,
+	limits: Limits,
+}
+
+impl Config {
+	/// New configuration which imports the gas function as `gas_import`.
+	///
+	/// A module name imports the function "gas" from it, see [`GasImport`]. If the module imports
+	/// the function already, the existing import is used.
+	pub fn new<I: Into<GasImport>>(gas_import: I) -> Self {
+		Config {
+			import: gas_import.into(),
+			backend: Backend::HostFunction,
+			max_block_cost: 0,
+			precision: GasPrecision::Bits32,
+			coalesce_charges: false,
+			placement: ChargePlacement::MeteredBlocks,
+			exempt_functions: Vec::new(),
+			exempt_exports: Vec::new(),
+			cold_roots: Vec::new(),
+			import_costs: Vec::new(),
+			import_placement: IndexPlacement::Last,
+			global_placement: IndexPlacement::Last,
+			mark: false,
+			instrumentation_map: false,
+			limits: Limits::new(),
+		}
+	}
+
+	/// Names the gas function is imported under.
+	pub fn gas_import(&self) -> &GasImport {
+		&self.import
+	}
+
+	/// Use the given backend to charge gas.
+	pub fn with_backend(mut self, backend: Backend) -> Self {
+		self.backend = backend;
+		self
+	}
+
+	/// Backend used to charge gas.
+	pub fn backend(&self) -> &Backend {
+		&self.backend
+	}
+
+	/// Limit the amount of gas a single charge can take.
+	///
+	/// Metered blocks with a higher cost are charged by several consecutive charges each of which
+	/// doesn't exceed the limit. The charge for `memory.grow` depends on the runtime argument
+	/// and is not subject to this limit.
+	///
+	/// Setting it to 0 (the default) removes the limit.
+	pub fn with_max_block_cost(mut self, val: u32) -> Self {
+		self.max_block_cost = val;
+		self
+	}
+
+	/// Maximal amount of gas a single charge can take, 0 if unlimited.
+	pub fn max_block_cost(&self) -> u32 {
+		self.max_block_cost
+	}
+
+	/// Set the width of the charged gas amounts, [`GasPrecision::Bits32`] by default.
+	pub fn with_precision(mut self, precision: GasPrecision) -> Self {
+		self.precision = precision;
+		self
+	}
+
+	/// Width of the charged gas amounts.
+	pub fn precision(&self) -> GasPrecision {
+		self.precision
+	}
+
+	/// Reduce the number of charges by charging for the code following a `block` or `loop`
+	/// together with the last metered block within it.
+	///
+	/// This is possible if the code can only be reached by falling through that metered block,
+	/// which is the case if there are no branches to the end of the control block. The charges
+	/// stay exact, but they are no longer placed at the beginning of each metered block.
+	pub fn with_coalesced_charges(mut self) -> Self {
+		self.coalesce_charges = true;
+		self
+	}
+
+	/// Whether charges are coalesced, see [`Config::with_coalesced_charges`].
+	pub fn coalesced_charges(&self) -> bool {
+		self.coalesce_charges
+	}
+
+	/// Set where gas is charged, [`ChargePlacement::MeteredBlocks`] by default.
+	///
+	/// Coalescing charges only applies to [`ChargePlacement::MeteredBlocks`].
+	pub fn with_placement(mut self, placement: ChargePlacement) -> Self {
+		self.placement = placement;
+		self
+	}
+
+	/// Where gas is charged.
+	pub fn placement(&self) -> ChargePlacement {
+		self.placement
+	}
+
+	/// Don't meter the function `func_idx`, e.g. because it is a trusted helper.
+	///
+	/// The function index refers to the function space of the module before instrumentation.
+	/// Neither the body nor the `memory.grow` instructions of the function are charged for, calls
+	/// of it from other functions are. Instrumented modules with exempt functions don't pass
+	/// [`verify`].
+	pub fn with_exempt_function(mut self, func_idx: u32) -> Self {
+		self.exempt_functions.push(func_idx);
+		self
+	}
+
+	/// Don't meter the function exported as `name`, see [`Config::with_exempt_function`].
+	///
+	/// Names which aren't exported by the module are ignored.
+	pub fn with_exempt_export(mut self, name: &str) -> Self {
+		self.exempt_exports.push(name.into());
+		self
+	}
+
+	/// Treat the function named `name` in the name section as the root of a cold path, e.g. the
+	/// panic handler `rust_begin_unwind`.
+	///
+	/// The roots and the functions only called from them, directly or indirectly, are charged
+	/// once at their entry as with [`ChargePlacement::FunctionEntry`], instead of according to
+	/// [`Config::with_placement`]. The error formatting reached from a panic is usually a big
+	/// part of a contract, but is executed at most once before execution aborts, so this saves
+	/// much of the size of the instrumentation. The functions exported, in the table or the
+	/// start function aren't cold, neither are the ones called from other functions which
+	/// aren't. Functions containing a loop are charged according to [`Config::with_placement`]
+	/// all the same, so that every iteration is charged for. Names which aren't in the name
+	/// section are ignored, so are all if the module has no name section.
+	pub fn with_cold_root(mut self, name: &str) -> Self {
+		self.cold_roots.push(name.into());
+		self
+	}
+
+	/// Charge `cost` before every call of the function imported as `field` from `module`, in
+	/// addition to the cost of the call instruction.
+	///
+	/// This accounts for host functions whose work isn't reflected by the instruction costs. The
+	/// surcharge applies to direct calls only. Instrumented modules with surcharges don't pass
+	/// [`verify`].
+	pub fn with_import_cost(mut self, module: &str, field: &str, cost: u32) -> Self {
+		self.import_costs.push((module.into(), field.into(), cost));
+		self
+	}
+
+	/// Record the instrumentation in the section [`crate::INSTRUMENTED_SECTION`] as the pass
+	/// [`PASS`], with the gas module name and the backend as parameters.
+	///
+	/// Modules with this record are refused by the instrumentation, whether this option is set or
+	/// not.
+	pub fn with_mark(mut self) -> Self {
+		self.mark = true;
+		self
+	}
+
+	/// Refuse modules exceeding `limits` before instrumenting them.
+	pub fn with_limits(mut self, limits: Limits) -> Self {
+		self.limits = limits;
+		self
+	}
+
+	/// Record the injected charges and functions in the section
+	/// [`crate::INSTRUMENTATION_MAP_SECTION`].
+	///
+	/// Replaced instructions, e.g. `memory.grow` calling the memory grow counter instead, aren't
+	/// recorded.
+	pub fn with_instrumentation_map(mut self) -> Self {
+		self.instrumentation_map = true;
+		self
+	}
+
+	/// Place the imported gas function among the function imports, after them by default.
+	///
+	/// The functions from the chosen index on are shifted. Nothing is inserted if the module
+	/// imports the function already.
+	pub fn with_import_placement(mut self, placement: IndexPlacement) -> Self {
+		self.import_placement = placement;
+		self
+	}
+
+	/// Place the gas global of the global backends in the global index space, after all existing
+	/// globals by default.
+	///
+	/// The globals from the chosen index on are shifted.
+	pub fn with_global_placement(mut self, placement: IndexPlacement) -> Self {
+		self.global_placement = placement;
+		self
+	}
+
+	/// Index in the function space of `module` at which the backend inserts its import, if any.
+	///
+	/// Nothing is inserted if `module` imports the function already, see [`Config::new`].
+	pub(crate) fn inserted_import(&self, module: &elements::Module) -> Option<u32> {
+		match self.backend {
+			Backend::HostFunction | Backend::BatchedHostFunction(_)
+				if !matches!(self.existing_import(module), Ok(Some(_))) =>
+				self.import_placement
+					.index(0, module.import_count(elements::ImportCountType::Function) as u32)
+					.ok(),
+			_ => None,
+		}
+	}
+
+	/// Index in the global space of `module` at which the backend inserts its global, if any.
+	pub(crate) fn inserted_global(&self, module: &elements::Module) -> Option<u32> {
+		match self.backend {
+			Backend::MutableGlobal(_) |
+			Backend::InlineMutableGlobal(_) |
+			Backend::BatchedHostFunction(_) => self
+				.global_placement
+				.index(
+					module.import_count(elements::ImportCountType::Global) as u32,
+					module.globals_space() as u32,
+				)
+				.ok(),
+			_ => None,
+		}
+	}
+
+	/// Signature of the function the backend imports, if any.
+	fn import_signature(&self) -> Option<elements::FunctionType> {
+		match self.backend {
+			Backend::HostFunction =>
+				Some(elements::FunctionType::new(vec![self.precision.value_type()], vec![])),
+			Backend::BatchedHostFunction(_) =>
+				Some(elements::FunctionType::new(vec![ValueType::I64], vec![ValueType::I64])),
+			_ => None,
+		}
+	}
+
+	/// Index of the function `module` imports already under the gas import, if the backend
+	/// imports a function at all.
+	///
+	/// Fails if the signature of the imported function isn't the one of the backend.
+	fn existing_import(&self, module: &elements::Module) -> Result<Option<u32>, Error> {
+		let expected = match self.import_signature() {
+			Some(expected) => expected,
+			None => return Ok(None),
+		};
+		let imports = module.import_section().map(|section| section.entries()).unwrap_or(&[]);
+		let found = imports
+			.iter()
+			.filter_map(|entry| match entry.external() {
+				elements::External::Function(type_idx) => Some((entry, *type_idx)),
+				_ => None,
+			})
+			.enumerate()
+			.find(|(_, (entry, _))| {
+				entry.module() == self.import.module && entry.field() == self.import.field
+			});
+		let (func_idx, type_idx) = match found {
+			Some((func_idx, (_, type_idx))) => (func_idx as u32, type_idx),
+			None => return Ok(None),
+		};
+		let types = module.type_section().map(|section| section.types()).unwrap_or(&[]);
+		match types.get(type_idx as usize) {
+			Some(elements::Type::Function(signature)) if *signature == expected =>
+				Ok(Some(func_idx)),
+			_ => Err(Error::GasImportSignature { func_idx, expected }),
+		}
+	}
+
+	/// Surcharges of the imported functions of `module` by function index.
+	fn import_costs(&self, module: &elements::Module) -> Map<u32, u64> {
+		let mut costs = Map::new();
+		let imports = module.import_section().map(|section| section.entries()).unwrap_or(&[]);
+		let functions = imports
+			.iter()
+			.filter(|entry| matches!(entry.external(), elements::External::Function(_)));
+		for (func_idx, entry) in functions.enumerate() {
+			for (module, field, cost) in &self.import_costs {
+				if entry.module() == module && entry.field() == field {
+					*costs.entry(func_idx as u32).or_insert(0) += u64::from(*cost);
+				}
 			}
 		}
+		costs
+	}
+
+	/// Indices of the functions exempt from metering in `module`, sorted.
+	fn exempt_functions(&self, module: &elements::Module) -> Vec<u32> {
+		let exports = module
+			.export_section()
+			.map(|section| section.entries())
+			.unwrap_or(&[])
+			.iter()
+			.filter(|entry| self.exempt_exports.iter().any(|name| name == entry.field()))
+			.filter_map(|entry| match *entry.internal() {
+				elements::Internal::Function(func_idx) => Some(func_idx),
+				_ => None,
+			});
+		let mut exempt = self.exempt_functions.iter().copied().chain(exports).collect::<Vec<_>>();
+		exempt.sort_unstable();
+		exempt.dedup();
+		exempt
+	}
+
+	/// Indices of the functions of `module` only reachable from the cold roots and without loops,
+	/// sorted.
+	fn cold_functions(&self, module: &elements::Module) -> Vec<u32> {
+		let names = match module.names_section().and_then(|section| section.functions()) {
+			Some(names) if !self.cold_roots.is_empty() => names.names(),
+			_ => return Vec::new(),
+		};
+		let roots: Vec<u32> = names
+			.iter()
+			.filter(|(_, name)| self.cold_roots.iter().any(|root| root == *name))
+			.map(|(func_idx, _)| func_idx)
+			.collect();
+
+		let exports = module.export_section().map(|section| section.entries()).unwrap_or(&[]);
+		let elements = module.elements_section().map(|section| section.entries()).unwrap_or(&[]);
+		let entries = exports
+			.iter()
+			.filter_map(|entry| match *entry.internal() {
+				elements::Internal::Function(func_idx) => Some(func_idx),
+				_ => None,
+			})
+			.chain(elements.iter().flat_map(|segment| segment.members()).copied())
+			.chain(module.start_section());
+		let hot = reachable_functions(module, entries, &roots);
+		let cold = reachable_functions(module, roots.iter().copied(), &[]);
+
+		let func_imports = module.import_count(elements::ImportCountType::Function);
+		let bodies = module.code_section().map(|section| section.bodies()).unwrap_or(&[]);
+		// A charge at the entry wouldn't cover the iterations of a loop.
+		let has_loop = |func_idx: usize| {
+			bodies[func_idx - func_imports]
+				.code()
+				.elements()
+				.iter()
+				.any(|instruction| matches!(instruction, elements::Instruction::Loop(_)))
+		};
+		(func_imports..cold.len())
+			.filter(|func_idx| cold[*func_idx] && !hot[*func_idx] && !has_loop(*func_idx))
+			.map(|func_idx| func_idx as u32)
+			.collect()
+	}
+
+	/// Maximal amount of gas a single charge can take with respect to the precision.
+	fn charge_limit(&self) -> u64 {
+		match self.max_block_cost {
+			0 => self.precision.max_charge(),
+			max_block_cost => min(max_block_cost.into(), self.precision.max_charge()),
+		}
 	}
 }
 
@@ -59,6 +749,11 @@ struct ControlBlock {
 	/// Whether the control block is a loop. Loops have the distinguishing feature that branches to
 	/// them jump to the beginning of the block, not the end as with the other control blocks.
 	is_loop: bool,
+
+	/// Whether the instruction following the `end` of the control block can be reached other than
+	/// by falling through the last metered block within it, i.e. by a branch to the control block
+	/// or by skipping the body of an `if`.
+	is_join: bool,
 }
 
 /// A block of code that metering instructions will be inserted at the beginning of. Metered blocks
@@ -69,7 +764,7 @@ pub(crate) struct MeteredBlock {
 	/// Index of the first instruction (aka `Opcode`) in the block.
 	start_pos: usize,
 	/// Sum of costs of all instructions until end of the block.
-	cost: u32,
+	cost: u64,
 }
 
 /// Counter is used to manage state during the gas metering algorithm implemented by
@@ -84,29 +779,47 @@ struct Counter {
 
 	/// A list of metered blocks that have been finalized, meaning they will no longer change.
 	finalized_blocks: Vec<MeteredBlock>,
+
+	/// Whether to charge for the code following a control block together with the last metered
+	/// block within it when possible, see [`Config::with_coalesced_charges`].
+	coalesce: bool,
 }
 
 impl Counter {
-	fn new() -> Counter {
-		Counter { stack: Vec::new(), finalized_blocks: Vec::new() }
+	fn new(coalesce: bool) -> Counter {
+		Counter { stack: Vec::new(), finalized_blocks: Vec::new(), coalesce }
 	}
 
 	/// Open a new control block. The cursor is the position of the first instruction in the block.
-	fn begin_control_block(&mut self, cursor: usize, is_loop: bool) {
+	fn begin_control_block(&mut self, cursor: usize, is_loop: bool, is_if: bool) {
 		let index = self.stack.len();
 		self.stack.push(ControlBlock {
 			lowest_forward_br_target: index,
 			active_metered_block: MeteredBlock { start_pos: cursor, cost: 0 },
 			is_loop,
+			is_join: is_if,
 		})
 	}
 
 	/// Close the last control block. The cursor is the position of the final (pseudo-)instruction
 	/// in the block.
-	fn finalize_control_block(&mut self, cursor: usize) -> Result<(), ()> {
-		// This either finalizes the active metered block or merges its cost into the active
-		// metered block in the previous control block on the stack.
-		self.finalize_metered_block(cursor)?;
+	fn finalize_control_block(&mut self, cursor: usize) -> Result<(), BodyError> {
+		// If the code following the control block is only reached by falling through its active
+		// metered block, that metered block is continued after the control block instead of
+		// being finalized. This only makes a difference if a new metered block would begin after
+		// the control block, that is if there may have been a branch out of it.
+		let fallthrough_block = if self.coalesce && self.may_coalesce() {
+			let control_block = self.stack.last_mut().ok_or(())?;
+			Some(mem::replace(
+				&mut control_block.active_metered_block,
+				MeteredBlock { start_pos: cursor + 1, cost: 0 },
+			))
+		} else {
+			// This either finalizes the active metered block or merges its cost into the active
+			// metered block in the previous control block on the stack.
+			self.finalize_metered_block(cursor)?;
+			None
+		};
 
 		// Pop the control block stack.
 		let closing_control_block = self.stack.pop().ok_or(())?;
@@ -132,13 +845,34 @@ impl Counter {
 			self.finalize_metered_block(cursor)?;
 		}
 
+		if let Some(fallthrough_block) = fallthrough_block {
+			*self.active_metered_block()? = fallthrough_block;
+		}
+
 		Ok(())
 	}
 
+	/// Whether the active metered block of the last control block may be continued after the
+	/// control block is closed.
+	fn may_coalesce(&self) -> bool {
+		let last_index = match self.stack.len().checked_sub(1) {
+			Some(last_index) if last_index > 0 => last_index,
+			_ => return false,
+		};
+		let control_block = &self.stack[last_index];
+		let prev_control_block = &self.stack[last_index - 1];
+		let may_br_out = control_block.lowest_forward_br_target < last_index;
+
+		may_br_out &&
+			!control_block.is_join &&
+			control_block.active_metered_block.start_pos !=
+				prev_control_block.active_metered_block.start_pos
+	}
+
 	/// Finalize the current active metered block.
 	///
 	/// Finalized blocks have final cost which will not change later.
-	fn finalize_metered_block(&mut self, cursor: usize) -> Result<(), ()> {
+	fn finalize_metered_block(&mut self, cursor: usize) -> Result<(), BodyError> {
 		let closing_metered_block = {
 			let control_block = self.stack.last_mut().ok_or(())?;
 			mem::replace(
@@ -160,7 +894,10 @@ impl Counter {
 				.expect("last_index is greater than 0; last_index is stack size - 1; qed");
 			let prev_metered_block = &mut prev_control_block.active_metered_block;
 			if closing_metered_block.start_pos == prev_metered_block.start_pos {
-				prev_metered_block.cost += closing_metered_block.cost;
+				prev_metered_block.cost = prev_metered_block
+					.cost
+					.checked_add(closing_metered_block.cost)
+					.ok_or(BodyError::Overflow)?;
 				return Ok(())
 			}
 		}
@@ -175,7 +912,7 @@ impl Counter {
 	/// instruction in the program. The indices are the stack positions of the target control
 	/// blocks. Recall that the index is 0 for a `return` and relatively indexed from the top of
 	/// the stack by the label of `br`, `br_if`, and `br_table` instructions.
-	fn branch(&mut self, cursor: usize, indices: &[usize]) -> Result<(), ()> {
+	fn branch(&mut self, cursor: usize, indices: &[usize]) -> Result<(), BodyError> {
 		self.finalize_metered_block(cursor)?;
 
 		// Update the lowest_forward_br_target of the current control block.
@@ -187,6 +924,7 @@ impl Counter {
 			if target_is_loop {
 				continue
 			}
+			self.stack.get_mut(index).ok_or(())?.is_join = true;
 
 			let control_block = self.stack.last_mut().ok_or(())?;
 			control_block.lowest_forward_br_target =
@@ -208,9 +946,9 @@ impl Counter {
 	}
 
 	/// Increment the cost of the current block by the specified value.
-	fn increment(&mut self, val: u32) -> Result<(), ()> {
+	fn increment(&mut self, val: u32) -> Result<(), BodyError> {
 		let top_block = self.active_metered_block()?;
-		top_block.cost = top_block.cost.checked_add(val).ok_or(())?;
+		top_block.cost = top_block.cost.checked_add(val.into()).ok_or(BodyError::Overflow)?;
 		Ok(())
 	}
 }
@@ -231,13 +969,11 @@ fn add_grow_counter<R: Rules>(
 	module: elements::Module,
 	rules: &R,
 	gas_func: u32,
+	precision: GasPrecision,
 ) -> elements::Module {
-	use crate::rules::MemoryGrowCost;
-	use parity_wasm::elements::Instruction::*;
-
 	let cost = match rules.memory_grow_cost() {
 		None => return module,
-		Some(MemoryGrowCost::Linear(val)) => val.get(),
+		Some(cost) => cost,
 	};
 
 	let mut b = builder::from_module(module);
@@ -248,16 +984,10 @@ fn add_grow_counter<R: Rules>(
 			.with_result(ValueType::I32)
 			.build()
 			.body()
-			.with_instructions(elements::Instructions::new(vec![
-				GetLocal(0),
-				GetLocal(0),
-				I32Const(cost as i32),
-				I32Mul,
-				// todo: there should be strong guarantee that it does not return anything on stack?
-				Call(gas_func),
-				GrowMemory(0),
-				End,
-			]))
+			.with_locals(grow_counter_locals(precision))
+			.with_instructions(elements::Instructions::new(grow_counter_body(
+				cost, gas_func, precision,
+			)))
 			.build()
 			.build(),
 	);
@@ -265,111 +995,675 @@ fn add_grow_counter<R: Rules>(
 	b.build()
 }
 
-pub(crate) fn determine_metered_blocks<R: Rules>(
-	instructions: &elements::Instructions,
-	rules: &R,
-) -> Result<Vec<MeteredBlock>, ()> {
-	use parity_wasm::elements::Instruction::*;
-
-	let mut counter = Counter::new();
-
-	// Begin an implicit function (i.e. `func...end`) block.
-	counter.begin_control_block(0, false);
+/// Locals of the memory grow counter besides the number of pages, see [`grow_counter_body`].
+fn grow_counter_locals(precision: GasPrecision) -> Vec<elements::Local> {
+	match precision {
+		GasPrecision::Bits32 => vec![elements::Local::new(1, ValueType::I64)],
+		GasPrecision::Bits64 => Vec::new(),
+	}
+}
 
-	for cursor in 0..instructions.elements().len() {
-		let instruction = &instructions.elements()[cursor];
-		let instruction_cost = rules.instruction_cost(instruction).ok_or(())?;
-		match instruction {
-			Block(_) => {
-				counter.increment(instruction_cost)?;
+/// Body of the memory grow counter: it takes the number of pages, charges for them and grows the
+/// memory.
+///
+/// With [`GasPrecision::Bits32`] it traps if the charge exceeds `u32::MAX`.
+pub(crate) fn grow_counter_body(
+	cost: MemoryGrowCost,
+	gas_func: u32,
+	precision: GasPrecision,
+) -> Vec<elements::Instruction> {
+	use parity_wasm::elements::Instruction::*;
 
-				// Begin new block. The cost of the following opcodes until `end` or `else` will
-				// be included into this block. The start position is set to that of the previous
-				// active metered block to signal that they should be merged in order to reduce
-				// unnecessary metering instructions.
-				let top_block_start_pos = counter.active_metered_block()?.start_pos;
-				counter.begin_control_block(top_block_start_pos, false);
-			},
-			If(_) => {
-				counter.increment(instruction_cost)?;
-				counter.begin_control_block(cursor + 1, false);
-			},
-			Loop(_) => {
-				counter.increment(instruction_cost)?;
-				counter.begin_control_block(cursor + 1, true);
-			},
-			End => {
-				counter.finalize_control_block(cursor)?;
-			},
-			Else => {
-				counter.finalize_metered_block(cursor)?;
-			},
-			Br(label) | BrIf(label) => {
-				counter.increment(instruction_cost)?;
+	let mut body = Vec::new();
+	if let Some(max_pages) = cost.max_pages() {
+		// if pages > max_pages: unreachable
+		body.extend([
+			GetLocal(0),
+			I32Const(max_pages as i32),
+			I32GtU,
+			If(elements::BlockType::NoResult),
+			Unreachable,
+			End,
+		]);
+	}
+	body.push(GetLocal(0));
+	match precision {
+		GasPrecision::Bits32 => {
+			// The charge is computed in 64 bits in the local 1, so that it can't wrap around.
+			body.extend([GetLocal(0), I64ExtendUI32, I64Const(i64::from(cost.per_page())), I64Mul]);
+			if cost.base() > 0 {
+				body.extend([I64Const(i64::from(cost.base())), I64Add]);
+			}
+			// if charge > u32::MAX: unreachable
+			body.extend([
+				TeeLocal(1),
+				I64Const(i64::from(u32::MAX)),
+				I64GtU,
+				If(elements::BlockType::NoResult),
+				Unreachable,
+				End,
+				GetLocal(1),
+				I32WrapI64,
+			]);
+		},
+		GasPrecision::Bits64 => {
+			body.extend([GetLocal(0), I64ExtendUI32, I64Const(i64::from(cost.per_page())), I64Mul]);
+			if cost.base() > 0 {
+				body.extend([I64Const(i64::from(cost.base())), I64Add]);
+			}
+		},
+	}
+	// todo: there should be strong guarantee that it does not return anything on stack?
+	body.extend([Call(gas_func), GrowMemory(0), End]);
+	body
+}
 
-				// Label is a relative index into the control stack.
-				let active_index = counter.active_control_block_index().ok_or(())?;
-				let target_index = active_index.checked_sub(*label as usize).ok_or(())?;
-				counter.branch(cursor, &[target_index])?;
-			},
-			BrTable(br_table_data) => {
-				counter.increment(instruction_cost)?;
+/// Replace `memory.copy`, `memory.fill` and `memory.init` with calls to helpers charging for the
+/// written bytes before performing the operation.
+///
+/// The replaced instructions are collected in `helpers`; the helper performing `helpers[i]` will
+/// have the index `first_helper + i`.
+#[cfg(feature = "bulk")]
+fn inject_bulk_memory_helpers(
+	instructions: &mut elements::Instructions,
+	first_helper: u32,
+	helpers: &mut Vec<elements::BulkInstruction>,
+) {
+	use parity_wasm::elements::{BulkInstruction::*, Instruction::*};
 
-				let active_index = counter.active_control_block_index().ok_or(())?;
-				let target_indices = [br_table_data.default]
-					.iter()
-					.chain(br_table_data.table.iter())
-					.map(|label| active_index.checked_sub(*label as usize))
-					.collect::<Option<Vec<_>>>()
-					.ok_or(())?;
-				counter.branch(cursor, &target_indices)?;
-			},
-			Return => {
-				counter.increment(instruction_cost)?;
-				counter.branch(cursor, &[0])?;
-			},
-			_ => {
-				// An ordinal non control flow instruction increments the cost of the current block.
-				counter.increment(instruction_cost)?;
+	for instruction in instructions.elements_mut() {
+		let bulk = match instruction {
+			Bulk(bulk @ (MemoryCopy | MemoryFill | MemoryInit(_))) => bulk.clone(),
+			_ => continue,
+		};
+		let position = match helpers.iter().position(|helper| *helper == bulk) {
+			Some(position) => position,
+			None => {
+				helpers.push(bulk);
+				helpers.len() - 1
 			},
-		}
+		};
+		*instruction = Call(first_helper + position as u32);
 	}
-
-	counter.finalized_blocks.sort_unstable_by_key(|block| block.start_pos);
-	Ok(counter.finalized_blocks)
 }
 
+/// Add the helpers for the instructions replaced by [`inject_bulk_memory_helpers`].
+///
+/// Each helper takes the three operands of the instruction, the last of which is the number of
+/// bytes, and charges `byte_cost` for each byte.
+#[cfg(feature = "bulk")]
+fn add_bulk_memory_helpers(
+	module: elements::Module,
+	helpers: Vec<elements::BulkInstruction>,
+	byte_cost: u32,
+	gas_func: u32,
+	precision: GasPrecision,
+) -> elements::Module {
+	use parity_wasm::elements::Instruction::*;
+
+	if helpers.is_empty() {
+		return module
+	}
+
+	let mut b = builder::from_module(module);
+	for helper in helpers {
+		// The product of two 32 bit values can't overflow 64 bits.
+		let mut instructions =
+			vec![GetLocal(2), I64ExtendUI32, I64Const(i64::from(byte_cost)), I64Mul];
+		let mut locals = Vec::new();
+		if precision == GasPrecision::Bits32 {
+			locals.push(elements::Local::new(1, ValueType::I64));
+			// Trap instead of charging a truncated amount.
+			instructions.extend([
+				SetLocal(3),
+				GetLocal(3),
+				I64Const(u32::MAX.into()),
+				I64GtU,
+				If(elements::BlockType::NoResult),
+				Unreachable,
+				End,
+				GetLocal(3),
+				I32WrapI64,
+			]);
+		}
+		instructions.extend([
+			Call(gas_func),
+			GetLocal(0),
+			GetLocal(1),
+			GetLocal(2),
+			Bulk(helper),
+			End,
+		]);
+
+		b.push_function(
+			builder::function()
+				.signature()
+				.with_params(vec![ValueType::I32; 3])
+				.build()
+				.body()
+				.with_locals(locals)
+				.with_instructions(elements::Instructions::new(instructions))
+				.build()
+				.build(),
+		);
+	}
+	b.build()
+}
+
+/// Import the gas function with the given signature from the host at the index `func_idx` of
+/// the function space.
+///
+/// References to the functions shifted by the import aren't updated.
+fn import_gas_function(
+	module: elements::Module,
+	import: &GasImport,
+	signature: builder::SignatureBuilder,
+	func_idx: u32,
+) -> elements::Module {
+	let mut mbuilder = builder::from_module(module);
+	let import_sig = mbuilder.push_signature(signature.build_sig());
+
+	mbuilder.push_import(
+		builder::import()
+			.module(&import.module)
+			.field(&import.field)
+			.external()
+			.func(import_sig)
+			.build(),
+	);
+
+	// back to plain module
+	let mut module = mbuilder.build();
+	let entries = module
+		.import_section_mut()
+		.expect("an import was just pushed; qed")
+		.entries_mut();
+	let position = entries
+		.iter()
+		.enumerate()
+		.filter(|(_, entry)| matches!(entry.external(), elements::External::Function(_)))
+		.nth(func_idx as usize)
+		.map(|(position, _)| position)
+		.expect("the pushed import is a function at or after `func_idx`; qed");
+	let entry = entries.pop().expect("an import was just pushed; qed");
+	entries.insert(position, entry);
+	module
+}
+
+/// Add the exported gas global and the local function charging gas from it.
+///
+/// The function takes the charge of the given precision and must end up at the index `gas_func`.
+/// If the global doesn't cover the charge, the function traps unless a `refill` function is
+/// given, see [`Backend::BatchedHostFunction`].
+fn add_gas_global(
+	module: elements::Module,
+	export_name: &str,
+	gas_func: u32,
+	refill: Option<u32>,
+	precision: GasPrecision,
+) -> elements::Module {
+	use parity_wasm::elements::Instruction::*;
+
+	// The global is always 64 bit wide, so 32 bit charges have to be extended.
+	let charge = match precision {
+		GasPrecision::Bits32 => vec![GetLocal(0), I64ExtendUI32],
+		GasPrecision::Bits64 => vec![GetLocal(0)],
+	};
+
+	let gas_global = module.globals_space() as u32;
+	let shortage = match refill {
+		None => vec![Unreachable],
+		// gas += refill(cost - gas)
+		Some(refill) => charge
+			.iter()
+			.cloned()
+			.chain(vec![
+				GetGlobal(gas_global),
+				I64Sub,
+				Call(refill),
+				GetGlobal(gas_global),
+				I64Add,
+				SetGlobal(gas_global),
+			])
+			.collect(),
+	};
+	let mut b = builder::from_module(module);
+	b.push_global(builder::global().value_type().i64().mutable().init_expr(I64Const(0)).build());
+	b.push_export(builder::export().field(export_name).internal().global(gas_global).build());
+	let location = b.push_function(
+		builder::function()
+			.signature()
+			.with_param(precision.value_type())
+			.build()
+			.body()
+			.with_instructions(elements::Instructions::new(
+				// if gas < cost: <shortage>
+				iter::once(GetGlobal(gas_global))
+					.chain(charge.clone())
+					.chain(vec![I64LtU, If(elements::BlockType::NoResult)])
+					.chain(shortage)
+					.chain(iter::once(End))
+					// gas -= cost
+					.chain(iter::once(GetGlobal(gas_global)))
+					.chain(charge)
+					.chain(vec![I64Sub, SetGlobal(gas_global), End])
+					.collect(),
+			))
+			.build()
+			.build(),
+	);
+	let module = b.build();
+	debug_assert_eq!(
+		module.import_count(elements::ImportCountType::Function) as u32 + location.body,
+		gas_func
+	);
+	module
+}
+
+/// Address of the gas counter of [`Backend::LinearMemory`], appending the dedicated page to the
+/// memory if requested.
+///
+/// Returns `None` if the module has no memory to keep the counter in.
+fn place_memory_counter(
+	module: &mut elements::Module,
+	offset: u32,
+	dedicated_page: bool,
+) -> Option<u32> {
+	const PAGE_SIZE: u32 = 65536;
+	// The counter is 8 bytes wide.
+	let end = offset.checked_add(8)?;
+
+	if !dedicated_page {
+		return (module.memory_space() > 0).then(|| offset)
+	}
+	if end > PAGE_SIZE {
+		return None
+	}
+	let memory = module.memory_section_mut()?.entries_mut().first_mut()?;
+	let initial = memory.limits().initial();
+	let grow = |pages: u32| pages.checked_add(1).filter(|pages| *pages <= PAGE_SIZE);
+	let maximum = match memory.limits().maximum() {
+		Some(maximum) => Some(grow(maximum)?),
+		None => None,
+	};
+	*memory = elements::MemoryType::new(grow(initial)?, maximum);
+	Some(initial * PAGE_SIZE + offset)
+}
+
+/// Add the function charging gas from the counter at `address` of the linear memory.
+///
+/// The function takes the charge of the given precision and must end up at the index `gas_func`.
+fn add_memory_gas_function(
+	module: elements::Module,
+	address: u32,
+	gas_func: u32,
+	precision: GasPrecision,
+) -> elements::Module {
+	use parity_wasm::elements::Instruction::*;
+
+	// The counter is always 64 bit wide, so 32 bit charges have to be extended.
+	let charge = match precision {
+		GasPrecision::Bits32 => vec![GetLocal(0), I64ExtendUI32],
+		GasPrecision::Bits64 => vec![GetLocal(0)],
+	};
+	let address = I32Const(address as i32);
+
+	let mut b = builder::from_module(module);
+	let location = b.push_function(
+		builder::function()
+			.signature()
+			.with_param(precision.value_type())
+			.build()
+			.body()
+			.with_instructions(elements::Instructions::new(
+				// if gas < cost: unreachable
+				vec![address.clone(), I64Load(3, 0)]
+					.into_iter()
+					.chain(charge.clone())
+					.chain(vec![I64LtU, If(elements::BlockType::NoResult), Unreachable, End])
+					// gas -= cost
+					.chain(vec![address.clone(), address, I64Load(3, 0)])
+					.chain(charge)
+					.chain(vec![I64Sub, I64Store(3, 0), End])
+					.collect(),
+			))
+			.build()
+			.build(),
+	);
+	let module = b.build();
+	debug_assert_eq!(
+		module.import_count(elements::ImportCountType::Function) as u32 + location.body,
+		gas_func
+	);
+	module
+}
+
+pub(crate) fn determine_metered_blocks<R: Rules>(
+	instructions: &elements::Instructions,
+	rules: &R,
+	coalesce: bool,
+) -> Result<Vec<MeteredBlock>, BodyError> {
+	use parity_wasm::elements::Instruction::*;
+
+	let mut counter = Counter::new(coalesce);
+
+	// Begin an implicit function (i.e. `func...end`) block.
+	counter.begin_control_block(0, false, false);
+
+	for cursor in 0..instructions.elements().len() {
+		let instruction = &instructions.elements()[cursor];
+		let instruction_cost =
+			rules.instruction_cost(instruction).ok_or(BodyError::Forbidden(cursor))?;
+		match instruction {
+			Block(_) => {
+				counter.increment(instruction_cost)?;
+
+				// Begin new block. The cost of the following opcodes until `end` or `else` will
+				// be included into this block. The start position is set to that of the previous
+				// active metered block to signal that they should be merged in order to reduce
+				// unnecessary metering instructions.
+				let top_block_start_pos = counter.active_metered_block()?.start_pos;
+				counter.begin_control_block(top_block_start_pos, false, false);
+			},
+			If(_) => {
+				counter.increment(instruction_cost)?;
+				counter.begin_control_block(cursor + 1, false, true);
+			},
+			Loop(_) => {
+				counter.increment(instruction_cost)?;
+				counter.begin_control_block(cursor + 1, true, false);
+			},
+			End => {
+				counter.finalize_control_block(cursor)?;
+			},
+			Else => {
+				counter.finalize_metered_block(cursor)?;
+			},
+			Br(label) | BrIf(label) => {
+				counter.increment(instruction_cost)?;
+
+				// Label is a relative index into the control stack.
+				let active_index = counter.active_control_block_index().ok_or(())?;
+				let target_index = active_index.checked_sub(*label as usize).ok_or(())?;
+				counter.branch(cursor, &[target_index])?;
+			},
+			BrTable(br_table_data) => {
+				counter.increment(instruction_cost)?;
+
+				let active_index = counter.active_control_block_index().ok_or(())?;
+				let target_indices = [br_table_data.default]
+					.iter()
+					.chain(br_table_data.table.iter())
+					.map(|label| active_index.checked_sub(*label as usize))
+					.collect::<Option<Vec<_>>>()
+					.ok_or(())?;
+				counter.branch(cursor, &target_indices)?;
+			},
+			Return => {
+				counter.increment(instruction_cost)?;
+				counter.branch(cursor, &[0])?;
+			},
+			_ => {
+				// An ordinal non control flow instruction increments the cost of the current block.
+				counter.increment(instruction_cost)?;
+			},
+		}
+	}
+
+	counter.finalized_blocks.sort_unstable_by_key(|block| block.start_pos);
+	Ok(counter.finalized_blocks)
+}
+
+/// A control block as seen by [`determine_branch_target_charges`].
+struct ChargeFrame {
+	/// Index of the charge active when the control block was opened.
+	outer_charge: usize,
+	is_loop: bool,
+	/// Whether a branch targets the end of the control block.
+	is_target: bool,
+}
+
+/// Determine the charges for [`ChargePlacement::BranchTargets`].
+///
+/// The returned blocks are not metered blocks in the strict sense: not all of their instructions
+/// are necessarily executed.
+pub(crate) fn determine_branch_target_charges<R: Rules>(
+	instructions: &elements::Instructions,
+	rules: &R,
+) -> Result<Vec<MeteredBlock>, BodyError> {
+	use parity_wasm::elements::Instruction::*;
+
+	let mut charges = vec![MeteredBlock { start_pos: 0, cost: 0 }];
+	let mut active = 0;
+	// The implicit function block.
+	let mut stack = vec![ChargeFrame { outer_charge: 0, is_loop: false, is_target: false }];
+
+	for (cursor, instruction) in instructions.elements().iter().enumerate() {
+		let instruction_cost =
+			rules.instruction_cost(instruction).ok_or(BodyError::Forbidden(cursor))?;
+		match instruction {
+			Else => {
+				// The `else` arm is dominated by the `if`, not by the charges within the `then`
+				// arm.
+				active = stack.last().ok_or(())?.outer_charge;
+				continue
+			},
+			End => {
+				let frame = stack.pop().ok_or(())?;
+				active = if frame.is_target {
+					charges.push(MeteredBlock { start_pos: cursor + 1, cost: 0 });
+					charges.len() - 1
+				} else {
+					frame.outer_charge
+				};
+				continue
+			},
+			_ => {},
+		}
+
+		let charge = &mut charges[active];
+		charge.cost =
+			charge.cost.checked_add(instruction_cost.into()).ok_or(BodyError::Overflow)?;
+
+		let targets = match instruction {
+			Block(_) | If(_) | Loop(_) => {
+				stack.push(ChargeFrame {
+					outer_charge: active,
+					is_loop: matches!(instruction, Loop(_)),
+					is_target: false,
+				});
+				if let Loop(_) = instruction {
+					charges.push(MeteredBlock { start_pos: cursor + 1, cost: 0 });
+					active = charges.len() - 1;
+				}
+				continue
+			},
+			Br(label) | BrIf(label) => vec![*label],
+			BrTable(br_table_data) => iter::once(br_table_data.default)
+				.chain(br_table_data.table.iter().copied())
+				.collect(),
+			_ => continue,
+		};
+		for label in targets {
+			let index = stack.len().checked_sub(label as usize + 1).ok_or(())?;
+			let frame = &mut stack[index];
+			// Branching to the function block returns.
+			if !frame.is_loop && index > 0 {
+				frame.is_target = true;
+			}
+		}
+	}
+
+	// Several charges can start at the same position if control blocks end there.
+	charges.sort_by_key(|charge| charge.start_pos);
+	let mut merged: Vec<MeteredBlock> = Vec::with_capacity(charges.len());
+	for charge in charges {
+		match merged.last_mut() {
+			Some(last) if last.start_pos == charge.start_pos =>
+				last.cost = last.cost.checked_add(charge.cost).ok_or(BodyError::Overflow)?,
+			_ => merged.push(charge),
+		}
+	}
+	merged.retain(|charge| charge.cost > 0);
+	Ok(merged)
+}
+
+/// Determine the single charge for [`ChargePlacement::FunctionEntry`].
+pub(crate) fn determine_function_charge<R: Rules>(
+	instructions: &elements::Instructions,
+	rules: &R,
+) -> Result<Vec<MeteredBlock>, BodyError> {
+	use parity_wasm::elements::Instruction::*;
+
+	let mut cost = 0u64;
+	for (cursor, instruction) in instructions.elements().iter().enumerate() {
+		let instruction_cost =
+			rules.instruction_cost(instruction).ok_or(BodyError::Forbidden(cursor))?;
+		if !matches!(instruction, Else | End) {
+			cost = cost.checked_add(instruction_cost.into()).ok_or(BodyError::Overflow)?;
+		}
+	}
+	Ok(Some(MeteredBlock { start_pos: 0, cost })
+		.filter(|block| block.cost > 0)
+		.into_iter()
+		.collect())
+}
+
+/// Number of metered blocks of a body which are charged for, without coalescing.
+pub(crate) fn charged_blocks<R: Rules>(
+	instructions: &elements::Instructions,
+	rules: &R,
+) -> Result<usize, BodyError> {
+	let blocks = determine_metered_blocks(instructions, rules, false)?;
+	Ok(blocks.iter().filter(|block| block.cost > 0).count())
+}
+
+/// Add the base cost of a metered block to every block with a cost.
+pub(crate) fn charge_block_base<R: Rules>(
+	blocks: &mut [MeteredBlock],
+	rules: &R,
+) -> Result<(), BodyError> {
+	let base = u64::from(rules.block_base_cost());
+	if base == 0 {
+		return Ok(())
+	}
+	for block in blocks.iter_mut().filter(|block| block.cost > 0) {
+		block.cost = block.cost.checked_add(base).ok_or(BodyError::Overflow)?;
+	}
+	Ok(())
+}
+
+/// Add the cost of initializing the declared locals to the charge at the function entry.
+pub(crate) fn charge_locals<R: Rules>(
+	blocks: &mut Vec<MeteredBlock>,
+	locals: &[elements::Local],
+	rules: &R,
+) -> Result<(), BodyError> {
+	let count: u64 = locals.iter().map(|local| u64::from(local.count())).sum();
+	let cost = count
+		.checked_mul(rules.call_per_local_cost().into())
+		.ok_or(BodyError::Overflow)?;
+	if cost == 0 {
+		return Ok(())
+	}
+	match blocks.first_mut() {
+		Some(block) if block.start_pos == 0 =>
+			block.cost = block.cost.checked_add(cost).ok_or(BodyError::Overflow)?,
+		_ => blocks.insert(0, MeteredBlock { start_pos: 0, cost }),
+	}
+	Ok(())
+}
+
+/// Functions of `module` reachable from `entries` through direct calls which don't go through
+/// any of `barriers`, by function index.
+fn reachable_functions(
+	module: &elements::Module,
+	entries: impl Iterator<Item = u32>,
+	barriers: &[u32],
+) -> Vec<bool> {
+	let func_imports = module.import_count(elements::ImportCountType::Function);
+	let bodies = module.code_section().map(|section| section.bodies()).unwrap_or(&[]);
+	let mut reached = vec![false; func_imports + bodies.len()];
+	let mut stack: Vec<u32> = entries.collect();
+	while let Some(func_idx) = stack.pop() {
+		match reached.get_mut(func_idx as usize) {
+			Some(reached) if !*reached => *reached = true,
+			_ => continue,
+		}
+		let body = match (func_idx as usize).checked_sub(func_imports) {
+			Some(defined_idx) => &bodies[defined_idx],
+			None => continue,
+		};
+		stack.extend(body.code().elements().iter().filter_map(|instruction| match instruction {
+			elements::Instruction::Call(callee) if !barriers.contains(callee) => Some(*callee),
+			_ => None,
+		}));
+	}
+	reached
+}
+
+/// Instrument a function body, returning the number of metered blocks, their total cost and the
+/// ranges of the injected charges.
 pub fn inject_counter<R: Rules>(
-	instructions: &mut elements::Instructions,
+	func_body: &mut elements::FuncBody,
 	rules: &R,
-	gas_func: u32,
-) -> Result<(), ()> {
-	let blocks = determine_metered_blocks(instructions, rules)?;
-	insert_metering_calls(instructions, blocks, gas_func)
+	charger: Charger,
+	config: &Config,
+) -> Result<(usize, u64, Vec<Range<usize>>), BodyError> {
+	let instructions = func_body.code();
+	let mut blocks = match config.placement {
+		ChargePlacement::MeteredBlocks =>
+			determine_metered_blocks(instructions, rules, config.coalesce_charges)?,
+		ChargePlacement::BranchTargets => determine_branch_target_charges(instructions, rules)?,
+		ChargePlacement::FunctionEntry => determine_function_charge(instructions, rules)?,
+	};
+	charge_block_base(&mut blocks, rules)?;
+	charge_locals(&mut blocks, func_body.locals(), rules)?;
+	let count = blocks.iter().filter(|block| block.cost > 0).count();
+	let cost = blocks.iter().fold(0u64, |cost, block| cost.saturating_add(block.cost));
+	let injected = insert_metering_calls(func_body.code_mut(), blocks, charger, config)?;
+	Ok((count, cost, injected))
+}
+
+/// Split `cost` into charges none of which exceeds `max_charge`.
+fn split_charges(cost: u64, max_charge: u64) -> impl Iterator<Item = u64> {
+	let (full, rest) = (cost / max_charge, cost % max_charge);
+	iter::repeat(max_charge)
+		.take(full as usize)
+		.chain(Some(rest).filter(|rest| *rest > 0))
 }
 
 // Then insert metering calls into a sequence of instructions given the block locations and costs.
 fn insert_metering_calls(
 	instructions: &mut elements::Instructions,
 	blocks: Vec<MeteredBlock>,
-	gas_func: u32,
-) -> Result<(), ()> {
-	use parity_wasm::elements::Instruction::*;
+	charger: Charger,
+	config: &Config,
+) -> Result<Vec<Range<usize>>, ()> {
+	let max_charge = config.charge_limit();
 
 	// To do this in linear time, construct a new vector of instructions, copying over old
 	// instructions one by one and injecting new ones as required.
-	let new_instrs_len = instructions.elements().len() + 2 * blocks.len();
+	let charges_count: usize =
+		blocks.iter().map(|block| split_charges(block.cost, max_charge).count()).sum();
+	let new_instrs_len = instructions.elements().len() + charger.len() * charges_count;
 	let original_instrs =
 		mem::replace(instructions.elements_mut(), Vec::with_capacity(new_instrs_len));
 	let new_instrs = instructions.elements_mut();
+	let mut injected = Vec::new();
 
 	let mut block_iter = blocks.into_iter().peekable();
 	for (original_pos, instr) in original_instrs.into_iter().enumerate() {
 		// If there the next block starts at this position, inject metering instructions.
 		let used_block = if let Some(block) = block_iter.peek() {
 			if block.start_pos == original_pos {
-				new_instrs.push(I32Const(block.cost as i32));
-				new_instrs.push(Call(gas_func));
+				let start = new_instrs.len();
+				for charge in split_charges(block.cost, max_charge) {
+					charger.charge(charge, config.precision, new_instrs);
+				}
+				if new_instrs.len() > start {
+					injected.push(start..new_instrs.len());
+				}
 				true
 			} else {
 				false
@@ -390,16 +1684,52 @@ fn insert_metering_calls(
 		return Err(())
 	}
 
-	Ok(())
+	Ok(injected)
+}
+
+/// Charge the surcharges of the called imports before the calls, see
+/// [`Config::with_import_cost`].
+///
+/// Returns the ranges of the injected charges.
+fn insert_import_surcharges(
+	instructions: &mut elements::Instructions,
+	surcharges: &Map<u32, u64>,
+	charger: Charger,
+	config: &Config,
+) -> Vec<Range<usize>> {
+	let max_charge = config.charge_limit();
+	let original_instrs = mem::take(instructions.elements_mut());
+	let new_instrs = instructions.elements_mut();
+	let mut injected = Vec::new();
+	for instr in original_instrs {
+		if let elements::Instruction::Call(func_idx) = instr {
+			if let Some(cost) = surcharges.get(&func_idx) {
+				let start = new_instrs.len();
+				for charge in split_charges(*cost, max_charge) {
+					charger.charge(charge, config.precision, new_instrs);
+				}
+				if new_instrs.len() > start {
+					injected.push(start..new_instrs.len());
+				}
+			}
+		}
+		new_instrs.push(instr);
+	}
+	injected
 }
 
 /// Transforms a given module into one that charges gas for code to be executed by proxy of an
 /// imported gas metering function.
 ///
-/// The output module imports a function "gas" from the specified module with type signature
-/// [i32] -> []. The argument is the amount of gas required to continue execution. The external
-/// function is meant to keep track of the total amount of gas used and trap or otherwise halt
-/// execution of the runtime if the gas usage exceeds some allowed limit.
+/// The output module imports a function with type signature [i32] -> [] as `gas_import`: either a
+/// module name to import the function "gas" from, or a pair of module and field names, e.g.
+/// `("seal0", "gas")`. The argument is the amount of gas required to continue execution. The
+/// external function is meant to keep track of the total amount of gas used and trap or otherwise
+/// halt execution of the runtime if the gas usage exceeds some allowed limit.
+///
+/// If the module imports a function as `gas_import` already, e.g. because another tool added it,
+/// that import is used and no function index changes. Its signature has to be the one above,
+/// otherwise [`Error::GasImportSignature`] is returned.
 ///
 /// The body of each function is divided into metered blocks, and the calls to charge gas are
 /// inserted at the beginning of every such block of code. A metered block is defined so that,
@@ -421,96 +1751,306 @@ fn insert_metering_calls(
 ///
 /// The above transformations are performed for every function body defined in the module. This
 /// function also rewrites all function indices references by code, table elements, etc., since
-/// the addition of an imported functions changes the indices of module-defined functions.
+/// the addition of an imported functions changes the indices of module-defined functions. The
+/// function and local names of the name section are remapped too, so debug names stay attached to
+/// their functions.
 ///
 /// This routine runs in time linear in the size of the input module.
 ///
-/// The function fails if the module contains any operation forbidden by gas rule set, returning
-/// the original module as an Err.
-pub fn inject_gas_counter<R: Rules>(
+/// The function fails if the module contains any operation forbidden by gas rule set, reporting
+/// the first such instruction.
+///
+/// Tail calls (`return_call` and `return_call_indirect`) are not supported: parity-wasm doesn't
+/// know these instructions, so modules using them already fail to deserialize.
+pub fn inject_gas_counter<R: Rules, I: Into<GasImport>>(
 	module: elements::Module,
 	rules: &R,
-	gas_module_name: &str,
-) -> Result<elements::Module, elements::Module> {
-	// Injecting gas counting external
-	let mut mbuilder = builder::from_module(module);
-	let import_sig =
-		mbuilder.push_signature(builder::signature().with_param(ValueType::I32).build_sig());
-
-	mbuilder.push_import(
-		builder::import()
-			.module(gas_module_name)
-			.field("gas")
-			.external()
-			.func(import_sig)
-			.build(),
-	);
+	gas_import: I,
+) -> Result<elements::Module, Error> {
+	inject_gas_counter_with_config(module, rules, &Config::new(gas_import))
+}
 
-	// back to plain module
-	let mut module = mbuilder.build();
+/// Transforms a given module into one that charges gas for code to be executed, using the given
+/// configuration.
+///
+/// See [`inject_gas_counter`] for details. With [`Backend::MutableGlobal`] no function is
+/// imported; instead the charges call a function appended to the module. With
+/// [`Backend::InlineMutableGlobal`] the charges are inlined. With
+/// [`Backend::BatchedHostFunction`] the charges call an appended function as well, which calls
+/// the imported function only when the cached allowance runs short. With
+/// [`Backend::LinearMemory`] the charges are inlined and the counter is in the linear memory.
+pub fn inject_gas_counter_with_config<R: Rules>(
+	module: elements::Module,
+	rules: &R,
+	config: &Config,
+) -> Result<elements::Module, Error> {
+	instrument(module, rules, config, |_| Ok::<_, Error>(())).map(|(module, _)| module)
+}
 
-	// calculate actual function index of the imported definition
-	//    (subtract all imports that are NOT functions)
+/// Like [`inject_gas_counter_with_config`], but also report the overhead of the instrumentation
+/// for every function defined by the module.
+pub fn inject_gas_counter_with_report<R: Rules>(
+	module: elements::Module,
+	rules: &R,
+	config: &Config,
+) -> Result<(elements::Module, Report), Error> {
+	let body_size = |body: &elements::FuncBody| {
+		parity_wasm::serialize(body.clone()).map_or(0, |bytes| bytes.len())
+	};
+	let original_sizes = module
+		.code_section()
+		.map(|section| section.bodies())
+		.unwrap_or(&[])
+		.iter()
+		.map(body_size)
+		.collect::<Vec<_>>();
+	let func_imports = module.import_count(elements::ImportCountType::Function) as u32;
+
+	let (module, charges) = instrument(module, rules, config, |_| Ok::<_, Error>(()))?;
+
+	// The instrumentation only appends functions, so the original ones keep their order.
+	let bodies = module.code_section().map(|section| section.bodies()).unwrap_or(&[]);
+	let functions = original_sizes
+		.into_iter()
+		.zip(bodies)
+		.zip(charges)
+		.enumerate()
+		.map(|(idx, ((original_size, body), (metered_blocks, static_cost)))| FunctionReport {
+			func_idx: func_imports + idx as u32,
+			metered_blocks,
+			static_cost,
+			size_increase: body_size(body).saturating_sub(original_size),
+		})
+		.collect();
+	Ok((module, Report { functions }))
+}
 
-	let gas_func = module.import_count(elements::ImportCountType::Function) as u32 - 1;
-	let total_func = module.functions_space() as u32;
-	let mut need_grow_counter = false;
-	let mut error = false;
-
-	// Updating calling addresses (all calls to function index >= `gas_func` should be incremented)
-	for section in module.sections_mut() {
-		match section {
-			elements::Section::Code(code_section) =>
-				for func_body in code_section.bodies_mut() {
-					update_call_index(func_body.code_mut(), gas_func);
-					if inject_counter(func_body.code_mut(), rules, gas_func).is_err() {
-						error = true;
-						break
-					}
-					if rules.memory_grow_cost().is_some() &&
-						inject_grow_counter(func_body.code_mut(), total_func) > 0
-					{
-						need_grow_counter = true;
-					}
-				},
-			elements::Section::Export(export_section) => {
-				for export in export_section.entries_mut() {
-					if let elements::Internal::Function(func_index) = export.internal_mut() {
-						if *func_index >= gas_func {
-							*func_index += 1
-						}
-					}
-				}
-			},
-			elements::Section::Element(elements_section) => {
-				// Note that we do not need to check the element type referenced because in the
-				// WebAssembly 1.0 spec, the only allowed element type is funcref.
-				for segment in elements_section.entries_mut() {
-					// update all indirect call addresses initial values
-					for func_index in segment.members_mut() {
-						if *func_index >= gas_func {
-							*func_index += 1
-						}
-					}
-				}
-			},
-			elements::Section::Start(start_idx) =>
-				if *start_idx >= gas_func {
-					*start_idx += 1
+/// Instrument the module, returning the number of metered blocks and their total cost for each
+/// defined function.
+///
+/// `finish_body` is called with every defined function body once it is metered, so that further
+/// instrumentation can be applied in the same traversal. It sees the function indices shifted by
+/// the gas import, if any.
+pub(crate) fn instrument<R, E, F>(
+	module: elements::Module,
+	rules: &R,
+	config: &Config,
+	mut finish_body: F,
+) -> Result<(elements::Module, Vec<(usize, u64)>), E>
+where
+	R: Rules,
+	E: From<Error>,
+	F: FnMut(&mut elements::FuncBody) -> Result<(), E>,
+{
+	if crate::is_instrumented(&module, PASS) {
+		return Err(Error::AlreadyInstrumented.into())
+	}
+	check_limits(&module, &config.limits).map_err(Error::LimitExceeded)?;
+	// The names have to be parsed for their function indices to be updated. A malformed name
+	// section is kept as is.
+	let mut module = module.parse_names().unwrap_or_else(|(_err, module)| module);
+	let func_imports = module.import_count(elements::ImportCountType::Function) as u32;
+	let exempt = config.exempt_functions(&module);
+	let cold = config.cold_functions(&module);
+	let cold_config = Config { placement: ChargePlacement::FunctionEntry, ..config.clone() };
+	let import_costs = config.import_costs(&module);
+	let reused_import = config.existing_import(&module)?;
+	// The placements are only checked if the backend inserts the entity.
+	let import_idx = match config.import_signature() {
+		Some(_) if reused_import.is_none() => config
+			.import_placement
+			.index(0, func_imports)
+			.map_err(|index| Error::InvalidPlacement { index })?,
+		_ => func_imports,
+	};
+	let global_idx = match config.backend {
+		Backend::MutableGlobal(_) |
+		Backend::InlineMutableGlobal(_) |
+		Backend::BatchedHostFunction(_) => config
+			.global_placement
+			.index(
+				module.import_count(elements::ImportCountType::Global) as u32,
+				module.globals_space() as u32,
+			)
+			.map_err(|index| Error::InvalidPlacement { index })?,
+		_ => module.globals_space() as u32,
+	};
+	let counter_address = match config.backend {
+		Backend::LinearMemory { offset, dedicated_page } => Some(
+			place_memory_counter(&mut module, offset, dedicated_page)
+				.ok_or(Error::CounterMemory)?,
+		),
+		_ => None,
+	};
+	let (mut module, gas_func, total_func) = match config.backend {
+		Backend::HostFunction if reused_import.is_some() => {
+			let gas_func = reused_import.expect("checked by the guard; qed");
+			let total_func = module.functions_space() as u32;
+			(module, gas_func, total_func)
+		},
+		Backend::HostFunction => {
+			let signature = builder::signature().with_param(config.precision.value_type());
+			let module = import_gas_function(module, &config.import, signature, import_idx);
+			let gas_func = import_idx;
+			let total_func = module.functions_space() as u32;
+			(module, gas_func, total_func)
+		},
+		Backend::MutableGlobal(_) |
+		Backend::InlineMutableGlobal(_) |
+		Backend::LinearMemory { .. } => {
+			// The charging function is appended after all functions, so no index is shifted.
+			let gas_func = module.functions_space() as u32;
+			(module, gas_func, gas_func + 1)
+		},
+		Backend::BatchedHostFunction(_) => {
+			// The refilling function is imported, the charging function appended after all
+			// functions.
+			let module = if reused_import.is_some() {
+				module
+			} else {
+				let signature =
+					builder::signature().with_param(ValueType::I64).with_result(ValueType::I64);
+				import_gas_function(module, &config.import, signature, import_idx)
+			};
+			let gas_func = module.functions_space() as u32;
+			(module, gas_func, gas_func + 1)
+		},
+	};
+	// Functions with an index from here on are shifted by the import, if one is added.
+	let first_shifted = match (&config.backend, reused_import) {
+		(_, Some(_)) => None,
+		(Backend::BatchedHostFunction(_), None) => Some(import_idx),
+		_ => Some(gas_func),
+	};
+	let charger = match (&config.backend, counter_address) {
+		(Backend::InlineMutableGlobal(_), _) => Charger::Inline(module.globals_space() as u32),
+		(_, Some(address)) => Charger::Memory(address),
+		_ => Charger::Call(gas_func),
+	};
+	let mut need_grow_counter = false;
+	let mut charges = Vec::new();
+	let mut injected = Vec::new();
+	let original_bodies = module.code_section().map_or(0, |section| section.bodies().len());
+
+	// Updating function indices (all references to index >= `gas_func` should be incremented)
+	if let Some(first_shifted) = first_shifted {
+		visit_function_indices(&mut module, |func_index, _| {
+			if *func_index >= first_shifted {
+				*func_index += 1
+			}
+		});
+	}
+
+	if let Some(code_section) = module.code_section_mut() {
+		for (idx, func_body) in code_section.bodies_mut().iter_mut().enumerate() {
+			let func_idx = func_imports + idx as u32;
+			if exempt.binary_search(&func_idx).is_ok() {
+				charges.push((0, 0));
+				injected.push(Vec::new());
+				finish_body(func_body)?;
+				continue
+			}
+			let body_config =
+				if cold.binary_search(&func_idx).is_ok() { &cold_config } else { config };
+			let mut body_injected = match inject_counter(func_body, rules, charger, body_config) {
+				Ok((count, cost, body_injected)) => {
+					charges.push((count, cost));
+					body_injected
 				},
-			_ => {},
+				Err(err) =>
+					return Err(match err {
+						BodyError::Forbidden(offset) => Error::Forbidden {
+							func_idx,
+							offset,
+							instruction: func_body.code().elements()[offset].clone(),
+						},
+						BodyError::Malformed => Error::Malformed { func_idx },
+						BodyError::Overflow => Error::Overflow { func_idx },
+					}
+					.into()),
+			};
+			if !import_costs.is_empty() {
+				let surcharges =
+					insert_import_surcharges(func_body.code_mut(), &import_costs, charger, config);
+				body_injected =
+					crate::instrumentation_map::merge_injected(&body_injected, &surcharges);
+			}
+			injected.push(body_injected);
+			if rules.memory_grow_cost().is_some() &&
+				inject_grow_counter(func_body.code_mut(), total_func) > 0
+			{
+				need_grow_counter = true;
+			}
+			finish_body(func_body)?;
 		}
 	}
 
-	if error {
-		return Err(module)
+	match &config.backend {
+		Backend::HostFunction => {},
+		Backend::MutableGlobal(export_name) | Backend::InlineMutableGlobal(export_name) => {
+			let appended = module.globals_space() as u32;
+			module = add_gas_global(module, export_name, gas_func, None, config.precision);
+			crate::indices::move_global(&mut module, appended, global_idx);
+		},
+		Backend::BatchedHostFunction(export_name) => {
+			let appended = module.globals_space() as u32;
+			let refill = reused_import.unwrap_or(import_idx);
+			module = add_gas_global(module, export_name, gas_func, Some(refill), config.precision);
+			crate::indices::move_global(&mut module, appended, global_idx);
+		},
+		Backend::LinearMemory { .. } => {
+			let address = counter_address.expect("set for the linear memory backend; qed");
+			module = add_memory_gas_function(module, address, gas_func, config.precision);
+		},
 	}
 
+	#[cfg(feature = "bulk")]
+	let bulk_memory_helpers = {
+		let mut helpers = Vec::new();
+		if rules.bulk_memory_byte_cost() > 0 {
+			let first_helper = total_func + need_grow_counter as u32;
+			let bodies = module.code_section_mut().map_or(&mut [][..], |s| s.bodies_mut());
+			for (idx, func_body) in bodies.iter_mut().enumerate() {
+				if exempt.binary_search(&(func_imports + idx as u32)).is_err() {
+					inject_bulk_memory_helpers(func_body.code_mut(), first_helper, &mut helpers);
+				}
+			}
+		}
+		helpers
+	};
+
 	if need_grow_counter {
-		Ok(add_grow_counter(module, rules, gas_func))
-	} else {
-		Ok(module)
+		module = add_grow_counter(module, rules, gas_func, config.precision);
+	}
+
+	#[cfg(feature = "bulk")]
+	{
+		module = add_bulk_memory_helpers(
+			module,
+			bulk_memory_helpers,
+			rules.bulk_memory_byte_cost(),
+			gas_func,
+			config.precision,
+		);
+	}
+
+	if config.mark {
+		let parameters = format!(
+			"module={} field={} backend={:?}",
+			config.import.module, config.import.field, config.backend
+		);
+		crate::mark_instrumented(&mut module, PASS, &parameters);
+	}
+	if config.instrumentation_map {
+		crate::instrumentation_map::record_injected_code(
+			&mut module,
+			PASS,
+			&injected,
+			original_bodies,
+		);
 	}
+
+	Ok((module, charges))
 }
 
 #[cfg(test)]
@@ -557,14 +2097,196 @@ mod tests {
 		);
 		assert_eq!(
 			get_function_body(&injected_module, 1).unwrap(),
-			&vec![GetLocal(0), GetLocal(0), I32Const(10000), I32Mul, Call(0), GrowMemory(0), End,]
-				[..]
+			&vec![
+				GetLocal(0),
+				GetLocal(0),
+				I64ExtendUI32,
+				I64Const(10000),
+				I64Mul,
+				TeeLocal(1),
+				I64Const(u32::MAX.into()),
+				I64GtU,
+				If(elements::BlockType::NoResult),
+				Unreachable,
+				End,
+				GetLocal(1),
+				I32WrapI64,
+				Call(0),
+				GrowMemory(0),
+				End,
+			][..]
 		);
 
 		let binary = serialize(injected_module).expect("serialization failed");
 		wabt::wasm2wat(&binary).unwrap();
 	}
 
+	#[test]
+	fn custom_gas_import() {
+		let module = builder::module()
+			.function()
+			.signature()
+			.build()
+			.body()
+			.with_instructions(elements::Instructions::new(vec![Nop, End]))
+			.build()
+			.build()
+			.build();
+
+		let injected_module =
+			inject_gas_counter(module.clone(), &rules::Set::default(), ("seal0", "charge"))
+				.unwrap();
+		let import = &injected_module.import_section().unwrap().entries()[0];
+		assert_eq!((import.module(), import.field()), ("seal0", "charge"));
+
+		let injected_module = inject_gas_counter(module, &rules::Set::default(), "seal0").unwrap();
+		let import = &injected_module.import_section().unwrap().entries()[0];
+		assert_eq!((import.module(), import.field()), ("seal0", "gas"));
+	}
+
+	#[test]
+	fn existing_gas_import() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "gas" (func (param i32)))
+	(func $callee)
+	(func (export "call")
+		(call $callee)
+	)
+)
+"#,
+		);
+
+		let injected_module =
+			inject_gas_counter(module.clone(), &rules::Set::default(), "env").unwrap();
+		assert_eq!(injected_module.import_count(elements::ImportCountType::Function), 1);
+		assert_eq!(
+			get_function_body(&injected_module, 1).unwrap(),
+			&vec![I32Const(1), Call(0), Call(1), End][..]
+		);
+
+		let config = Config::new("env").with_precision(GasPrecision::Bits64);
+		assert_eq!(
+			inject_gas_counter_with_config(module, &rules::Set::default(), &config),
+			Err(Error::GasImportSignature {
+				func_idx: 0,
+				expected: elements::FunctionType::new(vec![ValueType::I64], vec![]),
+			})
+		);
+	}
+
+	/// Run the straight-line body of a 32 bit memory grow counter with `pages`, returning the
+	/// amount it charges or `None` if it traps.
+	fn run_grow_counter(body: &[elements::Instruction], pages: u32) -> Option<u32> {
+		let mut locals = [u64::from(pages), 0];
+		let mut stack = Vec::new();
+		let mut skipping = false;
+		for instruction in body {
+			if skipping {
+				skipping = *instruction != End;
+				continue
+			}
+			match *instruction {
+				GetLocal(idx) => stack.push(locals[idx as usize]),
+				TeeLocal(idx) => locals[idx as usize] = *stack.last().unwrap(),
+				I64ExtendUI32 => {},
+				I32Const(value) => stack.push(u64::from(value as u32)),
+				I64Const(value) => stack.push(value as u64),
+				I64Mul | I64Add | I64GtU | I32GtU => {
+					let (b, a) = (stack.pop().unwrap(), stack.pop().unwrap());
+					stack.push(match *instruction {
+						I64Mul => a.wrapping_mul(b),
+						I64Add => a.wrapping_add(b),
+						_ => u64::from(a > b),
+					});
+				},
+				If(_) => skipping = stack.pop().unwrap() == 0,
+				Unreachable => return None,
+				End => {},
+				I32WrapI64 => *stack.last_mut().unwrap() &= u64::from(u32::MAX),
+				Call(_) => return Some(stack.pop().unwrap() as u32),
+				ref other => panic!("Unexpected instruction {:?}", other),
+			}
+		}
+		panic!("The gas function isn't called")
+	}
+
+	#[test]
+	fn grow_charge_overflow() {
+		let cost = rules::Set::default()
+			.with_grow_cost(100_000)
+			.with_grow_base_cost(5)
+			.memory_grow_cost()
+			.unwrap();
+		let body = grow_counter_body(cost, 0, GasPrecision::Bits32);
+
+		assert_eq!(run_grow_counter(&body, 1), Some(100_005));
+		assert_eq!(run_grow_counter(&body, 42_949), Some(4_294_900_005));
+		// 42_950 * 100_000 + 5 exceeds `u32::MAX` and would charge 32_709 if it wrapped.
+		assert_eq!(run_grow_counter(&body, 42_950), None);
+		assert_eq!(run_grow_counter(&body, 65_536), None);
+	}
+
+	#[test]
+	fn two_part_grow() {
+		let module = builder::module()
+			.function()
+			.signature()
+			.param()
+			.i32()
+			.build()
+			.body()
+			.with_instructions(elements::Instructions::new(vec![GetLocal(0), GrowMemory(0), End]))
+			.build()
+			.build()
+			.build();
+		let rules = rules::Set::default()
+			.with_grow_cost(3)
+			.with_grow_base_cost(10)
+			.with_grow_max_pages(16);
+
+		let injected_module = inject_gas_counter(module, &rules, "env").unwrap();
+
+		assert_eq!(
+			get_function_body(&injected_module, 1).unwrap(),
+			&vec![
+				GetLocal(0),
+				I32Const(16),
+				I32GtU,
+				If(elements::BlockType::NoResult),
+				Unreachable,
+				End,
+				GetLocal(0),
+				GetLocal(0),
+				I64ExtendUI32,
+				I64Const(3),
+				I64Mul,
+				I64Const(10),
+				I64Add,
+				TeeLocal(1),
+				I64Const(u32::MAX.into()),
+				I64GtU,
+				If(elements::BlockType::NoResult),
+				Unreachable,
+				End,
+				GetLocal(1),
+				I32WrapI64,
+				Call(0),
+				GrowMemory(0),
+				End,
+			][..]
+		);
+		assert_eq!(verify(&injected_module, &rules, "env"), Ok(()));
+		assert_eq!(
+			verify(&injected_module, &rules::Set::default().with_grow_cost(3), "env"),
+			Err(vec![Mismatch::GrowCost {
+				expected: rules::Set::default().with_grow_cost(3).memory_grow_cost(),
+				found: rules.memory_grow_cost().unwrap(),
+			}])
+		);
+	}
+
 	#[test]
 	fn grow_no_gas_no_track() {
 		let module = builder::module()
@@ -680,9 +2402,1185 @@ mod tests {
 
 		let rules = rules::Set::default().with_forbidden_floats();
 
-		if inject_gas_counter(module, &rules, "env").is_ok() {
-			panic!("Should be error because of the forbidden operation")
-		}
+		assert_eq!(
+			inject_gas_counter(module, &rules, "env").unwrap_err(),
+			Error::Forbidden { func_idx: 0, offset: 0, instruction: F32Const(555555) }
+		);
+	}
+
+	#[test]
+	fn tail_calls_are_rejected() {
+		// (module (func (return_call 0)))
+		let mut binary = [
+			0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+			0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // type section
+			0x03, 0x02, 0x01, 0x00, // function section
+			0x0a, 0x06, 0x01, 0x04, 0x00, 0x12, 0x00, 0x0b, // code section
+		];
+		assert!(elements::deserialize_buffer::<elements::Module>(&binary).is_err());
+
+		// The same module with a `call` instead.
+		binary[binary.len() - 3] = 0x10;
+		assert!(elements::deserialize_buffer::<elements::Module>(&binary).is_ok());
+	}
+
+	#[cfg(feature = "simd")]
+	#[test]
+	fn simd() {
+		use elements::SimdInstruction::*;
+
+		let module = builder::module()
+			.function()
+			.signature()
+			.build()
+			.body()
+			.with_instructions(elements::Instructions::new(vec![
+				Simd(V128Const(Box::new([0; 16]))),
+				Simd(I32x4Neg),
+				Drop,
+				End,
+			]))
+			.build()
+			.build()
+			.build();
+
+		assert_eq!(
+			inject_gas_counter(module.clone(), &rules::Set::default(), "env").unwrap_err(),
+			Error::Forbidden {
+				func_idx: 0,
+				offset: 0,
+				instruction: Simd(V128Const(Box::new([0; 16]))),
+			}
+		);
+
+		let rules = rules::Set::default().with_simd_cost(3);
+		let injected = inject_gas_counter(module, &rules, "env").unwrap();
+		assert_eq!(get_function_body(&injected, 0).unwrap()[..2], [I32Const(7), Call(0)]);
+	}
+
+	#[cfg(feature = "sign_ext")]
+	#[test]
+	fn sign_ext() {
+		let module = parse_wat(
+			r#"
+(module
+	(func (param i32 i64) (result i64)
+		(drop (i32.extend8_s (local.get 0)))
+		(i64.extend32_s (local.get 1))
+	)
+)
+"#,
+		);
+
+		let rules = rules::Set::default();
+		let injected = inject_gas_counter(module.clone(), &rules, "env").unwrap();
+		assert_eq!(get_function_body(&injected, 0).unwrap()[..2], [I32Const(5), Call(0)]);
+
+		let rules = rules::Set::new(
+			1,
+			vec![(rules::InstructionType::SignExt, rules::Metering::Forbidden)]
+				.into_iter()
+				.collect(),
+		);
+		assert_eq!(
+			inject_gas_counter(module, &rules, "env").unwrap_err(),
+			Error::Forbidden {
+				func_idx: 0,
+				offset: 1,
+				instruction: SignExt(elements::SignExtInstruction::I32Extend8S),
+			}
+		);
+	}
+
+	#[test]
+	fn max_block_cost() {
+		let module = builder::module()
+			.global()
+			.value_type()
+			.i32()
+			.build()
+			.function()
+			.signature()
+			.build()
+			.body()
+			.with_instructions(elements::Instructions::new(vec![
+				GetGlobal(0),
+				GetGlobal(0),
+				I32Add,
+				GetGlobal(0),
+				I32Add,
+				Drop,
+				End,
+			]))
+			.build()
+			.build()
+			.build();
+
+		let config = Config::new("env").with_max_block_cost(2);
+		let injected_module =
+			inject_gas_counter_with_config(module, &rules::Set::default(), &config).unwrap();
+
+		assert_eq!(
+			get_function_body(&injected_module, 0).unwrap(),
+			&vec![
+				I32Const(2),
+				Call(0),
+				I32Const(2),
+				Call(0),
+				I32Const(2),
+				Call(0),
+				GetGlobal(0),
+				GetGlobal(0),
+				I32Add,
+				GetGlobal(0),
+				I32Add,
+				Drop,
+				End,
+			][..]
+		);
+	}
+
+	#[test]
+	fn mutable_global_backend() {
+		let module = builder::module()
+			.global()
+			.value_type()
+			.i32()
+			.init_expr(I32Const(1))
+			.build()
+			.function()
+			.signature()
+			.result()
+			.i32()
+			.build()
+			.body()
+			.with_instructions(elements::Instructions::new(vec![GetGlobal(0), GrowMemory(0), End]))
+			.build()
+			.build()
+			.memory()
+			.build()
+			.export()
+			.field("f")
+			.internal()
+			.func(0)
+			.build()
+			.build();
+
+		let config = Config::new("env").with_backend(Backend::MutableGlobal("gas_left".into()));
+		let rules = rules::Set::default().with_grow_cost(10000);
+		let injected_module = inject_gas_counter_with_config(module, &rules, &config).unwrap();
+
+		assert!(injected_module.import_section().is_none());
+		assert_eq!(
+			get_function_body(&injected_module, 0).unwrap(),
+			&vec![I32Const(2), Call(1), GetGlobal(0), Call(2), End][..]
+		);
+		assert_eq!(
+			get_function_body(&injected_module, 1).unwrap(),
+			&vec![
+				GetGlobal(1),
+				GetLocal(0),
+				I64ExtendUI32,
+				I64LtU,
+				If(elements::BlockType::NoResult),
+				Unreachable,
+				End,
+				GetGlobal(1),
+				GetLocal(0),
+				I64ExtendUI32,
+				I64Sub,
+				SetGlobal(1),
+				End,
+			][..]
+		);
+		assert_eq!(
+			get_function_body(&injected_module, 2).unwrap(),
+			&vec![
+				GetLocal(0),
+				GetLocal(0),
+				I64ExtendUI32,
+				I64Const(10000),
+				I64Mul,
+				TeeLocal(1),
+				I64Const(u32::MAX.into()),
+				I64GtU,
+				If(elements::BlockType::NoResult),
+				Unreachable,
+				End,
+				GetLocal(1),
+				I32WrapI64,
+				Call(1),
+				GrowMemory(0),
+				End,
+			][..]
+		);
+		let exports = injected_module.export_section().unwrap().entries();
+		assert!(exports
+			.iter()
+			.any(|e| e.field() == "gas_left" && *e.internal() == elements::Internal::Global(1)));
+
+		let binary = serialize(injected_module).expect("serialization failed");
+		wabt::Module::read_binary(&binary, &Default::default())
+			.unwrap()
+			.validate()
+			.expect("injected module to be valid");
+	}
+
+	#[test]
+	fn batched_host_function_backend() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "ext" (func))
+	(memory 1)
+	(func (export "f")
+		(call 0)
+		(drop (memory.grow (i32.const 1)))
+	)
+)
+"#,
+		);
+
+		let config =
+			Config::new("env").with_backend(Backend::BatchedHostFunction("gas_left".into()));
+		let rules = rules::Set::default().with_grow_cost(10000);
+		let injected_module = inject_gas_counter_with_config(module, &rules, &config).unwrap();
+
+		let imports = injected_module.import_section().unwrap().entries();
+		assert_eq!(imports[1].field(), "gas");
+		let elements::Type::Function(ty) = &injected_module.type_section().unwrap().types()
+			[match imports[1].external() {
+				elements::External::Function(ty) => *ty as usize,
+				_ => panic!("gas import is not a function"),
+			}];
+		assert_eq!(ty.params(), &[ValueType::I64]);
+		assert_eq!(ty.results(), &[ValueType::I64]);
+
+		assert_eq!(
+			get_function_body(&injected_module, 0).unwrap(),
+			&vec![I32Const(4), Call(3), Call(0), I32Const(1), Call(4), Drop, End][..]
+		);
+		assert_eq!(
+			get_function_body(&injected_module, 1).unwrap(),
+			&vec![
+				GetGlobal(0),
+				GetLocal(0),
+				I64ExtendUI32,
+				I64LtU,
+				If(elements::BlockType::NoResult),
+				GetLocal(0),
+				I64ExtendUI32,
+				GetGlobal(0),
+				I64Sub,
+				Call(1),
+				GetGlobal(0),
+				I64Add,
+				SetGlobal(0),
+				End,
+				GetGlobal(0),
+				GetLocal(0),
+				I64ExtendUI32,
+				I64Sub,
+				SetGlobal(0),
+				End,
+			][..]
+		);
+		let exports = injected_module.export_section().unwrap().entries();
+		assert!(exports
+			.iter()
+			.any(|e| e.field() == "gas_left" && *e.internal() == elements::Internal::Global(0)));
+
+		let binary = serialize(injected_module).expect("serialization failed");
+		wabt::Module::read_binary(&binary, &Default::default())
+			.unwrap()
+			.validate()
+			.expect("injected module to be valid");
+	}
+
+	#[test]
+	fn placement() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "ext" (func))
+	(global $sp (export "sp") (mut i32) (i32.const 0))
+	(func (export "f")
+		(call 0)
+		(global.set $sp (i32.const 1))
+	)
+)
+"#,
+		);
+
+		let config = Config::new("env")
+			.with_backend(Backend::BatchedHostFunction("gas_left".into()))
+			.with_import_placement(IndexPlacement::First)
+			.with_global_placement(IndexPlacement::Last);
+		let injected_module =
+			inject_gas_counter_with_config(module.clone(), &rules::Set::default(), &config)
+				.unwrap();
+
+		let imports = injected_module.import_section().unwrap().entries();
+		assert_eq!(imports[0].field(), "gas");
+		assert_eq!(imports[1].field(), "ext");
+		assert_eq!(
+			get_function_body(&injected_module, 0).unwrap(),
+			&vec![I32Const(3), Call(3), Call(1), I32Const(1), SetGlobal(0), End][..]
+		);
+		assert_eq!(get_function_body(&injected_module, 1).unwrap()[9], Call(0));
+
+		let config = config.with_global_placement(IndexPlacement::First);
+		let injected_module =
+			inject_gas_counter_with_config(module.clone(), &rules::Set::default(), &config)
+				.unwrap();
+		assert_eq!(
+			get_function_body(&injected_module, 0).unwrap(),
+			&vec![I32Const(3), Call(3), Call(1), I32Const(1), SetGlobal(1), End][..]
+		);
+		let exports = injected_module.export_section().unwrap().entries();
+		assert!(exports
+			.iter()
+			.any(|e| e.field() == "gas_left" && *e.internal() == elements::Internal::Global(0)));
+		assert!(exports
+			.iter()
+			.any(|e| e.field() == "sp" && *e.internal() == elements::Internal::Global(1)));
+
+		let binary = serialize(injected_module).expect("serialization failed");
+		wabt::Module::read_binary(&binary, &Default::default())
+			.unwrap()
+			.validate()
+			.expect("injected module to be valid");
+
+		let config = Config::new("env").with_import_placement(IndexPlacement::At(2));
+		assert_eq!(
+			inject_gas_counter_with_config(module, &rules::Set::default(), &config),
+			Err(Error::InvalidPlacement { index: 2 })
+		);
+	}
+
+	#[test]
+	fn linear_memory_backend() {
+		let module = parse_wat(
+			r#"
+(module
+	(memory 1 2)
+	(func
+		(drop (memory.grow (i32.const 1)))
+	)
+)
+"#,
+		);
+
+		let config = Config::new("env")
+			.with_backend(Backend::LinearMemory { offset: 16, dedicated_page: true });
+		let rules = rules::Set::default().with_grow_cost(10000);
+		let injected_module =
+			inject_gas_counter_with_config(module.clone(), &rules, &config).unwrap();
+
+		assert!(injected_module.import_section().is_none());
+		let limits = injected_module.memory_section().unwrap().entries()[0].limits();
+		assert_eq!((limits.initial(), limits.maximum()), (2, Some(3)));
+
+		let mut charge = Vec::new();
+		Charger::Memory(65552).charge(3, GasPrecision::Bits32, &mut charge);
+		assert_eq!(
+			charge,
+			vec![
+				I32Const(65552),
+				I64Load(3, 0),
+				I64Const(3),
+				I64LtU,
+				If(elements::BlockType::NoResult),
+				Unreachable,
+				End,
+				I32Const(65552),
+				I32Const(65552),
+				I64Load(3, 0),
+				I64Const(3),
+				I64Sub,
+				I64Store(3, 0),
+			]
+		);
+		charge.extend([I32Const(1), Call(2), Drop, End]);
+		assert_eq!(get_function_body(&injected_module, 0).unwrap(), &charge[..]);
+		assert_eq!(
+			get_function_body(&injected_module, 2).unwrap(),
+			&vec![
+				GetLocal(0),
+				GetLocal(0),
+				I64ExtendUI32,
+				I64Const(10000),
+				I64Mul,
+				TeeLocal(1),
+				I64Const(u32::MAX.into()),
+				I64GtU,
+				If(elements::BlockType::NoResult),
+				Unreachable,
+				End,
+				GetLocal(1),
+				I32WrapI64,
+				Call(1),
+				GrowMemory(0),
+				End,
+			][..]
+		);
+
+		let binary = serialize(injected_module).expect("serialization failed");
+		wabt::Module::read_binary(&binary, &Default::default())
+			.unwrap()
+			.validate()
+			.expect("injected module to be valid");
+
+		let config = Config::new("env")
+			.with_backend(Backend::LinearMemory { offset: 0, dedicated_page: false });
+		let without_memory =
+			builder::module().function().signature().build().body().build().build().build();
+		assert_eq!(
+			inject_gas_counter_with_config(without_memory, &rules, &config).unwrap_err(),
+			Error::CounterMemory
+		);
+	}
+
+	#[test]
+	fn precision_64() {
+		let module = builder::module()
+			.global()
+			.value_type()
+			.i32()
+			.init_expr(I32Const(1))
+			.build()
+			.function()
+			.signature()
+			.result()
+			.i32()
+			.build()
+			.body()
+			.with_instructions(elements::Instructions::new(vec![GetGlobal(0), GrowMemory(0), End]))
+			.build()
+			.build()
+			.memory()
+			.build()
+			.build();
+
+		let config = Config::new("env").with_precision(GasPrecision::Bits64);
+		let rules = rules::Set::default().with_grow_cost(10000);
+		let injected_module = inject_gas_counter_with_config(module, &rules, &config).unwrap();
+
+		let import_ty = injected_module.type_section().unwrap().types()[1].clone();
+		let elements::Type::Function(import_ty) = import_ty;
+		assert_eq!(import_ty.params(), &[ValueType::I64]);
+		assert_eq!(
+			get_function_body(&injected_module, 0).unwrap(),
+			&vec![I64Const(2), Call(0), GetGlobal(0), Call(2), End][..]
+		);
+		assert_eq!(
+			get_function_body(&injected_module, 1).unwrap(),
+			&vec![
+				GetLocal(0),
+				GetLocal(0),
+				I64ExtendUI32,
+				I64Const(10000),
+				I64Mul,
+				Call(0),
+				GrowMemory(0),
+				End
+			][..]
+		);
+
+		let binary = serialize(injected_module).expect("serialization failed");
+		wabt::Module::read_binary(&binary, &Default::default())
+			.unwrap()
+			.validate()
+			.expect("injected module to be valid");
+	}
+
+	#[test]
+	fn custom_rules() {
+		struct CallsOnly;
+
+		impl Rules for CallsOnly {
+			fn instruction_cost(&self, instruction: &elements::Instruction) -> Option<u32> {
+				match instruction {
+					Call(_) => Some(100),
+					_ => Some(0),
+				}
+			}
+
+			fn memory_grow_cost(&self) -> Option<rules::MemoryGrowCost> {
+				None
+			}
+		}
+
+		let module = builder::module()
+			.function()
+			.signature()
+			.build()
+			.body()
+			.with_instructions(elements::Instructions::new(vec![Call(0), Nop, Call(0), End]))
+			.build()
+			.build()
+			.build();
+
+		let injected_module = inject_gas_counter(module, &CallsOnly, "env").unwrap();
+
+		assert_eq!(
+			get_function_body(&injected_module, 0).unwrap(),
+			&vec![I32Const(200), Call(0), Call(1), Nop, Call(1), End][..]
+		);
+	}
+
+	#[test]
+	fn coalesced_charges() {
+		let module = parse_wat(
+			r#"
+(module
+	(func
+		block
+			block
+				i32.const 1
+				br_if 1
+				nop
+			end
+			nop
+			nop
+		end
+		nop
+	)
+)
+"#,
+		);
+
+		let plain = inject_gas_counter(module.clone(), &rules::Set::default(), "env").unwrap();
+		assert_eq!(
+			get_function_body(&plain, 0).unwrap(),
+			&vec![
+				I32Const(5),
+				Call(0),
+				Block(elements::BlockType::NoResult),
+				Block(elements::BlockType::NoResult),
+				I32Const(1),
+				BrIf(1),
+				I32Const(1),
+				Call(0),
+				Nop,
+				End,
+				I32Const(2),
+				Call(0),
+				Nop,
+				Nop,
+				End,
+				Nop,
+				End,
+			][..]
+		);
+
+		let config = Config::new("env").with_coalesced_charges();
+		let coalesced =
+			inject_gas_counter_with_config(module, &rules::Set::default(), &config).unwrap();
+		assert_eq!(
+			get_function_body(&coalesced, 0).unwrap(),
+			&vec![
+				I32Const(5),
+				Call(0),
+				Block(elements::BlockType::NoResult),
+				Block(elements::BlockType::NoResult),
+				I32Const(1),
+				BrIf(1),
+				I32Const(3),
+				Call(0),
+				Nop,
+				End,
+				Nop,
+				Nop,
+				End,
+				Nop,
+				End,
+			][..]
+		);
+	}
+
+	#[test]
+	fn branch_target_charges() {
+		let module = parse_wat(
+			r#"
+(module
+	(func (param i32)
+		local.get 0
+		if
+			nop
+		else
+			nop
+			nop
+		end
+		block
+			local.get 0
+			br_if 0
+			nop
+		end
+		nop
+		loop
+			nop
+		end
+		nop
+	)
+)
+"#,
+		);
+
+		let config = Config::new("env").with_placement(ChargePlacement::BranchTargets);
+		let injected =
+			inject_gas_counter_with_config(module, &rules::Set::default(), &config).unwrap();
+		assert_eq!(
+			get_function_body(&injected, 0).unwrap(),
+			&vec![
+				I32Const(9),
+				Call(0),
+				GetLocal(0),
+				If(elements::BlockType::NoResult),
+				Nop,
+				Else,
+				Nop,
+				Nop,
+				End,
+				Block(elements::BlockType::NoResult),
+				GetLocal(0),
+				BrIf(0),
+				Nop,
+				End,
+				I32Const(3),
+				Call(0),
+				Nop,
+				Loop(elements::BlockType::NoResult),
+				I32Const(1),
+				Call(0),
+				Nop,
+				End,
+				Nop,
+				End,
+			][..]
+		);
+	}
+
+	#[test]
+	fn function_entry_charge() {
+		let module = parse_wat(
+			r#"
+(module
+	(func (param i32)
+		local.get 0
+		if
+			nop
+		else
+			loop
+				nop
+			end
+		end
+	)
+)
+"#,
+		);
+
+		let config = Config::new("env").with_placement(ChargePlacement::FunctionEntry);
+		let injected =
+			inject_gas_counter_with_config(module, &rules::Set::default(), &config).unwrap();
+		assert_eq!(
+			get_function_body(&injected, 0).unwrap(),
+			&vec![
+				I32Const(5),
+				Call(0),
+				GetLocal(0),
+				If(elements::BlockType::NoResult),
+				Nop,
+				Else,
+				Loop(elements::BlockType::NoResult),
+				Nop,
+				End,
+				End,
+				End,
+			][..]
+		);
+	}
+
+	#[test]
+	fn local_cost() {
+		let module = parse_wat(
+			r#"
+(module
+	(func (param i32) (local i32 i64) (local f32)
+		loop
+			nop
+		end
+	)
+	(func (local i32)
+	)
+)
+"#,
+		);
+
+		let rules = rules::Set::default().with_local_cost(10);
+		let injected = inject_gas_counter(module, &rules, "env").unwrap();
+		assert_eq!(
+			get_function_body(&injected, 0).unwrap(),
+			&vec![
+				I32Const(31),
+				Call(0),
+				Loop(elements::BlockType::NoResult),
+				I32Const(1),
+				Call(0),
+				Nop,
+				End,
+				End,
+			][..]
+		);
+		assert_eq!(get_function_body(&injected, 1).unwrap(), &vec![I32Const(10), Call(0), End][..]);
+	}
+
+	#[test]
+	fn block_base_cost() {
+		let module = parse_wat(
+			r#"
+(module
+	(func
+		loop
+			nop
+		end
+	)
+	(func)
+)
+"#,
+		);
+
+		let rules = rules::Set::default().with_block_base_cost(5);
+		let injected = inject_gas_counter(module, &rules, "env").unwrap();
+		assert_eq!(
+			get_function_body(&injected, 0).unwrap(),
+			&vec![
+				I32Const(6),
+				Call(0),
+				Loop(elements::BlockType::NoResult),
+				I32Const(6),
+				Call(0),
+				Nop,
+				End,
+				End,
+			][..]
+		);
+		// A block without cost isn't charged the base cost either.
+		assert_eq!(get_function_body(&injected, 1).unwrap(), &vec![End][..]);
+		assert_eq!(verify(&injected, &rules, "env"), Ok(()));
+	}
+
+	#[test]
+	fn exempt_functions() {
+		let module = parse_wat(
+			r#"
+(module
+	(memory 1)
+	(func $alloc (export "alloc") (param i32) (result i32)
+		(memory.grow (local.get 0))
+	)
+	(func $helper
+		(nop)
+	)
+	(func (export "call")
+		(drop (call $alloc (i32.const 1)))
+		(call $helper)
+	)
+)
+"#,
+		);
+
+		let rules = rules::Set::default().with_grow_cost(10);
+		let config = Config::new("env").with_exempt_export("alloc").with_exempt_function(1);
+		let injected = inject_gas_counter_with_config(module, &rules, &config).unwrap();
+
+		assert_eq!(
+			get_function_body(&injected, 0).unwrap(),
+			&vec![GetLocal(0), GrowMemory(0), End][..]
+		);
+		assert_eq!(get_function_body(&injected, 1).unwrap(), &vec![Nop, End][..]);
+		assert_eq!(
+			get_function_body(&injected, 2).unwrap(),
+			&vec![I32Const(4), Call(0), I32Const(1), Call(1), Drop, Call(2), End][..]
+		);
+	}
+
+	#[test]
+	fn import_costs() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "storage_write" (func))
+	(import "env" "log" (func))
+	(func
+		(call 0)
+		(call 1)
+		(call 0)
+	)
+)
+"#,
+		);
+
+		let config = Config::new("env")
+			.with_import_cost("env", "storage_write", 100)
+			.with_import_cost("seal0", "log", 50);
+		let injected =
+			inject_gas_counter_with_config(module, &rules::Set::default(), &config).unwrap();
+
+		assert_eq!(
+			get_function_body(&injected, 0).unwrap(),
+			&vec![
+				I32Const(3),
+				Call(2),
+				I32Const(100),
+				Call(2),
+				Call(0),
+				Call(1),
+				I32Const(100),
+				Call(2),
+				Call(0),
+				End,
+			][..]
+		);
+	}
+
+	#[test]
+	fn name_section_remapped() {
+		let module_bytes = wabt::Wat2Wasm::new()
+			.write_debug_names(true)
+			.convert(
+				r#"
+(module
+	(import "env" "ext" (func $ext))
+	(func $first (param $arg i32)
+		(call $ext)
+	)
+	(func $second (local $tmp i64)
+		(call $first (i32.const 0))
+	)
+)
+"#,
+			)
+			.expect("failed to parse module");
+		let module = elements::deserialize_buffer(module_bytes.as_ref()).unwrap();
+
+		let injected = inject_gas_counter(module, &rules::Set::default(), "env").unwrap();
+		let injected =
+			elements::deserialize_buffer::<elements::Module>(&serialize(injected).unwrap())
+				.unwrap()
+				.parse_names()
+				.unwrap();
+
+		let names = injected.names_section().unwrap();
+		let function_names = names.functions().unwrap().names();
+		assert_eq!(function_names.get(0).map(String::as_str), Some("ext"));
+		assert_eq!(function_names.get(1), None);
+		assert_eq!(function_names.get(2).map(String::as_str), Some("first"));
+		assert_eq!(function_names.get(3).map(String::as_str), Some("second"));
+		let local_names = names.locals().unwrap().local_names();
+		assert_eq!(local_names.get(2).and_then(|l| l.get(0)).map(String::as_str), Some("arg"));
+		assert_eq!(local_names.get(3).and_then(|l| l.get(0)).map(String::as_str), Some("tmp"));
+	}
+
+	#[test]
+	fn cold_paths() {
+		let module_bytes = wabt::Wat2Wasm::new()
+			.write_debug_names(true)
+			.convert(
+				r#"
+(module
+	(func $rust_begin_unwind (param i32)
+		(if (local.get 0) (then (call $fmt (local.get 0))))
+		(call $shared (local.get 0))
+		(unreachable)
+	)
+	(func $fmt (param i32)
+		(if (local.get 0) (then (call $shared (local.get 0))))
+	)
+	(func $shared (param i32)
+		(if (local.get 0) (then (nop)))
+	)
+	(func (export "main") (param i32)
+		(if (local.get 0) (then (call $rust_begin_unwind (local.get 0))))
+		(call $shared (local.get 0))
+	)
+)
+"#,
+			)
+			.expect("failed to parse module");
+		let module = elements::deserialize_buffer::<elements::Module>(module_bytes.as_ref())
+			.unwrap()
+			.parse_names()
+			.unwrap();
+
+		let config = Config::new("env").with_cold_root("rust_begin_unwind");
+		let injected =
+			inject_gas_counter_with_config(module, &rules::Set::default(), &config).unwrap();
+
+		let charges = |index| {
+			get_function_body(&injected, index)
+				.unwrap()
+				.iter()
+				.filter(|instruction| **instruction == Call(0))
+				.count()
+		};
+		// The panic handler and the function only it calls are charged once, the others in
+		// every metered block.
+		assert_eq!(charges(0), 1);
+		assert_eq!(charges(1), 1);
+		assert_eq!(charges(2), 2);
+		assert_eq!(charges(3), 2);
+		assert_eq!(verify(&injected, &rules::Set::default(), "env"), Ok(()));
+	}
+
+	#[test]
+	fn looping_cold_function() {
+		let module_bytes = wabt::Wat2Wasm::new()
+			.write_debug_names(true)
+			.convert(
+				r#"
+(module
+	(func $rust_begin_unwind
+		(loop (br 0))
+	)
+	(func (export "main")
+		(call $rust_begin_unwind)
+	)
+)
+"#,
+			)
+			.expect("failed to parse module");
+		let module = elements::deserialize_buffer::<elements::Module>(module_bytes.as_ref())
+			.unwrap()
+			.parse_names()
+			.unwrap();
+
+		let rules = rules::Set::default();
+		let config = Config::new("env").with_cold_root("rust_begin_unwind");
+		let injected = inject_gas_counter_with_config(module, &rules, &config).unwrap();
+
+		// Every iteration of the loop is charged for.
+		assert_eq!(
+			get_function_body(&injected, 0).unwrap(),
+			&vec![
+				I32Const(1),
+				Call(0),
+				Loop(elements::BlockType::NoResult),
+				I32Const(1),
+				Call(0),
+				Br(0),
+				End,
+				End,
+			][..]
+		);
+		assert_eq!(verify(&injected, &rules, "env"), Ok(()));
+	}
+
+	#[test]
+	fn double_instrumentation_refused() {
+		let module = parse_wat("(module (func (nop)))");
+
+		let config = Config::new("env").with_mark();
+		let injected =
+			inject_gas_counter_with_config(module, &rules::Set::default(), &config).unwrap();
+		assert_eq!(
+			crate::instrumentation_marks(&injected),
+			vec![(PASS.into(), "module=env field=gas backend=HostFunction".into())]
+		);
+		assert_eq!(
+			inject_gas_counter(injected, &rules::Set::default(), "env"),
+			Err(Error::AlreadyInstrumented)
+		);
+	}
+
+	#[test]
+	fn limits() {
+		let module = parse_wat("(module (func (nop)))");
+
+		let config = Config::new("env").with_limits(Limits::new().with_max_body_instructions(1));
+		assert_eq!(
+			inject_gas_counter_with_config(module, &rules::Set::default(), &config),
+			Err(Error::LimitExceeded(LimitExceeded::BodyInstructions {
+				func_idx: 0,
+				count: 2,
+				limit: 1
+			}))
+		);
+	}
+
+	#[test]
+	fn report() {
+		let module = parse_wat(
+			r#"
+(module
+	(func (param i32)
+		(if (local.get 0)
+			(then (nop))
+		)
+	)
+	(func (export "exempt")
+		(nop)
+	)
+)
+"#,
+		);
+
+		let config = Config::new("env").with_exempt_export("exempt");
+		let (_, report) =
+			inject_gas_counter_with_report(module, &rules::Set::default(), &config).unwrap();
+
+		assert_eq!(
+			report.functions,
+			vec![
+				FunctionReport { func_idx: 0, metered_blocks: 2, static_cost: 3, size_increase: 8 },
+				FunctionReport { func_idx: 1, metered_blocks: 0, static_cost: 0, size_increase: 0 },
+			]
+		);
+		#[cfg(feature = "serde")]
+		assert_eq!(
+			serde_json::to_string(&report.functions[1]).unwrap(),
+			r#"{"func_idx":1,"metered_blocks":0,"static_cost":0,"size_increase":0}"#
+		);
+	}
+
+	#[test]
+	fn cost_overflow() {
+		let module = builder::module()
+			.function()
+			.signature()
+			.build()
+			.body()
+			.with_locals(vec![elements::Local::new(u32::MAX, elements::ValueType::I64); 2])
+			.build()
+			.build()
+			.build();
+
+		let rules = rules::Set::default().with_local_cost(u32::MAX);
+		assert_eq!(
+			inject_gas_counter(module, &rules, "env").unwrap_err(),
+			Error::Overflow { func_idx: 0 }
+		);
+	}
+
+	#[test]
+	fn inline_mutable_global_backend() {
+		let module = builder::module()
+			.global()
+			.value_type()
+			.i32()
+			.init_expr(I32Const(1))
+			.build()
+			.function()
+			.signature()
+			.build()
+			.body()
+			.with_instructions(elements::Instructions::new(vec![GetGlobal(0), Drop, End]))
+			.build()
+			.build()
+			.build();
+
+		let config =
+			Config::new("env").with_backend(Backend::InlineMutableGlobal("gas_left".into()));
+		let injected_module =
+			inject_gas_counter_with_config(module, &rules::Set::default(), &config).unwrap();
+
+		assert!(injected_module.import_section().is_none());
+		assert_eq!(
+			get_function_body(&injected_module, 0).unwrap(),
+			&vec![
+				GetGlobal(1),
+				I64Const(2),
+				I64LtU,
+				If(elements::BlockType::NoResult),
+				Unreachable,
+				End,
+				GetGlobal(1),
+				I64Const(2),
+				I64Sub,
+				SetGlobal(1),
+				GetGlobal(0),
+				Drop,
+				End,
+			][..]
+		);
+
+		let binary = serialize(injected_module).expect("serialization failed");
+		wabt::Module::read_binary(&binary, &Default::default())
+			.unwrap()
+			.validate()
+			.expect("injected module to be valid");
+	}
+
+	#[cfg(feature = "bulk")]
+	#[test]
+	fn bulk_memory_byte_cost() {
+		use parity_wasm::elements::BulkInstruction::MemoryFill;
+
+		let module = parse_wat(
+			r#"
+(module
+	(memory 1)
+	(func (param i32)
+		(memory.fill (i32.const 0) (i32.const 1) (local.get 0))
+		(memory.fill (i32.const 8) (i32.const 0) (i32.const 4))
+	)
+)
+"#,
+		);
+
+		let rules = rules::Set::default().with_bulk_byte_cost(3);
+		let injected_module = inject_gas_counter(module, &rules, "env").unwrap();
+
+		assert_eq!(
+			get_function_body(&injected_module, 0).unwrap(),
+			&vec![
+				I32Const(8),
+				Call(0),
+				I32Const(0),
+				I32Const(1),
+				GetLocal(0),
+				Call(2),
+				I32Const(8),
+				I32Const(0),
+				I32Const(4),
+				Call(2),
+				End,
+			][..]
+		);
+		assert_eq!(
+			get_function_body(&injected_module, 1).unwrap(),
+			&vec![
+				GetLocal(2),
+				I64ExtendUI32,
+				I64Const(3),
+				I64Mul,
+				SetLocal(3),
+				GetLocal(3),
+				I64Const(u32::MAX.into()),
+				I64GtU,
+				If(elements::BlockType::NoResult),
+				Unreachable,
+				End,
+				GetLocal(3),
+				I32WrapI64,
+				Call(0),
+				GetLocal(0),
+				GetLocal(1),
+				GetLocal(2),
+				Bulk(MemoryFill),
+				End,
+			][..]
+		);
+		// Both fills share the helper.
+		assert!(get_function_body(&injected_module, 2).is_none());
+	}
+
+	#[test]
+	fn split_charges_respects_cap() {
+		assert_eq!(split_charges(7, u64::MAX).collect::<Vec<_>>(), vec![7]);
+		assert_eq!(split_charges(7, 3).collect::<Vec<_>>(), vec![3, 3, 1]);
+		assert_eq!(split_charges(6, 3).collect::<Vec<_>>(), vec![3, 3]);
+		assert_eq!(split_charges(2, 3).collect::<Vec<_>>(), vec![2]);
 	}
 
 	fn parse_wat(source: &str) -> elements::Module {
@@ -31,10 +31,10 @@ struct ControlFlowNode {
 	first_instr_pos: Option<usize>,
 
 	/// The actual gas cost of executing all instructions in the basic block.
-	actual_cost: u32,
+	actual_cost: u64,
 
 	/// The amount of gas charged by the injected metering instructions within this basic block.
-	charged_cost: u32,
+	charged_cost: u64,
 
 	/// Whether there are any other nodes in the graph that loop back to this one. Every cycle in
 	/// the control flow graph contains at least one node with this flag set.
@@ -75,11 +75,11 @@ impl ControlFlowGraph {
 		self.nodes.len() - 1
 	}
 
-	fn increment_actual_cost(&mut self, node_id: NodeId, cost: u32) {
+	fn increment_actual_cost(&mut self, node_id: NodeId, cost: u64) {
 		self.get_node_mut(node_id).actual_cost += cost;
 	}
 
-	fn increment_charged_cost(&mut self, node_id: NodeId, cost: u32) {
+	fn increment_charged_cost(&mut self, node_id: NodeId, cost: u64) {
 		self.get_node_mut(node_id).charged_cost += cost;
 	}
 
@@ -157,7 +157,7 @@ fn build_control_flow_graph(
 			graph.increment_charged_cost(active_node_id, next_metered_block.cost);
 		}
 
-		let instruction_cost = rules.instruction_cost(instruction).ok_or(())?;
+		let instruction_cost = u64::from(rules.instruction_cost(instruction).ok_or(())?);
 		match instruction {
 			Instruction::Block(_) => {
 				graph.increment_actual_cost(active_node_id, instruction_cost);
@@ -275,9 +275,9 @@ fn validate_graph_gas_costs(graph: &ControlFlowGraph) -> bool {
 	fn visit(
 		graph: &ControlFlowGraph,
 		node_id: NodeId,
-		mut total_actual: u32,
-		mut total_charged: u32,
-		loop_costs: &mut Map<NodeId, (u32, u32)>,
+		mut total_actual: u64,
+		mut total_charged: u64,
+		loop_costs: &mut Map<NodeId, (u64, u64)>,
 	) -> bool {
 		let node = graph.get_node(node_id);
 
@@ -351,10 +351,13 @@ mod tests {
 			for func_body in module.code_section().iter().flat_map(|section| section.bodies()) {
 				let rules = RuleSet::default();
 
-				let metered_blocks = determine_metered_blocks(func_body.code(), &rules).unwrap();
-				let success =
-					validate_metering_injections(func_body, &rules, &metered_blocks).unwrap();
-				assert!(success);
+				for coalesce in [false, true] {
+					let metered_blocks =
+						determine_metered_blocks(func_body.code(), &rules, coalesce).unwrap();
+					let success =
+						validate_metering_injections(func_body, &rules, &metered_blocks).unwrap();
+					assert!(success);
+				}
 			}
 		}
 	}
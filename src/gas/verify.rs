@@ -0,0 +1,508 @@
+//! Check that the gas charges injected into a module match a set of rules.
+
+use super::{
+	charge_block_base, charge_locals, determine_branch_target_charges, determine_function_charge,
+	determine_metered_blocks, GasImport, MeteredBlock,
+};
+use crate::{
+	rules::{MemoryGrowCost, Rules},
+	std::{
+		collections::{BTreeMap, BTreeSet},
+		fmt,
+		vec::Vec,
+	},
+};
+use parity_wasm::elements::{self, Instruction};
+
+/// A discrepancy between the instrumentation of a module and the rules it is checked against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+	/// The module doesn't import the gas function it is checked against.
+	MissingGasFunction,
+	/// The function `func_idx` contains an instruction forbidden by the rules or is malformed.
+	Forbidden { func_idx: u32 },
+	/// The gas charged before the instruction at `position` of the function `func_idx` differs
+	/// from the cost derived from the rules.
+	///
+	/// `position` refers to the body with all injected charges removed.
+	Charge { func_idx: u32, position: usize, expected: u64, found: u64 },
+	/// The function `func_idx` contains a `memory.grow` which is not charged for although the
+	/// rules define a cost for it.
+	UnmeteredGrow { func_idx: u32 },
+	/// The cost charged by the memory grow counter differs from the rules.
+	GrowCost { expected: Option<MemoryGrowCost>, found: MemoryGrowCost },
+	/// The function `func_idx` calls the gas function before the instruction at `position` with
+	/// an argument which isn't a constant, so the charge can't be checked and may be zero.
+	///
+	/// `position` refers to the body with all injected charges removed.
+	DynamicCharge { func_idx: u32, position: usize },
+}
+
+impl fmt::Display for Mismatch {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			Mismatch::MissingGasFunction => write!(f, "No gas function is imported"),
+			Mismatch::Forbidden { func_idx } =>
+				write!(f, "Function {} is malformed or contains a forbidden instruction", func_idx),
+			Mismatch::Charge { func_idx, position, expected, found } => write!(
+				f,
+				"Function {} charges {} instead of {} before instruction {}",
+				func_idx, found, expected, position
+			),
+			Mismatch::UnmeteredGrow { func_idx } =>
+				write!(f, "Function {} grows the memory without charging for it", func_idx),
+			Mismatch::GrowCost { expected: Some(expected), found } =>
+				write!(f, "Memory growth costs {} instead of {}", found, expected),
+			Mismatch::GrowCost { expected: None, found } =>
+				write!(f, "Memory growth costs {} instead of nothing", found),
+			Mismatch::DynamicCharge { func_idx, position } => write!(
+				f,
+				"Function {} charges a computed amount before instruction {}",
+				func_idx, position
+			),
+		}
+	}
+}
+
+/// Checks that the module was instrumented by [`inject_gas_counter`] with the given rules.
+///
+/// The costs of the metered blocks are derived again from the function bodies and compared
+/// against the charges found in the module, which detects tampered or stale instrumentation
+/// without instrumenting the module again. Charges split because of
+/// [`Config::with_max_block_cost`], coalesced charges, all charge placements and both gas
+/// precisions are recognised, except that [`ChargePlacement::FunctionEntry`] is rejected for
+/// functions containing a loop since their iterations aren't charged for.
+/// Modules limited by the stack height limiter afterwards or with
+/// [`crate::inject_gas_and_stack_limiter`] are accepted as well: the checks around calls are
+/// removed before comparing and the thunks aren't expected to be charged for.
+/// Only the [`Backend::HostFunction`] backend with the function imported as `gas_import` is
+/// supported, see [`Config::gas_import`], and modules charging for bulk memory operations are
+/// not.
+///
+/// [`inject_gas_counter`]: super::inject_gas_counter
+/// [`Config::with_max_block_cost`]: super::Config::with_max_block_cost
+/// [`Config::gas_import`]: super::Config::gas_import
+/// [`ChargePlacement::FunctionEntry`]: super::ChargePlacement::FunctionEntry
+/// [`Backend::HostFunction`]: super::Backend::HostFunction
+pub fn verify<R: Rules, I: Into<GasImport>>(
+	module: &elements::Module,
+	rules: &R,
+	gas_import: I,
+) -> Result<(), Vec<Mismatch>> {
+	let gas_func = match gas_function(module, &gas_import.into()) {
+		Some(gas_func) => gas_func,
+		None => return Err(vec![Mismatch::MissingGasFunction]),
+	};
+	let func_imports = module.import_count(elements::ImportCountType::Function) as u32;
+	let bodies = module.code_section().map(|s| s.bodies()).unwrap_or(&[]);
+
+	let mut mismatches = Vec::new();
+
+	let expected_grow_cost = rules.memory_grow_cost();
+	let grow_counter = bodies.iter().enumerate().find_map(|(idx, body)| {
+		grow_counter_cost(body.code().elements(), gas_func).map(|cost| (idx as u32, cost))
+	});
+	if let Some((_, found)) = grow_counter {
+		let parts = |cost: &MemoryGrowCost| (cost.base(), cost.per_page(), cost.max_pages());
+		if expected_grow_cost.as_ref().map_or((0, 0, None), parts) != parts(&found) {
+			mismatches.push(Mismatch::GrowCost { expected: expected_grow_cost, found });
+		}
+	}
+	let grow_counter_func = grow_counter.map(|(idx, _)| func_imports + idx);
+
+	for (idx, body) in bodies.iter().enumerate() {
+		let func_idx = func_imports + idx as u32;
+		if Some(func_idx) == grow_counter_func {
+			continue
+		}
+
+		let (code, guarded) = strip_stack_guards(body.code().elements());
+		if guarded && is_thunk(&code, body) {
+			continue
+		}
+		if expected_grow_cost.is_some() &&
+			code.iter().any(|i| matches!(i, Instruction::GrowMemory(_)))
+		{
+			mismatches.push(Mismatch::UnmeteredGrow { func_idx });
+		}
+
+		let (original, found, dynamic) = strip_charges(&code, gas_func, grow_counter_func);
+		mismatches.extend(
+			dynamic
+				.into_iter()
+				.map(|position| Mismatch::DynamicCharge { func_idx, position }),
+		);
+
+		let instructions = elements::Instructions::new(original);
+		match charge_mismatches(func_idx, &instructions, body.locals(), &found, rules) {
+			Ok(charge_mismatches) => mismatches.extend(charge_mismatches),
+			Err(()) => mismatches.push(Mismatch::Forbidden { func_idx }),
+		}
+	}
+
+	if mismatches.is_empty() {
+		Ok(())
+	} else {
+		Err(mismatches)
+	}
+}
+
+/// Compare the charges found in a function body with the costs of its metered blocks.
+///
+/// The charges are accepted if they match any of the placements, otherwise the mismatches for
+/// the metered blocks without coalescing are returned. The placement charging only at the entry
+/// is only accepted for bodies without loops.
+fn charge_mismatches<R: Rules>(
+	func_idx: u32,
+	instructions: &elements::Instructions,
+	locals: &[elements::Local],
+	found: &BTreeMap<usize, u64>,
+	rules: &R,
+) -> Result<Vec<Mismatch>, ()> {
+	let compare = |mut blocks: Vec<MeteredBlock>| -> Result<Vec<Mismatch>, ()> {
+		charge_block_base(&mut blocks, rules).map_err(|_| ())?;
+		charge_locals(&mut blocks, locals, rules).map_err(|_| ())?;
+		let expected: BTreeMap<usize, u64> =
+			blocks.into_iter().map(|block| (block.start_pos, block.cost)).collect();
+
+		let positions = expected.keys().chain(found.keys()).collect::<BTreeSet<_>>();
+		Ok(positions
+			.into_iter()
+			.filter_map(|position| {
+				let expected = expected.get(position).copied().unwrap_or(0);
+				let found = found.get(position).copied().unwrap_or(0);
+				(expected != found).then(|| Mismatch::Charge {
+					func_idx,
+					position: *position,
+					expected,
+					found,
+				})
+			})
+			.collect())
+	};
+
+	let mismatches =
+		compare(determine_metered_blocks(instructions, rules, false).map_err(|_| ())?)?;
+	if mismatches.is_empty() {
+		return Ok(mismatches)
+	}
+	let mut alternatives = vec![
+		determine_metered_blocks(instructions, rules, true),
+		determine_branch_target_charges(instructions, rules),
+	];
+	// A single charge at the entry leaves the iterations of a loop after the first unmetered.
+	if !instructions.elements().iter().any(|i| matches!(i, Instruction::Loop(_))) {
+		alternatives.push(determine_function_charge(instructions, rules));
+	}
+	for blocks in alternatives {
+		if compare(blocks.map_err(|_| ())?)?.is_empty() {
+			return Ok(Vec::new())
+		}
+	}
+	Ok(mismatches)
+}
+
+/// Index of the imported gas function.
+fn gas_function(module: &elements::Module, gas_import: &GasImport) -> Option<u32> {
+	module
+		.import_section()?
+		.entries()
+		.iter()
+		.filter(|entry| matches!(entry.external(), elements::External::Function(_)))
+		.enumerate()
+		.filter(|(_, entry)| {
+			entry.module() == gas_import.module() && entry.field() == gas_import.field()
+		})
+		.map(|(idx, _)| idx as u32)
+		.last()
+}
+
+/// Cost charged by the body if it is a memory grow counter.
+fn grow_counter_cost(body: &[Instruction], gas_func: u32) -> Option<MemoryGrowCost> {
+	use elements::BlockType::NoResult;
+	use Instruction::*;
+
+	let (max_pages, charge) = match body {
+		[GetLocal(0), I32Const(max_pages), I32GtU, If(NoResult), Unreachable, End, charge @ ..] =>
+			(Some(*max_pages as u32), charge),
+		_ => (None, body),
+	};
+	let (per_page, base, rest) = match charge {
+		[GetLocal(0), GetLocal(0), I64ExtendUI32, I64Const(per_page), I64Mul, rest @ ..] =>
+			match rest {
+				[I64Const(base), I64Add, rest @ ..] => (*per_page as u32, *base as u32, rest),
+				_ => (*per_page as u32, 0, rest),
+			},
+		_ => return None,
+	};
+	// The 32 bit charge is checked against `u32::MAX` before it is passed on.
+	let rest = match rest {
+		[TeeLocal(1), I64Const(max), I64GtU, If(NoResult), Unreachable, End, GetLocal(1), I32WrapI64, rest @ ..]
+			if *max == i64::from(u32::MAX) =>
+			rest,
+		rest => rest,
+	};
+	match rest {
+		[Call(f), GrowMemory(0), End] if *f == gas_func =>
+			Some(MemoryGrowCost::Affine { base, per_page, max_pages }),
+		_ => None,
+	}
+}
+
+/// Remove the checks of the stack height limiter around calls, keeping the calls.
+///
+/// Returns the body without the checks and whether any was found.
+fn strip_stack_guards(body: &[Instruction]) -> (Vec<Instruction>, bool) {
+	let mut stripped = Vec::with_capacity(body.len());
+	let mut guarded = false;
+	let mut cursor = 0;
+	while cursor < body.len() {
+		if let Some(callee) = stack_guard(&body[cursor..]) {
+			stripped.push(Instruction::Call(callee));
+			guarded = true;
+			cursor += STACK_GUARD_LEN;
+		} else {
+			stripped.push(body[cursor].clone());
+			cursor += 1;
+		}
+	}
+	(stripped, guarded)
+}
+
+/// Number of instructions of a call wrapped by the stack height limiter.
+const STACK_GUARD_LEN: usize = 15;
+
+/// The callee if `code` starts with a call wrapped by the stack height limiter.
+fn stack_guard(code: &[Instruction]) -> Option<u32> {
+	use elements::BlockType::NoResult;
+	use Instruction::*;
+
+	let guard = code.get(..STACK_GUARD_LEN)?;
+	let (global, cost, callee) = match guard {
+		[GetGlobal(global), I32Const(cost), I32Add, .., Call(callee), _, _, _, _] =>
+			(*global, *cost, *callee),
+		_ => return None,
+	};
+	let expected_prefix = [GetGlobal(global), I32Const(cost), I32Add, SetGlobal(global)];
+	let expected_suffix = [GetGlobal(global), I32Const(cost), I32Sub, SetGlobal(global)];
+	let checks_limit = matches!(
+		guard[4..10],
+		[GetGlobal(g), I32Const(_), I32GtU, If(NoResult), Unreachable, End] if g == global
+	);
+	(guard[..4] == expected_prefix && checks_limit && guard[11..] == expected_suffix)
+		.then(|| callee)
+}
+
+/// Whether the body, with the stack height checks removed, is a thunk generated by the stack
+/// height limiter: it only forwards its parameters to a call.
+fn is_thunk(code: &[Instruction], body: &elements::FuncBody) -> bool {
+	match code {
+		[forwards @ .., Instruction::Call(_), Instruction::End] =>
+			body.locals().is_empty() &&
+				forwards
+					.iter()
+					.enumerate()
+					.all(|(idx, instruction)| *instruction == Instruction::GetLocal(idx as u32)),
+		_ => false,
+	}
+}
+
+/// Remove the injected code from a function body.
+///
+/// Returns the body as it was before instrumentation, the charges keyed by the position of the
+/// instruction they precede, and the positions of calls of the gas function with a computed
+/// argument.
+fn strip_charges(
+	body: &[Instruction],
+	gas_func: u32,
+	grow_counter_func: Option<u32>,
+) -> (Vec<Instruction>, BTreeMap<usize, u64>, Vec<usize>) {
+	use Instruction::*;
+
+	let mut original = Vec::with_capacity(body.len());
+	let mut charges = BTreeMap::new();
+	let mut dynamic = Vec::new();
+	let mut cursor = 0;
+	while cursor < body.len() {
+		let charge = match (&body[cursor], body.get(cursor + 1)) {
+			(I32Const(charge), Some(Call(f))) if *f == gas_func => Some(*charge as u32 as u64),
+			(I64Const(charge), Some(Call(f))) if *f == gas_func => Some(*charge as u64),
+			_ => None,
+		};
+		if let Some(charge) = charge {
+			*charges.entry(original.len()).or_insert(0u64) += charge;
+			cursor += 2;
+			continue
+		}
+
+		original.push(match body[cursor] {
+			Call(f) if f == gas_func => {
+				dynamic.push(original.len());
+				cursor += 1;
+				continue
+			},
+			Call(f) if Some(f) == grow_counter_func => GrowMemory(0),
+			Call(f) if f > gas_func => Call(f - 1),
+			ref instruction => instruction.clone(),
+		});
+		cursor += 1;
+	}
+
+	(original, charges, dynamic)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		gas::{
+			inject_gas_counter, inject_gas_counter_with_config, ChargePlacement, Config,
+			GasPrecision,
+		},
+		rules,
+	};
+
+	fn parse_wat(source: &str) -> elements::Module {
+		let module_bytes = wabt::Wat2Wasm::new().convert(source).unwrap();
+		elements::deserialize_buffer(module_bytes.as_ref()).unwrap()
+	}
+
+	const SOURCE: &str = r#"
+(module
+	(memory 1)
+	(func $f (param i32) (result i32) (local i64)
+		local.get 0
+		if (result i32)
+			i32.const 1
+			memory.grow
+		else
+			local.get 0
+			call $f
+		end
+	)
+)
+"#;
+
+	#[test]
+	fn accepts_injected() {
+		let rules = rules::Set::default().with_grow_cost(3).with_local_cost(2);
+		let module = inject_gas_counter(parse_wat(SOURCE), &rules, "env").unwrap();
+		assert_eq!(verify(&module, &rules, "env"), Ok(()));
+
+		let config = Config::new("env")
+			.with_max_block_cost(1)
+			.with_precision(GasPrecision::Bits64)
+			.with_coalesced_charges();
+		let module = inject_gas_counter_with_config(parse_wat(SOURCE), &rules, &config).unwrap();
+		assert_eq!(verify(&module, &rules, "env"), Ok(()));
+
+		for placement in [ChargePlacement::BranchTargets, ChargePlacement::FunctionEntry] {
+			let config = Config::new("env").with_placement(placement);
+			let module =
+				inject_gas_counter_with_config(parse_wat(SOURCE), &rules, &config).unwrap();
+			assert_eq!(verify(&module, &rules, "env"), Ok(()));
+		}
+	}
+
+	#[test]
+	fn detects_stale() {
+		let module =
+			inject_gas_counter(parse_wat(SOURCE), &rules::Set::new(1, Default::default()), "env")
+				.unwrap();
+
+		assert_eq!(
+			verify(&parse_wat(SOURCE), &rules::Set::default(), "env"),
+			Err(vec![Mismatch::MissingGasFunction])
+		);
+		assert_eq!(
+			verify(&module, &rules::Set::new(2, Default::default()), "env"),
+			Err(vec![
+				Mismatch::Charge { func_idx: 1, position: 0, expected: 4, found: 2 },
+				Mismatch::Charge { func_idx: 1, position: 2, expected: 4, found: 2 },
+				Mismatch::Charge { func_idx: 1, position: 5, expected: 4, found: 2 },
+			])
+		);
+		assert_eq!(
+			verify(&module, &rules::Set::new(1, Default::default()).with_grow_cost(3), "env"),
+			Err(vec![Mismatch::UnmeteredGrow { func_idx: 1 }])
+		);
+	}
+	#[test]
+	fn configured_import() {
+		let rules = rules::Set::default();
+		let config = Config::new(("seal0", "charge"));
+		let module = inject_gas_counter_with_config(parse_wat(SOURCE), &rules, &config).unwrap();
+
+		assert_eq!(verify(&module, &rules, ("seal0", "charge")), Ok(()));
+		assert_eq!(verify(&module, &rules, "seal0"), Err(vec![Mismatch::MissingGasFunction]));
+		assert_eq!(
+			verify(&module, &rules, ("env", "charge")),
+			Err(vec![Mismatch::MissingGasFunction])
+		);
+	}
+
+	#[test]
+	fn rejects_unmetered_loop() {
+		let source = r#"
+(module
+	(func (param i32)
+		(loop
+			(br_if 0 (local.get 0))
+		)
+	)
+)
+"#;
+		let rules = rules::Set::default();
+		let config = Config::new("env").with_placement(ChargePlacement::FunctionEntry);
+		let module = inject_gas_counter_with_config(parse_wat(source), &rules, &config).unwrap();
+
+		assert_eq!(
+			verify(&module, &rules, "env"),
+			Err(vec![
+				Mismatch::Charge { func_idx: 1, position: 0, expected: 1, found: 3 },
+				Mismatch::Charge { func_idx: 1, position: 1, expected: 2, found: 0 },
+			])
+		);
+	}
+
+	#[test]
+	fn accepts_stack_limited() {
+		use crate::{inject_gas_and_stack_limiter, stack_height};
+
+		let rules = rules::Set::default().with_grow_cost(3).with_local_cost(2);
+		let config = Config::new("env");
+		let stack_config = stack_height::Config::new(1024);
+		// Exported, so that the stack height limiter generates a thunk for it.
+		let source = SOURCE.replace("(func $f", "(func $f (export \"f\")");
+
+		let sequential = stack_height::inject_limiter_with_config(
+			inject_gas_counter_with_config(parse_wat(&source), &rules, &config).unwrap(),
+			&stack_config,
+		)
+		.unwrap();
+		assert_eq!(verify(&sequential, &rules, "env"), Ok(()));
+
+		let combined =
+			inject_gas_and_stack_limiter(parse_wat(&source), &rules, &config, &stack_config)
+				.unwrap();
+		assert_eq!(verify(&combined, &rules, "env"), Ok(()));
+	}
+
+	#[test]
+	fn detects_dynamic_charge() {
+		use Instruction::*;
+
+		let rules = rules::Set::default();
+		let mut module = inject_gas_counter(parse_wat(SOURCE), &rules, "env").unwrap();
+		let code = module.code_section_mut().unwrap().bodies_mut()[0].code_mut().elements_mut();
+		assert!(matches!(code[..2], [I32Const(_), Call(0)]));
+		code[0] = GetLocal(0);
+
+		let mismatches = verify(&module, &rules, "env").unwrap_err();
+		// The argument is kept in the body, the call comes after it.
+		assert_eq!(mismatches[0], Mismatch::DynamicCharge { func_idx: 1, position: 1 });
+		assert!(mismatches
+			.iter()
+			.any(|m| matches!(m, Mismatch::Charge { func_idx: 1, position: 0, found: 0, .. })));
+	}
+}
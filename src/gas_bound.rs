@@ -0,0 +1,397 @@
+//! Static estimation of the gas an exported function can consume.
+
+use crate::std::{
+	collections::{BTreeMap as Map, BTreeSet as Set},
+	fmt,
+	string::String,
+	vec::Vec,
+};
+
+use crate::{
+	gas::{self, Error},
+	rules::Rules,
+	stack_effect::resolve_func_type,
+};
+use parity_wasm::elements::{self, Instruction, Internal, Type};
+
+/// Upper bound of the gas charged by the instrumentation of [`crate::inject_gas_counter`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GasBound {
+	Gas(u64),
+	/// The code may be executed an unbounded number of times, or the cost of an instruction
+	/// depends on an operand which isn't known statically.
+	Unbounded,
+}
+
+impl GasBound {
+	fn add(self, other: Self) -> Self {
+		match (self, other) {
+			(GasBound::Gas(a), GasBound::Gas(b)) =>
+				a.checked_add(b).map_or(GasBound::Unbounded, GasBound::Gas),
+			_ => GasBound::Unbounded,
+		}
+	}
+
+	/// The bound of executing either of the code with `self` or `other` as bound.
+	fn max(self, other: Self) -> Self {
+		match (self, other) {
+			(GasBound::Gas(a), GasBound::Gas(b)) => GasBound::Gas(a.max(b)),
+			_ => GasBound::Unbounded,
+		}
+	}
+}
+
+impl fmt::Display for GasBound {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			GasBound::Gas(gas) => write!(f, "{} gas", gas),
+			GasBound::Unbounded => write!(f, "unbounded"),
+		}
+	}
+}
+
+/// Cost of a function body without the functions it calls, and the calls.
+struct Body {
+	cost: GasBound,
+	calls: Vec<Call>,
+}
+
+enum Call {
+	Direct(u32),
+	Indirect(u32),
+}
+
+struct Analysis<'a, R> {
+	module: &'a elements::Module,
+	rules: &'a R,
+	func_imports: u32,
+	/// Number of pages `memory.grow` can add, if the memory has a maximum.
+	grow_limit: Option<u32>,
+	/// Functions in the table.
+	table_members: Vec<u32>,
+	bounds: Map<u32, GasBound>,
+	on_stack: Set<u32>,
+}
+
+/// A function whose bound is being computed.
+struct Frame {
+	func_idx: u32,
+	/// Bound of the function so far.
+	bound: GasBound,
+	/// Calls which aren't visited yet, in reverse order.
+	calls: Vec<Call>,
+	/// Callees of the current call which aren't visited yet, in reverse order.
+	callees: Vec<u32>,
+	/// Bound of the callees of the current call visited so far, if the frame is visiting a call.
+	call_bound: Option<GasBound>,
+}
+
+impl<'a, R: Rules> Analysis<'a, R> {
+	/// Functions which may be called by a `call_indirect` with the type `type_idx`.
+	fn indirect_callees(&self, type_idx: u32) -> Vec<u32> {
+		let expected = self
+			.module
+			.type_section()
+			.and_then(|section| section.types().get(type_idx as usize))
+			.map(|Type::Function(ty)| ty);
+		let mut callees = self
+			.table_members
+			.iter()
+			.copied()
+			.filter(|func_idx| match resolve_func_type(*func_idx, self.module) {
+				Ok(ty) => Some(ty) == expected,
+				Err(_) => false,
+			})
+			.collect::<Vec<_>>();
+		callees.sort_unstable();
+		callees.dedup();
+		callees
+	}
+
+	fn body(&self, func_idx: u32, body: &elements::FuncBody) -> Result<Body, Error> {
+		let code = body.code().elements();
+		let locals: u64 = body.locals().iter().map(|local| u64::from(local.count())).sum();
+		let mut cost = locals
+			.checked_mul(self.rules.call_per_local_cost().into())
+			.map_or(GasBound::Unbounded, GasBound::Gas);
+		let mut calls = Vec::new();
+		for (offset, instruction) in code.iter().enumerate() {
+			let instruction_cost = self.rules.instruction_cost(instruction).ok_or_else(|| {
+				Error::Forbidden { func_idx, offset, instruction: instruction.clone() }
+			})?;
+			match instruction {
+				// Not charged for by the instrumentation.
+				Instruction::End | Instruction::Else => continue,
+				// Any loop may be executed an unbounded number of times.
+				Instruction::Loop(_) => cost = GasBound::Unbounded,
+				Instruction::GrowMemory(_) => {
+					if let Some(grow_cost) = self.rules.memory_grow_cost() {
+						let pages = match offset.checked_sub(1).map(|prev| &code[prev]) {
+							Some(Instruction::I32Const(pages)) => Some(
+								self.grow_limit
+									.map_or(*pages as u32, |limit| limit.min(*pages as u32)),
+							),
+							_ => self.grow_limit,
+						};
+						// Growing by more pages than allowed traps before charging.
+						let pages = match (pages, grow_cost.max_pages()) {
+							(Some(pages), Some(max_pages)) => Some(pages.min(max_pages)),
+							(pages, max_pages) => pages.or(max_pages),
+						};
+						cost = cost.add(
+							pages
+								.and_then(|pages| grow_cost.cost(pages))
+								.map_or(GasBound::Unbounded, GasBound::Gas),
+						);
+					}
+				},
+				// The number of bytes isn't known statically.
+				#[cfg(feature = "bulk")]
+				Instruction::Bulk(
+					elements::BulkInstruction::MemoryCopy |
+					elements::BulkInstruction::MemoryFill |
+					elements::BulkInstruction::MemoryInit(_),
+				) if self.rules.bulk_memory_byte_cost() > 0 => cost = GasBound::Unbounded,
+				Instruction::Call(callee) => calls.push(Call::Direct(*callee)),
+				Instruction::CallIndirect(type_idx, _) => calls.push(Call::Indirect(*type_idx)),
+				_ => {},
+			}
+			cost = cost.add(GasBound::Gas(instruction_cost.into()));
+		}
+		let block_base_cost = u64::from(self.rules.block_base_cost());
+		if block_base_cost > 0 {
+			// Coalescing charges only merges metered blocks, so this is the most of them.
+			cost = cost.add(
+				gas::charged_blocks(body.code(), self.rules)
+					.ok()
+					.and_then(|count| block_base_cost.checked_mul(count as u64))
+					.map_or(GasBound::Unbounded, GasBound::Gas),
+			);
+		}
+		Ok(Body { cost, calls })
+	}
+
+	/// The bound of `func_idx` if it is known without visiting its calls, or a new frame for it.
+	fn enter(&mut self, func_idx: u32) -> Result<Result<GasBound, Frame>, Error> {
+		if let Some(bound) = self.bounds.get(&func_idx) {
+			return Ok(Ok(*bound))
+		}
+		// The instrumentation doesn't charge for the work of the host.
+		let defined_idx = match func_idx.checked_sub(self.func_imports) {
+			Some(defined_idx) => defined_idx as usize,
+			None => return Ok(Ok(GasBound::Gas(0))),
+		};
+		// Recursion: the functions in the cycle may be executed an unbounded number of times.
+		if self.on_stack.contains(&func_idx) {
+			return Ok(Ok(GasBound::Unbounded))
+		}
+		let body = match self.module.code_section().and_then(|s| s.bodies().get(defined_idx)) {
+			Some(body) => self.body(func_idx, body)?,
+			None => return Ok(Ok(GasBound::Unbounded)),
+		};
+
+		self.on_stack.insert(func_idx);
+		let mut calls = body.calls;
+		calls.reverse();
+		Ok(Err(Frame { func_idx, bound: body.cost, calls, callees: Vec::new(), call_bound: None }))
+	}
+
+	/// The bound of `func_idx`, including the functions it calls.
+	///
+	/// The call graph is walked with an explicit stack, since it may be arbitrarily deep.
+	fn bound(&mut self, func_idx: u32) -> Result<GasBound, Error> {
+		let mut frames = match self.enter(func_idx)? {
+			Ok(bound) => return Ok(bound),
+			Err(frame) => vec![frame],
+		};
+		// Bound of the function which was finished last.
+		let mut returned = None;
+		while let Some(frame) = frames.last_mut() {
+			if let Some(callee_bound) = returned.take() {
+				// Only one of the candidates of a call is called.
+				frame.call_bound = frame.call_bound.map(|bound| bound.max(callee_bound));
+			}
+			if let Some(callee) = frame.callees.pop() {
+				match self.enter(callee)? {
+					Ok(bound) => returned = Some(bound),
+					Err(callee_frame) => frames.push(callee_frame),
+				}
+				continue
+			}
+			if let Some(call_bound) = frame.call_bound.take() {
+				frame.bound = frame.bound.add(call_bound);
+			}
+
+			match frame.calls.pop() {
+				Some(Call::Direct(callee)) => frame.callees = vec![callee],
+				Some(Call::Indirect(type_idx)) => {
+					let mut callees = self.indirect_callees(type_idx);
+					callees.reverse();
+					frame.callees = callees;
+				},
+				None => {
+					let Frame { func_idx, bound, .. } = *frame;
+					frames.pop();
+					self.on_stack.remove(&func_idx);
+					self.bounds.insert(func_idx, bound);
+					returned = Some(bound);
+					continue
+				},
+			}
+			frame.call_bound = Some(GasBound::Gas(0));
+		}
+		Ok(returned.expect("the outermost frame returns its bound; qed"))
+	}
+}
+
+/// Estimate how much gas each exported function can consume in a single invocation when the
+/// module is instrumented according to `rules`.
+///
+/// The module isn't modified. The estimate covers all functions reachable by calls, assuming a
+/// `call_indirect` may call any function of a matching type in the table. It is unbounded if a
+/// loop or recursion is reachable, since their code may be executed any number of times. The
+/// charge for `memory.grow` counts with the operand if it is a constant, and with the pages that
+/// can be added to the initial memory otherwise. The charges for bulk memory operations are
+/// unbounded. Imported functions cost nothing, as the instrumentation doesn't charge for the
+/// work of the host.
+///
+/// Fails like the instrumentation if a reachable function contains an instruction forbidden by
+/// the rules.
+///
+/// Returns the estimate for each exported function by export name, in export order.
+pub fn max_gas<R: Rules>(
+	module: &elements::Module,
+	rules: &R,
+) -> Result<Vec<(String, GasBound)>, Error> {
+	let grow_limit = module
+		.memory_section()
+		.and_then(|section| section.entries().first().map(|memory| *memory.limits()))
+		.or_else(|| {
+			module
+				.import_section()?
+				.entries()
+				.iter()
+				.find_map(|entry| match entry.external() {
+					elements::External::Memory(memory) => Some(*memory.limits()),
+					_ => None,
+				})
+		})
+		.and_then(|limits| Some(limits.maximum()?.saturating_sub(limits.initial())));
+
+	let mut analysis = Analysis {
+		module,
+		rules,
+		func_imports: module.import_count(elements::ImportCountType::Function) as u32,
+		grow_limit,
+		table_members: module
+			.elements_section()
+			.map(|section| section.entries())
+			.unwrap_or(&[])
+			.iter()
+			.flat_map(|segment| segment.members().iter().copied())
+			.collect(),
+		bounds: Map::new(),
+		on_stack: Set::new(),
+	};
+	module
+		.export_section()
+		.map(|section| section.entries())
+		.unwrap_or(&[])
+		.iter()
+		.filter_map(|entry| match *entry.internal() {
+			Internal::Function(func_idx) => Some((entry.field(), func_idx)),
+			_ => None,
+		})
+		.map(|(field, func_idx)| Ok((field.into(), analysis.bound(func_idx)?)))
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{rules, testing::module_fixture};
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	#[test]
+	fn gas_per_export() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "ext" (func $ext))
+	(memory 1 3)
+	(table 2 funcref)
+	(elem (i32.const 0) $cheap $expensive)
+	(func $cheap
+		(nop)
+	)
+	(func $expensive (local i64 i64)
+		(drop (i32.add (i32.const 1) (i32.const 2)))
+	)
+	(func (export "straight") (param i32)
+		(if (local.get 0)
+			(then (call $expensive))
+			(else (call $ext))
+		)
+	)
+	(func (export "indirect")
+		(call_indirect (i32.const 0))
+	)
+	(func (export "grow") (param i32)
+		(drop (memory.grow (local.get 0)))
+	)
+	(func (export "looping")
+		(loop
+			(nop)
+		)
+	)
+	(func $recursive (export "recursive")
+		(call $recursive)
+	)
+)
+"#,
+		);
+
+		let rules = rules::Set::default().with_local_cost(10).with_grow_cost(100);
+		assert_eq!(
+			max_gas(&module, &rules).unwrap(),
+			vec![
+				("straight".into(), GasBound::Gas(28)),
+				("indirect".into(), GasBound::Gas(26)),
+				("grow".into(), GasBound::Gas(203)),
+				("looping".into(), GasBound::Unbounded),
+				("recursive".into(), GasBound::Unbounded),
+			]
+		);
+
+		// Every metered block is charged the base cost, the `if` has three.
+		let rules = rules::Set::default().with_block_base_cost(5);
+		assert_eq!(
+			max_gas(&module, &rules).unwrap()[..3],
+			[
+				("straight".into(), GasBound::Gas(28)),
+				("indirect".into(), GasBound::Gas(16)),
+				("grow".into(), GasBound::Gas(8)),
+			]
+		);
+
+		let rules = rules::Set::default().with_override("nop", rules::Metering::Forbidden);
+		assert!(matches!(
+			max_gas(&module, &rules),
+			Err(Error::Forbidden { func_idx: 1, offset: 0, .. })
+		));
+	}
+
+	#[test]
+	fn deep_call_chain() {
+		let module = module_fixture().with_call_chain(200_000).with_export("call", 0).build();
+
+		assert_eq!(
+			max_gas(&module, &rules::Set::default()).unwrap(),
+			vec![("call".into(), GasBound::Gas(199_999))]
+		);
+	}
+}
@@ -0,0 +1,188 @@
+//! Gas metering with the interface of the `gas_metering` module of the wasm-instrument crate.
+//!
+//! Projects migrating between this crate and wasm-instrument can keep their call sites:
+//!
+//! ```
+//! use pwasm_utils::gas_metering::{self, host_function, ConstantCostRules};
+//! # let module = parity_wasm::elements::Module::default();
+//!
+//! let backend = host_function::Injector::new("env", "gas");
+//! let module = gas_metering::inject(module, backend, &ConstantCostRules::default()).unwrap();
+//! ```
+//!
+//! The instrumentation is the one of [`crate::inject_gas_counter_with_config`], configured like
+//! wasm-instrument's backends: the charges are `i64` values.
+
+use crate::{gas, std::num::NonZeroU32, GasConfig, GasPrecision};
+use parity_wasm::elements::{Instruction, Module};
+
+pub use crate::rules::{MemoryGrowCost, Rules};
+
+/// The way the injected code charges gas.
+pub trait Backend {
+	/// Configuration of the gas metering using this backend.
+	fn config(self) -> GasConfig;
+}
+
+/// Charge gas by calling a function imported from the host.
+pub mod host_function {
+	use super::{Backend, GasConfig, GasPrecision};
+
+	/// Import the charging function as `name` from `module`.
+	///
+	/// The function has the signature [i64] -> [].
+	#[derive(Debug, Clone, PartialEq, Eq)]
+	pub struct Injector<'a> {
+		module: &'a str,
+		name: &'a str,
+	}
+
+	impl<'a> Injector<'a> {
+		/// Backend importing the charging function as `name` from `module`.
+		pub fn new(module: &'a str, name: &'a str) -> Self {
+			Injector { module, name }
+		}
+	}
+
+	impl Backend for Injector<'_> {
+		fn config(self) -> GasConfig {
+			GasConfig::new((self.module, self.name)).with_precision(GasPrecision::Bits64)
+		}
+	}
+}
+
+/// Charge gas from a mutable global exported to the host.
+pub mod mutable_global {
+	use super::{Backend, GasConfig, GasPrecision};
+	use crate::GasBackend;
+
+	/// Keep the remaining gas in an `i64` global exported as `global_name`, and trap when it
+	/// would underflow.
+	#[derive(Debug, Clone, PartialEq, Eq)]
+	pub struct Injector<'a> {
+		global_name: &'a str,
+	}
+
+	impl<'a> Injector<'a> {
+		/// Backend exporting the gas global as `global_name`.
+		pub fn new(global_name: &'a str) -> Self {
+			Injector { global_name }
+		}
+	}
+
+	impl Backend for Injector<'_> {
+		fn config(self) -> GasConfig {
+			GasConfig::new("env")
+				.with_backend(GasBackend::MutableGlobal(self.global_name.into()))
+				.with_precision(GasPrecision::Bits64)
+		}
+	}
+}
+
+/// Rules charging the same cost for every instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConstantCostRules {
+	instruction_cost: u32,
+	memory_grow_cost: u32,
+	call_per_local_cost: u32,
+}
+
+impl ConstantCostRules {
+	/// Charge `instruction_cost` for every instruction, `memory_grow_cost` for every page the
+	/// memory grows by and `call_per_local_cost` for every local of a called function.
+	pub fn new(instruction_cost: u32, memory_grow_cost: u32, call_per_local_cost: u32) -> Self {
+		ConstantCostRules { instruction_cost, memory_grow_cost, call_per_local_cost }
+	}
+}
+
+impl Default for ConstantCostRules {
+	fn default() -> Self {
+		ConstantCostRules::new(1, 10000, 1)
+	}
+}
+
+impl Rules for ConstantCostRules {
+	fn instruction_cost(&self, _: &Instruction) -> Option<u32> {
+		Some(self.instruction_cost)
+	}
+
+	fn memory_grow_cost(&self) -> Option<MemoryGrowCost> {
+		NonZeroU32::new(self.memory_grow_cost).map(MemoryGrowCost::Linear)
+	}
+
+	fn call_per_local_cost(&self) -> u32 {
+		self.call_per_local_cost
+	}
+}
+
+/// Meter `module` with gas charged by `backend` according to `rules`.
+///
+/// Like in wasm-instrument, the original module is returned if it can't be instrumented. Use
+/// [`crate::inject_gas_counter_with_config`] for the reason.
+pub fn inject<R: Rules, B: Backend>(
+	module: Module,
+	backend: B,
+	rules: &R,
+) -> Result<Module, Module> {
+	gas::inject_gas_counter_with_config(module.clone(), rules, &backend.config())
+		.map_err(|_| module)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use parity_wasm::elements;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	#[test]
+	fn backends() {
+		let module = parse_wat(
+			r#"
+(module
+	(memory 1)
+	(func (export "f") (param i32) (local i32)
+		(drop (memory.grow (local.get 0)))
+	)
+)
+"#,
+		);
+		let rules = ConstantCostRules::default();
+
+		let injected =
+			inject(module.clone(), host_function::Injector::new("seal0", "gas"), &rules).unwrap();
+		let expected = gas::inject_gas_counter_with_config(
+			module.clone(),
+			&rules,
+			&GasConfig::new(("seal0", "gas")).with_precision(GasPrecision::Bits64),
+		)
+		.unwrap();
+		assert_eq!(injected, expected);
+
+		let injected =
+			inject(module.clone(), mutable_global::Injector::new("gas_left"), &rules).unwrap();
+		assert!(injected
+			.export_section()
+			.unwrap()
+			.entries()
+			.iter()
+			.any(|entry| entry.field() == "gas_left"));
+
+		struct Forbidding;
+		impl Rules for Forbidding {
+			fn instruction_cost(&self, _: &Instruction) -> Option<u32> {
+				None
+			}
+			fn memory_grow_cost(&self) -> Option<MemoryGrowCost> {
+				None
+			}
+		}
+		assert_eq!(
+			inject(module.clone(), host_function::Injector::new("env", "gas"), &Forbidding),
+			Err(module)
+		);
+	}
+}
@@ -33,6 +33,8 @@ pub enum Error {
 	Format(elements::Error),
 	/// Detached entry
 	DetachedEntry,
+	/// Invalid JSON representation
+	Json(String),
 }
 
 /// Function origin (imported or internal).
@@ -196,8 +198,65 @@ pub struct Module {
 	pub elements: Vec<ElementSegment>,
 	/// List of data segments.
 	pub data: Vec<DataSegment>,
-	/// Other module functions that are not decoded or processed.
-	pub other: BTreeMap<usize, elements::Section>,
+	/// Other module sections that are not decoded or processed.
+	///
+	/// They are keyed by the known section they follow and their order among the sections
+	/// following it, so that they keep valid positions when known sections are added or removed.
+	pub other: BTreeMap<(SectionAnchor, usize), elements::Section>,
+}
+
+/// Known section after which a section that is not decoded (e.g. custom one) is placed.
+///
+/// Variants are ordered as the sections are in a module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "graph-json", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "graph-json", serde(rename_all = "snake_case"))]
+pub enum SectionAnchor {
+	/// Before any known section.
+	Head,
+	/// After the type section.
+	Type,
+	/// After the import section.
+	Import,
+	/// After the function section.
+	Function,
+	/// After the table section.
+	Table,
+	/// After the memory section.
+	Memory,
+	/// After the global section.
+	Global,
+	/// After the export section.
+	Export,
+	/// After the start section.
+	Start,
+	/// After the element section.
+	Element,
+	/// After the code section.
+	Code,
+	/// After the data section.
+	Data,
+}
+
+impl SectionAnchor {
+	/// Anchor corresponding to a known section, `None` for other sections.
+	fn of(section: &elements::Section) -> Option<Self> {
+		use parity_wasm::elements::Section::*;
+		Some(match section {
+			Type(_) => SectionAnchor::Type,
+			Import(_) => SectionAnchor::Import,
+			Function(_) => SectionAnchor::Function,
+			Table(_) => SectionAnchor::Table,
+			Memory(_) => SectionAnchor::Memory,
+			Global(_) => SectionAnchor::Global,
+			Export(_) => SectionAnchor::Export,
+			Start(_) => SectionAnchor::Start,
+			Element(_) => SectionAnchor::Element,
+			Code(_) => SectionAnchor::Code,
+			Data(_) => SectionAnchor::Data,
+			_ => return None,
+		})
+	}
 }
 
 impl Module {
@@ -241,7 +300,15 @@ impl Module {
 		let mut res = Module::default();
 		let mut imported_functions = 0;
 
-		for (idx, section) in module.sections().iter().enumerate() {
+		let mut anchor = SectionAnchor::Head;
+		let mut other_idx = 0;
+
+		for section in module.sections() {
+			if let Some(section_anchor) = SectionAnchor::of(section) {
+				anchor = section_anchor;
+				other_idx = 0;
+			}
+
 			match section {
 				elements::Section::Type(type_section) => {
 					res.types = RefList::from_slice(type_section.types());
@@ -390,7 +457,8 @@ impl Module {
 					}
 				},
 				_ => {
-					res.other.insert(idx, section.clone());
+					res.other.insert((anchor, other_idx), section.clone());
+					other_idx += 1;
 				},
 			}
 		}
@@ -398,14 +466,19 @@ impl Module {
 		Ok(res)
 	}
 
+	/// Push all other sections placed after the `anchor` section.
+	fn push_other(&self, anchor: SectionAnchor, sections: &mut Vec<elements::Section>) {
+		sections
+			.extend(self.other.range((anchor, 0)..=(anchor, usize::MAX)).map(|(_, s)| s.clone()));
+	}
+
 	/// Generate raw format representation.
 	pub fn generate(&self) -> Result<elements::Module, Error> {
 		use self::ImportedOrDeclared::*;
 
-		let mut idx = 0;
 		let mut sections = Vec::new();
 
-		custom_round(&self.other, &mut idx, &mut sections);
+		self.push_other(SectionAnchor::Head, &mut sections);
 
 		if !self.types.is_empty() {
 			// TYPE SECTION (1)
@@ -418,10 +491,8 @@ impl Module {
 				}
 			}
 			sections.push(elements::Section::Type(type_section));
-			idx += 1;
-
-			custom_round(&self.other, &mut idx, &mut sections);
 		}
+		self.push_other(SectionAnchor::Type, &mut sections);
 
 		// IMPORT SECTION (2)
 		let mut import_section = elements::ImportSection::default();
@@ -487,9 +558,8 @@ impl Module {
 
 		if add {
 			sections.push(elements::Section::Import(import_section));
-			idx += 1;
-			custom_round(&self.other, &mut idx, &mut sections);
 		}
+		self.push_other(SectionAnchor::Import, &mut sections);
 
 		if !self.funcs.is_empty() {
 			// FUNC SECTION (3)
@@ -509,10 +579,8 @@ impl Module {
 				}
 			}
 			sections.push(elements::Section::Function(func_section));
-			idx += 1;
-
-			custom_round(&self.other, &mut idx, &mut sections);
 		}
+		self.push_other(SectionAnchor::Function, &mut sections);
 
 		if !self.tables.is_empty() {
 			// TABLE SECTION (4)
@@ -533,10 +601,8 @@ impl Module {
 				}
 			}
 			sections.push(elements::Section::Table(table_section));
-			idx += 1;
-
-			custom_round(&self.other, &mut idx, &mut sections);
 		}
+		self.push_other(SectionAnchor::Table, &mut sections);
 
 		if !self.memory.is_empty() {
 			// MEMORY SECTION (5)
@@ -557,10 +623,8 @@ impl Module {
 				}
 			}
 			sections.push(elements::Section::Memory(memory_section));
-			idx += 1;
-
-			custom_round(&self.other, &mut idx, &mut sections);
 		}
+		self.push_other(SectionAnchor::Memory, &mut sections);
 
 		if !self.globals.is_empty() {
 			// GLOBAL SECTION (6)
@@ -584,10 +648,8 @@ impl Module {
 				}
 			}
 			sections.push(elements::Section::Global(global_section));
-			idx += 1;
-
-			custom_round(&self.other, &mut idx, &mut sections);
 		}
+		self.push_other(SectionAnchor::Global, &mut sections);
 
 		if !self.exports.is_empty() {
 			// EXPORT SECTION (7)
@@ -615,10 +677,8 @@ impl Module {
 				}
 			}
 			sections.push(elements::Section::Export(export_section));
-			idx += 1;
-
-			custom_round(&self.other, &mut idx, &mut sections);
 		}
+		self.push_other(SectionAnchor::Export, &mut sections);
 
 		if let Some(func_ref) = &self.start {
 			// START SECTION (8)
@@ -626,6 +686,7 @@ impl Module {
 				func_ref.order().ok_or(Error::DetachedEntry)? as u32
 			));
 		}
+		self.push_other(SectionAnchor::Start, &mut sections);
 
 		if !self.elements.is_empty() {
 			// START SECTION (9)
@@ -655,10 +716,8 @@ impl Module {
 			}
 
 			sections.push(elements::Section::Element(element_section));
-			idx += 1;
-
-			custom_round(&self.other, &mut idx, &mut sections);
 		}
+		self.push_other(SectionAnchor::Element, &mut sections);
 
 		if !self.funcs.is_empty() {
 			// CODE SECTION (10)
@@ -681,10 +740,8 @@ impl Module {
 				}
 			}
 			sections.push(elements::Section::Code(code_section));
-			idx += 1;
-
-			custom_round(&self.other, &mut idx, &mut sections);
 		}
+		self.push_other(SectionAnchor::Code, &mut sections);
 
 		if !self.data.is_empty() {
 			// DATA SECTION (11)
@@ -709,26 +766,13 @@ impl Module {
 			}
 
 			sections.push(elements::Section::Data(data_section));
-			idx += 1;
-
-			custom_round(&self.other, &mut idx, &mut sections);
 		}
+		self.push_other(SectionAnchor::Data, &mut sections);
 
 		Ok(elements::Module::new(sections))
 	}
 }
 
-fn custom_round(
-	map: &BTreeMap<usize, elements::Section>,
-	idx: &mut usize,
-	sections: &mut Vec<elements::Section>,
-) {
-	while let Some(other_section) = map.get(idx) {
-		sections.push(other_section.clone());
-		*idx += 1;
-	}
-}
-
 /// New module from parity-wasm `Module`
 pub fn parse(wasm: &[u8]) -> Result<Module, Error> {
 	Module::from_elements(&::parity_wasm::deserialize_buffer(wasm).map_err(Error::Format)?)
@@ -953,4 +997,47 @@ mod tests {
 			"Call should be recalculated to 1"
 		);
 	}
+
+	#[test]
+	fn custom_sections_keep_position() {
+		let mut module: elements::Module = elements::deserialize_buffer(
+			&wabt::wat2wasm(indoc!(
+				r#"
+				(module
+					(import "env" "foo" (func))
+					(func (export "main")))"#
+			))
+			.expect("failed to parse wat!"),
+		)
+		.expect("failed to deserialize");
+		let custom = |name: &str| {
+			elements::Section::Custom(elements::CustomSection::new(name.to_owned(), vec![]))
+		};
+		// type, import, "after_import", function, export, code, "after_code"
+		module.sections_mut().insert(2, custom("after_import"));
+		module.sections_mut().push(custom("after_code"));
+
+		let mut sample =
+			super::Module::from_elements(&module).expect("error making representation");
+
+		// Removing the unused import drops the import section.
+		sample.funcs.begin_delete().push(0).done();
+
+		let generated = sample.generate().expect("Failed to generate module");
+		let names: Vec<&str> = generated
+			.sections()
+			.iter()
+			.map(|section| match section {
+				elements::Section::Custom(custom) => custom.name(),
+				elements::Section::Type(_) => "type",
+				elements::Section::Function(_) => "function",
+				elements::Section::Export(_) => "export",
+				elements::Section::Code(_) => "code",
+				_ => "other",
+			})
+			.collect();
+		assert_eq!(names, vec!["type", "after_import", "function", "export", "code", "after_code"]);
+
+		validate_sample(&sample);
+	}
 }
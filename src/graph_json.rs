@@ -0,0 +1,726 @@
+//! JSON representation of the graph [`Module`].
+//!
+//! It lets tools not written in Rust, e.g. analysis scripts, inspect the structure of a module
+//! and feed an edited structure back to generate the module from.
+//!
+//! The entities (types, functions, memories, tables and globals) are listed in the order of
+//! their index spaces, each with an id, and referred to by these ids. [`Module::to_json`]
+//! derives the ids from the kind and the index of the entity, e.g. `func3`, so they are the same
+//! every time a module is exported. [`Module::from_json`] accepts any unique ids, so entities
+//! can be added without renaming the others.
+//!
+//! Instructions referring to entities (`call`, `call_indirect`, `get_global` and `set_global`)
+//! are objects with the id of the entity, the other ones are `plain` with their binary encoding
+//! in hex. The contents of data segments and of the sections which aren't decoded are in hex as
+//! well. Data and element segments have to be active with an offset, [`Module::from_json`]
+//! rejects passive ones.
+
+use crate::{
+	graph::{
+		DataSegment, ElementSegment, Error, Export, ExportLocal, Func, FuncBody, Global,
+		ImportedOrDeclared, Instruction, Memory, Module, SectionAnchor, SegmentLocation, Table,
+	},
+	ref_list::EntryRef,
+	std::{
+		collections::BTreeMap,
+		fmt::Write,
+		string::{String, ToString},
+		vec::Vec,
+	},
+};
+use parity_wasm::elements;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+struct JsonModule {
+	types: Vec<JsonType>,
+	funcs: Vec<JsonFunc>,
+	memory: Vec<JsonLimited>,
+	tables: Vec<JsonLimited>,
+	globals: Vec<JsonGlobal>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	start: Option<String>,
+	exports: Vec<JsonExport>,
+	elements: Vec<JsonElementSegment>,
+	data: Vec<JsonDataSegment>,
+	other: Vec<JsonSection>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonImport {
+	module: String,
+	field: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonType {
+	id: String,
+	params: Vec<String>,
+	results: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonLocal {
+	count: u32,
+	value_type: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonBody {
+	locals: Vec<JsonLocal>,
+	code: Vec<JsonInstruction>,
+}
+
+/// Function, either imported or with a body.
+#[derive(Serialize, Deserialize)]
+struct JsonFunc {
+	id: String,
+	#[serde(rename = "type")]
+	type_ref: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	import: Option<JsonImport>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	body: Option<JsonBody>,
+}
+
+/// Memory or table.
+#[derive(Serialize, Deserialize)]
+struct JsonLimited {
+	id: String,
+	initial: u32,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	maximum: Option<u32>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	import: Option<JsonImport>,
+}
+
+/// Global, either imported or with an initializer.
+#[derive(Serialize, Deserialize)]
+struct JsonGlobal {
+	id: String,
+	value_type: String,
+	mutable: bool,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	import: Option<JsonImport>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	init: Option<Vec<JsonInstruction>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum JsonInstruction {
+	Plain(String),
+	Call(String),
+	CallIndirect {
+		#[serde(rename = "type")]
+		type_ref: String,
+		table: u8,
+	},
+	GetGlobal(String),
+	SetGlobal(String),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum JsonLocation {
+	Passive,
+	Default(Vec<JsonInstruction>),
+	WithIndex(u32, Vec<JsonInstruction>),
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonElementSegment {
+	location: JsonLocation,
+	funcs: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonDataSegment {
+	location: JsonLocation,
+	value: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum JsonExportLocal {
+	Func(String),
+	Global(String),
+	Table(String),
+	Memory(String),
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonExport {
+	name: String,
+	local: JsonExportLocal,
+}
+
+/// Section which isn't decoded, in its binary encoding.
+#[derive(Serialize, Deserialize)]
+struct JsonSection {
+	after: SectionAnchor,
+	section: String,
+}
+
+fn invalid(message: impl ToString) -> Error {
+	Error::Json(message.to_string())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+	let mut hex = String::with_capacity(bytes.len() * 2);
+	for byte in bytes {
+		write!(hex, "{:02x}", byte).expect("writing to a string doesn't fail; qed");
+	}
+	hex
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>, Error> {
+	if hex.len() % 2 != 0 || !hex.is_ascii() {
+		return Err(invalid(format!("invalid hex string `{}`", hex)))
+	}
+	(0..hex.len())
+		.step_by(2)
+		.map(|pos| {
+			u8::from_str_radix(&hex[pos..pos + 2], 16)
+				.map_err(|_| invalid(format!("invalid hex string `{}`", hex)))
+		})
+		.collect()
+}
+
+fn parse_value_type(name: &str) -> Result<elements::ValueType, Error> {
+	use parity_wasm::elements::ValueType::*;
+	Ok(match name {
+		"i32" => I32,
+		"i64" => I64,
+		"f32" => F32,
+		"f64" => F64,
+		#[cfg(feature = "simd")]
+		"v128" => V128,
+		_ => return Err(invalid(format!("unknown value type `{}`", name))),
+	})
+}
+
+fn parse_value_types(names: &[String]) -> Result<Vec<elements::ValueType>, Error> {
+	names.iter().map(|name| parse_value_type(name)).collect()
+}
+
+fn value_type_names(value_types: &[elements::ValueType]) -> Vec<String> {
+	value_types.iter().map(ToString::to_string).collect()
+}
+
+/// Id of an entry, derived from its index.
+fn entry_id<T>(kind: &str, entry: &EntryRef<T>) -> Result<String, Error> {
+	Ok(format!("{}{}", kind, entry.order().ok_or(Error::DetachedEntry)?))
+}
+
+fn json_import<T>(origin: &ImportedOrDeclared<T>) -> Option<JsonImport> {
+	match origin {
+		ImportedOrDeclared::Imported(module, field) =>
+			Some(JsonImport { module: module.clone(), field: field.clone() }),
+		ImportedOrDeclared::Declared(_) => None,
+	}
+}
+
+fn json_instructions(instructions: &[Instruction]) -> Result<Vec<JsonInstruction>, Error> {
+	instructions
+		.iter()
+		.map(|instruction| {
+			Ok(match instruction {
+				Instruction::Plain(plain) => JsonInstruction::Plain(to_hex(
+					&elements::serialize(plain.clone()).map_err(Error::Format)?,
+				)),
+				Instruction::Call(func) => JsonInstruction::Call(entry_id("func", func)?),
+				Instruction::CallIndirect(ty, table) =>
+					JsonInstruction::CallIndirect { type_ref: entry_id("type", ty)?, table: *table },
+				Instruction::GetGlobal(global) =>
+					JsonInstruction::GetGlobal(entry_id("global", global)?),
+				Instruction::SetGlobal(global) =>
+					JsonInstruction::SetGlobal(entry_id("global", global)?),
+			})
+		})
+		.collect()
+}
+
+fn json_location(location: &SegmentLocation) -> Result<JsonLocation, Error> {
+	Ok(match location {
+		SegmentLocation::Passive => JsonLocation::Passive,
+		SegmentLocation::Default(offset) => JsonLocation::Default(json_instructions(offset)?),
+		SegmentLocation::WithIndex(idx, offset) =>
+			JsonLocation::WithIndex(*idx, json_instructions(offset)?),
+	})
+}
+
+fn json_limited<T>(
+	kind: &str,
+	entry: &EntryRef<T>,
+	limits: &elements::ResizableLimits,
+	origin: &ImportedOrDeclared,
+) -> Result<JsonLimited, Error> {
+	Ok(JsonLimited {
+		id: entry_id(kind, entry)?,
+		initial: limits.initial(),
+		maximum: limits.maximum(),
+		import: json_import(origin),
+	})
+}
+
+/// Entries of one kind by their ids.
+struct Entries<T> {
+	kind: &'static str,
+	by_id: BTreeMap<String, EntryRef<T>>,
+}
+
+impl<T> Entries<T> {
+	fn new(kind: &'static str) -> Self {
+		Entries { kind, by_id: BTreeMap::new() }
+	}
+
+	fn insert(&mut self, id: &str, entry: EntryRef<T>) -> Result<(), Error> {
+		if self.by_id.insert(id.into(), entry).is_some() {
+			return Err(invalid(format!("duplicate {} id `{}`", self.kind, id)))
+		}
+		Ok(())
+	}
+
+	fn get(&self, id: &str) -> Result<EntryRef<T>, Error> {
+		self.by_id
+			.get(id)
+			.cloned()
+			.ok_or_else(|| invalid(format!("unknown {} `{}`", self.kind, id)))
+	}
+}
+
+struct Resolver {
+	types: Entries<elements::Type>,
+	funcs: Entries<Func>,
+	memory: Entries<Memory>,
+	tables: Entries<Table>,
+	globals: Entries<Global>,
+}
+
+impl Resolver {
+	fn instructions(&self, instructions: &[JsonInstruction]) -> Result<Vec<Instruction>, Error> {
+		instructions
+			.iter()
+			.map(|instruction| {
+				Ok(match instruction {
+					JsonInstruction::Plain(hex) => Instruction::Plain(
+						elements::deserialize_buffer(&from_hex(hex)?).map_err(Error::Format)?,
+					),
+					JsonInstruction::Call(func) => Instruction::Call(self.funcs.get(func)?),
+					JsonInstruction::CallIndirect { type_ref, table } =>
+						Instruction::CallIndirect(self.types.get(type_ref)?, *table),
+					JsonInstruction::GetGlobal(global) =>
+						Instruction::GetGlobal(self.globals.get(global)?),
+					JsonInstruction::SetGlobal(global) =>
+						Instruction::SetGlobal(self.globals.get(global)?),
+				})
+			})
+			.collect()
+	}
+
+	fn location(&self, location: &JsonLocation) -> Result<SegmentLocation, Error> {
+		// Only these are generated, see `graph::Module::generate`.
+		match location {
+			JsonLocation::Default(offset) | JsonLocation::WithIndex(0, offset) =>
+				Ok(SegmentLocation::Default(self.instructions(offset)?)),
+			JsonLocation::Passive | JsonLocation::WithIndex(..) =>
+				Err(invalid("only active segments with an offset are supported")),
+		}
+	}
+}
+
+fn origin(import: &Option<JsonImport>) -> ImportedOrDeclared {
+	match import {
+		Some(import) => ImportedOrDeclared::Imported(import.module.clone(), import.field.clone()),
+		None => ImportedOrDeclared::Declared(()),
+	}
+}
+
+fn limits(limited: &JsonLimited) -> elements::ResizableLimits {
+	elements::ResizableLimits::new(limited.initial, limited.maximum)
+}
+
+impl Module {
+	/// Representation of the module in JSON.
+	///
+	/// See the [module documentation](self) for the format.
+	pub fn to_json(&self) -> Result<String, Error> {
+		let types = self
+			.types
+			.iter()
+			.map(|entry| {
+				let elements::Type::Function(signature) = &**entry.read();
+				Ok(JsonType {
+					id: entry_id("type", entry)?,
+					params: value_type_names(signature.params()),
+					results: value_type_names(signature.results()),
+				})
+			})
+			.collect::<Result<_, Error>>()?;
+
+		let funcs = self
+			.funcs
+			.iter()
+			.map(|entry| {
+				let func = entry.read();
+				let body = match &func.origin {
+					ImportedOrDeclared::Declared(body) => Some(JsonBody {
+						locals: body
+							.locals
+							.iter()
+							.map(|local| JsonLocal {
+								count: local.count(),
+								value_type: local.value_type().to_string(),
+							})
+							.collect(),
+						code: json_instructions(&body.code)?,
+					}),
+					ImportedOrDeclared::Imported(..) => None,
+				};
+				Ok(JsonFunc {
+					id: entry_id("func", entry)?,
+					type_ref: entry_id("type", &func.type_ref)?,
+					import: json_import(&func.origin),
+					body,
+				})
+			})
+			.collect::<Result<_, Error>>()?;
+
+		let memory = self
+			.memory
+			.iter()
+			.map(|entry| {
+				let memory = entry.read();
+				json_limited("memory", entry, &memory.limits, &memory.origin)
+			})
+			.collect::<Result<_, Error>>()?;
+		let tables = self
+			.tables
+			.iter()
+			.map(|entry| {
+				let table = entry.read();
+				json_limited("table", entry, &table.limits, &table.origin)
+			})
+			.collect::<Result<_, Error>>()?;
+
+		let globals = self
+			.globals
+			.iter()
+			.map(|entry| {
+				let global = entry.read();
+				let init = match &global.origin {
+					ImportedOrDeclared::Declared(init) => Some(json_instructions(init)?),
+					ImportedOrDeclared::Imported(..) => None,
+				};
+				Ok(JsonGlobal {
+					id: entry_id("global", entry)?,
+					value_type: global.content.to_string(),
+					mutable: global.is_mut,
+					import: json_import(&global.origin),
+					init,
+				})
+			})
+			.collect::<Result<_, Error>>()?;
+
+		let exports = self
+			.exports
+			.iter()
+			.map(|export| {
+				let local = match &export.local {
+					ExportLocal::Func(func) => JsonExportLocal::Func(entry_id("func", func)?),
+					ExportLocal::Global(global) =>
+						JsonExportLocal::Global(entry_id("global", global)?),
+					ExportLocal::Table(table) => JsonExportLocal::Table(entry_id("table", table)?),
+					ExportLocal::Memory(memory) =>
+						JsonExportLocal::Memory(entry_id("memory", memory)?),
+				};
+				Ok(JsonExport { name: export.name.clone(), local })
+			})
+			.collect::<Result<_, Error>>()?;
+
+		let elements = self
+			.elements
+			.iter()
+			.map(|segment| {
+				Ok(JsonElementSegment {
+					location: json_location(&segment.location)?,
+					funcs: segment
+						.value
+						.iter()
+						.map(|func| entry_id("func", func))
+						.collect::<Result<_, _>>()?,
+				})
+			})
+			.collect::<Result<_, Error>>()?;
+		let data = self
+			.data
+			.iter()
+			.map(|segment| {
+				Ok(JsonDataSegment {
+					location: json_location(&segment.location)?,
+					value: to_hex(&segment.value),
+				})
+			})
+			.collect::<Result<_, Error>>()?;
+
+		let other = self
+			.other
+			.iter()
+			.map(|((anchor, _), section)| {
+				Ok(JsonSection {
+					after: *anchor,
+					section: to_hex(&elements::serialize(section.clone()).map_err(Error::Format)?),
+				})
+			})
+			.collect::<Result<_, Error>>()?;
+
+		let start = self.start.as_ref().map(|func| entry_id("func", func)).transpose()?;
+
+		serde_json::to_string_pretty(&JsonModule {
+			types,
+			funcs,
+			memory,
+			tables,
+			globals,
+			start,
+			exports,
+			elements,
+			data,
+			other,
+		})
+		.map_err(invalid)
+	}
+
+	/// Module from its representation in JSON, e.g. produced by [`Module::to_json`].
+	pub fn from_json(json: &str) -> Result<Self, Error> {
+		let json: JsonModule = serde_json::from_str(json).map_err(invalid)?;
+		let mut module = Module::default();
+		let mut resolver = Resolver {
+			types: Entries::new("type"),
+			funcs: Entries::new("func"),
+			memory: Entries::new("memory"),
+			tables: Entries::new("table"),
+			globals: Entries::new("global"),
+		};
+
+		for ty in &json.types {
+			let signature = elements::FunctionType::new(
+				parse_value_types(&ty.params)?,
+				parse_value_types(&ty.results)?,
+			);
+			resolver
+				.types
+				.insert(&ty.id, module.types.push(elements::Type::Function(signature)))?;
+		}
+
+		// The code and the initializers may refer to any function and global, so they are
+		// resolved once all of them are known.
+		for func in &json.funcs {
+			let origin = match (&func.import, &func.body) {
+				(Some(import), None) =>
+					ImportedOrDeclared::Imported(import.module.clone(), import.field.clone()),
+				(None, Some(body)) => ImportedOrDeclared::Declared(FuncBody {
+					locals: body
+						.locals
+						.iter()
+						.map(|local| {
+							Ok(elements::Local::new(
+								local.count,
+								parse_value_type(&local.value_type)?,
+							))
+						})
+						.collect::<Result<_, Error>>()?,
+					code: Vec::new(),
+				}),
+				_ =>
+					return Err(invalid(format!(
+						"func `{}` needs either an import or a body",
+						func.id
+					))),
+			};
+			let type_ref = resolver.types.get(&func.type_ref)?;
+			resolver.funcs.insert(&func.id, module.funcs.push(Func { type_ref, origin }))?;
+		}
+		for global in &json.globals {
+			let origin = match (&global.import, &global.init) {
+				(Some(import), None) =>
+					ImportedOrDeclared::Imported(import.module.clone(), import.field.clone()),
+				(None, Some(_)) => ImportedOrDeclared::Declared(Vec::new()),
+				_ =>
+					return Err(invalid(format!(
+						"global `{}` needs either an import or an initializer",
+						global.id
+					))),
+			};
+			let entry = module.globals.push(Global {
+				content: parse_value_type(&global.value_type)?,
+				is_mut: global.mutable,
+				origin,
+			});
+			resolver.globals.insert(&global.id, entry)?;
+		}
+		for memory in &json.memory {
+			let entry = module
+				.memory
+				.push(Memory { limits: limits(memory), origin: origin(&memory.import) });
+			resolver.memory.insert(&memory.id, entry)?;
+		}
+		for table in &json.tables {
+			let entry = module
+				.tables
+				.push(Table { limits: limits(table), origin: origin(&table.import) });
+			resolver.tables.insert(&table.id, entry)?;
+		}
+
+		for (func, entry) in json.funcs.iter().zip(module.funcs.iter()) {
+			if let (Some(body), ImportedOrDeclared::Declared(declared)) =
+				(&func.body, &mut entry.write().origin)
+			{
+				declared.code = resolver.instructions(&body.code)?;
+			}
+		}
+		for (global, entry) in json.globals.iter().zip(module.globals.iter()) {
+			if let (Some(init), ImportedOrDeclared::Declared(declared)) =
+				(&global.init, &mut entry.write().origin)
+			{
+				*declared = resolver.instructions(init)?;
+			}
+		}
+
+		module.start = json.start.as_deref().map(|func| resolver.funcs.get(func)).transpose()?;
+		for export in &json.exports {
+			let local = match &export.local {
+				JsonExportLocal::Func(func) => ExportLocal::Func(resolver.funcs.get(func)?),
+				JsonExportLocal::Global(global) =>
+					ExportLocal::Global(resolver.globals.get(global)?),
+				JsonExportLocal::Table(table) => ExportLocal::Table(resolver.tables.get(table)?),
+				JsonExportLocal::Memory(memory) =>
+					ExportLocal::Memory(resolver.memory.get(memory)?),
+			};
+			module.exports.push(Export { name: export.name.clone(), local });
+		}
+		for segment in &json.elements {
+			module.elements.push(ElementSegment {
+				location: resolver.location(&segment.location)?,
+				value: segment
+					.funcs
+					.iter()
+					.map(|func| resolver.funcs.get(func))
+					.collect::<Result<_, _>>()?,
+			});
+		}
+		for segment in &json.data {
+			module.data.push(DataSegment {
+				location: resolver.location(&segment.location)?,
+				value: from_hex(&segment.value)?,
+			});
+		}
+
+		let mut order = BTreeMap::new();
+		for section in &json.other {
+			let idx = order.entry(section.after).or_insert(0);
+			let decoded = elements::deserialize_buffer(&from_hex(&section.section)?)
+				.map_err(Error::Format)?;
+			module.other.insert((section.after, *idx), decoded);
+			*idx += 1;
+		}
+
+		Ok(module)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::graph;
+
+	#[test]
+	fn round_trip() {
+		let wasm = wabt::wat2wasm(
+			r#"
+(module
+	(import "env" "log" (func $log (param i32)))
+	(import "env" "base" (global $base i32))
+	(type $unary (func (param i32) (result i32)))
+	(memory (export "memory") 1 2)
+	(table 2 funcref)
+	(global $counter (mut i32) (global.get $base))
+	(elem (i32.const 0) $double $main)
+	(func $double (type $unary)
+		(call $log (local.get 0))
+		(i32.mul (local.get 0) (i32.const 2))
+	)
+	(func $main (export "main") (param i32) (result i32) (local i64)
+		(global.set $counter (i32.const 1))
+		(call_indirect (type $unary) (local.get 0) (i32.const 0))
+	)
+	(data (i32.const 8) "hello")
+)
+"#,
+		)
+		.unwrap();
+		let module = graph::parse(&wasm).unwrap();
+
+		let json = module.to_json().unwrap();
+		assert!(json.contains(r#""call": "func0""#));
+		let parsed = Module::from_json(&json).unwrap();
+		assert_eq!(parsed.to_json().unwrap(), json);
+		assert_eq!(graph::generate(&parsed).unwrap(), wasm);
+	}
+
+	#[test]
+	fn hand_edited() {
+		let json = r#"{
+			"types": [{ "id": "unit", "params": [], "results": [] }],
+			"funcs": [
+				{ "id": "main", "type": "unit", "body": { "locals": [], "code": [
+					{ "call": "helper" }, { "plain": "0b" }
+				] } },
+				{ "id": "helper", "type": "unit", "body": { "locals": [], "code": [
+					{ "plain": "0b" }
+				] } }
+			],
+			"memory": [],
+			"tables": [],
+			"globals": [],
+			"start": "main",
+			"exports": [],
+			"elements": [],
+			"data": [],
+			"other": []
+		}"#;
+		let module = Module::from_json(json).unwrap();
+		let generated = module.generate().unwrap();
+		assert_eq!(generated.start_section(), Some(0));
+		assert_eq!(
+			generated.code_section().unwrap().bodies()[0].code().elements(),
+			&[elements::Instruction::Call(1), elements::Instruction::End]
+		);
+
+		let unknown = json.replace(r#"{ "call": "helper" }"#, r#"{ "call": "missing" }"#);
+		assert!(matches!(
+			Module::from_json(&unknown),
+			Err(Error::Json(message)) if message == "unknown func `missing`"
+		));
+	}
+
+	#[test]
+	fn passive_segment() {
+		let json = r#"{
+			"types": [],
+			"funcs": [],
+			"memory": [{ "id": "memory", "initial": 1 }],
+			"tables": [],
+			"globals": [],
+			"exports": [],
+			"elements": [],
+			"data": [{ "location": "passive", "value": "00" }],
+			"other": []
+		}"#;
+		assert!(matches!(
+			Module::from_json(json),
+			Err(Error::Json(message)) if message == "only active segments with an offset are supported"
+		));
+	}
+}
@@ -0,0 +1,93 @@
+//! Canonical hashing of the code of a module.
+//!
+//! The hash covers only the semantic sections (types, imports, functions, tables, memories,
+//! globals, exports, start, elements, code and data) in their canonical order. Custom sections,
+//! including the name section, are skipped, so stripping or adding them doesn't change the hash.
+
+use crate::std::vec::Vec;
+
+use blake2::{digest::consts::U32, Blake2b};
+use parity_wasm::elements::{self, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Hash algorithm used by [`code_hash`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgo {
+	/// SHA-256.
+	Sha256,
+	/// BLAKE2b with 256 bit output.
+	Blake2b256,
+}
+
+/// Position of a semantic section in the canonical order, `None` for other sections.
+fn canonical_position(section: &elements::Section) -> Option<u8> {
+	use parity_wasm::elements::Section::*;
+	Some(match section {
+		Type(_) => 0,
+		Import(_) => 1,
+		Function(_) => 2,
+		Table(_) => 3,
+		Memory(_) => 4,
+		Global(_) => 5,
+		Export(_) => 6,
+		Start(_) => 7,
+		Element(_) => 8,
+		Code(_) => 9,
+		Data(_) => 10,
+		_ => return None,
+	})
+}
+
+/// Hash of the semantic sections of `module`.
+///
+/// # Panics
+///
+/// Panics if a section can't be serialized, which can't happen for a module that was
+/// deserialized or built by `parity-wasm`.
+pub fn code_hash(module: &elements::Module, algo: HashAlgo) -> [u8; 32] {
+	let mut sections: Vec<_> = module
+		.sections()
+		.iter()
+		.filter_map(|section| canonical_position(section).map(|pos| (pos, section)))
+		.collect();
+	sections.sort_by_key(|(pos, _)| *pos);
+
+	let mut bytes = Vec::new();
+	for (_, section) in sections {
+		section
+			.clone()
+			.serialize(&mut bytes)
+			.expect("sections of a valid module are serializable; qed");
+	}
+
+	match algo {
+		HashAlgo::Sha256 => Sha256::digest(&bytes).into(),
+		HashAlgo::Blake2b256 => Blake2b::<U32>::digest(&bytes).into(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::module_fixture;
+
+	#[test]
+	fn ignores_custom_sections() {
+		let module = module_fixture().with_call_chain(2).with_export("call", 0).build();
+		let mut with_custom = module.clone();
+		with_custom.set_custom_section("fingerprint", vec![1, 2, 3]);
+
+		for algo in [HashAlgo::Sha256, HashAlgo::Blake2b256] {
+			assert_eq!(code_hash(&module, algo), code_hash(&with_custom, algo));
+		}
+		assert_ne!(code_hash(&module, HashAlgo::Sha256), code_hash(&module, HashAlgo::Blake2b256));
+	}
+
+	#[test]
+	fn detects_code_changes() {
+		let module = module_fixture().with_call_chain(2).build();
+		let other = module_fixture().with_functions(2).build();
+
+		assert_ne!(code_hash(&module, HashAlgo::Sha256), code_hash(&other, HashAlgo::Sha256));
+	}
+}
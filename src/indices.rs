@@ -0,0 +1,283 @@
+use crate::std::mem;
+
+use parity_wasm::elements::{self, IndexMap, Instruction, Internal, Section};
+
+/// Place of a module where a function index is referred to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexSite {
+	/// Target of a `call` instruction.
+	Call,
+	/// Exported function.
+	Export,
+	/// Member of a table element segment.
+	Element,
+	/// Start function.
+	Start,
+	/// Function name in the name section.
+	FunctionName,
+	/// Function which local names are given in the name section.
+	LocalNames,
+}
+
+/// Call `f` on every function index the module refers to, allowing to rewrite it.
+///
+/// Passes inserting or removing functions use this to keep all references consistent. The name
+/// section is only visited if it was parsed, see [`elements::Module::parse_names`].
+pub fn visit_function_indices<F: FnMut(&mut u32, IndexSite)>(
+	module: &mut elements::Module,
+	mut f: F,
+) {
+	for section in module.sections_mut() {
+		match section {
+			Section::Code(code_section) =>
+				for func_body in code_section.bodies_mut() {
+					for instruction in func_body.code_mut().elements_mut() {
+						if let Instruction::Call(call_index) = instruction {
+							f(call_index, IndexSite::Call);
+						}
+					}
+				},
+			Section::Export(export_section) =>
+				for export in export_section.entries_mut() {
+					if let Internal::Function(func_index) = export.internal_mut() {
+						f(func_index, IndexSite::Export);
+					}
+				},
+			Section::Element(elements_section) =>
+				for segment in elements_section.entries_mut() {
+					for func_index in segment.members_mut() {
+						f(func_index, IndexSite::Element);
+					}
+				},
+			Section::Start(start_idx) => f(start_idx, IndexSite::Start),
+			Section::Name(name_section) => {
+				if let Some(func_name) = name_section.functions_mut() {
+					visit_keys(func_name.names_mut(), IndexSite::FunctionName, &mut f);
+				}
+				if let Some(local_name) = name_section.locals_mut() {
+					visit_keys(local_name.local_names_mut(), IndexSite::LocalNames, &mut f);
+				}
+			},
+			_ => {},
+		}
+	}
+}
+
+/// Call `f` on every global index the module refers to, allowing to rewrite it.
+///
+/// The global indices are referred to by instructions, by the initializers of globals and
+/// segments, and by exports.
+pub fn visit_global_indices<F: FnMut(&mut u32)>(module: &mut elements::Module, mut f: F) {
+	for section in module.sections_mut() {
+		match section {
+			Section::Code(code_section) =>
+				for func_body in code_section.bodies_mut() {
+					visit_global_code(func_body.code_mut().elements_mut(), &mut f);
+				},
+			Section::Global(global_section) =>
+				for global in global_section.entries_mut() {
+					visit_global_code(global.init_expr_mut().code_mut(), &mut f);
+				},
+			Section::Element(elements_section) =>
+				for segment in elements_section.entries_mut() {
+					if let Some(offset) = segment.offset_mut() {
+						visit_global_code(offset.code_mut(), &mut f);
+					}
+				},
+			Section::Data(data_section) =>
+				for segment in data_section.entries_mut() {
+					if let Some(offset) = segment.offset_mut() {
+						visit_global_code(offset.code_mut(), &mut f);
+					}
+				},
+			Section::Export(export_section) =>
+				for export in export_section.entries_mut() {
+					if let Internal::Global(global_idx) = export.internal_mut() {
+						f(global_idx);
+					}
+				},
+			_ => {},
+		}
+	}
+}
+
+fn visit_global_code<F: FnMut(&mut u32)>(code: &mut [Instruction], f: &mut F) {
+	for instruction in code {
+		if let Instruction::GetGlobal(global_idx) | Instruction::SetGlobal(global_idx) = instruction
+		{
+			f(global_idx);
+		}
+	}
+}
+
+/// Where an entity injected by an instrumentation pass is placed in its index space.
+///
+/// Embedders freezing some indices in their ABI, e.g. requiring the global 0 to be the stack
+/// pointer, can keep the injected entities out of the way, or put them where they expect them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexPlacement {
+	/// Before all entities of the same kind. Injected globals are placed before the globals
+	/// defined in the module, but after the imported ones.
+	First,
+	/// After all entities of the same kind, the default. Injected imports are placed after the
+	/// other imports of the same kind.
+	Last,
+	/// At the given index, shifting the entities from this index on.
+	At(u32),
+}
+
+impl Default for IndexPlacement {
+	fn default() -> Self {
+		IndexPlacement::Last
+	}
+}
+
+impl IndexPlacement {
+	/// Index of an entity inserted among the entities `first..end`, or the explicit index as an
+	/// error if it isn't in the range `first..=end`.
+	pub(crate) fn index(self, first: u32, end: u32) -> Result<u32, u32> {
+		match self {
+			IndexPlacement::First => Ok(first),
+			IndexPlacement::Last => Ok(end),
+			IndexPlacement::At(idx) if (first..=end).contains(&idx) => Ok(idx),
+			IndexPlacement::At(idx) => Err(idx),
+		}
+	}
+}
+
+/// Move the global `from` defined by the module to the index `to`, shifting the globals in
+/// between and rewriting all references, including the ones in the section
+/// [`crate::INTERNAL_GLOBALS_SECTION`].
+///
+/// Both indices have to refer to globals defined by the module.
+pub(crate) fn move_global(module: &mut elements::Module, from: u32, to: u32) {
+	if from == to {
+		return
+	}
+	let remap = |global_idx: u32| {
+		if global_idx == from {
+			to
+		} else if from < global_idx && global_idx <= to {
+			global_idx - 1
+		} else if to <= global_idx && global_idx < from {
+			global_idx + 1
+		} else {
+			global_idx
+		}
+	};
+	remap_globals(module, remap);
+
+	let imported = module.import_count(elements::ImportCountType::Global) as u32;
+	let entries = module
+		.global_section_mut()
+		.expect("the moved global is defined by the module; qed")
+		.entries_mut();
+	let global = entries.remove((from - imported) as usize);
+	entries.insert((to - imported) as usize, global);
+}
+
+/// Import a global with `entry` after the other imported globals, shifting the globals defined by
+/// the module and rewriting all references to them.
+///
+/// Returns the index of the imported global.
+pub(crate) fn insert_global_import(
+	module: &mut elements::Module,
+	entry: elements::ImportEntry,
+) -> u32 {
+	let imported = module.import_count(elements::ImportCountType::Global) as u32;
+	remap_globals(
+		module,
+		|global_idx| if global_idx >= imported { global_idx + 1 } else { global_idx },
+	);
+	crate::sections::get_or_insert_import_section(module).entries_mut().push(entry);
+	imported
+}
+
+/// Import a function with `entry` after the other imported functions, shifting the functions
+/// defined by the module and rewriting all references to them, including the ones in the name
+/// section.
+///
+/// Returns the index of the imported function.
+pub(crate) fn insert_function_import(
+	module: &mut elements::Module,
+	entry: elements::ImportEntry,
+) -> u32 {
+	let imported = module.import_count(elements::ImportCountType::Function) as u32;
+	// The names have to be parsed for their function indices to be updated. A malformed name
+	// section is kept as is.
+	*module = mem::take(module).parse_names().unwrap_or_else(|(_err, module)| module);
+	visit_function_indices(module, |func_idx, _| {
+		if *func_idx >= imported {
+			*func_idx += 1;
+		}
+	});
+	crate::sections::get_or_insert_import_section(module).entries_mut().push(entry);
+	imported
+}
+
+/// Rewrite all references to globals with `remap`, including the ones in the section
+/// [`crate::INTERNAL_GLOBALS_SECTION`].
+fn remap_globals<F: Fn(u32) -> u32>(module: &mut elements::Module, remap: F) {
+	visit_global_indices(module, |global_idx| *global_idx = remap(*global_idx));
+	let internal = crate::internal_globals(module);
+	if !internal.is_empty() {
+		crate::internal_globals::set_internal_globals(
+			module,
+			internal.into_iter().map(remap).collect(),
+		);
+	}
+}
+
+fn visit_keys<T, F: FnMut(&mut u32, IndexSite)>(map: &mut IndexMap<T>, site: IndexSite, f: &mut F) {
+	*map = mem::replace(map, IndexMap::with_capacity(0))
+		.into_iter()
+		.map(|(mut index, value)| {
+			f(&mut index, site);
+			(index, value)
+		})
+		.collect();
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{std::vec::Vec, testing::module_fixture};
+
+	#[test]
+	fn visits_all_sites() {
+		let mut module =
+			module_fixture().with_call_chain(3).with_table(2).with_export("f", 2).build();
+		let mut fnames = elements::FunctionNameSubsection::default();
+		fnames.names_mut().insert(1, "one".into());
+		module.sections_mut().push(Section::Start(0));
+		module.sections_mut().push(Section::Name(elements::NameSection::new(
+			None,
+			Some(fnames),
+			None,
+		)));
+
+		let mut sites = Vec::new();
+		visit_function_indices(&mut module, |index, site| {
+			sites.push((*index, site));
+			*index += 10;
+		});
+
+		assert_eq!(
+			sites,
+			vec![
+				(2, IndexSite::Export),
+				(0, IndexSite::Element),
+				(1, IndexSite::Element),
+				(1, IndexSite::Call),
+				(2, IndexSite::Call),
+				(0, IndexSite::Start),
+				(1, IndexSite::FunctionName),
+			]
+		);
+		assert_eq!(module.start_section(), Some(10));
+		assert_eq!(
+			module.names_section().unwrap().functions().unwrap().names().get(11),
+			Some(&"one".into())
+		);
+	}
+}
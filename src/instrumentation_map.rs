@@ -0,0 +1,243 @@
+//! Map of the code injected by the instrumentation passes.
+//!
+//! Tools presenting instrumented code, e.g. disassemblers, can use it to tell the injected code
+//! apart from the original one. The gas metering and the stack height limiter record the code
+//! they inject in a custom section named [`INSTRUMENTATION_MAP_SECTION`] if configured to. Passes
+//! applied later shift the recorded ranges past the code they inject themselves.
+//!
+//! The payload is a sequence of records, each consisting of the length of the name of the pass
+//! as a byte, the name, and the body index, the start and the end of the range as little endian
+//! `u32`s. Passes removing or reordering functions, like the optimizer, invalidate the map.
+
+use crate::std::{ops::Range, str, string::String, vec::Vec};
+
+use byteorder::{ByteOrder, LittleEndian};
+use parity_wasm::elements;
+
+/// Name of the custom section that maps the injected code.
+pub const INSTRUMENTATION_MAP_SECTION: &str = "pwasm.instrumentation.map";
+
+/// A range of instructions injected into a function body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InjectedCode {
+	/// Name of the pass which injected the code, e.g. [`crate::GAS_PASS`].
+	pub pass: String,
+	/// Index of the function body in the code section.
+	///
+	/// Unlike the function index, this isn't shifted by functions imported later.
+	pub body_idx: u32,
+	/// Index of the first injected instruction in the body.
+	pub start: u32,
+	/// Index of the instruction following the injected ones.
+	pub end: u32,
+}
+
+/// Read the map of the injected code from the custom section [`INSTRUMENTATION_MAP_SECTION`].
+///
+/// Returns an empty map if there is no such section and `None` if it is malformed.
+pub fn read_instrumentation_map(module: &elements::Module) -> Option<Vec<InjectedCode>> {
+	let mut payload =
+		match module.custom_sections().find(|s| s.name() == INSTRUMENTATION_MAP_SECTION) {
+			Some(section) => section.payload(),
+			None => return Some(Vec::new()),
+		};
+	let mut map = Vec::new();
+	while let Some((&len, rest)) = payload.split_first() {
+		let (pass, rest) = (rest.get(..len as usize)?, rest.get(len as usize..)?);
+		let record = rest.get(..12)?;
+		map.push(InjectedCode {
+			pass: str::from_utf8(pass).ok()?.into(),
+			body_idx: LittleEndian::read_u32(&record[0..4]),
+			start: LittleEndian::read_u32(&record[4..8]),
+			end: LittleEndian::read_u32(&record[8..12]),
+		});
+		payload = &rest[12..];
+	}
+	Some(map)
+}
+
+fn serialize_instrumentation_map(map: &[InjectedCode]) -> Vec<u8> {
+	let mut payload = Vec::new();
+	for code in map {
+		let mut record = [0u8; 12];
+		LittleEndian::write_u32(&mut record[0..4], code.body_idx);
+		LittleEndian::write_u32(&mut record[4..8], code.start);
+		LittleEndian::write_u32(&mut record[8..12], code.end);
+		payload.push(code.pass.len() as u8);
+		payload.extend_from_slice(code.pass.as_bytes());
+		payload.extend_from_slice(&record);
+	}
+	payload
+}
+
+/// Position of the instruction at `pos` of a body after the ranges `injected` were inserted.
+///
+/// `injected` are sorted positions in the body after the insertion.
+fn shift_position(injected: &[Range<usize>], pos: usize) -> usize {
+	let mut shifted = pos;
+	for range in injected {
+		if range.start > shifted {
+			break
+		}
+		shifted += range.len();
+	}
+	shifted
+}
+
+/// Shift the ranges `earlier` injected into a body by the ranges `later` injected afterwards,
+/// and merge both.
+pub(crate) fn merge_injected(
+	earlier: &[Range<usize>],
+	later: &[Range<usize>],
+) -> Vec<Range<usize>> {
+	let mut merged: Vec<Range<usize>> = earlier
+		.iter()
+		.filter(|range| !range.is_empty())
+		.map(|range| shift_position(later, range.start)..shift_position(later, range.end - 1) + 1)
+		.chain(later.iter().cloned())
+		.collect();
+	merged.sort_by_key(|range| range.start);
+	merged.dedup_by(|next, prev| {
+		if next.start > prev.end {
+			return false
+		}
+		prev.end = prev.end.max(next.end);
+		true
+	});
+	merged
+}
+
+/// Record the code injected by `pass` into the map of `module`, shifting the recorded ranges.
+///
+/// `injected` holds the sorted ranges injected into every body which existed before the pass,
+/// the bodies from `appended_from` on were added by the pass altogether.
+pub(crate) fn record_injected_code(
+	module: &mut elements::Module,
+	pass: &str,
+	injected: &[Vec<Range<usize>>],
+	appended_from: usize,
+) {
+	let mut map = read_instrumentation_map(module).unwrap_or_default();
+	for code in &mut map {
+		let ranges = match injected.get(code.body_idx as usize) {
+			Some(ranges) => ranges,
+			None => continue,
+		};
+		if code.start < code.end {
+			code.start = shift_position(ranges, code.start as usize) as u32;
+			code.end = shift_position(ranges, code.end as usize - 1) as u32 + 1;
+		}
+	}
+
+	let bodies = module.code_section().map(|section| section.bodies()).unwrap_or(&[]);
+	for (body_idx, ranges) in injected.iter().enumerate() {
+		map.extend(ranges.iter().map(|range| InjectedCode {
+			pass: pass.into(),
+			body_idx: body_idx as u32,
+			start: range.start as u32,
+			end: range.end as u32,
+		}));
+	}
+	for (body_idx, body) in bodies.iter().enumerate().skip(appended_from) {
+		map.push(InjectedCode {
+			pass: pass.into(),
+			body_idx: body_idx as u32,
+			start: 0,
+			end: body.code().elements().len() as u32,
+		});
+	}
+
+	let payload = serialize_instrumentation_map(&map);
+	module.set_custom_section(INSTRUMENTATION_MAP_SECTION, payload);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	#[test]
+	fn shift_and_merge() {
+		// Original: a b c, `earlier` injects x before b and w before c: a x b w c.
+		let earlier = [1..2, 3..4];
+		// `later` injects y before a and z before c: y a x b w z c.
+		let later = [0..1, 5..6];
+		assert_eq!(shift_position(&later, 0), 1);
+		assert_eq!(shift_position(&later, 2), 3);
+		assert_eq!(shift_position(&later, 4), 6);
+		assert_eq!(merge_injected(&earlier, &later), vec![0..1, 2..3, 4..6]);
+	}
+
+	#[test]
+	fn gas_and_stack_height_recorded() {
+		use crate::{inject_gas_counter_with_config, rules, stack_height, GasConfig};
+
+		let module = parse_wat(
+			r#"
+(module
+	(func $callee (param i32) (result i32)
+		(local.get 0)
+	)
+	(func (export "call") (param i32) (result i32)
+		(call $callee (local.get 0))
+	)
+)
+"#,
+		);
+		let original: Vec<_> = module
+			.code_section()
+			.unwrap()
+			.bodies()
+			.iter()
+			.map(|body| body.code().elements().to_vec())
+			.collect();
+
+		let module = inject_gas_counter_with_config(
+			module,
+			&rules::Set::default(),
+			&GasConfig::new("env").with_instrumentation_map(),
+		)
+		.unwrap();
+		let module = stack_height::inject_limiter_with_config(
+			module,
+			&stack_height::Config::new(1024).with_instrumentation_map(),
+		)
+		.unwrap();
+
+		let map = read_instrumentation_map(&module).unwrap();
+		let bodies = module.code_section().unwrap().bodies();
+		// The thunk of the exported function is injected altogether.
+		assert!(map.contains(&InjectedCode {
+			pass: "stack_height".into(),
+			body_idx: 2,
+			start: 0,
+			end: bodies[2].code().elements().len() as u32,
+		}));
+		// Without the injected code, the original bodies remain, with the call shifted by the
+		// gas import.
+		for (body_idx, original) in original.iter().enumerate() {
+			let remaining: Vec<_> = bodies[body_idx]
+				.code()
+				.elements()
+				.iter()
+				.enumerate()
+				.filter(|(pos, _)| {
+					!map.iter().any(|code| {
+						code.body_idx == body_idx as u32 &&
+							(code.start..code.end).contains(&(*pos as u32))
+					})
+				})
+				.map(|(_, instruction)| match instruction {
+					elements::Instruction::Call(func_idx) =>
+						elements::Instruction::Call(func_idx - 1),
+					instruction => instruction.clone(),
+				})
+				.collect();
+			assert_eq!(&remaining, original);
+		}
+	}
+}
@@ -0,0 +1,76 @@
+//! Registry of the instrumentation passes applied to a module.
+//!
+//! Passes applying the same instrumentation twice produce broken modules, e.g. metering the gas
+//! metering code itself. The passes applied to a module can be recorded in a custom section named
+//! [`INSTRUMENTED_SECTION`] whose payload is UTF-8 text with a line per pass: the name of the
+//! pass, followed by a space and its parameters. Passes refuse to instrument a module recording
+//! that they were applied already.
+
+use crate::std::{borrow::ToOwned, str, string::String, vec::Vec};
+
+use parity_wasm::elements;
+
+/// Name of the custom section that lists the applied instrumentation passes.
+pub const INSTRUMENTED_SECTION: &str = "pwasm_utils.instrumented";
+
+/// Returns the recorded instrumentation passes as pairs of (pass, parameters), in the order they
+/// were applied.
+///
+/// Lines which aren't valid UTF-8 are ignored.
+pub fn instrumentation_marks(module: &elements::Module) -> Vec<(String, String)> {
+	let payload = match module.custom_sections().find(|s| s.name() == INSTRUMENTED_SECTION) {
+		Some(section) => section.payload(),
+		None => return Vec::new(),
+	};
+	payload
+		.split(|byte| *byte == b'\n')
+		.filter_map(|line| str::from_utf8(line).ok())
+		.filter(|line| !line.is_empty())
+		.map(|line| match line.split_once(' ') {
+			Some((pass, parameters)) => (pass.to_owned(), parameters.to_owned()),
+			None => (line.to_owned(), String::new()),
+		})
+		.collect()
+}
+
+/// Whether `module` records that the instrumentation `pass` was applied.
+pub fn is_instrumented(module: &elements::Module, pass: &str) -> bool {
+	instrumentation_marks(module).iter().any(|(applied, _)| applied == pass)
+}
+
+/// Record that the instrumentation `pass` was applied to `module` with `parameters`.
+///
+/// `pass` must not contain spaces, neither it nor `parameters` may contain line breaks.
+pub fn mark_instrumented(module: &mut elements::Module, pass: &str, parameters: &str) {
+	let mut payload = module
+		.custom_sections()
+		.find(|s| s.name() == INSTRUMENTED_SECTION)
+		.map(|section| section.payload().to_vec())
+		.unwrap_or_default();
+	payload.extend_from_slice(pass.as_bytes());
+	payload.push(b' ');
+	payload.extend_from_slice(parameters.as_bytes());
+	payload.push(b'\n');
+	module.set_custom_section(INSTRUMENTED_SECTION, payload);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn mark_and_read() {
+		let mut module = elements::Module::default();
+		assert!(instrumentation_marks(&module).is_empty());
+
+		mark_instrumented(&mut module, "gas", "module=env");
+		mark_instrumented(&mut module, "stack_height", "");
+
+		assert_eq!(
+			instrumentation_marks(&module),
+			vec![("gas".into(), "module=env".into()), ("stack_height".into(), String::new())]
+		);
+		assert!(is_instrumented(&module, "gas"));
+		assert!(!is_instrumented(&module, "fuel"));
+	}
+}
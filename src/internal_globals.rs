@@ -0,0 +1,61 @@
+//! Registry of globals injected by instrumentation passes.
+//!
+//! Such globals hold internal state of the instrumentation (e.g. the current stack height) and
+//! must not be exposed to the outside world. They are recorded in a custom section named
+//! [`INTERNAL_GLOBALS_SECTION`] whose payload is a sequence of little endian `u32` indices in
+//! the global index space. Passes like `export_mutable_globals` skip the recorded globals.
+
+use crate::std::vec::Vec;
+
+use byteorder::{ByteOrder, LittleEndian};
+use parity_wasm::elements;
+
+/// Name of the custom section that lists internal globals.
+pub const INTERNAL_GLOBALS_SECTION: &str = "pwasm_utils.internal_globals";
+
+/// Returns indices (in the global index space) of all globals marked as internal.
+///
+/// Trailing bytes of a malformed section are ignored.
+pub fn internal_globals(module: &elements::Module) -> Vec<u32> {
+	module
+		.custom_sections()
+		.find(|section| section.name() == INTERNAL_GLOBALS_SECTION)
+		.map(|section| section.payload().chunks_exact(4).map(LittleEndian::read_u32).collect())
+		.unwrap_or_default()
+}
+
+/// Mark the global `global_idx` (in the global index space) as internal.
+pub fn mark_internal_global(module: &mut elements::Module, global_idx: u32) {
+	let mut globals = internal_globals(module);
+	if globals.contains(&global_idx) {
+		return
+	}
+	globals.push(global_idx);
+	set_internal_globals(module, globals);
+}
+
+/// Replace the globals marked as internal with `globals`.
+pub(crate) fn set_internal_globals(module: &mut elements::Module, globals: Vec<u32>) {
+	let mut payload = vec![0u8; globals.len() * 4];
+	for (idx, bytes) in globals.into_iter().zip(payload.chunks_exact_mut(4)) {
+		LittleEndian::write_u32(bytes, idx);
+	}
+	module.set_custom_section(INTERNAL_GLOBALS_SECTION, payload);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn mark_and_read() {
+		let mut module = elements::Module::default();
+		assert!(internal_globals(&module).is_empty());
+
+		mark_internal_global(&mut module, 3);
+		mark_internal_global(&mut module, 1);
+		mark_internal_global(&mut module, 3);
+
+		assert_eq!(internal_globals(&module), vec![3, 1]);
+	}
+}
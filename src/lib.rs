@@ -4,37 +4,128 @@
 #[macro_use]
 extern crate alloc;
 
+pub mod analysis;
 pub mod rules;
 
 mod build;
+mod call_counters;
+#[cfg(feature = "cli")]
+pub mod cli_args;
+#[cfg(feature = "codegen")]
+pub mod codegen;
+mod combined;
+mod data_segments;
+mod dead_code;
 #[cfg(feature = "std")]
 mod export_globals;
 mod ext;
 mod gas;
+mod gas_bound;
+pub mod gas_metering;
 mod graph;
+#[cfg(feature = "graph-json")]
+mod graph_json;
+#[cfg(feature = "hash")]
+pub mod hash;
+mod indices;
+mod instrumentation_map;
+mod instrumented;
+mod internal_globals;
+mod limits;
 #[cfg(feature = "cli")]
 pub mod logger;
+mod memory_growth;
+mod module_state;
+mod mutable_globals;
+mod normalize;
 mod optimizer;
 mod pack;
+#[cfg(feature = "parallel")]
+pub mod parallel;
+mod peephole;
 mod ref_list;
 mod runtime_type;
+mod skeleton;
+#[cfg(feature = "std")]
+mod source;
+mod stack_region;
+#[cfg(feature = "std")]
+mod streaming;
+mod strip;
+mod structure;
 mod symbols;
+mod table_limits;
 
+pub mod sections;
+pub mod stack_effect;
 pub mod stack_height;
+pub mod testing;
 
-pub use build::{build, Error as BuildError, SourceTarget};
+pub use build::{
+	build, build_with_config, normalize_memory, Config as BuildConfig, Error as BuildError,
+	MemoryFix, SourceTarget,
+};
+pub use call_counters::{
+	inject_call_counters, CallCounters, Error as CallCountersError, HostCallCount,
+};
+pub use combined::{inject_gas_and_stack_limiter, Error as CombinedError};
+pub use data_segments::unused_data_segments;
+pub use dead_code::remove_dead_code;
 #[cfg(feature = "std")]
 pub use export_globals::export_mutable_globals;
 pub use ext::{
 	externalize, externalize_mem, shrink_unknown_stack, underscore_funcs, ununderscore_funcs,
 };
-pub use gas::inject_gas_counter;
-pub use graph::{generate as graph_generate, parse as graph_parse, Module};
-pub use optimizer::{optimize, Error as OptimizerError};
-pub use pack::{pack_instance, Error as PackingError};
+pub use gas::{
+	inject_gas_counter, inject_gas_counter_with_config, inject_gas_counter_with_report,
+	inject_wasmtime_fuel, verify as verify_gas_counter, Backend as GasBackend, ChargePlacement,
+	Config as GasConfig, Error as GasError, FunctionReport as GasFunctionReport, GasImport,
+	GasPrecision, Mismatch as GasMismatch, Report as GasReport, PASS as GAS_PASS,
+};
+pub use gas_bound::{max_gas, GasBound};
+pub use graph::{
+	generate as graph_generate, parse as graph_parse, Error as GraphError, Module,
+	SectionAnchor as GraphSectionAnchor,
+};
+pub use indices::{visit_function_indices, visit_global_indices, IndexPlacement, IndexSite};
+pub use instrumentation_map::{
+	read_instrumentation_map, InjectedCode, INSTRUMENTATION_MAP_SECTION,
+};
+pub use instrumented::{
+	instrumentation_marks, is_instrumented, mark_instrumented, INSTRUMENTED_SECTION,
+};
+pub use internal_globals::{internal_globals, mark_internal_global, INTERNAL_GLOBALS_SECTION};
+pub use limits::{check_limits, LimitExceeded, Limits};
+pub use memory_growth::{max_memory_growth, MemoryGrowth};
+pub use module_state::{Error as ModuleStateError, ModuleState, Pass};
+pub use mutable_globals::{check_mutable_globals, MutableGlobalViolation, MutableGlobalsPolicy};
+pub use normalize::{normalize, Normalized};
+pub use optimizer::{
+	optimize, optimize_graph, optimize_with_config, optimize_with_trace, Config as OptimizerConfig,
+	Error as OptimizerError, ImportRetention, RemovedFunction, RemovedImport,
+	Report as OptimizerReport, Retainer,
+};
+pub use pack::{
+	pack_instance, pack_instance_with_config, Config as PackConfig, Error as PackingError,
+};
 pub use parity_wasm;
+pub use peephole::peephole;
 pub use ref_list::{DeleteTransaction, Entry, EntryRef, RefList};
-pub use runtime_type::inject_runtime_type;
+pub use runtime_type::{
+	inject_runtime_type, inject_runtime_type_with_placement, Error as RuntimeTypeError,
+};
+pub use skeleton::skeletonize;
+#[cfg(feature = "std")]
+pub use source::{cargo_target_dir, SourceInput, EMSCRIPTEN_TRIPLET, UNKNOWN_TRIPLET};
+pub use stack_region::{
+	find_shadow_stack, relocate_stack, stack_overlaps, DataOverlap, Error as StackRegionError,
+	ShadowStack, StackPointer,
+};
+#[cfg(feature = "std")]
+pub use streaming::{serialize_to_file, serialize_to_writer};
+pub use strip::strip_custom_sections;
+pub use structure::{check_structure, StructureError};
+pub use table_limits::{compact_tables, limit_tables, TableCompaction, TableLimit};
 
 pub struct TargetSymbols {
 	pub create: &'static str,
@@ -66,6 +157,13 @@ impl TargetRuntime {
 			TargetRuntime::PWasm(s) => s,
 		}
 	}
+
+	/// Module and field name under which the runtime provides the memory.
+	pub fn memory_import(&self) -> (&'static str, &'static str) {
+		match self {
+			TargetRuntime::Substrate(_) | TargetRuntime::PWasm(_) => ("env", "memory"),
+		}
+	}
 }
 
 #[cfg(not(feature = "std"))]
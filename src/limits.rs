@@ -0,0 +1,147 @@
+//! Hard limits on the size of the modules passes accept.
+//!
+//! Instrumenting a module takes time and memory proportional to its size, and the passes add
+//! functions to the index spaces. Embedders instrumenting untrusted modules set limits to reject
+//! pathological modules upfront.
+
+use crate::std::fmt;
+
+use parity_wasm::elements;
+
+/// Hard limits on the size of a module, none by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+	functions: Option<u32>,
+	body_instructions: Option<usize>,
+	total_instructions: Option<usize>,
+}
+
+impl Limits {
+	/// Limits which accept every module.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Limit the number of functions, imported and defined.
+	pub fn with_max_functions(mut self, max: u32) -> Self {
+		self.functions = Some(max);
+		self
+	}
+
+	/// Limit the number of instructions in a single function body.
+	pub fn with_max_body_instructions(mut self, max: usize) -> Self {
+		self.body_instructions = Some(max);
+		self
+	}
+
+	/// Limit the number of instructions in all function bodies together.
+	pub fn with_max_total_instructions(mut self, max: usize) -> Self {
+		self.total_instructions = Some(max);
+		self
+	}
+}
+
+/// Limit a module exceeds, see [`check_limits`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LimitExceeded {
+	/// The module has `count` functions, more than `limit`.
+	Functions { count: usize, limit: u32 },
+	/// The body of the function `func_idx` has `count` instructions, more than `limit`.
+	BodyInstructions { func_idx: u32, count: usize, limit: usize },
+	/// The function bodies have `count` instructions together, more than `limit`.
+	TotalInstructions { count: usize, limit: usize },
+}
+
+impl fmt::Display for LimitExceeded {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			LimitExceeded::Functions { count, limit } =>
+				write!(f, "Module has {} functions, the limit is {}", count, limit),
+			LimitExceeded::BodyInstructions { func_idx, count, limit } => write!(
+				f,
+				"Function {} has {} instructions, the limit is {}",
+				func_idx, count, limit
+			),
+			LimitExceeded::TotalInstructions { count, limit } =>
+				write!(f, "Module has {} instructions, the limit is {}", count, limit),
+		}
+	}
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for LimitExceeded {}
+
+/// Check that `module` stays within `limits`.
+///
+/// This is cheap compared to the passes: it only counts functions and instructions.
+pub fn check_limits(module: &elements::Module, limits: &Limits) -> Result<(), LimitExceeded> {
+	let functions = module.functions_space();
+	if let Some(limit) = limits.functions {
+		if functions > limit as usize {
+			return Err(LimitExceeded::Functions { count: functions, limit })
+		}
+	}
+
+	let func_imports = module.import_count(elements::ImportCountType::Function);
+	let bodies = module.code_section().map(|section| section.bodies()).unwrap_or(&[]);
+	let mut total = 0usize;
+	for (idx, body) in bodies.iter().enumerate() {
+		let count = body.code().elements().len();
+		if let Some(limit) = limits.body_instructions {
+			if count > limit {
+				let func_idx = (func_imports + idx) as u32;
+				return Err(LimitExceeded::BodyInstructions { func_idx, count, limit })
+			}
+		}
+		total = total.saturating_add(count);
+	}
+	if let Some(limit) = limits.total_instructions {
+		if total > limit {
+			return Err(LimitExceeded::TotalInstructions { count: total, limit })
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	#[test]
+	fn exceeded_limits() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "f" (func))
+	(func
+		(nop)
+	)
+	(func
+		(drop (i32.const 1))
+	)
+)
+"#,
+		);
+
+		assert_eq!(check_limits(&module, &Limits::new()), Ok(()));
+		assert_eq!(
+			check_limits(&module, &Limits::new().with_max_functions(2)),
+			Err(LimitExceeded::Functions { count: 3, limit: 2 })
+		);
+		assert_eq!(
+			check_limits(&module, &Limits::new().with_max_body_instructions(2)),
+			Err(LimitExceeded::BodyInstructions { func_idx: 2, count: 3, limit: 2 })
+		);
+		assert_eq!(
+			check_limits(&module, &Limits::new().with_max_total_instructions(4)),
+			Err(LimitExceeded::TotalInstructions { count: 5, limit: 4 })
+		);
+		assert_eq!(check_limits(&module, &Limits::new().with_max_total_instructions(5)), Ok(()));
+	}
+}
@@ -0,0 +1,428 @@
+//! Static estimation of the memory an exported function can request with `memory.grow`.
+
+use crate::std::{
+	cmp::min,
+	collections::{BTreeMap as Map, BTreeSet as Set},
+	fmt,
+	string::String,
+	vec::Vec,
+};
+
+use crate::stack_effect::resolve_func_type;
+use parity_wasm::elements::{self, Instruction, Internal, Type};
+
+/// Upper bound of the number of pages requested by `memory.grow`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryGrowth {
+	Pages(u32),
+	/// The operand of a `memory.grow` isn't known statically, or it may be executed an unbounded
+	/// number of times, and the memory has no maximum.
+	Unbounded,
+}
+
+impl MemoryGrowth {
+	fn add(self, other: Self) -> Self {
+		match (self, other) {
+			(MemoryGrowth::Pages(a), MemoryGrowth::Pages(b)) =>
+				a.checked_add(b).map_or(MemoryGrowth::Unbounded, MemoryGrowth::Pages),
+			_ => MemoryGrowth::Unbounded,
+		}
+	}
+
+	/// Growth of either of two alternatives.
+	fn either(self, other: Self) -> Self {
+		match (self, other) {
+			(MemoryGrowth::Pages(a), MemoryGrowth::Pages(b)) => MemoryGrowth::Pages(a.max(b)),
+			_ => MemoryGrowth::Unbounded,
+		}
+	}
+
+	/// Growth of code which may be executed an unbounded number of times.
+	fn repeated(self) -> Self {
+		match self {
+			MemoryGrowth::Pages(0) => self,
+			_ => MemoryGrowth::Unbounded,
+		}
+	}
+
+	/// Whether the growth doesn't exceed `pages`.
+	pub fn within(self, pages: u32) -> bool {
+		match self {
+			MemoryGrowth::Pages(growth) => growth <= pages,
+			MemoryGrowth::Unbounded => false,
+		}
+	}
+}
+
+impl fmt::Display for MemoryGrowth {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			MemoryGrowth::Pages(pages) => write!(f, "{} pages", pages),
+			MemoryGrowth::Unbounded => write!(f, "unbounded"),
+		}
+	}
+}
+
+/// Instruction of a function body relevant for the memory growth.
+enum Site {
+	/// `memory.grow` with the operand if it is a constant.
+	Grow(Option<u32>),
+	Call(u32),
+	CallIndirect(u32),
+}
+
+/// A function whose growth is being computed.
+struct Frame {
+	func_idx: u32,
+	/// Index of the next site.
+	site: usize,
+	/// Callees of the current call site which aren't visited yet, in reverse order.
+	callees: Vec<u32>,
+	/// Whether the current call site is within a loop, if the frame is visiting a call site.
+	in_loop: Option<bool>,
+	/// Growth of the callees of the current call site visited so far.
+	site_growth: MemoryGrowth,
+	growth: MemoryGrowth,
+}
+
+impl Frame {
+	fn add_site(&mut self, growth: MemoryGrowth, in_loop: bool) {
+		self.growth = self.growth.add(if in_loop { growth.repeated() } else { growth });
+	}
+}
+
+struct Analysis<'a> {
+	module: &'a elements::Module,
+	func_imports: u32,
+	/// Functions in the table.
+	table_members: Vec<u32>,
+	/// Sites of each defined function and whether they are within a loop.
+	sites: Vec<Vec<(Site, bool)>>,
+	growth: Map<u32, MemoryGrowth>,
+	on_stack: Set<u32>,
+}
+
+impl<'a> Analysis<'a> {
+	fn new(module: &'a elements::Module) -> Self {
+		let func_imports = module.import_count(elements::ImportCountType::Function) as u32;
+		let table_members = module
+			.elements_section()
+			.map(|section| section.entries())
+			.unwrap_or(&[])
+			.iter()
+			.flat_map(|segment| segment.members().iter().copied())
+			.collect();
+		let sites = module
+			.code_section()
+			.map(|section| section.bodies())
+			.unwrap_or(&[])
+			.iter()
+			.map(|body| function_sites(body.code().elements()))
+			.collect();
+		Analysis {
+			module,
+			func_imports,
+			table_members,
+			sites,
+			growth: Map::new(),
+			on_stack: Set::new(),
+		}
+	}
+
+	/// Functions which may be called by a `call_indirect` with the type `type_idx`.
+	fn indirect_callees(&self, type_idx: u32) -> Vec<u32> {
+		let expected = self
+			.module
+			.type_section()
+			.and_then(|section| section.types().get(type_idx as usize))
+			.map(|Type::Function(ty)| ty);
+		let mut callees = self
+			.table_members
+			.iter()
+			.copied()
+			.filter(|func_idx| match resolve_func_type(*func_idx, self.module) {
+				Ok(ty) => Some(ty) == expected,
+				Err(_) => false,
+			})
+			.collect::<Vec<_>>();
+		callees.sort_unstable();
+		callees.dedup();
+		callees
+	}
+
+	/// The growth of `func_idx` if it is known without visiting its sites, or a new frame for it.
+	fn enter(&mut self, func_idx: u32) -> Result<MemoryGrowth, Frame> {
+		if let Some(growth) = self.growth.get(&func_idx) {
+			return Ok(*growth)
+		}
+		// Imported functions can't grow the memory of the module unless it's imported too, in
+		// which case the host is responsible for the limits anyway.
+		if func_idx < self.func_imports {
+			return Ok(MemoryGrowth::Pages(0))
+		}
+		// Recursion: the functions in the cycle may be executed an unbounded number of times.
+		if self.on_stack.contains(&func_idx) {
+			return Ok(if self.may_grow(func_idx) {
+				MemoryGrowth::Unbounded
+			} else {
+				MemoryGrowth::Pages(0)
+			})
+		}
+		self.on_stack.insert(func_idx);
+		Err(Frame {
+			func_idx,
+			site: 0,
+			callees: Vec::new(),
+			in_loop: None,
+			site_growth: MemoryGrowth::Pages(0),
+			growth: MemoryGrowth::Pages(0),
+		})
+	}
+
+	/// The growth of `func_idx`, including the functions it calls.
+	///
+	/// The call graph is walked with an explicit stack, since it may be arbitrarily deep.
+	fn growth(&mut self, func_idx: u32) -> MemoryGrowth {
+		let mut frames = match self.enter(func_idx) {
+			Ok(growth) => return growth,
+			Err(frame) => vec![frame],
+		};
+		// Growth of the function which was finished last.
+		let mut returned = None;
+		while let Some(frame) = frames.last_mut() {
+			if let Some(growth) = returned.take() {
+				// Only one of the candidates of a site is called.
+				frame.site_growth = frame.site_growth.either(growth);
+			}
+			if let Some(callee) = frame.callees.pop() {
+				match self.enter(callee) {
+					Ok(growth) => returned = Some(growth),
+					Err(callee_frame) => frames.push(callee_frame),
+				}
+				continue
+			}
+			if let Some(in_loop) = frame.in_loop.take() {
+				frame.add_site(frame.site_growth, in_loop);
+			}
+
+			let defined_idx = (frame.func_idx - self.func_imports) as usize;
+			let (site, in_loop) =
+				match self.sites.get(defined_idx).and_then(|sites| sites.get(frame.site)) {
+					Some((site, in_loop)) => (site, *in_loop),
+					None => {
+						let Frame { func_idx, growth, .. } = *frame;
+						frames.pop();
+						self.on_stack.remove(&func_idx);
+						self.growth.insert(func_idx, growth);
+						returned = Some(growth);
+						continue
+					},
+				};
+			frame.site += 1;
+			let mut callees = match site {
+				Site::Grow(pages) => {
+					frame.add_site(
+						pages.map_or(MemoryGrowth::Unbounded, MemoryGrowth::Pages),
+						in_loop,
+					);
+					continue
+				},
+				Site::Call(callee) => vec![*callee],
+				Site::CallIndirect(type_idx) => self.indirect_callees(*type_idx),
+			};
+			callees.reverse();
+			frame.callees = callees;
+			frame.in_loop = Some(in_loop);
+			frame.site_growth = MemoryGrowth::Pages(0);
+		}
+		returned.expect("the outermost frame returns its growth; qed")
+	}
+
+	/// Whether any function reachable from `func_idx` contains a `memory.grow`.
+	fn may_grow(&self, func_idx: u32) -> bool {
+		let mut visited = Set::new();
+		let mut queue = vec![func_idx];
+		while let Some(func_idx) = queue.pop() {
+			if !visited.insert(func_idx) {
+				continue
+			}
+			let sites = match func_idx.checked_sub(self.func_imports) {
+				Some(defined_idx) => match self.sites.get(defined_idx as usize) {
+					Some(sites) => sites,
+					None => continue,
+				},
+				None => continue,
+			};
+			for (site, _) in sites {
+				match site {
+					Site::Grow(Some(0)) => {},
+					Site::Grow(_) => return true,
+					Site::Call(callee) => queue.push(*callee),
+					Site::CallIndirect(type_idx) => queue.extend(self.indirect_callees(*type_idx)),
+				}
+			}
+		}
+		false
+	}
+}
+
+fn function_sites(code: &[Instruction]) -> Vec<(Site, bool)> {
+	// For each open control block whether it is a loop.
+	let mut blocks = Vec::new();
+	let mut sites = Vec::new();
+	for (pos, instruction) in code.iter().enumerate() {
+		let in_loop = blocks.contains(&true);
+		match instruction {
+			Instruction::Block(_) | Instruction::If(_) => blocks.push(false),
+			Instruction::Loop(_) => blocks.push(true),
+			Instruction::End => {
+				blocks.pop();
+			},
+			Instruction::GrowMemory(_) => {
+				let pages = match pos.checked_sub(1).map(|prev| &code[prev]) {
+					Some(Instruction::I32Const(pages)) => Some(*pages as u32),
+					_ => None,
+				};
+				sites.push((Site::Grow(pages), in_loop));
+			},
+			Instruction::Call(callee) => sites.push((Site::Call(*callee), in_loop)),
+			Instruction::CallIndirect(type_idx, _) =>
+				sites.push((Site::CallIndirect(*type_idx), in_loop)),
+			_ => {},
+		}
+	}
+	sites
+}
+
+/// Estimate how many pages each exported function can request with `memory.grow` in a single
+/// invocation.
+///
+/// The estimate covers all functions reachable by calls, assuming a `call_indirect` may call any
+/// function of a matching type in the table. A `memory.grow` counts with its operand if it is a
+/// constant, and makes the growth unbounded otherwise, as does a `memory.grow` that may be executed
+/// repeatedly because of a loop or recursion. If the memory has a maximum, the estimate is capped
+/// by the pages that can be added to the initial memory.
+///
+/// Returns the estimate for each exported function by export name, in export order.
+pub fn max_memory_growth(module: &elements::Module) -> Vec<(String, MemoryGrowth)> {
+	let limit = module
+		.memory_section()
+		.and_then(|section| section.entries().first().map(|memory| *memory.limits()))
+		.or_else(|| {
+			module
+				.import_section()?
+				.entries()
+				.iter()
+				.find_map(|entry| match entry.external() {
+					elements::External::Memory(memory) => Some(*memory.limits()),
+					_ => None,
+				})
+		})
+		.and_then(|limits| Some(limits.maximum()?.saturating_sub(limits.initial())));
+
+	let mut analysis = Analysis::new(module);
+	module
+		.export_section()
+		.map(|section| section.entries())
+		.unwrap_or(&[])
+		.iter()
+		.filter_map(|entry| match *entry.internal() {
+			Internal::Function(func_idx) => Some((entry.field(), func_idx)),
+			_ => None,
+		})
+		.map(|(field, func_idx)| {
+			let growth = match (analysis.growth(func_idx), limit) {
+				(MemoryGrowth::Pages(pages), Some(limit)) => MemoryGrowth::Pages(min(pages, limit)),
+				(MemoryGrowth::Unbounded, Some(limit)) => MemoryGrowth::Pages(limit),
+				(growth, None) => growth,
+			};
+			(field.into(), growth)
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testing::module_fixture;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	#[test]
+	fn growth_per_export() {
+		let module = parse_wat(
+			r#"
+(module
+	(memory 1)
+	(table 1 funcref)
+	(elem (i32.const 0) $grow_two)
+	(func $grow_two
+		(drop (memory.grow (i32.const 2)))
+	)
+	(func (export "direct")
+		(drop (memory.grow (i32.const 1)))
+		(call $grow_two)
+	)
+	(func (export "indirect")
+		(call_indirect (i32.const 0))
+	)
+	(func (export "dynamic") (param i32)
+		(drop (memory.grow (local.get 0)))
+	)
+	(func (export "looping")
+		(loop
+			(call $grow_two)
+		)
+	)
+	(func $recursive (export "recursive")
+		(call $grow_two)
+		(call $recursive)
+	)
+	(func (export "none")
+		(loop
+			(nop)
+		)
+	)
+)
+"#,
+		);
+
+		assert_eq!(
+			max_memory_growth(&module),
+			vec![
+				("direct".into(), MemoryGrowth::Pages(3)),
+				("indirect".into(), MemoryGrowth::Pages(2)),
+				("dynamic".into(), MemoryGrowth::Unbounded),
+				("looping".into(), MemoryGrowth::Unbounded),
+				("recursive".into(), MemoryGrowth::Unbounded),
+				("none".into(), MemoryGrowth::Pages(0)),
+			]
+		);
+	}
+
+	#[test]
+	fn capped_by_maximum() {
+		let module = parse_wat(
+			r#"
+(module
+	(memory 1 4)
+	(func (export "dynamic") (param i32)
+		(drop (memory.grow (local.get 0)))
+	)
+)
+"#,
+		);
+
+		assert_eq!(max_memory_growth(&module), vec![("dynamic".into(), MemoryGrowth::Pages(3))]);
+	}
+
+	#[test]
+	fn deep_call_chain() {
+		let module = module_fixture().with_call_chain(200_000).with_export("call", 0).build();
+
+		assert_eq!(max_memory_growth(&module), vec![("call".into(), MemoryGrowth::Pages(0))]);
+	}
+}
@@ -0,0 +1,231 @@
+//! Applying the passes to a module in a valid order.
+
+use crate::std::{fmt, vec::Vec};
+
+use crate::{
+	gas, optimize, pack_instance, rules::Rules, stack_height, OptimizerError, PackingError,
+	TargetRuntime,
+};
+use parity_wasm::elements;
+
+/// Pass applied to a module by [`ModuleState`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pass {
+	Optimize,
+	Gas,
+	StackHeight,
+	Pack,
+}
+
+impl fmt::Display for Pass {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			Pass::Optimize => write!(f, "optimization"),
+			Pass::Gas => write!(f, "gas metering"),
+			Pass::StackHeight => write!(f, "stack height limiting"),
+			Pass::Pack => write!(f, "packing"),
+		}
+	}
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+	/// The pass was already applied.
+	Repeated(Pass),
+	/// `pass` can't be applied after `applied`.
+	Order {
+		pass: Pass,
+		applied: Pass,
+	},
+	/// The code module packed into a constructor lacks an instrumentation the constructor has.
+	Uninstrumented(Pass),
+	Encoding(elements::Error),
+	Optimizer(OptimizerError),
+	Gas(gas::Error),
+	StackHeight(stack_height::Error),
+	Packing(PackingError),
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			Error::Repeated(pass) => write!(f, "The module was already processed by {}", pass),
+			Error::Order { pass, applied } =>
+				write!(f, "The module can't be processed by {} after {}", pass, applied),
+			Error::Uninstrumented(pass) =>
+				write!(f, "The packed code module lacks the {} of the constructor", pass),
+			Error::Encoding(err) => write!(f, "Encoding error ({})", err),
+			Error::Optimizer(err) => write!(f, "Optimization failed: {}", err),
+			Error::Gas(err) => write!(f, "Gas metering failed: {}", err),
+			Error::StackHeight(err) => write!(f, "Stack height limiting failed: {}", err),
+			Error::Packing(err) => write!(f, "Packing failed: {}", err),
+		}
+	}
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			Error::Encoding(err) => Some(err),
+			Error::Optimizer(err) => Some(err),
+			Error::Gas(err) => Some(err),
+			Error::StackHeight(err) => Some(err),
+			Error::Packing(err) => Some(err),
+			Error::Repeated(_) | Error::Order { .. } | Error::Uninstrumented(_) => None,
+		}
+	}
+}
+
+/// A module along with the passes applied to it.
+///
+/// The passes are rejected if applied in an order producing a broken module:
+///
+/// - no pass is applied twice, as this would meter or limit the instrumentation itself;
+/// - no pass follows packing, since it would miss the code module embedded in the constructor;
+/// - optimization precedes the instrumentations, which it may break by removing their exports;
+/// - gas metering precedes stack height limiting, so the limiter isn't metered.
+#[derive(Debug)]
+pub struct ModuleState {
+	module: elements::Module,
+	applied: Vec<Pass>,
+}
+
+impl ModuleState {
+	/// State of a module no pass was applied to.
+	pub fn new(module: elements::Module) -> Self {
+		ModuleState { module, applied: Vec::new() }
+	}
+
+	pub fn module(&self) -> &elements::Module {
+		&self.module
+	}
+
+	pub fn into_module(self) -> elements::Module {
+		self.module
+	}
+
+	/// The passes applied so far, in order.
+	pub fn applied(&self) -> &[Pass] {
+		&self.applied
+	}
+
+	/// See [`crate::optimize`].
+	pub fn optimize(mut self, used_exports: Vec<&str>) -> Result<Self, Error> {
+		self.check(Pass::Optimize, &[Pass::Gas, Pass::StackHeight, Pass::Pack])?;
+		optimize(&mut self.module, used_exports).map_err(Error::Optimizer)?;
+		self.applied.push(Pass::Optimize);
+		Ok(self)
+	}
+
+	/// See [`crate::inject_gas_counter_with_config`].
+	pub fn inject_gas_counter<R: Rules>(
+		mut self,
+		rules: &R,
+		config: &gas::Config,
+	) -> Result<Self, Error> {
+		self.check(Pass::Gas, &[Pass::StackHeight, Pass::Pack])?;
+		self.module =
+			gas::inject_gas_counter_with_config(self.module, rules, config).map_err(Error::Gas)?;
+		self.applied.push(Pass::Gas);
+		Ok(self)
+	}
+
+	/// See [`stack_height::inject_limiter_with_config`].
+	pub fn inject_stack_limiter(mut self, config: &stack_height::Config) -> Result<Self, Error> {
+		self.check(Pass::StackHeight, &[Pass::Pack])?;
+		self.module = stack_height::inject_limiter_with_config(self.module, config)
+			.map_err(Error::StackHeight)?;
+		self.applied.push(Pass::StackHeight);
+		Ok(self)
+	}
+
+	/// Pack `code` into this module, the constructor, see [`crate::pack_instance`].
+	///
+	/// The code module must have been instrumented at least like the constructor.
+	pub fn pack(mut self, code: ModuleState, target: &TargetRuntime) -> Result<Self, Error> {
+		self.check(Pass::Pack, &[])?;
+		for pass in [Pass::Gas, Pass::StackHeight] {
+			if self.applied.contains(&pass) && !code.applied.contains(&pass) {
+				return Err(Error::Uninstrumented(pass))
+			}
+		}
+		let raw_module = parity_wasm::serialize(code.module).map_err(Error::Encoding)?;
+		self.module = pack_instance(raw_module, self.module, target).map_err(Error::Packing)?;
+		self.applied.push(Pass::Pack);
+		Ok(self)
+	}
+
+	/// Check that `pass` can be applied, given that it must not follow any of `successors`.
+	fn check(&self, pass: Pass, successors: &[Pass]) -> Result<(), Error> {
+		if self.applied.contains(&pass) {
+			return Err(Error::Repeated(pass))
+		}
+		match self.applied.iter().rev().find(|applied| successors.contains(applied)) {
+			Some(applied) => Err(Error::Order { pass, applied: *applied }),
+			None => Ok(()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{rules, testing::module_fixture};
+
+	#[test]
+	fn rejects_invalid_orders() {
+		let target = TargetRuntime::pwasm();
+		let module = || {
+			module_fixture()
+				.with_imported_memory()
+				.with_functions(3)
+				.with_export(target.symbols().call, 1)
+				.with_export(target.symbols().create, 2)
+				.build()
+		};
+		let rules = rules::Set::default();
+		let gas = gas::Config::new("env");
+		let stack = stack_height::Config::new(1024);
+
+		let code = ModuleState::new(module())
+			.inject_gas_counter(&rules, &gas)
+			.unwrap()
+			.inject_stack_limiter(&stack)
+			.unwrap();
+		assert_eq!(code.applied(), &[Pass::Gas, Pass::StackHeight]);
+
+		assert!(matches!(
+			ModuleState::new(module())
+				.inject_stack_limiter(&stack)
+				.unwrap()
+				.inject_gas_counter(&rules, &gas),
+			Err(Error::Order { pass: Pass::Gas, applied: Pass::StackHeight })
+		));
+		assert!(matches!(
+			ModuleState::new(module())
+				.inject_gas_counter(&rules, &gas)
+				.unwrap()
+				.inject_gas_counter(&rules, &gas),
+			Err(Error::Repeated(Pass::Gas))
+		));
+		assert!(matches!(
+			ModuleState::new(module())
+				.inject_gas_counter(&rules, &gas)
+				.unwrap()
+				.pack(ModuleState::new(module()), &target),
+			Err(Error::Uninstrumented(Pass::Gas))
+		));
+
+		let ctor = ModuleState::new(module())
+			.inject_gas_counter(&rules, &gas)
+			.unwrap()
+			.pack(code, &target)
+			.unwrap();
+		assert!(matches!(
+			ctor.optimize(vec![target.symbols().call]),
+			Err(Error::Order { pass: Pass::Optimize, applied: Pass::Pack })
+		));
+	}
+}
@@ -0,0 +1,180 @@
+use crate::std::{fmt, string::String, vec::Vec};
+
+use parity_wasm::elements;
+
+/// Which mutable globals a module may share with the embedder.
+///
+/// Importing and exporting mutable globals requires the mutable globals proposal. Runtimes not
+/// supporting it can use [`check_mutable_globals`] with an empty policy to reject such modules
+/// early. Modules processed by [`crate::export_mutable_globals`] can be checked to export exactly
+/// the expected globals by allowing the prefix that was used.
+#[derive(Debug, Default, Clone)]
+pub struct MutableGlobalsPolicy {
+	imports: Vec<(String, String)>,
+	exports: Vec<String>,
+	export_prefixes: Vec<String>,
+}
+
+impl MutableGlobalsPolicy {
+	/// Policy allowing no mutable global imports and exports.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Allow importing the mutable global `field` from `module`.
+	pub fn with_import(mut self, module: &str, field: &str) -> Self {
+		self.imports.push((module.into(), field.into()));
+		self
+	}
+
+	/// Allow exporting a mutable global as `field`.
+	pub fn with_export(mut self, field: &str) -> Self {
+		self.exports.push(field.into());
+		self
+	}
+
+	/// Allow exporting mutable globals under the names generated by
+	/// [`crate::export_mutable_globals`] for `prefix`.
+	pub fn with_export_prefix(mut self, prefix: &str) -> Self {
+		self.export_prefixes.push(prefix.into());
+		self
+	}
+
+	fn allows_import(&self, module: &str, field: &str) -> bool {
+		self.imports.iter().any(|(m, f)| m == module && f == field)
+	}
+
+	fn allows_export(&self, field: &str) -> bool {
+		self.exports.iter().any(|f| f == field) ||
+			self.export_prefixes.iter().any(|prefix| {
+				field
+					.strip_prefix(prefix.as_str())
+					.and_then(|rest| rest.strip_prefix('_'))
+					.map_or(false, |index| {
+						!index.is_empty() && index.bytes().all(|b| b.is_ascii_digit())
+					})
+			})
+	}
+}
+
+/// A mutable global shared with the embedder against the policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MutableGlobalViolation {
+	/// The mutable global `field` is imported from `module`.
+	Import { module: String, field: String },
+	/// A mutable global is exported as `field`.
+	Export { field: String },
+}
+
+impl fmt::Display for MutableGlobalViolation {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			MutableGlobalViolation::Import { module, field } =>
+				write!(f, "Mutable global `{}::{}` is imported", module, field),
+			MutableGlobalViolation::Export { field } =>
+				write!(f, "Mutable global is exported as `{}`", field),
+		}
+	}
+}
+
+/// Check that the module only imports and exports the mutable globals allowed by `policy`.
+pub fn check_mutable_globals(
+	module: &elements::Module,
+	policy: &MutableGlobalsPolicy,
+) -> Result<(), Vec<MutableGlobalViolation>> {
+	let mut violations = Vec::new();
+
+	// Mutability of all globals in the global index space.
+	let mut mutability = Vec::new();
+	for entry in module.import_section().map(|s| s.entries()).unwrap_or(&[]) {
+		if let elements::External::Global(global_type) = entry.external() {
+			mutability.push(global_type.is_mutable());
+			if global_type.is_mutable() && !policy.allows_import(entry.module(), entry.field()) {
+				violations.push(MutableGlobalViolation::Import {
+					module: entry.module().into(),
+					field: entry.field().into(),
+				});
+			}
+		}
+	}
+	for entry in module.global_section().map(|s| s.entries()).unwrap_or(&[]) {
+		mutability.push(entry.global_type().is_mutable());
+	}
+
+	for entry in module.export_section().map(|s| s.entries()).unwrap_or(&[]) {
+		if let elements::Internal::Global(global_idx) = *entry.internal() {
+			let is_mutable = mutability.get(global_idx as usize).copied().unwrap_or(false);
+			if is_mutable && !policy.allows_export(entry.field()) {
+				violations.push(MutableGlobalViolation::Export { field: entry.field().into() });
+			}
+		}
+	}
+
+	if violations.is_empty() {
+		Ok(())
+	} else {
+		Err(violations)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use parity_wasm::builder;
+
+	fn module() -> elements::Module {
+		builder::module()
+			.import()
+			.module("env")
+			.field("counter")
+			.external()
+			.global(elements::ValueType::I32, true)
+			.build()
+			.global()
+			.value_type()
+			.i32()
+			.mutable()
+			.init_expr(elements::Instruction::I32Const(0))
+			.build()
+			.global()
+			.value_type()
+			.i32()
+			.init_expr(elements::Instruction::I32Const(0))
+			.build()
+			.export()
+			.field("exported_global_0")
+			.internal()
+			.global(1)
+			.build()
+			.export()
+			.field("constant")
+			.internal()
+			.global(2)
+			.build()
+			.build()
+	}
+
+	#[test]
+	fn rejects_by_default() {
+		assert_eq!(
+			check_mutable_globals(&module(), &MutableGlobalsPolicy::new()),
+			Err(vec![
+				MutableGlobalViolation::Import { module: "env".into(), field: "counter".into() },
+				MutableGlobalViolation::Export { field: "exported_global_0".into() },
+			])
+		);
+	}
+
+	#[test]
+	fn whitelisted() {
+		let policy = MutableGlobalsPolicy::new()
+			.with_import("env", "counter")
+			.with_export_prefix("exported_global");
+		assert_eq!(check_mutable_globals(&module(), &policy), Ok(()));
+
+		let policy = MutableGlobalsPolicy::new()
+			.with_import("env", "counter")
+			.with_export_prefix("exported");
+		assert!(check_mutable_globals(&module(), &policy).is_err());
+	}
+}
@@ -0,0 +1,82 @@
+//! Canonical byte form of a module.
+//!
+//! The binary format allows integers to be encoded with more LEB128 bytes than necessary, e.g. `0`
+//! as `0x80 0x00`, so the same module has several byte forms. parity-wasm accepts the padded
+//! encodings up to the maximal length of the integer type and always writes the minimal one, so
+//! decoding and re-encoding a module yields its canonical form.
+
+use crate::std::vec::Vec;
+
+use parity_wasm::elements;
+
+/// Canonical form of a module, see [`normalize`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Normalized {
+	/// The module with all integers encoded minimally.
+	pub bytes: Vec<u8>,
+	/// Offset of the first byte of the input which differs from the canonical form, `None` if the
+	/// input is canonical.
+	pub first_difference: Option<usize>,
+}
+
+/// Re-encode the module `bytes` with all integers encoded minimally, and report whether it was
+/// canonical already.
+///
+/// Code hashes and deterministic builds should work on the canonical form. Callers requiring
+/// canonical input reject it if [`Normalized::first_difference`] is set. The payloads of custom
+/// sections, including the name section, are kept as they are.
+///
+/// Fails if the module can't be decoded.
+pub fn normalize(bytes: &[u8]) -> Result<Normalized, elements::Error> {
+	let module: elements::Module = elements::deserialize_buffer(bytes)?;
+	let normalized = elements::serialize(module)?;
+	let first_difference =
+		bytes.iter().zip(&normalized).position(|(a, b)| a != b).or_else(|| {
+			(bytes.len() != normalized.len()).then(|| bytes.len().min(normalized.len()))
+		});
+	Ok(Normalized { bytes: normalized, first_difference })
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn padded_integers() {
+		let canonical = wabt::wat2wasm(
+			r#"
+(module
+	(func (export "call") (result i32)
+		(i32.const 1)
+	)
+)
+"#,
+		)
+		.expect("Failed to wat2wasm");
+		assert_eq!(
+			normalize(&canonical).unwrap(),
+			Normalized { bytes: canonical.clone(), first_difference: None }
+		);
+
+		// Pad the count of the types to 5 bytes, and the size of the type section along with it.
+		// The type section follows the magic number and the version.
+		let type_section = 8;
+		assert_eq!(canonical[type_section], 1);
+		let mut padded = canonical[..type_section + 1].to_vec();
+		padded.push(canonical[type_section + 1] + 4);
+		padded.extend(&[0x81, 0x80, 0x80, 0x80, 0x00]);
+		padded.extend(&canonical[type_section + 3..]);
+
+		let normalized = normalize(&padded).unwrap();
+		assert_eq!(normalized.bytes, canonical);
+		assert_eq!(normalized.first_difference, Some(type_section + 1));
+	}
+
+	#[test]
+	fn oversized_integer() {
+		let mut bytes = b"\0asm\x01\0\0\0".to_vec();
+		// A type section with a count taking 6 bytes.
+		bytes.extend(&[0x01, 0x06, 0x80, 0x80, 0x80, 0x80, 0x80, 0x00]);
+		assert!(normalize(&bytes).is_err());
+	}
+}
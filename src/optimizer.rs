@@ -2,34 +2,91 @@
 use crate::std::collections::BTreeSet as Set;
 #[cfg(features = "std")]
 use crate::std::collections::HashSet as Set;
-use crate::std::{mem, vec::Vec};
+use crate::std::{fmt, mem, string::String, vec::Vec};
 
-use crate::symbols::{expand_symbols, push_code_symbols, resolve_function, Symbol};
+use crate::{
+	graph,
+	ref_list::EntryRef,
+	sections::data_section_mut,
+	symbols::{resolve_function, resolve_memory, resolve_table, retention_parents, Symbol},
+	unused_data_segments,
+};
 use log::trace;
 use parity_wasm::elements;
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
 	/// Since optimizer starts with export entries, export
 	///   section is supposed to exist.
 	NoExportSection,
+	/// The module refers to entities it doesn't have.
+	Graph(graph::Error),
 }
 
-pub fn optimize(
-	module: &mut elements::Module, // Module to optimize
-	used_exports: Vec<&str>,       // List of only exports that will be usable after optimization
-) -> Result<(), Error> {
-	// WebAssembly exports optimizer
-	// Motivation: emscripten compiler backend compiles in many unused exports
-	//   which in turn compile in unused imports and leaves unused functions
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			Error::NoExportSection => write!(f, "No export section in the module"),
+			Error::Graph(err) => write!(f, "Inconsistent module: {:?}", err),
+		}
+	}
+}
 
-	// try to parse name section
-	let module_temp = mem::take(module);
-	let module_temp = module_temp.parse_names().unwrap_or_else(|(_err, module)| module);
-	*module = module_temp;
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
 
-	// Algo starts from the top, listing all items that should stay
-	let mut stay = Set::new();
+/// Prefixes of the entries of the keep-list only matching exports of a single kind.
+const EXPORT_KINDS: [&str; 4] = ["func", "global", "memory", "table"];
+
+/// Whether the export `name` of the given kind, one of [`EXPORT_KINDS`], matches one of the
+/// patterns in `used_exports`.
+fn is_used(used_exports: &[&str], name: &str, kind: &str) -> bool {
+	used_exports.iter().any(|pattern| {
+		let (pattern_kind, pattern) = match pattern.split_once(':') {
+			Some((pattern_kind, rest)) if EXPORT_KINDS.contains(&pattern_kind) =>
+				(Some(pattern_kind), rest),
+			_ => (None, *pattern),
+		};
+		pattern_kind.map_or(true, |pattern_kind| pattern_kind == kind) && glob_match(pattern, name)
+	})
+}
+
+/// Match `name` against `pattern`, where `*` matches any sequence of characters and `?` matches
+/// any single character. All other characters match themselves.
+pub(crate) fn glob_match(pattern: &str, name: &str) -> bool {
+	let pattern = pattern.chars().collect::<Vec<_>>();
+	let name = name.chars().collect::<Vec<_>>();
+	let (mut p, mut n) = (0, 0);
+	// Position of the last `*` in the pattern and of the character of the name it matched up to.
+	let mut backtrack = None;
+	while n < name.len() {
+		match pattern.get(p) {
+			Some('*') => {
+				backtrack = Some((p, n));
+				p += 1;
+			},
+			Some(c) if *c == '?' || *c == name[n] => {
+				p += 1;
+				n += 1;
+			},
+			// Let the last `*` match one more character.
+			_ => match backtrack {
+				Some((star, matched)) => {
+					backtrack = Some((star, matched + 1));
+					p = star + 1;
+					n = matched + 1;
+				},
+				None => return false,
+			},
+		}
+	}
+	pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Symbols kept by the optimizer regardless of their uses.
+fn roots(module: &elements::Module, used_exports: &[&str]) -> Result<Vec<Symbol>, Error> {
+	let mut roots = Vec::new();
 	for (index, entry) in module
 		.export_section()
 		.ok_or(Error::NoExportSection)?
@@ -37,479 +94,431 @@ pub fn optimize(
 		.iter()
 		.enumerate()
 	{
-		if used_exports.iter().any(|e| *e == entry.field()) {
-			stay.insert(Symbol::Export(index));
+		let kind = match entry.internal() {
+			elements::Internal::Function(_) => "func",
+			elements::Internal::Global(_) => "global",
+			elements::Internal::Memory(_) => "memory",
+			elements::Internal::Table(_) => "table",
+		};
+		if is_used(used_exports, entry.field(), kind) {
+			roots.push(Symbol::Export(index));
 		}
 	}
 
 	// If there is start function in module, it should stary
-	module.start_section().map(|ss| stay.insert(resolve_function(module, ss)));
-
-	// All symbols used in data/element segments are also should be preserved
-	let mut init_symbols = Vec::new();
-	if let Some(data_section) = module.data_section() {
-		for segment in data_section.entries() {
-			push_code_symbols(
-				module,
-				segment
-					.offset()
-					.as_ref()
-					.expect("parity-wasm is compiled without bulk-memory operations")
-					.code(),
-				&mut init_symbols,
-			);
-		}
+	roots.extend(module.start_section().map(|ss| resolve_function(module, ss)));
+
+	// Tables and memories are never eliminated, and so are all data/element segments.
+	// Make them roots, so that all symbols used by the segments are preserved as well.
+	for table_idx in 0..module.table_space() {
+		roots.push(resolve_table(module, table_idx as u32));
 	}
-	if let Some(elements_section) = module.elements_section() {
-		for segment in elements_section.entries() {
-			push_code_symbols(
-				module,
-				segment
-					.offset()
-					.as_ref()
-					.expect("parity-wasm is compiled without bulk-memory operations")
-					.code(),
-				&mut init_symbols,
-			);
-			for func_index in segment.members() {
-				stay.insert(resolve_function(module, *func_index));
-			}
-		}
+	for memory_idx in 0..module.memory_space() {
+		roots.push(resolve_memory(module, memory_idx as u32));
 	}
-	for symbol in init_symbols.drain(..) {
-		stay.insert(symbol);
+	Ok(roots)
+}
+
+/// Item keeping an import alive, see [`optimize_with_trace`].
+///
+/// Indices refer to the index spaces of the module before optimization.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Retainer {
+	Export(String),
+	/// The start function.
+	Start,
+	Import {
+		module: String,
+		field: String,
+	},
+	Function(u32),
+	Global(u32),
+	/// A table, which is never eliminated, and its element segments.
+	Table(u32),
+	/// A memory, which is never eliminated, and its data segments.
+	Memory(u32),
+}
+
+/// Why an import survived optimization, see [`optimize_with_trace`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportRetention {
+	pub module: String,
+	pub field: String,
+	/// Shortest chain of references keeping the import, starting with a kept export, the start
+	/// function, or a table or memory. Each item refers to the next one, and the last one to the
+	/// import.
+	///
+	/// Empty for imported tables and memories, which are kept by themselves.
+	pub path: Vec<Retainer>,
+}
+
+impl Retainer {
+	fn of(module: &elements::Module, symbol: Symbol) -> Option<Self> {
+		let imports = |kind| module.import_count(kind) as u32;
+		Some(match symbol {
+			Symbol::Export(idx) => Retainer::Export(
+				module.export_section().expect("exports are roots; qed").entries()[idx]
+					.field()
+					.into(),
+			),
+			Symbol::Import(idx) => {
+				let entry =
+					&module.import_section().expect("imports are resolved; qed").entries()[idx];
+				Retainer::Import { module: entry.module().into(), field: entry.field().into() }
+			},
+			Symbol::Function(idx) =>
+				Retainer::Function(imports(elements::ImportCountType::Function) + idx as u32),
+			Symbol::Global(idx) =>
+				Retainer::Global(imports(elements::ImportCountType::Global) + idx as u32),
+			Symbol::Table(idx) =>
+				Retainer::Table(imports(elements::ImportCountType::Table) + idx as u32),
+			Symbol::Memory(idx) =>
+				Retainer::Memory(imports(elements::ImportCountType::Memory) + idx as u32),
+			Symbol::Type(_) => return None,
+		})
 	}
+}
 
-	// Call function which will traverse the list recursively, filling stay with all symbols
-	// that are already used by those which already there
-	expand_symbols(module, &mut stay);
+/// Like [`optimize`], but report for every import which survives why it is kept.
+///
+/// Security reviewers can use this to justify each host capability the module retains. The
+/// retentions are in the order of the imports.
+pub fn optimize_with_trace(
+	module: &mut elements::Module,
+	used_exports: Vec<&str>,
+) -> Result<Vec<ImportRetention>, Error> {
+	let roots = roots(module, &used_exports)?;
+	let parents = retention_parents(module, &roots);
+	let start = module.start_section().map(|start| resolve_function(module, start));
 
-	for symbol in stay.iter() {
-		trace!("symbol to stay: {:?}", symbol);
+	let mut retentions = Vec::new();
+	let imports = module.import_section().map(|section| section.entries()).unwrap_or(&[]);
+	for (idx, entry) in imports.iter().enumerate() {
+		let mut symbol = Symbol::Import(idx);
+		if !roots.contains(&symbol) && !parents.contains_key(&symbol) {
+			continue
+		}
+		let mut path = Vec::new();
+		while let Some(parent) = parents.get(&symbol) {
+			path.extend(Retainer::of(module, *parent));
+			symbol = *parent;
+		}
+		// `symbol` is the root keeping the import now.
+		if Some(symbol) == start {
+			path.push(Retainer::Start);
+		}
+		path.reverse();
+		retentions.push(ImportRetention {
+			module: entry.module().into(),
+			field: entry.field().into(),
+			path,
+		});
 	}
 
-	// Keep track of referreable symbols to rewire calls/globals
-	let mut eliminated_funcs = Vec::new();
-	let mut eliminated_globals = Vec::new();
-	let mut eliminated_types = Vec::new();
+	optimize(module, used_exports)?;
+	Ok(retentions)
+}
 
-	// First, iterate through types
-	let mut index = 0;
-	let mut old_index = 0;
+/// Function removed by [`optimize`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RemovedFunction {
+	/// Index of the function in the function space before optimization.
+	pub func_idx: u32,
+	/// Name of the function in the name section, if any.
+	pub name: Option<String>,
+}
 
-	{
-		loop {
-			if type_section(module).map(|section| section.types_mut().len()).unwrap_or(0) == index {
-				break
-			}
+/// Import removed by [`optimize`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RemovedImport {
+	pub module: String,
+	pub field: String,
+}
 
-			if stay.contains(&Symbol::Type(old_index)) {
-				index += 1;
-			} else {
-				type_section(module)
-					.expect("If type section does not exists, the loop will break at the beginning of first iteration")
-					.types_mut().remove(index);
-				eliminated_types.push(old_index);
-				trace!("Eliminated type({})", old_index);
-			}
-			old_index += 1;
-		}
+/// Entries removed by [`optimize`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Report {
+	/// Removed functions defined by the module, in order.
+	pub functions: Vec<RemovedFunction>,
+	/// Removed imports, the functions in order, then the globals in order.
+	pub imports: Vec<RemovedImport>,
+	/// Indices of the removed globals defined by the module in the global space before
+	/// optimization, in ascending order.
+	pub globals: Vec<u32>,
+	/// Indices of the removed types, in ascending order.
+	pub types: Vec<u32>,
+	/// Indices of the removed data segments, in ascending order, see
+	/// [`Config::with_unused_data_elimination`].
+	pub data_segments: Vec<usize>,
+	/// Number of bytes the encoded module shrank by.
+	pub size_reduction: usize,
+}
+
+/// Configuration of [`optimize_with_config`].
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+	drop_unused_data: bool,
+}
+
+impl Config {
+	/// Configuration optimizing like [`optimize`].
+	pub fn new() -> Self {
+		Self::default()
 	}
 
-	// Second, iterate through imports
-	let mut top_funcs = 0;
-	let mut top_globals = 0;
-	index = 0;
-	old_index = 0;
-
-	if let Some(imports) = import_section(module) {
-		loop {
-			let mut remove = false;
-			match imports.entries()[index].external() {
-				elements::External::Function(_) => {
-					if stay.contains(&Symbol::Import(old_index)) {
-						index += 1;
-					} else {
-						remove = true;
-						eliminated_funcs.push(top_funcs);
-						trace!(
-							"Eliminated import({}) func({}, {})",
-							old_index,
-							top_funcs,
-							imports.entries()[index].field()
-						);
-					}
-					top_funcs += 1;
-				},
-				elements::External::Global(_) => {
-					if stay.contains(&Symbol::Import(old_index)) {
-						index += 1;
-					} else {
-						remove = true;
-						eliminated_globals.push(top_globals);
-						trace!(
-							"Eliminated import({}) global({}, {})",
-							old_index,
-							top_globals,
-							imports.entries()[index].field()
-						);
-					}
-					top_globals += 1;
-				},
-				_ => {
-					index += 1;
-				},
-			}
-			if remove {
-				imports.entries_mut().remove(index);
-			}
+	/// Also remove the data segments no address known to the optimized module points into, see
+	/// [`crate::unused_data_segments`] for the assumptions this relies on.
+	pub fn with_unused_data_elimination(mut self) -> Self {
+		self.drop_unused_data = true;
+		self
+	}
+}
 
-			old_index += 1;
+/// Remove the exports not in `used_exports` and every function, global and type only they need.
+///
+/// The entries of `used_exports` are glob patterns: `*` matches any sequence of characters and `?`
+/// any single character, so `seal_*` keeps all exports starting with `seal_`. A pattern prefixed
+/// with `func:`, `global:`, `memory:` or `table:` only keeps exports of that kind, e.g. `memory:*`
+/// keeps all exported memories. Everything a kept export refers to is kept as well, like the
+/// imported globals the initializer of an exported global reads.
+///
+/// The tables and memories are kept, and so are the start function and all segments with what
+/// they need. The name section is updated, other custom sections are dropped.
+///
+/// Returns what was removed. Fails if the module has no export section.
+pub fn optimize(
+	module: &mut elements::Module, // Module to optimize
+	used_exports: Vec<&str>,       // Patterns of the only exports usable after optimization
+) -> Result<Report, Error> {
+	optimize_with_config(module, used_exports, &Config::default())
+}
 
-			if index == imports.entries().len() {
-				break
-			}
-		}
+/// Same as [`optimize`], but with the given configuration.
+pub fn optimize_with_config(
+	module: &mut elements::Module,
+	used_exports: Vec<&str>,
+	config: &Config,
+) -> Result<Report, Error> {
+	// WebAssembly exports optimizer
+	// Motivation: emscripten compiler backend compiles in many unused exports
+	//   which in turn compile in unused imports and leaves unused functions
+
+	if module.export_section().is_none() {
+		return Err(Error::NoExportSection)
 	}
 
-	// Third, iterate through globals
-	if let Some(globals) = global_section(module) {
-		index = 0;
-		old_index = 0;
+	let module_size = |module: &elements::Module| {
+		parity_wasm::serialize(module.clone()).map_or(0, |bytes| bytes.len())
+	};
+	let original_size = module_size(module);
 
-		loop {
-			if globals.entries_mut().len() == index {
-				break
-			}
-			if stay.contains(&Symbol::Global(old_index)) {
-				index += 1;
-			} else {
-				globals.entries_mut().remove(index);
-				eliminated_globals.push(top_globals + old_index);
-				trace!("Eliminated global({})", top_globals + old_index);
-			}
-			old_index += 1;
+	// try to parse name section
+	let module_temp = mem::take(module);
+	let module_temp = module_temp.parse_names().unwrap_or_else(|(_err, module)| module);
+	*module = module_temp;
+
+	let mut graph = graph::Module::from_elements(module).map_err(Error::Graph)?;
+	// The entries by their original index, to rewire the names and report the removed ones.
+	let funcs = graph.funcs.iter().cloned().collect::<Vec<_>>();
+	let globals = graph.globals.iter().cloned().collect::<Vec<_>>();
+	let types = graph.types.iter().cloned().collect::<Vec<_>>();
+	let func_names = module.names_section().and_then(|section| section.functions());
+
+	optimize_graph(&mut graph, &used_exports);
+
+	let mut report = Report::default();
+	for (func_idx, func) in funcs.iter().enumerate().filter(|(_, func)| func.order().is_none()) {
+		match &func.read().origin {
+			graph::ImportedOrDeclared::Imported(module, field) => report
+				.imports
+				.push(RemovedImport { module: module.clone(), field: field.clone() }),
+			graph::ImportedOrDeclared::Declared(_) => report.functions.push(RemovedFunction {
+				func_idx: func_idx as u32,
+				name: func_names.and_then(|names| names.names().get(func_idx as u32)).cloned(),
+			}),
 		}
 	}
+	for (global_idx, global) in globals.iter().enumerate().filter(|(_, g)| g.order().is_none()) {
+		match &global.read().origin {
+			graph::ImportedOrDeclared::Imported(module, field) => report
+				.imports
+				.push(RemovedImport { module: module.clone(), field: field.clone() }),
+			graph::ImportedOrDeclared::Declared(_) => report.globals.push(global_idx as u32),
+		}
+	}
+	report.types = (0..types.len() as u32)
+		.filter(|idx| types[*idx as usize].order().is_none())
+		.collect();
 
-	// Forth, delete orphaned functions
-	if function_section(module).is_some() && code_section(module).is_some() {
-		index = 0;
-		old_index = 0;
-
-		loop {
-			if function_section(module).expect("Functons section to exist").entries_mut().len() ==
-				index
-			{
-				break
+	// Also drop all custom sections
+	graph
+		.other
+		.retain(|_, section| !matches!(section, elements::Section::Custom(_)));
+	for section in graph.other.values_mut() {
+		if let elements::Section::Name(name_section) = section {
+			if let Some(func_name) = name_section.functions_mut() {
+				rewire_names(func_name.names_mut(), &funcs);
 			}
-			if stay.contains(&Symbol::Function(old_index)) {
-				index += 1;
-			} else {
-				function_section(module)
-					.expect("Functons section to exist")
-					.entries_mut()
-					.remove(index);
-				code_section(module).expect("Code section to exist").bodies_mut().remove(index);
-
-				eliminated_funcs.push(top_funcs + old_index);
-				trace!("Eliminated function({})", top_funcs + old_index);
+			if let Some(local_name) = name_section.locals_mut() {
+				rewire_names(local_name.local_names_mut(), &funcs);
 			}
-			old_index += 1;
 		}
 	}
 
-	// Fifth, eliminate unused exports
-	{
-		let exports = export_section(module).ok_or(Error::NoExportSection)?;
+	*module = graph.generate().map_err(Error::Graph)?;
+	if config.drop_unused_data {
+		report.data_segments = unused_data_segments(module);
+		if let Some(section) = data_section_mut(module) {
+			let mut idx = 0;
+			section.entries_mut().retain(|_| {
+				idx += 1;
+				!report.data_segments.contains(&(idx - 1))
+			});
+		}
+	}
+	report.size_reduction = original_size.saturating_sub(module_size(module));
+	Ok(report)
+}
 
-		index = 0;
-		old_index = 0;
+/// Like [`optimize`], but on the graph representation of a module, where removing an entry
+/// detaches it and all references to the remaining entries follow their new indices.
+///
+/// Sections which aren't decoded, like the name section, are left as they are.
+pub fn optimize_graph(module: &mut graph::Module, used_exports: &[&str]) {
+	module.exports.retain(|export| {
+		let kind = match export.local {
+			graph::ExportLocal::Func(_) => "func",
+			graph::ExportLocal::Global(_) => "global",
+			graph::ExportLocal::Memory(_) => "memory",
+			graph::ExportLocal::Table(_) => "table",
+		};
+		is_used(used_exports, &export.name, kind)
+	});
 
-		loop {
-			if exports.entries_mut().len() == index {
-				break
-			}
-			if stay.contains(&Symbol::Export(old_index)) {
-				index += 1;
-			} else {
-				trace!(
-					"Eliminated export({}, {})",
-					old_index,
-					exports.entries_mut()[index].field()
-				);
-				exports.entries_mut().remove(index);
-			}
-			old_index += 1;
+	// Algo starts from the top, listing all items that should stay
+	let mut kept = Kept::default();
+	for export in &module.exports {
+		match &export.local {
+			graph::ExportLocal::Func(func) => kept.push_func(func),
+			graph::ExportLocal::Global(global) => kept.push_global(global),
+			// Tables and memories are never eliminated.
+			graph::ExportLocal::Table(_) | graph::ExportLocal::Memory(_) => {},
+		}
+	}
+	// If there is start function in module, it should stary
+	if let Some(start) = &module.start {
+		kept.push_func(start);
+	}
+	// All data/element segments are kept, and so are all symbols used by them.
+	for segment in &module.elements {
+		kept.push_location(&segment.location);
+		for func in &segment.value {
+			kept.push_func(func);
 		}
 	}
+	for segment in &module.data {
+		kept.push_location(&segment.location);
+	}
 
-	if !eliminated_globals.is_empty() ||
-		!eliminated_funcs.is_empty() ||
-		!eliminated_types.is_empty()
-	{
-		// Finaly, rewire all calls, globals references and types to the new indices
-		//   (only if there is anything to do)
-		// When sorting primitives sorting unstable is faster without any difference in result.
-		eliminated_globals.sort_unstable();
-		eliminated_funcs.sort_unstable();
-		eliminated_types.sort_unstable();
-
-		for section in module.sections_mut() {
-			match section {
-				elements::Section::Start(func_index) if !eliminated_funcs.is_empty() => {
-					let totalle =
-						eliminated_funcs.iter().take_while(|i| (**i as u32) < *func_index).count();
-					*func_index -= totalle as u32;
-				},
-				elements::Section::Function(function_section) if !eliminated_types.is_empty() =>
-					for func_signature in function_section.entries_mut() {
-						let totalle = eliminated_types
-							.iter()
-							.take_while(|i| (**i as u32) < func_signature.type_ref())
-							.count();
-						*func_signature.type_ref_mut() -= totalle as u32;
-					},
-				elements::Section::Import(import_section) if !eliminated_types.is_empty() => {
-					for import_entry in import_section.entries_mut() {
-						if let elements::External::Function(type_ref) = import_entry.external_mut()
-						{
-							let totalle = eliminated_types
-								.iter()
-								.take_while(|i| (**i as u32) < *type_ref)
-								.count();
-							*type_ref -= totalle as u32;
-						}
-					}
-				},
-				elements::Section::Code(code_section)
-					if !eliminated_globals.is_empty() || !eliminated_funcs.is_empty() =>
-				{
-					for func_body in code_section.bodies_mut() {
-						if !eliminated_funcs.is_empty() {
-							update_call_index(func_body.code_mut(), &eliminated_funcs);
-						}
-						if !eliminated_globals.is_empty() {
-							update_global_index(
-								func_body.code_mut().elements_mut(),
-								&eliminated_globals,
-							)
-						}
-						if !eliminated_types.is_empty() {
-							update_type_index(func_body.code_mut(), &eliminated_types)
-						}
-					}
-				},
-				elements::Section::Export(export_section) => {
-					for export in export_section.entries_mut() {
-						match export.internal_mut() {
-							elements::Internal::Function(func_index) => {
-								let totalle = eliminated_funcs
-									.iter()
-									.take_while(|i| (**i as u32) < *func_index)
-									.count();
-								*func_index -= totalle as u32;
-							},
-							elements::Internal::Global(global_index) => {
-								let totalle = eliminated_globals
-									.iter()
-									.take_while(|i| (**i as u32) < *global_index)
-									.count();
-								*global_index -= totalle as u32;
-							},
-							_ => {},
-						}
-					}
-				},
-				elements::Section::Global(global_section) => {
-					for global_entry in global_section.entries_mut() {
-						update_global_index(
-							global_entry.init_expr_mut().code_mut(),
-							&eliminated_globals,
-						)
-					}
-				},
-				elements::Section::Data(data_section) =>
-					for segment in data_section.entries_mut() {
-						update_global_index(
-							segment
-								.offset_mut()
-								.as_mut()
-								.expect("parity-wasm is compiled without bulk-memory operations")
-								.code_mut(),
-							&eliminated_globals,
-						)
-					},
-				elements::Section::Element(elements_section) => {
-					for segment in elements_section.entries_mut() {
-						update_global_index(
-							segment
-								.offset_mut()
-								.as_mut()
-								.expect("parity-wasm is compiled without bulk-memory operations")
-								.code_mut(),
-							&eliminated_globals,
-						);
-						// update all indirect call addresses initial values
-						for func_index in segment.members_mut() {
-							let totalle = eliminated_funcs
-								.iter()
-								.take_while(|i| (**i as u32) < *func_index)
-								.count();
-							*func_index -= totalle as u32;
-						}
-					}
-				},
-				elements::Section::Name(name_section) => {
-					if let Some(func_name) = name_section.functions_mut() {
-						let mut func_name_map = mem::take(func_name.names_mut());
-						for index in &eliminated_funcs {
-							func_name_map.remove(*index as u32);
-						}
-						let updated_map = func_name_map
-							.into_iter()
-							.map(|(index, value)| {
-								let totalle = eliminated_funcs
-									.iter()
-									.take_while(|i| (**i as u32) < index)
-									.count() as u32;
-								(index - totalle, value)
-							})
-							.collect();
-						*func_name.names_mut() = updated_map;
-					}
-
-					if let Some(local_name) = name_section.locals_mut() {
-						let mut local_names_map = mem::take(local_name.local_names_mut());
-						for index in &eliminated_funcs {
-							local_names_map.remove(*index as u32);
-						}
-						let updated_map = local_names_map
-							.into_iter()
-							.map(|(index, value)| {
-								let totalle = eliminated_funcs
-									.iter()
-									.take_while(|i| (**i as u32) < index)
-									.count() as u32;
-								(index - totalle, value)
-							})
-							.collect();
-						*local_name.local_names_mut() = updated_map;
-					}
-				},
-				_ => {},
-			}
+	// Traverse the list recursively, keeping all symbols used by those which are kept already
+	while let Some(item) = kept.fringe.pop() {
+		match item {
+			Item::Func(idx) => {
+				let func = module.funcs.get_ref(idx).read();
+				kept.push_type(&func.type_ref);
+				if let graph::ImportedOrDeclared::Declared(body) = &func.origin {
+					kept.push_code(&body.code);
+				}
+			},
+			Item::Global(idx) => {
+				let global = module.globals.get_ref(idx).read();
+				if let graph::ImportedOrDeclared::Declared(init_code) = &global.origin {
+					kept.push_code(init_code);
+				}
+			},
+			Item::Type(_) => {},
 		}
 	}
 
-	// Also drop all custom sections
-	module
-		.sections_mut()
-		.retain(|section| !matches!(section, elements::Section::Custom(_)));
+	let eliminated = |len: usize, kind: fn(usize) -> Item| {
+		(0..len).filter(|idx| !kept.items.contains(&kind(*idx))).collect::<Vec<_>>()
+	};
+	let funcs = eliminated(module.funcs.len(), Item::Func);
+	let globals = eliminated(module.globals.len(), Item::Global);
+	let types = eliminated(module.types.len(), Item::Type);
+	trace!("Eliminated functions {:?}, globals {:?}, types {:?}", funcs, globals, types);
 
-	Ok(())
+	module.funcs.delete(&funcs);
+	module.globals.delete(&globals);
+	module.types.delete(&types);
 }
 
-pub fn update_call_index(instructions: &mut elements::Instructions, eliminated_indices: &[usize]) {
-	use parity_wasm::elements::Instruction::*;
-	for instruction in instructions.elements_mut().iter_mut() {
-		if let Call(call_index) = instruction {
-			let totalle =
-				eliminated_indices.iter().take_while(|i| (**i as u32) < *call_index).count();
-			trace!("rewired call {} -> call {}", *call_index, *call_index - totalle as u32);
-			*call_index -= totalle as u32;
-		}
-	}
+/// Entry of a graph module which can be eliminated, by its index before elimination.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Copy, Clone, Debug)]
+enum Item {
+	Func(usize),
+	Global(usize),
+	Type(usize),
 }
 
-/// Updates global references considering the _ordered_ list of eliminated indices
-pub fn update_global_index(
-	instructions: &mut Vec<elements::Instruction>,
-	eliminated_indices: &[usize],
-) {
-	use parity_wasm::elements::Instruction::*;
-	for instruction in instructions.iter_mut() {
-		match instruction {
-			GetGlobal(index) | SetGlobal(index) => {
-				let totalle =
-					eliminated_indices.iter().take_while(|i| (**i as u32) < *index).count();
-				trace!("rewired global {} -> global {}", *index, *index - totalle as u32);
-				*index -= totalle as u32;
-			},
-			_ => {},
-		}
-	}
+/// Entries kept by [`optimize_graph`].
+#[derive(Default)]
+struct Kept {
+	items: Set<Item>,
+	/// Kept items whose references aren't traversed yet.
+	fringe: Vec<Item>,
 }
 
-/// Updates global references considering the _ordered_ list of eliminated indices
-pub fn update_type_index(instructions: &mut elements::Instructions, eliminated_indices: &[usize]) {
-	use parity_wasm::elements::Instruction::*;
-	for instruction in instructions.elements_mut().iter_mut() {
-		if let CallIndirect(call_index, _) = instruction {
-			let totalle =
-				eliminated_indices.iter().take_while(|i| (**i as u32) < *call_index).count();
-			trace!(
-				"rewired call_indrect {} -> call_indirect {}",
-				*call_index,
-				*call_index - totalle as u32
-			);
-			*call_index -= totalle as u32;
+impl Kept {
+	fn push(&mut self, item: Item) {
+		if self.items.insert(item) {
+			self.fringe.push(item);
 		}
 	}
-}
 
-pub fn import_section(module: &mut elements::Module) -> Option<&mut elements::ImportSection> {
-	for section in module.sections_mut() {
-		if let elements::Section::Import(sect) = section {
-			return Some(sect)
-		}
+	fn push_func(&mut self, func: &EntryRef<graph::Func>) {
+		self.push(Item::Func(func.order().expect("kept entries are attached; qed")));
 	}
-	None
-}
 
-pub fn global_section(module: &mut elements::Module) -> Option<&mut elements::GlobalSection> {
-	for section in module.sections_mut() {
-		if let elements::Section::Global(sect) = section {
-			return Some(sect)
-		}
+	fn push_global(&mut self, global: &EntryRef<graph::Global>) {
+		self.push(Item::Global(global.order().expect("kept entries are attached; qed")));
 	}
-	None
-}
 
-pub fn function_section(module: &mut elements::Module) -> Option<&mut elements::FunctionSection> {
-	for section in module.sections_mut() {
-		if let elements::Section::Function(sect) = section {
-			return Some(sect)
-		}
+	fn push_type(&mut self, ty: &EntryRef<elements::Type>) {
+		self.push(Item::Type(ty.order().expect("kept entries are attached; qed")));
 	}
-	None
-}
 
-pub fn code_section(module: &mut elements::Module) -> Option<&mut elements::CodeSection> {
-	for section in module.sections_mut() {
-		if let elements::Section::Code(sect) = section {
-			return Some(sect)
+	fn push_code(&mut self, code: &[graph::Instruction]) {
+		for instruction in code {
+			match instruction {
+				graph::Instruction::Call(func) => self.push_func(func),
+				graph::Instruction::CallIndirect(ty, _) => self.push_type(ty),
+				graph::Instruction::GetGlobal(global) | graph::Instruction::SetGlobal(global) =>
+					self.push_global(global),
+				graph::Instruction::Plain(_) => {},
+			}
 		}
 	}
-	None
-}
 
-pub fn export_section(module: &mut elements::Module) -> Option<&mut elements::ExportSection> {
-	for section in module.sections_mut() {
-		if let elements::Section::Export(sect) = section {
-			return Some(sect)
+	fn push_location(&mut self, location: &graph::SegmentLocation) {
+		match location {
+			graph::SegmentLocation::Default(code) | graph::SegmentLocation::WithIndex(_, code) =>
+				self.push_code(code),
+			graph::SegmentLocation::Passive => {},
 		}
 	}
-	None
 }
 
-pub fn type_section(module: &mut elements::Module) -> Option<&mut elements::TypeSection> {
-	for section in module.sections_mut() {
-		if let elements::Section::Type(sect) = section {
-			return Some(sect)
-		}
-	}
-	None
+/// Move the names of the functions to their indices after the optimization, dropping the ones of
+/// the eliminated functions. `funcs` are all functions by their original index.
+fn rewire_names<T>(names: &mut elements::IndexMap<T>, funcs: &[EntryRef<graph::Func>]) {
+	*names = mem::replace(names, elements::IndexMap::with_capacity(0))
+		.into_iter()
+		.filter_map(|(idx, name)| Some((funcs.get(idx as usize)?.order()? as u32, name)))
+		.collect();
 }
 
 #[cfg(test)]
@@ -803,4 +812,334 @@ mod tests {
 			},
 		}
 	}
+
+	/// @spec 5
+	/// Imagine the unoptimized module exports only its table. The element segment of this table
+	/// is placed at an offset given by an imported global and refers to a function which is not
+	/// exported. Both the function and the global import should survive the optimization, while
+	/// the unused global import should not.
+	#[test]
+	fn exported_table() {
+		let mut module = builder::module()
+			.import()
+			.module("env")
+			.field("unused")
+			.external()
+			.global(elements::ValueType::I32, false)
+			.build()
+			.import()
+			.module("env")
+			.field("table_base")
+			.external()
+			.global(elements::ValueType::I32, false)
+			.build()
+			.function()
+			.signature()
+			.param()
+			.i32()
+			.build()
+			.build()
+			.with_table(elements::TableType::new(1, None))
+			.with_section(elements::Section::Element(elements::ElementSection::with_entries(vec![
+				elements::ElementSegment::new(
+					0,
+					Some(elements::InitExpr::new(vec![
+						elements::Instruction::GetGlobal(1),
+						elements::Instruction::End,
+					])),
+					vec![0],
+				),
+			])))
+			.export()
+			.field("table")
+			.internal()
+			.table(0)
+			.build()
+			.build();
+
+		optimize(&mut module, vec!["table"]).expect("optimizer to succeed");
+
+		let imports = module.import_section().expect("import section to be generated").entries();
+		assert_eq!(1, imports.len(), "Only the global used by the element segment should stay");
+		assert_eq!("table_base", imports[0].field());
+		assert_eq!(
+			1,
+			module
+				.function_section()
+				.expect("function section to be generated")
+				.entries()
+				.len(),
+			"Function referenced by the exported table should stay"
+		);
+		let segment = &module.elements_section().expect("element section to exist").entries()[0];
+		assert_eq!(
+			&[elements::Instruction::GetGlobal(0), elements::Instruction::End][..],
+			segment.offset().as_ref().expect("offset to exist").code(),
+			"Offset should be rewired to the only global left"
+		);
+	}
+
+	/// @spec 6
+	/// Imagine the unoptimized module exports only its memory, and its data segment is placed
+	/// at an offset given by a global. This global should survive the optimization.
+	#[test]
+	fn exported_memory() {
+		let mut module = builder::module()
+			.global()
+			.value_type()
+			.i32()
+			.build()
+			.global()
+			.value_type()
+			.i32()
+			.build()
+			.memory()
+			.with_min(1)
+			.build()
+			.with_data_segment(elements::DataSegment::new(
+				0,
+				Some(elements::InitExpr::new(vec![
+					elements::Instruction::GetGlobal(1),
+					elements::Instruction::End,
+				])),
+				vec![1, 2, 3],
+			))
+			.export()
+			.field("memory")
+			.internal()
+			.memory(0)
+			.build()
+			.build();
+
+		optimize(&mut module, vec!["memory"]).expect("optimizer to succeed");
+
+		assert_eq!(
+			1,
+			module.global_section().expect("global section to be generated").entries().len(),
+			"Only the global used by the data segment should stay"
+		);
+		assert_eq!(
+			1,
+			module.export_section().expect("export section to be generated").entries().len(),
+			"Memory export should stay"
+		);
+	}
+
+	#[test]
+	fn import_retention() {
+		let mut module = elements::deserialize_buffer(
+			&wabt::wat2wasm(
+				r#"
+(module
+	(import "env" "storage_write" (func $storage_write))
+	(import "env" "log" (func $log))
+	(import "env" "unused" (func $unused))
+	(import "env" "memory" (memory 1))
+	(import "env" "seed" (global i32))
+	(table 1 funcref)
+	(elem (i32.const 0) $callback)
+	(func $helper (call $storage_write))
+	(func $callback (call $log))
+	(func (export "call")
+		(call $helper)
+		(drop (global.get 0))
+	)
+	(func (export "dead") (call $unused))
+)
+"#,
+			)
+			.expect("Failed to wat2wasm"),
+		)
+		.expect("Failed to deserialize the module");
+
+		let retention =
+			|field: &str, path| ImportRetention { module: "env".into(), field: field.into(), path };
+		assert_eq!(
+			optimize_with_trace(&mut module, vec!["call"]).expect("optimizer to succeed"),
+			vec![
+				retention(
+					"storage_write",
+					vec![
+						Retainer::Export("call".into()),
+						Retainer::Function(5),
+						Retainer::Function(3)
+					]
+				),
+				retention("log", vec![Retainer::Table(0), Retainer::Function(4)]),
+				retention("memory", vec![]),
+				retention("seed", vec![Retainer::Export("call".into()), Retainer::Function(5)]),
+			]
+		);
+		assert_eq!(module.import_section().expect("imports to stay").entries().len(), 4);
+	}
+
+	#[test]
+	fn function_names() {
+		let mut module: elements::Module = elements::deserialize_buffer(
+			&wabt::wat2wasm(
+				r#"
+(module
+	(import "env" "unused" (func $unused))
+	(import "env" "used" (func $used))
+	(func $dead (call $unused))
+	(func $helper (call $used))
+	(func (export "call") (call $helper))
+	(func (export "dead") (call $dead))
+)
+"#,
+			)
+			.expect("Failed to wat2wasm"),
+		)
+		.expect("Failed to deserialize the module");
+		let mut function_names = elements::FunctionNameSubsection::default();
+		for (idx, name) in ["unused", "used", "dead", "helper", "call"].iter().enumerate() {
+			function_names.names_mut().insert(idx as u32, name.to_string());
+		}
+		module.sections_mut().push(elements::Section::Name(elements::NameSection::new(
+			None,
+			Some(function_names),
+			None,
+		)));
+
+		let report = optimize(&mut module, vec!["call"]).expect("optimizer to succeed");
+
+		assert_eq!(
+			report.functions,
+			vec![
+				RemovedFunction { func_idx: 2, name: Some("dead".into()) },
+				RemovedFunction { func_idx: 5, name: None }
+			]
+		);
+		assert_eq!(
+			report.imports,
+			vec![RemovedImport { module: "env".into(), field: "unused".into() }]
+		);
+		assert!(report.globals.is_empty());
+		assert!(report.types.is_empty());
+		assert!(report.size_reduction > 0);
+
+		assert_eq!(module.import_section().expect("imports to stay").entries().len(), 1);
+		let code = module.code_section().expect("code section to stay").bodies();
+		assert_eq!(code[0].code().elements()[0], elements::Instruction::Call(0));
+		assert_eq!(code[1].code().elements()[0], elements::Instruction::Call(1));
+		let names = module.names_section().expect("name section to stay").functions();
+		let names = names.expect("function names to stay").names();
+		assert_eq!(
+			names.iter().map(|(idx, name)| (idx, name.as_str())).collect::<Vec<_>>(),
+			vec![(0, "used"), (1, "helper"), (2, "call")]
+		);
+	}
+
+	#[test]
+	fn glob_patterns() {
+		assert!(glob_match("call", "call"));
+		assert!(!glob_match("call", "call2"));
+		assert!(glob_match("seal_*", "seal_call"));
+		assert!(glob_match("seal_*", "seal_"));
+		assert!(!glob_match("seal_*", "deploy"));
+		assert!(glob_match("*_v?", "seal_call_v1"));
+		assert!(!glob_match("*_v?", "seal_call_v10"));
+		assert!(glob_match("a*b*c", "aXbYbZc"));
+		assert!(!glob_match("a*b*c", "aXbYcZ"));
+		assert!(glob_match("*", ""));
+
+		let mut module: elements::Module = elements::deserialize_buffer(
+			&wabt::wat2wasm(
+				r#"
+(module
+	(func (export "seal_call"))
+	(func (export "seal_deploy"))
+	(func (export "helper"))
+)
+"#,
+			)
+			.expect("Failed to wat2wasm"),
+		)
+		.expect("Failed to deserialize the module");
+
+		optimize(&mut module, vec!["seal_*"]).expect("optimizer to succeed");
+
+		let exports = module.export_section().expect("export section to stay").entries();
+		assert_eq!(
+			exports.iter().map(|entry| entry.field()).collect::<Vec<_>>(),
+			vec!["seal_call", "seal_deploy"]
+		);
+		assert_eq!(module.code_section().expect("code section to stay").bodies().len(), 2);
+	}
+
+	#[test]
+	fn exports_by_kind() {
+		let mut module: elements::Module = elements::deserialize_buffer(
+			&wabt::wat2wasm(
+				r#"
+(module
+	(import "env" "base" (global $base i32))
+	(import "env" "unused" (global $unused i32))
+	(global $heap_base (export "heap_base") i32 (global.get $base))
+	(global $other (export "other") i32 (global.get $unused))
+	(memory (export "memory") 1)
+	(table (export "table") 1 funcref)
+	(func (export "call"))
+	(func (export "base"))
+)
+"#,
+			)
+			.expect("Failed to wat2wasm"),
+		)
+		.expect("Failed to deserialize the module");
+
+		let report = optimize(&mut module, vec!["call", "global:heap_*", "memory:*", "func:other"])
+			.expect("optimizer to succeed");
+
+		let exports = module.export_section().expect("export section to stay").entries();
+		assert_eq!(
+			exports.iter().map(|entry| entry.field()).collect::<Vec<_>>(),
+			vec!["heap_base", "memory", "call"]
+		);
+		assert_eq!(
+			report.imports,
+			vec![RemovedImport { module: "env".into(), field: "unused".into() }]
+		);
+		assert_eq!(report.globals, vec![3]);
+		let imports = module.import_section().expect("import section to stay").entries();
+		assert_eq!(imports[0].field(), "base");
+		let globals = module.global_section().expect("global section to stay").entries();
+		assert_eq!(globals[0].init_expr().code()[0], elements::Instruction::GetGlobal(0));
+		// Tables are never eliminated.
+		assert_eq!(module.table_section().expect("table section to stay").entries().len(), 1);
+	}
+
+	#[test]
+	fn unused_data() {
+		let mut module: elements::Module = elements::deserialize_buffer(
+			&wabt::wat2wasm(
+				r#"
+(module
+	(memory 1)
+	(data (i32.const 0x100) "used")
+	(data (i32.const 0x200) "dead")
+	(func (export "call") (result i32) (i32.load (i32.const 0x100)))
+	(func (export "dead") (result i32) (i32.load (i32.const 0x200)))
+)
+"#,
+			)
+			.expect("Failed to wat2wasm"),
+		)
+		.expect("Failed to deserialize the module");
+
+		let mut kept = module.clone();
+		let report = optimize(&mut kept, vec!["call"]).expect("optimizer to succeed");
+		assert!(report.data_segments.is_empty());
+		assert_eq!(kept.data_section().expect("data section to stay").entries().len(), 2);
+
+		let config = Config::new().with_unused_data_elimination();
+		let report =
+			optimize_with_config(&mut module, vec!["call"], &config).expect("optimizer to succeed");
+		assert_eq!(report.data_segments, vec![1]);
+		let segments = module.data_section().expect("data section to stay").entries();
+		assert_eq!(
+			segments.iter().map(|segment| segment.value()).collect::<Vec<_>>(),
+			vec![b"used"]
+		);
+	}
 }
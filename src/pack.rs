@@ -1,19 +1,26 @@
-use crate::std::{borrow::ToOwned, fmt, vec::Vec};
+use crate::std::{borrow::ToOwned, fmt, ops::Range, vec::Vec};
 
-use super::{gas::update_call_index, TargetRuntime};
+use super::{
+	sections::{export_section_mut, get_or_insert_data_section},
+	visit_function_indices, TargetRuntime,
+};
 use parity_wasm::{
 	builder,
 	elements::{
-		self, DataSection, DataSegment, External, ImportCountType, InitExpr, Instruction, Internal,
-		Section,
+		self, BlockType, DataSegment, External, ImportCountType, InitExpr, Instruction, Internal,
+		Local, ValueType,
 	},
 };
 
+/// Data segments shorter than this aren't shared, the code copying them would be bigger.
+const MIN_SHARED_DATA: usize = 64;
+
 /// Pack error.
 ///
 /// Pack has number of assumptions of passed module structure.
 /// When they are violated, pack_instance returns one of these.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
 	MalformedModule,
 	NoTypeSection,
@@ -23,6 +30,8 @@ pub enum Error {
 	NoCreateSymbol(&'static str),
 	InvalidCreateMember(&'static str),
 	NoImportSection,
+	/// The contract code doesn't fit into the 32-bit address space after the last data segment.
+	Overflow,
 }
 
 impl fmt::Display for Error {
@@ -40,17 +49,55 @@ impl fmt::Display for Error {
 			},
 			Error::NoCreateSymbol(sym) => write!(f, "No exported `{}` symbol", sym),
 			Error::NoImportSection => write!(f, "No import section in the module"),
+			Error::Overflow => write!(f, "Contract code exceeds the address space"),
 		}
 	}
 }
 
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+/// Configuration of [`pack_instance_with_config`].
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+	share_data: bool,
+}
+
+impl Config {
+	/// Configuration embedding the code module as is.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Leave the data segments the code module has in common with the constructor out of the
+	/// embedded code module.
+	///
+	/// The packed constructor copies them from its own data into place before it calls the
+	/// "create" function, so they must not be modified during instantiation, e.g. by a start
+	/// function.
+	pub fn with_shared_data(mut self) -> Self {
+		self.share_data = true;
+		self
+	}
+}
+
 /// If a pwasm module has an exported function matching "create" symbol we want to pack it into "constructor".
 /// `raw_module` is the actual contract code
 /// `ctor_module` is the constructor which should return `raw_module`
 pub fn pack_instance(
+	raw_module: Vec<u8>,
+	ctor_module: elements::Module,
+	target: &TargetRuntime,
+) -> Result<elements::Module, Error> {
+	pack_instance_with_config(raw_module, ctor_module, target, &Config::default())
+}
+
+/// Same as [`pack_instance`], but with the given configuration.
+pub fn pack_instance_with_config(
 	raw_module: Vec<u8>,
 	mut ctor_module: elements::Module,
 	target: &TargetRuntime,
+	config: &Config,
 ) -> Result<elements::Module, Error> {
 	// Total number of constructor module import functions
 	let ctor_import_functions = ctor_module.import_section().map(|x| x.functions()).unwrap_or(0);
@@ -132,36 +179,11 @@ pub fn pack_instance(
 
 			let ret_func = ctor_module.import_count(ImportCountType::Function) as u32 - 1;
 
-			for section in ctor_module.sections_mut() {
-				match section {
-					elements::Section::Code(code_section) => {
-						for func_body in code_section.bodies_mut() {
-							update_call_index(func_body.code_mut(), ret_func);
-						}
-					},
-					elements::Section::Export(export_section) => {
-						for export in export_section.entries_mut() {
-							if let elements::Internal::Function(func_index) = export.internal_mut()
-							{
-								if *func_index >= ret_func {
-									*func_index += 1
-								}
-							}
-						}
-					},
-					elements::Section::Element(elements_section) => {
-						for segment in elements_section.entries_mut() {
-							// update all indirect call addresses initial values
-							for func_index in segment.members_mut() {
-								if *func_index >= ret_func {
-									*func_index += 1
-								}
-							}
-						}
-					},
-					_ => {},
+			visit_function_indices(&mut ctor_module, |func_index, _| {
+				if *func_index >= ret_func {
+					*func_index += 1
 				}
-			}
+			});
 
 			create_func_id += 1;
 			ret_func
@@ -173,74 +195,83 @@ pub fn pack_instance(
 	// If new function is put in ctor module, it will have this callable index
 	let last_function_index = ctor_module.functions_space();
 
-	// We ensure here that module has the DataSection
-	if !ctor_module
-		.sections()
-		.iter()
-		.any(|section| matches!(*section, Section::Data(_)))
-	{
-		// DataSection has to be the last non-custom section according the to the spec
-		ctor_module
-			.sections_mut()
-			.push(Section::Data(DataSection::with_entries(vec![])));
-	}
-
 	// Code data address is an address where we put the contract's code (raw_module)
-	let mut code_data_address = 0i32;
-
-	for section in ctor_module.sections_mut() {
-		if let Section::Data(data_section) = section {
-			let (index, offset) = if let Some(entry) = data_section.entries().iter().last() {
-				let init_expr = entry
-					.offset()
-					.as_ref()
-					.expect("parity-wasm is compiled without bulk-memory operations")
-					.code();
-				if let Instruction::I32Const(offst) = init_expr[0] {
-					let len = entry.value().len() as i32;
-					let offst = offst as i32;
-					(entry.index(), offst + (len + 4) - len % 4)
-				} else {
-					(0, 0)
-				}
-			} else {
-				(0, 0)
-			};
-			let code_data = DataSegment::new(
+	let data_section = get_or_insert_data_section(&mut ctor_module);
+	let (index, code_data_address) = if let Some(entry) = data_section.entries().iter().last() {
+		let init_expr = entry
+			.offset()
+			.as_ref()
+			.expect("parity-wasm is compiled without bulk-memory operations")
+			.code();
+		if let Instruction::I32Const(offst) = init_expr[0] {
+			// Addresses are unsigned, the constant is just reinterpreted.
+			let len = u32::try_from(entry.value().len()).map_err(|_| Error::Overflow)?;
+			let address = (offst as u32).checked_add(len + 4 - len % 4).ok_or(Error::Overflow)?;
+			(entry.index(), address)
+		} else {
+			(0, 0)
+		}
+	} else {
+		(0, 0)
+	};
+	let code_len = u32::try_from(raw_module.len()).map_err(|_| Error::Overflow)?;
+	code_data_address.checked_add(code_len).ok_or(Error::Overflow)?;
+
+	let shared = if config.share_data {
+		shared_regions(&raw_module, data_section.entries())?
+	} else {
+		Vec::new()
+	};
+
+	// The code module is stored without the shared regions, these are copied in place below.
+	let mut stored_from = 0;
+	for region in shared.iter().map(Some).chain(Some(None)) {
+		let stored_to = region.map_or(raw_module.len(), |region| region.position as usize);
+		if stored_to > stored_from {
+			data_section.entries_mut().push(DataSegment::new(
 				index,
-				Some(InitExpr::new(vec![Instruction::I32Const(offset), Instruction::End])),
-				raw_module.clone(),
-			);
-			data_section.entries_mut().push(code_data);
-			code_data_address = offset;
+				Some(InitExpr::new(vec![
+					Instruction::I32Const((code_data_address + stored_from as u32) as i32),
+					Instruction::End,
+				])),
+				raw_module[stored_from..stored_to].to_vec(),
+			));
 		}
+		if let Some(region) = region {
+			stored_from = (region.position + region.len) as usize;
+		}
+	}
+
+	let mut instructions = Vec::new();
+	for region in &shared {
+		copy_region(region, code_data_address, &mut instructions);
 	}
+	instructions.extend([
+		Instruction::Call((create_func_id + ctor_import_functions) as u32),
+		Instruction::I32Const(code_data_address as i32),
+		Instruction::I32Const(code_len as i32),
+		Instruction::Call(ret_function_id as u32),
+		Instruction::End,
+	]);
+	let locals = if shared.is_empty() { Vec::new() } else { vec![Local::new(1, ValueType::I32)] };
 
 	let mut new_module = builder::from_module(ctor_module)
 		.function()
 		.signature()
 		.build()
 		.body()
-		.with_instructions(elements::Instructions::new(vec![
-			Instruction::Call((create_func_id + ctor_import_functions) as u32),
-			Instruction::I32Const(code_data_address),
-			Instruction::I32Const(raw_module.len() as i32),
-			Instruction::Call(ret_function_id as u32),
-			Instruction::End,
-		]))
+		.with_locals(locals)
+		.with_instructions(elements::Instructions::new(instructions))
 		.build()
 		.build()
 		.build();
 
-	for section in new_module.sections_mut() {
-		if let Section::Export(export_section) = section {
-			for entry in export_section.entries_mut().iter_mut() {
-				if target.symbols().create == entry.field() {
-					// change `create` symbol export name into default `call` symbol name.
-					*entry.field_mut() = target.symbols().call.to_owned();
-					*entry.internal_mut() =
-						elements::Internal::Function(last_function_index as u32);
-				}
+	if let Some(export_section) = export_section_mut(&mut new_module) {
+		for entry in export_section.entries_mut().iter_mut() {
+			if target.symbols().create == entry.field() {
+				// change `create` symbol export name into default `call` symbol name.
+				*entry.field_mut() = target.symbols().call.to_owned();
+				*entry.internal_mut() = elements::Internal::Function(last_function_index as u32);
 			}
 		}
 	}
@@ -248,9 +279,120 @@ pub fn pack_instance(
 	Ok(new_module)
 }
 
+/// Part of the code module identical to a data segment of the constructor.
+#[derive(Debug)]
+struct SharedRegion {
+	/// Position in the code module.
+	position: u32,
+	/// Address of the data segment of the constructor.
+	source: u32,
+	len: u32,
+}
+
+/// Find the data segments of the code module which the constructor has too.
+///
+/// The regions are returned in the order of their positions and don't overlap.
+fn shared_regions(
+	raw_module: &[u8],
+	ctor_data: &[DataSegment],
+) -> Result<Vec<SharedRegion>, Error> {
+	let module: elements::Module =
+		parity_wasm::deserialize_buffer(raw_module).map_err(|_| Error::MalformedModule)?;
+	let data = data_section_range(raw_module).ok_or(Error::MalformedModule)?;
+
+	let mut regions = Vec::new();
+	let mut search_from = data.start;
+	for segment in module.data_section().map(|section| section.entries()).unwrap_or(&[]) {
+		let value = segment.value();
+		if value.len() < MIN_SHARED_DATA {
+			continue
+		}
+		let source = ctor_data.iter().find_map(|ctor_segment| {
+			match ctor_segment.offset().as_ref().map(|offset| offset.code()) {
+				Some([Instruction::I32Const(offset), ..]) if ctor_segment.value() == value =>
+					Some(*offset as u32),
+				_ => None,
+			}
+		});
+		let source = match source {
+			Some(source) => source,
+			None => continue,
+		};
+		// Segments are stored in order, so this finds the segment right after the previous one.
+		let position = match raw_module[search_from..data.end]
+			.windows(value.len())
+			.position(|window| window == value)
+		{
+			Some(offset) => search_from + offset,
+			None => continue,
+		};
+		search_from = position + value.len();
+		regions.push(SharedRegion { position: position as u32, source, len: value.len() as u32 });
+	}
+	Ok(regions)
+}
+
+/// Byte range of the contents of the data section of a serialized module.
+fn data_section_range(module: &[u8]) -> Option<Range<usize>> {
+	const DATA_SECTION_ID: u8 = 11;
+
+	// Skip the magic number and the version.
+	let mut pos = 8;
+	while pos < module.len() {
+		let id = module[pos];
+		let (size, size_len) = read_varuint32(module.get(pos + 1..)?)?;
+		let start = pos + 1 + size_len;
+		let end = start.checked_add(size as usize).filter(|end| *end <= module.len())?;
+		if id == DATA_SECTION_ID {
+			return Some(start..end)
+		}
+		pos = end;
+	}
+	None
+}
+
+/// Read an unsigned LEB128 value, returns it with the number of bytes read.
+fn read_varuint32(bytes: &[u8]) -> Option<(u32, usize)> {
+	let mut value = 0u32;
+	for (i, byte) in bytes.iter().take(5).enumerate() {
+		value |= u32::from(byte & 0x7f) << (7 * i);
+		if byte & 0x80 == 0 {
+			return Some((value, i + 1))
+		}
+	}
+	None
+}
+
+/// Append the instructions copying `region` from the constructor data to the code module, which
+/// starts at `code_data_address`. The first local is used as the counter.
+fn copy_region(region: &SharedRegion, code_data_address: u32, instructions: &mut Vec<Instruction>) {
+	instructions.extend([
+		Instruction::I32Const(0),
+		Instruction::SetLocal(0),
+		Instruction::Block(BlockType::NoResult),
+		Instruction::Loop(BlockType::NoResult),
+		Instruction::GetLocal(0),
+		Instruction::I32Const(region.len as i32),
+		Instruction::I32GeU,
+		Instruction::BrIf(1),
+		Instruction::GetLocal(0),
+		Instruction::GetLocal(0),
+		Instruction::I32Load8U(0, region.source),
+		Instruction::I32Store8(0, code_data_address + region.position),
+		Instruction::GetLocal(0),
+		Instruction::I32Const(1),
+		Instruction::I32Add,
+		Instruction::SetLocal(0),
+		Instruction::Br(0),
+		Instruction::End,
+		Instruction::End,
+	]);
+}
+
 #[cfg(test)]
 mod test {
 	use super::{super::optimize, *};
+	use crate::testing::module_fixture;
 
 	fn test_packer(mut module: elements::Module, target_runtime: &TargetRuntime) {
 		let mut ctor_module = module.clone();
@@ -281,47 +423,13 @@ mod test {
 		let target_runtime = TargetRuntime::pwasm();
 
 		test_packer(
-			builder::module()
-				.import()
-				.module("env")
-				.field("memory")
-				.external()
-				.memory(1, Some(1))
-				.build()
-				.function()
-				.signature()
-				.params()
-				.i32()
-				.i32()
-				.build()
-				.build()
-				.body()
-				.build()
-				.build()
-				.function()
-				.signature()
-				.build()
-				.body()
-				.with_instructions(elements::Instructions::new(vec![elements::Instruction::End]))
-				.build()
-				.build()
-				.function()
-				.signature()
-				.build()
-				.body()
-				.with_instructions(elements::Instructions::new(vec![elements::Instruction::End]))
-				.build()
-				.build()
-				.export()
-				.field(target_runtime.symbols().call)
-				.internal()
-				.func(1)
-				.build()
-				.export()
-				.field(target_runtime.symbols().create)
-				.internal()
-				.func(2)
-				.build()
+			module_fixture()
+				.with_imported_memory()
+				.with_memory_max(1)
+				.with_functions(3)
+				.with_params(0, vec![elements::ValueType::I32, elements::ValueType::I32])
+				.with_export(target_runtime.symbols().call, 1)
+				.with_export(target_runtime.symbols().create, 2)
 				.build(),
 			&target_runtime,
 		);
@@ -332,53 +440,94 @@ mod test {
 		let target_runtime = TargetRuntime::pwasm();
 
 		test_packer(
-			builder::module()
-				.import()
-				.module("env")
-				.field("memory")
-				.external()
-				.memory(1, Some(1))
-				.build()
-				.data()
-				.offset(elements::Instruction::I32Const(16))
-				.value(vec![0u8])
-				.build()
-				.function()
-				.signature()
-				.params()
-				.i32()
-				.i32()
-				.build()
-				.build()
-				.body()
-				.build()
-				.build()
-				.function()
-				.signature()
-				.build()
-				.body()
-				.with_instructions(elements::Instructions::new(vec![elements::Instruction::End]))
-				.build()
-				.build()
-				.function()
-				.signature()
-				.build()
-				.body()
-				.with_instructions(elements::Instructions::new(vec![elements::Instruction::End]))
-				.build()
-				.build()
-				.export()
-				.field(target_runtime.symbols().call)
-				.internal()
-				.func(1)
-				.build()
-				.export()
-				.field(target_runtime.symbols().create)
-				.internal()
-				.func(2)
-				.build()
+			module_fixture()
+				.with_imported_memory()
+				.with_memory_max(1)
+				.with_data(16, vec![0u8])
+				.with_functions(3)
+				.with_params(0, vec![elements::ValueType::I32, elements::ValueType::I32])
+				.with_export(target_runtime.symbols().call, 1)
+				.with_export(target_runtime.symbols().create, 2)
 				.build(),
 			&target_runtime,
 		);
 	}
+
+	#[test]
+	fn data_at_end_of_address_space() {
+		let target_runtime = TargetRuntime::pwasm();
+		let module = |offset| {
+			module_fixture()
+				.with_imported_memory()
+				.with_data(offset, vec![0u8; 4])
+				.with_functions(3)
+				.with_export(target_runtime.symbols().create, 2)
+				.build()
+		};
+
+		let raw_module = parity_wasm::serialize(module(0)).unwrap();
+		assert!(matches!(
+			pack_instance(raw_module.clone(), module(u32::MAX - 4), &target_runtime),
+			Err(Error::Overflow)
+		));
+		assert!(matches!(
+			pack_instance(raw_module, module(u32::MAX - 8 - 16), &target_runtime),
+			Err(Error::Overflow)
+		));
+	}
+
+	#[test]
+	fn shared_data() {
+		let target_runtime = TargetRuntime::pwasm();
+		let table = (0..100u8).collect::<Vec<_>>();
+		let module = module_fixture()
+			.with_imported_memory()
+			.with_data(16, vec![1u8; 4])
+			.with_data(32, table.clone())
+			.with_functions(3)
+			.with_export(target_runtime.symbols().call, 1)
+			.with_export(target_runtime.symbols().create, 2)
+			.build();
+		let raw_module = parity_wasm::serialize(module.clone()).unwrap();
+
+		let config = Config::new().with_shared_data();
+		let packed =
+			pack_instance_with_config(raw_module.clone(), module, &target_runtime, &config)
+				.expect("Packing failed");
+
+		// Instantiate the memory and perform the copies of the packed constructor.
+		let mut memory = vec![0u8; 65536];
+		for segment in packed.data_section().unwrap().entries() {
+			let offset = match segment.offset().as_ref().unwrap().code() {
+				[Instruction::I32Const(offset), Instruction::End] => *offset as usize,
+				_ => panic!("Unexpected offset"),
+			};
+			memory[offset..offset + segment.value().len()].copy_from_slice(segment.value());
+		}
+		let stored: usize =
+			packed.data_section().unwrap().entries().iter().map(|s| s.value().len()).sum();
+		assert_eq!(stored, 4 + 100 + raw_module.len() - 100);
+
+		let body = packed.code_section().unwrap().bodies().last().unwrap().code().elements();
+		let (mut len, mut source) = (0, 0);
+		for pair in body.windows(2) {
+			match pair {
+				[Instruction::I32Const(value), Instruction::I32GeU] => len = *value as usize,
+				[Instruction::I32Load8U(0, address), _] => source = *address as usize,
+				[Instruction::I32Store8(0, address), _] => {
+					let address = *address as usize;
+					memory.copy_within(source..source + len, address);
+				},
+				_ => {},
+			}
+		}
+		assert_eq!(source, 32);
+
+		let (address, len) = match body[body.len() - 4..] {
+			[Instruction::I32Const(address), Instruction::I32Const(len), Instruction::Call(_), Instruction::End] =>
+				(address as usize, len as usize),
+			_ => panic!("Unexpected constructor body"),
+		};
+		assert_eq!(&memory[address..address + len], &raw_module[..]);
+	}
 }
@@ -0,0 +1,171 @@
+//! Decoding and encoding modules with the function bodies processed in parallel.
+//!
+//! The code section dominates the time spent in [`elements::deserialize_buffer`] and
+//! [`elements::serialize`], and its bodies are independent of each other. The functions here
+//! produce the same results as their counterparts in parity-wasm, but process the bodies on the
+//! rayon thread pool.
+
+use parity_wasm::elements::{
+	self, Deserialize, Error, FuncBody, Serialize, Uint32, VarUint32, VarUint7,
+};
+use rayon::prelude::*;
+use std::{fs, mem, ops::Range, path::Path};
+
+/// Id of the code section.
+const CODE_SECTION_ID: u8 = 10;
+
+/// Encoding of an empty function body, standing in for the bodies while the other sections are
+/// decoded.
+const PLACEHOLDER_BODY: [u8; 3] = [0x02, 0x00, 0x0b];
+
+/// Deserialize a module like [`elements::deserialize_buffer`], decoding the function bodies in
+/// parallel.
+pub fn deserialize_buffer(contents: &[u8]) -> Result<elements::Module, Error> {
+	// Any malformation is reported by the sequential decoder, so that the errors are the same.
+	decode(contents).or_else(|_| elements::deserialize_buffer(contents))
+}
+
+/// Deserialize the module in the file at `path`, decoding the function bodies in parallel.
+pub fn deserialize_file<P: AsRef<Path>>(path: P) -> Result<elements::Module, Error> {
+	let contents = fs::read(path)
+		.map_err(|err| Error::HeapOther(format!("Can't read from the file: {:?}", err)))?;
+	deserialize_buffer(&contents)
+}
+
+/// Serialize a module like [`elements::serialize`], encoding the function bodies in parallel.
+pub fn serialize(module: elements::Module) -> Result<Vec<u8>, Error> {
+	let mut output = Vec::new();
+	output.extend_from_slice(b"\0asm");
+	Uint32::from(module.version()).serialize(&mut output)?;
+	for section in module.into_sections() {
+		let bodies = match section {
+			elements::Section::Code(mut code) => mem::take(code.bodies_mut()),
+			section => {
+				section.serialize(&mut output)?;
+				continue
+			},
+		};
+		let encoded = bodies
+			.into_par_iter()
+			.map(|body| {
+				let mut encoded = Vec::new();
+				body.serialize(&mut encoded)?;
+				Ok(encoded)
+			})
+			.collect::<Result<Vec<_>, Error>>()?;
+
+		let mut payload = Vec::new();
+		VarUint32::from(encoded.len()).serialize(&mut payload)?;
+		for body in encoded {
+			payload.extend_from_slice(&body);
+		}
+		VarUint7::from(CODE_SECTION_ID).serialize(&mut output)?;
+		VarUint32::from(payload.len()).serialize(&mut output)?;
+		output.extend_from_slice(&payload);
+	}
+	Ok(output)
+}
+
+/// Serialize the module into the file at `path`, encoding the function bodies in parallel.
+pub fn serialize_to_file<P: AsRef<Path>>(path: P, module: elements::Module) -> Result<(), Error> {
+	let output = serialize(module)?;
+	fs::write(path, output)
+		.map_err(|err| Error::HeapOther(format!("Can't create the file: {:?}", err)))
+}
+
+/// Decode the module with placeholder bodies, then decode the actual bodies in parallel.
+fn decode(contents: &[u8]) -> Result<elements::Module, Error> {
+	let (code, bodies) = match split_code_section(contents)? {
+		Some(split) => split,
+		None => return elements::deserialize_buffer(contents),
+	};
+
+	let mut payload = Vec::new();
+	VarUint32::from(bodies.len()).serialize(&mut payload)?;
+	for _ in 0..bodies.len() {
+		payload.extend_from_slice(&PLACEHOLDER_BODY);
+	}
+	let mut stripped = contents[..code.start].to_vec();
+	stripped.push(CODE_SECTION_ID);
+	VarUint32::from(payload.len()).serialize(&mut stripped)?;
+	stripped.extend_from_slice(&payload);
+	stripped.extend_from_slice(&contents[code.end..]);
+	let mut module: elements::Module = elements::deserialize_buffer(&stripped)?;
+
+	let decoded = bodies
+		.into_par_iter()
+		.map(|range| FuncBody::deserialize(&mut &contents[range]))
+		.collect::<Result<Vec<_>, Error>>()?;
+	*module.code_section_mut().expect("a code section was decoded; qed").bodies_mut() = decoded;
+	Ok(module)
+}
+
+/// Range of the code section and of the encoded function bodies in it.
+type CodeLayout = (Range<usize>, Vec<Range<usize>>);
+
+/// Locate the code section and the encoded function bodies in it, including their size prefix.
+fn split_code_section(contents: &[u8]) -> Result<Option<CodeLayout>, Error> {
+	let mut reader = contents.get(8..).ok_or(Error::UnexpectedEof)?;
+	while !reader.is_empty() {
+		let start = contents.len() - reader.len();
+		let id = u8::from(VarUint7::deserialize(&mut reader)?);
+		let size = u32::from(VarUint32::deserialize(&mut reader)?) as usize;
+		let payload_start = contents.len() - reader.len();
+		let payload = reader.get(..size).ok_or(Error::UnexpectedEof)?;
+		reader = &reader[size..];
+		if id != CODE_SECTION_ID {
+			continue
+		}
+
+		let mut bodies_reader = payload;
+		let count = u32::from(VarUint32::deserialize(&mut bodies_reader)?);
+		let mut bodies = Vec::new();
+		for _ in 0..count {
+			let body_start = payload_start + payload.len() - bodies_reader.len();
+			let body_size = u32::from(VarUint32::deserialize(&mut bodies_reader)?) as usize;
+			bodies_reader = bodies_reader.get(body_size..).ok_or(Error::UnexpectedEof)?;
+			bodies.push(body_start..payload_start + payload.len() - bodies_reader.len());
+		}
+		return Ok(Some((start..payload_start + size, bodies)))
+	}
+	Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	#[test]
+	fn same_as_sequential() {
+		let module = parse_wat(
+			r#"
+(module
+	(memory 1)
+	(func (param i32) (result i32) (local i64)
+		(i32.add (local.get 0) (i32.const 1))
+	)
+	(func (export "call") (result i32)
+		(call 0 (i32.const 2))
+	)
+	(data (i32.const 0) "abc")
+)
+"#,
+		);
+		let encoded = elements::serialize(module.clone()).unwrap();
+
+		assert_eq!(serialize(module.clone()).unwrap(), encoded);
+		assert_eq!(deserialize_buffer(&encoded).unwrap(), module);
+		let truncated = &encoded[..encoded.len() - 1];
+		assert_eq!(
+			deserialize_buffer(truncated).unwrap_err().to_string(),
+			elements::deserialize_buffer::<elements::Module>(truncated)
+				.unwrap_err()
+				.to_string()
+		);
+	}
+}
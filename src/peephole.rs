@@ -0,0 +1,131 @@
+//! Replacement of instruction sequences by shorter equivalent ones.
+
+use crate::std::{mem, vec::Vec};
+
+use parity_wasm::elements::{self, Instruction};
+
+/// Whether the instruction only pushes a value, without side effects.
+fn is_pure_push(instruction: &Instruction) -> bool {
+	matches!(
+		instruction,
+		Instruction::GetLocal(_) |
+			Instruction::GetGlobal(_) |
+			Instruction::I32Const(_) |
+			Instruction::I64Const(_) |
+			Instruction::F32Const(_) |
+			Instruction::F64Const(_)
+	)
+}
+
+/// Append `instruction` to `code`, rewriting the end of `code` if a shorter equivalent exists.
+///
+/// Returns the number of rewrites.
+fn push(code: &mut Vec<Instruction>, instruction: Instruction) -> usize {
+	use Instruction::*;
+	let last = code.last();
+	match (last, &instruction) {
+		// `x == 0` is `x.eqz`.
+		(Some(I32Const(0)), I32Eq) => {
+			code.pop();
+			1 + push(code, I32Eqz)
+		},
+		(Some(I64Const(0)), I64Eq) => {
+			code.pop();
+			1 + push(code, I64Eqz)
+		},
+		// A value which is dropped right away needn't be pushed.
+		(Some(pushed), Drop) if is_pure_push(pushed) => {
+			code.pop();
+			1
+		},
+		// Negating the sign twice is a no-op, even for NaNs.
+		(Some(F32Neg), F32Neg) | (Some(F64Neg), F64Neg) => {
+			code.pop();
+			1
+		},
+		// Conditions only tell zero from non-zero, which a double `eqz` preserves.
+		(Some(I32Eqz), If(_) | BrIf(_) | Select)
+			if code.len() >= 2 && code[code.len() - 2] == I32Eqz =>
+		{
+			code.truncate(code.len() - 2);
+			1 + push(code, instruction)
+		},
+		_ => {
+			code.push(instruction);
+			0
+		},
+	}
+}
+
+/// Replace instruction sequences in the function bodies of `module` by shorter equivalents.
+///
+/// The following rewrites are applied until none of them matches anymore:
+///
+/// - `i32.const 0; i32.eq` becomes `i32.eqz`, likewise for `i64`;
+/// - a `local.get`, `global.get` or constant followed by `drop` is removed;
+/// - two consecutive `f32.neg` or `f64.neg` are removed;
+/// - two consecutive `i32.eqz` before an `if`, `br_if` or `select` are removed.
+///
+/// The rewrites don't change the behaviour of the module, but they do change its code, so the
+/// pass should run before any instrumentation.
+///
+/// Returns the number of rewrites.
+pub fn peephole(module: &mut elements::Module) -> usize {
+	let mut rewrites = 0;
+	let bodies = module.code_section_mut().map_or(&mut [][..], |section| section.bodies_mut());
+	for body in bodies {
+		let original = mem::take(body.code_mut().elements_mut());
+		let code = body.code_mut().elements_mut();
+		code.reserve(original.len());
+		for instruction in original {
+			rewrites += push(code, instruction);
+		}
+	}
+	rewrites
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use parity_wasm::elements::Instruction::*;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	#[test]
+	fn shorter_sequences() {
+		let mut module = parse_wat(
+			r#"
+(module
+	(func (param i32 f32) (result i32)
+		(drop (local.get 1))
+		(drop (f32.neg (f32.neg (local.get 1))))
+		(if (i32.eqz (i32.eqz (local.get 0)))
+			(then (br_if 0 (i32.eq (i32.const 0) (i32.const 0))))
+		)
+		(i64.eq (i64.const 1) (i64.const 0))
+	)
+)
+"#,
+		);
+
+		assert_eq!(peephole(&mut module), 6);
+		assert_eq!(
+			module.code_section().unwrap().bodies()[0].code().elements(),
+			&[
+				GetLocal(0),
+				If(elements::BlockType::NoResult),
+				I32Const(0),
+				I32Eqz,
+				BrIf(0),
+				End,
+				I64Const(1),
+				I64Eqz,
+				End,
+			]
+		);
+		assert_eq!(peephole(&mut module), 0);
+	}
+}
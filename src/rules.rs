@@ -3,7 +3,12 @@ use crate::std::collections::BTreeMap as Map;
 #[cfg(features = "std")]
 use crate::std::collections::HashMap as Map;
 
-use crate::std::{num::NonZeroU32, str::FromStr};
+use crate::std::{
+	fmt::{self, Write},
+	num::NonZeroU32,
+	str::{self, FromStr},
+	string::String,
+};
 use parity_wasm::elements::Instruction;
 
 pub struct UnknownInstruction;
@@ -26,6 +31,32 @@ pub trait Rules {
 	/// those costs depend on the stack and must be injected as code into the function calling
 	/// `memory.grow`. Therefore returning `Some` comes with a performance cost.
 	fn memory_grow_cost(&self) -> Option<MemoryGrowCost>;
+
+	/// Returns the cost of each local variable declared by a function.
+	///
+	/// The engine zero-initializes the locals on every call, so their number determines the
+	/// cost of entering a function. It is charged together with the first metered block of the
+	/// function body. Parameters are not included.
+	fn call_per_local_cost(&self) -> u32 {
+		0
+	}
+
+	/// Returns the cost charged for every metered block in addition to the costs of its
+	/// instructions.
+	///
+	/// Metered blocks whose instructions cost nothing aren't charged, including the base cost.
+	fn block_base_cost(&self) -> u32 {
+		0
+	}
+
+	/// Returns the cost of each byte written by `memory.copy`, `memory.fill` and `memory.init`.
+	///
+	/// Like the costs for growing the memory, this depends on an operand of the instruction and
+	/// is charged at runtime in addition to the costs specified by `instruction_cost`. Returning
+	/// 0 leads to no additional charge. It only matters with the `bulk` feature.
+	fn bulk_memory_byte_cost(&self) -> u32 {
+		0
+	}
 }
 
 /// Dynamic costs for memory growth.
@@ -33,16 +64,73 @@ pub trait Rules {
 pub enum MemoryGrowCost {
 	/// Charge the specified amount for each page that the memory is grown by.
 	Linear(NonZeroU32),
+	/// Charge `base` for every `memory.grow`, even by 0 pages, and `per_page` for each page that
+	/// the memory is grown by.
+	///
+	/// If `max_pages` is given, growing the memory by more pages in one step traps before
+	/// charging.
+	Affine { base: u32, per_page: u32, max_pages: Option<u32> },
+}
+
+impl MemoryGrowCost {
+	/// Charge for every `memory.grow`, independent of the number of pages.
+	pub fn base(&self) -> u32 {
+		match self {
+			MemoryGrowCost::Linear(_) => 0,
+			MemoryGrowCost::Affine { base, .. } => *base,
+		}
+	}
+
+	/// Charge for each page the memory is grown by.
+	pub fn per_page(&self) -> u32 {
+		match self {
+			MemoryGrowCost::Linear(per_page) => per_page.get(),
+			MemoryGrowCost::Affine { per_page, .. } => *per_page,
+		}
+	}
+
+	/// Maximal number of pages the memory can be grown by in one step.
+	pub fn max_pages(&self) -> Option<u32> {
+		match self {
+			MemoryGrowCost::Linear(_) => None,
+			MemoryGrowCost::Affine { max_pages, .. } => *max_pages,
+		}
+	}
+
+	/// Gas charged for growing the memory by `pages`, `None` if it traps instead.
+	pub fn cost(&self, pages: u32) -> Option<u64> {
+		if self.max_pages().map_or(false, |max_pages| pages > max_pages) {
+			return None
+		}
+		Some(u64::from(self.base()) + u64::from(pages) * u64::from(self.per_page()))
+	}
+}
+
+impl fmt::Display for MemoryGrowCost {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		write!(f, "{} + {} per page", self.base(), self.per_page())?;
+		if let Some(max_pages) = self.max_pages() {
+			write!(f, " up to {} pages", max_pages)?;
+		}
+		Ok(())
+	}
 }
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[cfg_attr(feature = "rules-serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "rules-serde", serde(rename_all = "snake_case"))]
 pub enum Metering {
 	Regular,
 	Forbidden,
 	Fixed(u32),
 }
 
+/// Class of instructions metered alike.
+///
+/// When (de)serialized the names accepted by `from_str` are used.
 #[derive(Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
+#[cfg_attr(feature = "rules-serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "rules-serde", serde(rename_all = "snake_case"))]
 pub enum InstructionType {
 	Bit,
 	Add,
@@ -54,20 +142,45 @@ pub enum InstructionType {
 	FloatConst,
 	Local,
 	Global,
+	#[cfg_attr(feature = "rules-serde", serde(rename = "flow"))]
 	ControlFlow,
+	/// `drop` and `select`, which only shuffle values.
+	Parametric,
+	#[cfg_attr(feature = "rules-serde", serde(rename = "integer_comp"))]
 	IntegerComparison,
+	#[cfg_attr(feature = "rules-serde", serde(rename = "float_comp"))]
 	FloatComparison,
 	Float,
 	Conversion,
+	/// Conversions between floats and integers, forbidden along with the other float instructions
+	/// by [`Set::with_forbidden_floats`].
+	///
+	/// The saturating conversions of the non-trapping float-to-int proposal, e.g.
+	/// `i32.trunc_sat_f32_s`, belong here as well, but parity-wasm 0.42 can't decode them. Modules
+	/// using them fail to deserialize before they reach the instrumentation.
 	FloatConversion,
+	#[cfg_attr(feature = "rules-serde", serde(rename = "reinterpret"))]
 	Reinterpretation,
 	Unreachable,
 	Nop,
+	#[cfg_attr(feature = "rules-serde", serde(rename = "current_mem"))]
 	CurrentMemory,
+	#[cfg_attr(feature = "rules-serde", serde(rename = "grow_mem"))]
 	GrowMemory,
 
 	#[cfg(feature = "sign_ext")]
 	SignExt,
+
+	#[cfg(feature = "bulk")]
+	Bulk,
+
+	/// Forbidden unless the cost schedule has an entry for it.
+	#[cfg(feature = "simd")]
+	Simd,
+
+	/// Forbidden unless the cost schedule has an entry for it.
+	#[cfg(feature = "atomics")]
+	Atomic,
 }
 
 impl FromStr for InstructionType {
@@ -85,6 +198,7 @@ impl FromStr for InstructionType {
 			"local" => Ok(InstructionType::Local),
 			"global" => Ok(InstructionType::Global),
 			"flow" => Ok(InstructionType::ControlFlow),
+			"parametric" => Ok(InstructionType::Parametric),
 			"integer_comp" => Ok(InstructionType::IntegerComparison),
 			"float_comp" => Ok(InstructionType::FloatComparison),
 			"float" => Ok(InstructionType::Float),
@@ -99,6 +213,15 @@ impl FromStr for InstructionType {
 			#[cfg(feature = "sign_ext")]
 			"sign_ext" => Ok(InstructionType::SignExt),
 
+			#[cfg(feature = "bulk")]
+			"bulk" => Ok(InstructionType::Bulk),
+
+			#[cfg(feature = "simd")]
+			"simd" => Ok(InstructionType::Simd),
+
+			#[cfg(feature = "atomics")]
+			"atomic" => Ok(InstructionType::Atomic),
+
 			_ => Err(UnknownInstruction),
 		}
 	}
@@ -122,8 +245,8 @@ impl InstructionType {
 			Return => InstructionType::ControlFlow,
 			Call(_) => InstructionType::ControlFlow,
 			CallIndirect(_, _) => InstructionType::ControlFlow,
-			Drop => InstructionType::ControlFlow,
-			Select => InstructionType::ControlFlow,
+			Drop => InstructionType::Parametric,
+			Select => InstructionType::Parametric,
 
 			GetLocal(_) => InstructionType::Local,
 			SetLocal(_) => InstructionType::Local,
@@ -300,26 +423,173 @@ impl InstructionType {
 
 			#[cfg(feature = "sign_ext")]
 			SignExt(_) => InstructionType::SignExt,
+
+			#[cfg(feature = "bulk")]
+			Bulk(_) => InstructionType::Bulk,
+
+			#[cfg(feature = "simd")]
+			Simd(_) => InstructionType::Simd,
+
+			#[cfg(feature = "atomics")]
+			Atomics(_) => InstructionType::Atomic,
+		}
+	}
+}
+
+/// Mnemonic of an instruction, that is its textual representation without immediates.
+struct Mnemonic {
+	buf: [u8; 32],
+	len: usize,
+	complete: bool,
+}
+
+impl Mnemonic {
+	/// Returns `None` if the mnemonic doesn't fit into the buffer.
+	fn of(instruction: &Instruction) -> Option<Self> {
+		let mut mnemonic = Mnemonic { buf: [0; 32], len: 0, complete: false };
+		// Writing fails once the mnemonic is complete, which is expected.
+		let _ = write!(mnemonic, "{}", instruction);
+		if mnemonic.len == mnemonic.buf.len() && !mnemonic.complete {
+			return None
+		}
+		Some(mnemonic)
+	}
+
+	fn as_str(&self) -> &str {
+		// Only whole `str`s up to the first space are copied, so this is valid UTF-8.
+		str::from_utf8(&self.buf[..self.len]).expect("buffer only contains whole strs; qed")
+	}
+}
+
+impl Write for Mnemonic {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		let s = match s.find(' ') {
+			Some(pos) => {
+				self.complete = true;
+				&s[..pos]
+			},
+			None => s,
+		};
+		let end = self.len + s.len();
+		if end > self.buf.len() {
+			self.len = self.buf.len();
+			return Err(fmt::Error)
+		}
+		self.buf[self.len..end].copy_from_slice(s.as_bytes());
+		self.len = end;
+		if self.complete {
+			Err(fmt::Error)
+		} else {
+			Ok(())
 		}
 	}
 }
 
+/// A cost schedule.
+///
+/// With the `rules-serde` feature it can be (de)serialized, e.g. to keep it in a config file:
+///
+/// ```json
+/// { "regular": 1, "entries": { "mul": { "fixed": 3 } }, "overrides": { "i64.rotl": "forbidden" } }
+/// ```
+///
+/// All fields but `regular` are optional. SIMD and atomic instructions are forbidden unless there is
+/// an entry for `simd` or `atomic` respectively.
 #[derive(Debug)]
+#[cfg_attr(feature = "rules-serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Set {
 	regular: u32,
+	#[cfg_attr(feature = "rules-serde", serde(default))]
 	entries: Map<InstructionType, Metering>,
+	#[cfg_attr(feature = "rules-serde", serde(default))]
+	overrides: Map<String, Metering>,
+	#[cfg_attr(feature = "rules-serde", serde(default))]
 	grow: u32,
+	#[cfg_attr(feature = "rules-serde", serde(default))]
+	grow_base: u32,
+	#[cfg_attr(feature = "rules-serde", serde(default))]
+	grow_max_pages: Option<u32>,
+	#[cfg_attr(feature = "rules-serde", serde(default))]
+	local: u32,
+	#[cfg_attr(feature = "rules-serde", serde(default))]
+	block_base: u32,
+	#[cfg_attr(feature = "rules-serde", serde(default))]
+	br_table_target: u32,
+	#[cfg_attr(feature = "rules-serde", serde(default))]
+	bulk_byte: u32,
 }
 
 impl Default for Set {
 	fn default() -> Self {
-		Set { regular: 1, entries: Map::new(), grow: 0 }
+		Set {
+			regular: 1,
+			entries: Map::new(),
+			overrides: Map::new(),
+			grow: 0,
+			grow_base: 0,
+			grow_max_pages: None,
+			local: 0,
+			block_base: 0,
+			br_table_target: 0,
+			bulk_byte: 0,
+		}
 	}
 }
 
 impl Set {
 	pub fn new(regular: u32, entries: Map<InstructionType, Metering>) -> Self {
-		Set { regular, entries, grow: 0 }
+		Set {
+			regular,
+			entries,
+			overrides: Map::new(),
+			grow: 0,
+			grow_base: 0,
+			grow_max_pages: None,
+			local: 0,
+			block_base: 0,
+			br_table_target: 0,
+			bulk_byte: 0,
+		}
+	}
+
+	/// Meter the instruction with the given mnemonic, e.g. `i64.rotl`, independently of its
+	/// [`InstructionType`].
+	///
+	/// Overrides take precedence over the entries for instruction types. Mnemonics are the ones
+	/// printed by `parity_wasm`, so locals and globals are accessed by `get_local`, `set_global`
+	/// and so forth.
+	pub fn with_override(mut self, mnemonic: &str, metering: Metering) -> Self {
+		self.overrides.insert(mnemonic.into(), metering);
+		self
+	}
+
+	/// Read a JSON encoded schedule.
+	///
+	/// Malformed schedules are reported as [`std::io::ErrorKind::InvalidData`].
+	#[cfg(all(feature = "rules-serde", feature = "std"))]
+	pub fn from_reader<R: std::io::Read>(mut reader: R) -> std::io::Result<Self> {
+		let mut buf = Vec::new();
+		reader.read_to_end(&mut buf)?;
+		serde_json::from_slice(&buf)
+			.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))
+	}
+
+	fn metering(&self, instruction: &Instruction) -> Option<&Metering> {
+		if !self.overrides.is_empty() {
+			let overridden = Mnemonic::of(instruction)
+				.and_then(|mnemonic| self.overrides.get(mnemonic.as_str()));
+			if overridden.is_some() {
+				return overridden
+			}
+		}
+		let class = InstructionType::op(instruction);
+		match self.entries.get(&class) {
+			#[cfg(feature = "simd")]
+			None if class == InstructionType::Simd => Some(&Metering::Forbidden),
+			#[cfg(feature = "atomics")]
+			None if class == InstructionType::Atomic => Some(&Metering::Forbidden),
+			metering => metering,
+		}
 	}
 
 	pub fn grow_cost(&self) -> u32 {
@@ -331,6 +601,67 @@ impl Set {
 		self
 	}
 
+	pub fn grow_base_cost(&self) -> u32 {
+		self.grow_base
+	}
+
+	/// Charge every `memory.grow` additionally by the given cost, independent of the number of
+	/// pages, so that probing the memory size with `memory.grow 0` isn't free.
+	pub fn with_grow_base_cost(mut self, val: u32) -> Self {
+		self.grow_base = val;
+		self
+	}
+
+	pub fn grow_max_pages(&self) -> Option<u32> {
+		self.grow_max_pages
+	}
+
+	/// Trap on any `memory.grow` by more than `max` pages at once.
+	pub fn with_grow_max_pages(mut self, max: u32) -> Self {
+		self.grow_max_pages = Some(max);
+		self
+	}
+
+	pub fn local_cost(&self) -> u32 {
+		self.local
+	}
+
+	pub fn with_local_cost(mut self, val: u32) -> Self {
+		self.local = val;
+		self
+	}
+
+	pub fn block_base_cost(&self) -> u32 {
+		self.block_base
+	}
+
+	/// Charge every metered block additionally by the given cost, see
+	/// [`Rules::block_base_cost`].
+	pub fn with_block_base_cost(mut self, val: u32) -> Self {
+		self.block_base = val;
+		self
+	}
+
+	pub fn br_table_target_cost(&self) -> u32 {
+		self.br_table_target
+	}
+
+	/// Charge `br_table` additionally by the given cost for each label in its table, so that the
+	/// cost grows with the number of targets.
+	pub fn with_br_table_target_cost(mut self, val: u32) -> Self {
+		self.br_table_target = val;
+		self
+	}
+
+	pub fn bulk_byte_cost(&self) -> u32 {
+		self.bulk_byte
+	}
+
+	pub fn with_bulk_byte_cost(mut self, val: u32) -> Self {
+		self.bulk_byte = val;
+		self
+	}
+
 	pub fn with_forbidden_floats(mut self) -> Self {
 		self.entries.insert(InstructionType::Float, Metering::Forbidden);
 		self.entries.insert(InstructionType::FloatComparison, Metering::Forbidden);
@@ -338,18 +669,231 @@ impl Set {
 		self.entries.insert(InstructionType::FloatConversion, Metering::Forbidden);
 		self
 	}
+
+	#[cfg(feature = "simd")]
+	pub fn with_forbidden_simd(mut self) -> Self {
+		self.entries.insert(InstructionType::Simd, Metering::Forbidden);
+		self
+	}
+
+	/// Allow SIMD instructions at the given cost.
+	#[cfg(feature = "simd")]
+	pub fn with_simd_cost(mut self, val: u32) -> Self {
+		self.entries.insert(InstructionType::Simd, Metering::Fixed(val));
+		self
+	}
+
+	#[cfg(feature = "atomics")]
+	pub fn with_forbidden_atomics(mut self) -> Self {
+		self.entries.insert(InstructionType::Atomic, Metering::Forbidden);
+		self
+	}
+
+	/// Allow atomic instructions at the given cost.
+	#[cfg(feature = "atomics")]
+	pub fn with_atomic_cost(mut self, val: u32) -> Self {
+		self.entries.insert(InstructionType::Atomic, Metering::Fixed(val));
+		self
+	}
+}
+
+/// Parses a JSON encoded schedule.
+#[cfg(feature = "rules-serde")]
+impl FromStr for Set {
+	type Err = serde_json::Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		serde_json::from_str(s)
+	}
 }
 
 impl Rules for Set {
 	fn instruction_cost(&self, instruction: &Instruction) -> Option<u32> {
-		match self.entries.get(&InstructionType::op(instruction)) {
-			None | Some(Metering::Regular) => Some(self.regular),
-			Some(Metering::Fixed(val)) => Some(*val),
-			Some(Metering::Forbidden) => None,
+		let cost = match self.metering(instruction) {
+			None | Some(Metering::Regular) => self.regular,
+			Some(Metering::Fixed(val)) => *val,
+			Some(Metering::Forbidden) => return None,
+		};
+		match instruction {
+			Instruction::BrTable(data) if self.br_table_target > 0 => Some(
+				cost.saturating_add(self.br_table_target.saturating_mul(data.table.len() as u32)),
+			),
+			_ => Some(cost),
 		}
 	}
 
 	fn memory_grow_cost(&self) -> Option<MemoryGrowCost> {
-		NonZeroU32::new(self.grow).map(MemoryGrowCost::Linear)
+		match (self.grow_base, self.grow_max_pages) {
+			(0, None) => NonZeroU32::new(self.grow).map(MemoryGrowCost::Linear),
+			(base, max_pages) =>
+				Some(MemoryGrowCost::Affine { base, per_page: self.grow, max_pages }),
+		}
+	}
+
+	fn call_per_local_cost(&self) -> u32 {
+		self.local
+	}
+
+	fn block_base_cost(&self) -> u32 {
+		self.block_base
+	}
+
+	fn bulk_memory_byte_cost(&self) -> u32 {
+		self.bulk_byte
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn overrides() {
+		let set = Set::new(1, Map::new())
+			.with_override("i64.rotl", Metering::Fixed(5))
+			.with_override("get_local", Metering::Forbidden);
+
+		assert_eq!(set.instruction_cost(&Instruction::I64Rotl), Some(5));
+		assert_eq!(set.instruction_cost(&Instruction::I32Add), Some(1));
+		assert_eq!(set.instruction_cost(&Instruction::GetLocal(3)), None);
+		assert_eq!(set.instruction_cost(&Instruction::I32Load(2, 8)), Some(1));
+	}
+
+	#[test]
+	fn br_table_targets() {
+		use parity_wasm::elements::BrTableData;
+
+		let br_table = |targets: usize| {
+			Instruction::BrTable(Box::new(BrTableData {
+				table: vec![0; targets].into_boxed_slice(),
+				default: 0,
+			}))
+		};
+		let set = Set::default();
+		assert_eq!(set.instruction_cost(&br_table(2)), Some(1));
+		assert_eq!(set.instruction_cost(&br_table(10_000)), Some(1));
+
+		let set = Set::default().with_br_table_target_cost(3);
+		assert_eq!(set.instruction_cost(&br_table(2)), Some(7));
+		assert_eq!(set.instruction_cost(&br_table(10_000)), Some(30_001));
+		assert_eq!(set.instruction_cost(&Instruction::Br(0)), Some(1));
+	}
+
+	#[test]
+	fn two_part_grow_cost() {
+		assert_eq!(Set::default().memory_grow_cost(), None);
+		let linear = Set::default().with_grow_cost(3).memory_grow_cost().unwrap();
+		assert_eq!(linear, MemoryGrowCost::Linear(NonZeroU32::new(3).unwrap()));
+		assert_eq!(linear.cost(0), Some(0));
+
+		let set = Set::default().with_grow_cost(3).with_grow_base_cost(10).with_grow_max_pages(4);
+		let affine = set.memory_grow_cost().unwrap();
+		assert_eq!(affine, MemoryGrowCost::Affine { base: 10, per_page: 3, max_pages: Some(4) });
+		assert_eq!(affine.cost(0), Some(10));
+		assert_eq!(affine.cost(4), Some(22));
+		assert_eq!(affine.cost(5), None);
+	}
+
+	#[test]
+	fn parametric() {
+		assert_eq!(InstructionType::op(&Instruction::Drop), InstructionType::Parametric);
+		assert_eq!(InstructionType::op(&Instruction::Select), InstructionType::Parametric);
+		assert!(matches!("parametric".parse(), Ok(InstructionType::Parametric)));
+
+		let set = Set::new(
+			1,
+			vec![
+				(InstructionType::ControlFlow, Metering::Fixed(0)),
+				(InstructionType::Parametric, Metering::Fixed(2)),
+			]
+			.into_iter()
+			.collect(),
+		);
+		assert_eq!(set.instruction_cost(&Instruction::Drop), Some(2));
+		assert_eq!(set.instruction_cost(&Instruction::Select), Some(2));
+		assert_eq!(set.instruction_cost(&Instruction::Br(0)), Some(0));
+	}
+
+	#[cfg(feature = "sign_ext")]
+	#[test]
+	fn sign_ext() {
+		use parity_wasm::elements::SignExtInstruction;
+
+		let extend = Instruction::SignExt(SignExtInstruction::I32Extend8S);
+		assert_eq!(InstructionType::op(&extend), InstructionType::SignExt);
+		assert!(matches!("sign_ext".parse(), Ok(InstructionType::SignExt)));
+
+		let set =
+			Set::new(1, vec![(InstructionType::SignExt, Metering::Fixed(3))].into_iter().collect());
+		assert_eq!(set.instruction_cost(&extend), Some(3));
+		let set = set.with_override("i32.extend8_s", Metering::Forbidden);
+		assert_eq!(set.instruction_cost(&extend), None);
+	}
+
+	#[cfg(feature = "simd")]
+	#[test]
+	fn simd() {
+		use parity_wasm::elements::SimdInstruction;
+
+		let add = Instruction::Simd(SimdInstruction::I32x4Add);
+		assert_eq!(InstructionType::op(&add), InstructionType::Simd);
+		assert!(matches!("simd".parse(), Ok(InstructionType::Simd)));
+
+		assert_eq!(Set::default().instruction_cost(&add), None);
+		assert_eq!(Set::default().with_simd_cost(4).instruction_cost(&add), Some(4));
+		assert_eq!(
+			Set::default().with_simd_cost(4).with_forbidden_simd().instruction_cost(&add),
+			None
+		);
+		let set = Set::default().with_override("i32x4.add", Metering::Fixed(2));
+		assert_eq!(set.instruction_cost(&add), Some(2));
+	}
+
+	#[cfg(feature = "atomics")]
+	#[test]
+	fn atomics() {
+		use parity_wasm::elements::{AtomicsInstruction, MemArg};
+
+		let add = Instruction::Atomics(AtomicsInstruction::I32AtomicRmwAdd(MemArg {
+			align: 2,
+			offset: 0,
+		}));
+		assert_eq!(InstructionType::op(&add), InstructionType::Atomic);
+		assert!(matches!("atomic".parse(), Ok(InstructionType::Atomic)));
+
+		assert_eq!(Set::default().instruction_cost(&add), None);
+		assert_eq!(Set::default().with_atomic_cost(5).instruction_cost(&add), Some(5));
+		let set = Set::default().with_atomic_cost(5).with_forbidden_atomics();
+		assert_eq!(set.instruction_cost(&add), None);
+	}
+
+	#[test]
+	fn mnemonic() {
+		let mnemonic = |instruction| Mnemonic::of(&instruction).unwrap().as_str().to_owned();
+		assert_eq!(mnemonic(Instruction::I32Load(2, 8)), "i32.load");
+		assert_eq!(mnemonic(Instruction::Call(7)), "call");
+		assert_eq!(mnemonic(Instruction::Nop), "nop");
+	}
+
+	#[cfg(feature = "rules-serde")]
+	#[test]
+	fn from_json() {
+		let set: Set = r#"{
+			"regular": 2,
+			"entries": { "flow": { "fixed": 5 }, "float": "forbidden" },
+			"overrides": { "i64.rotl": { "fixed": 7 } },
+			"grow": 100
+		}"#
+		.parse()
+		.unwrap();
+
+		assert_eq!(set.instruction_cost(&Instruction::I32Add), Some(2));
+		assert_eq!(set.instruction_cost(&Instruction::Br(0)), Some(5));
+		assert_eq!(set.instruction_cost(&Instruction::F32Add), None);
+		assert_eq!(set.instruction_cost(&Instruction::I64Rotl), Some(7));
+		assert_eq!(set.grow_cost(), 100);
+
+		let minimal = Set::from_reader(&br#"{ "regular": 3 }"#[..]).unwrap();
+		assert_eq!(minimal.instruction_cost(&Instruction::Nop), Some(3));
 	}
 }
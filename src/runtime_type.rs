@@ -2,45 +2,159 @@ use self::elements::{
 	ExportEntry, External, GlobalEntry, GlobalType, InitExpr, Instruction, Internal, Module,
 	ValueType,
 };
+use crate::{std::fmt, IndexPlacement};
 use byteorder::{ByteOrder, LittleEndian};
 use parity_wasm::{builder, elements};
 
-pub fn inject_runtime_type(module: Module, runtime_type: [u8; 4], runtime_version: u32) -> Module {
-	let runtime_type: u32 = LittleEndian::read_u32(&runtime_type);
-	let globals_count: u32 = match module.global_section() {
-		Some(section) => section.entries().len() as u32,
-		None => 0,
-	};
-	let imported_globals_count: u32 = match module.import_section() {
-		Some(section) => section
-			.entries()
-			.iter()
-			.filter(|e| matches!(*e.external(), External::Global(_)))
-			.count() as u32,
-		None => 0,
+const RUNTIME_TYPE: &str = "RUNTIME_TYPE";
+const RUNTIME_VERSION: &str = "RUNTIME_VERSION";
+
+#[derive(Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+	/// The export already exists and overwriting it was not requested.
+	AlreadyExported(&'static str),
+	/// The existing export doesn't refer to a global defined by the module.
+	NotDefinedGlobal(&'static str),
+	/// The globals can't be placed at the `index` given to
+	/// [`inject_runtime_type_with_placement`].
+	InvalidPlacement { index: u32 },
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match *self {
+			Error::AlreadyExported(field) => write!(f, "Module already exports `{}`", field),
+			Error::NotDefinedGlobal(field) => {
+				write!(f, "Exported `{}` is not a global defined by the module", field)
+			},
+			Error::InvalidPlacement { index } =>
+				write!(f, "The runtime type globals can't be placed at index {}", index),
+		}
+	}
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+fn init_expr(value: u32) -> InitExpr {
+	InitExpr::new(vec![Instruction::I32Const(value as i32), Instruction::End])
+}
+
+/// Set the init value of the global backing the export `field`.
+///
+/// Returns `Ok(false)` if there is no such export.
+fn update_export(
+	module: &mut Module,
+	field: &'static str,
+	value: u32,
+	overwrite: bool,
+) -> Result<bool, Error> {
+	let global_idx = match module
+		.export_section()
+		.and_then(|section| section.entries().iter().find(|e| e.field() == field))
+	{
+		None => return Ok(false),
+		Some(_) if !overwrite => return Err(Error::AlreadyExported(field)),
+		Some(export) => match *export.internal() {
+			Internal::Global(global_idx) => global_idx,
+			_ => return Err(Error::NotDefinedGlobal(field)),
+		},
 	};
-	let total_globals_count: u32 = globals_count + imported_globals_count;
-
-	builder::from_module(module)
-		.with_global(GlobalEntry::new(
-			GlobalType::new(ValueType::I32, false),
-			InitExpr::new(vec![Instruction::I32Const(runtime_type as i32), Instruction::End]),
-		))
-		.with_export(ExportEntry::new("RUNTIME_TYPE".into(), Internal::Global(total_globals_count)))
-		.with_global(GlobalEntry::new(
-			GlobalType::new(ValueType::I32, false),
-			InitExpr::new(vec![Instruction::I32Const(runtime_version as i32), Instruction::End]),
-		))
-		.with_export(ExportEntry::new(
-			"RUNTIME_VERSION".into(),
-			Internal::Global(total_globals_count + 1),
-		))
-		.build()
+
+	let imported_globals_count = module.import_count(elements::ImportCountType::Global) as u32;
+	let global = global_idx
+		.checked_sub(imported_globals_count)
+		.and_then(|idx| module.global_section_mut()?.entries_mut().get_mut(idx as usize))
+		.ok_or(Error::NotDefinedGlobal(field))?;
+	*global.init_expr_mut() = init_expr(value);
+	Ok(true)
+}
+
+/// Export the runtime type and version as the globals `RUNTIME_TYPE` and `RUNTIME_VERSION`.
+///
+/// If the module already exports them, e.g. because it was processed before, the init values of
+/// the existing globals are updated in place when `overwrite` is set and an error is returned
+/// otherwise.
+pub fn inject_runtime_type(
+	module: Module,
+	runtime_type: [u8; 4],
+	runtime_version: u32,
+	overwrite: bool,
+) -> Result<Module, Error> {
+	inject_runtime_type_with_placement(
+		module,
+		runtime_type,
+		runtime_version,
+		overwrite,
+		IndexPlacement::Last,
+	)
+}
+
+/// Like [`inject_runtime_type`], but places the added globals in the global index space as
+/// given, shifting the globals from there on.
+///
+/// The added globals stay adjacent, `RUNTIME_TYPE` first.
+pub fn inject_runtime_type_with_placement(
+	mut module: Module,
+	runtime_type: [u8; 4],
+	runtime_version: u32,
+	overwrite: bool,
+	placement: IndexPlacement,
+) -> Result<Module, Error> {
+	let runtime_type: u32 = LittleEndian::read_u32(&runtime_type);
+	let first_global = placement
+		.index(
+			module.import_count(elements::ImportCountType::Global) as u32,
+			module.globals_space() as u32,
+		)
+		.map_err(|index| Error::InvalidPlacement { index })?;
+	let mut added = 0;
+
+	for (field, value) in [(RUNTIME_TYPE, runtime_type), (RUNTIME_VERSION, runtime_version)] {
+		if update_export(&mut module, field, value, overwrite)? {
+			continue
+		}
+
+		let globals_count: u32 = match module.global_section() {
+			Some(section) => section.entries().len() as u32,
+			None => 0,
+		};
+		let imported_globals_count: u32 = match module.import_section() {
+			Some(section) => section
+				.entries()
+				.iter()
+				.filter(|e| matches!(*e.external(), External::Global(_)))
+				.count() as u32,
+			None => 0,
+		};
+		let total_globals_count: u32 = globals_count + imported_globals_count;
+
+		module = builder::from_module(module)
+			.with_global(GlobalEntry::new(GlobalType::new(ValueType::I32, false), init_expr(value)))
+			.with_export(ExportEntry::new(field.into(), Internal::Global(total_globals_count)))
+			.build();
+		crate::indices::move_global(&mut module, total_globals_count, first_global + added);
+		added += 1;
+	}
+
+	Ok(module)
 }
 
 #[cfg(test)]
 mod tests {
 	use super::*;
+
+	fn global_value(module: &Module, field: &str) -> Option<Instruction> {
+		let export = module.export_section()?.entries().iter().find(|e| e.field() == field)?;
+		let global_idx = match *export.internal() {
+			Internal::Global(idx) => idx,
+			_ => return None,
+		};
+		let global = &module.global_section()?.entries()[global_idx as usize];
+		global.init_expr().code().first().cloned()
+	}
+
 	#[test]
 	fn it_injects() {
 		let mut module = builder::module()
@@ -51,11 +165,73 @@ mod tests {
 			.build();
 		let mut runtime_type: [u8; 4] = Default::default();
 		runtime_type.copy_from_slice(b"emcc");
-		module = inject_runtime_type(module, runtime_type, 1);
+		module = inject_runtime_type(module, runtime_type, 1, false).unwrap();
 		let global_section = module.global_section().expect("Global section expected");
 		assert_eq!(3, global_section.entries().len());
 		let export_section = module.export_section().expect("Export section expected");
 		assert!(export_section.entries().iter().any(|e| e.field() == "RUNTIME_TYPE"));
 		assert!(export_section.entries().iter().any(|e| e.field() == "RUNTIME_VERSION"));
 	}
+
+	#[test]
+	fn reinjection() {
+		let module = inject_runtime_type(builder::module().build(), *b"emcc", 1, false).unwrap();
+
+		assert_eq!(
+			inject_runtime_type(module.clone(), *b"emcc", 2, false).unwrap_err(),
+			Error::AlreadyExported("RUNTIME_TYPE")
+		);
+
+		let module = inject_runtime_type(module, *b"wasm", 2, true).unwrap();
+		assert_eq!(module.global_section().unwrap().entries().len(), 2);
+		assert_eq!(module.export_section().unwrap().entries().len(), 2);
+		assert_eq!(
+			global_value(&module, "RUNTIME_TYPE"),
+			Some(Instruction::I32Const(LittleEndian::read_u32(b"wasm") as i32))
+		);
+		assert_eq!(global_value(&module, "RUNTIME_VERSION"), Some(Instruction::I32Const(2)));
+	}
+	#[test]
+	fn placement() {
+		let module = builder::module()
+			.with_global(GlobalEntry::new(GlobalType::new(ValueType::I32, true), init_expr(7)))
+			.with_export(ExportEntry::new("sp".into(), Internal::Global(0)))
+			.build();
+
+		let first = inject_runtime_type_with_placement(
+			module.clone(),
+			*b"emcc",
+			1,
+			false,
+			IndexPlacement::First,
+		)
+		.unwrap();
+		assert_eq!(
+			global_value(&first, "RUNTIME_TYPE"),
+			Some(Instruction::I32Const(LittleEndian::read_u32(b"emcc") as i32))
+		);
+		assert_eq!(global_value(&first, "RUNTIME_VERSION"), Some(Instruction::I32Const(1)));
+		assert_eq!(global_value(&first, "sp"), Some(Instruction::I32Const(7)));
+		let export_idx = |module: &Module, field| {
+			module
+				.export_section()
+				.unwrap()
+				.entries()
+				.iter()
+				.find(|e| e.field() == field)
+				.map(|e| *e.internal())
+		};
+		assert_eq!(export_idx(&first, "RUNTIME_TYPE"), Some(Internal::Global(0)));
+		assert_eq!(export_idx(&first, "RUNTIME_VERSION"), Some(Internal::Global(1)));
+		assert_eq!(export_idx(&first, "sp"), Some(Internal::Global(2)));
+
+		let last = inject_runtime_type(module.clone(), *b"emcc", 1, false).unwrap();
+		assert_eq!(export_idx(&last, "sp"), Some(Internal::Global(0)));
+
+		assert_eq!(
+			inject_runtime_type_with_placement(module, *b"emcc", 1, false, IndexPlacement::At(2))
+				.unwrap_err(),
+			Error::InvalidPlacement { index: 2 }
+		);
+	}
 }
@@ -0,0 +1,91 @@
+//! Accessors for the sections of a module.
+//!
+//! For each kind of known section there is a function returning a reference to it, one returning
+//! a mutable reference, and one inserting an empty section at the correct position if the module
+//! doesn't have one yet.
+
+use parity_wasm::elements::{
+	CodeSection, DataSection, ElementSection, ExportSection, FunctionSection, GlobalSection,
+	ImportSection, MemorySection, Module, Section, TableSection, TypeSection,
+};
+
+macro_rules! accessors {
+	($($variant:ident($ty:ident) => $get:ident, $get_mut:ident, $get_or_insert:ident;)*) => {
+		$(
+			#[doc = concat!("The `", stringify!($ty), "` of the module, if any.")]
+			pub fn $get(module: &Module) -> Option<&$ty> {
+				module.sections().iter().find_map(|section| match section {
+					Section::$variant(section) => Some(section),
+					_ => None,
+				})
+			}
+
+			#[doc = concat!("The mutable `", stringify!($ty), "` of the module, if any.")]
+			pub fn $get_mut(module: &mut Module) -> Option<&mut $ty> {
+				module.sections_mut().iter_mut().find_map(|section| match section {
+					Section::$variant(section) => Some(section),
+					_ => None,
+				})
+			}
+
+			#[doc = concat!(
+				"The mutable `", stringify!($ty), "` of the module, inserting an empty one if ",
+				"there is none."
+			)]
+			pub fn $get_or_insert(module: &mut Module) -> &mut $ty {
+				if $get(module).is_none() {
+					module
+						.insert_section(Section::$variant(Default::default()))
+						.expect("the module has no such section; qed");
+				}
+				$get_mut(module).expect("the section exists or was inserted above; qed")
+			}
+		)*
+	};
+}
+
+accessors! {
+	Type(TypeSection) => type_section, type_section_mut, get_or_insert_type_section;
+	Import(ImportSection) => import_section, import_section_mut, get_or_insert_import_section;
+	Function(FunctionSection) =>
+		function_section, function_section_mut, get_or_insert_function_section;
+	Table(TableSection) => table_section, table_section_mut, get_or_insert_table_section;
+	Memory(MemorySection) => memory_section, memory_section_mut, get_or_insert_memory_section;
+	Global(GlobalSection) => global_section, global_section_mut, get_or_insert_global_section;
+	Export(ExportSection) => export_section, export_section_mut, get_or_insert_export_section;
+	Element(ElementSection) =>
+		elements_section, elements_section_mut, get_or_insert_elements_section;
+	Code(CodeSection) => code_section, code_section_mut, get_or_insert_code_section;
+	Data(DataSection) => data_section, data_section_mut, get_or_insert_data_section;
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use parity_wasm::builder;
+
+	#[test]
+	fn inserts_in_order() {
+		let mut module =
+			builder::module().function().signature().build().body().build().build().build();
+		assert!(global_section(&module).is_none());
+
+		get_or_insert_global_section(&mut module);
+		get_or_insert_data_section(&mut module);
+		get_or_insert_global_section(&mut module);
+
+		let kinds = module
+			.sections()
+			.iter()
+			.map(|section| match section {
+				Section::Type(_) => "type",
+				Section::Function(_) => "function",
+				Section::Global(_) => "global",
+				Section::Code(_) => "code",
+				Section::Data(_) => "data",
+				_ => "other",
+			})
+			.collect::<crate::std::vec::Vec<_>>();
+		assert_eq!(kinds, vec!["type", "function", "global", "code", "data"]);
+	}
+}
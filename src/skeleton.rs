@@ -0,0 +1,105 @@
+//! Stripping the code of a module while keeping its structure.
+
+use crate::std::vec::Vec;
+
+use parity_wasm::elements::{self, Instruction};
+
+/// Copy of `module` with every function body replaced by `unreachable`.
+///
+/// The signatures, imports, exports, tables, memories, globals and the element and data segments
+/// are kept, so the skeleton links against the same host functions and has the same memory
+/// layout as the original module. This makes it suitable as a host-side mock or for sharing a
+/// reproduction of a bug without the code of the module.
+///
+/// The start function is dropped, since calling it would trap on instantiation. The custom
+/// sections, including the names, are dropped as well, as they may describe the removed code.
+pub fn skeletonize(module: &elements::Module) -> elements::Module {
+	let sections = module
+		.sections()
+		.iter()
+		.filter_map(|section| match section {
+			elements::Section::Code(code) => {
+				let bodies = code
+					.bodies()
+					.iter()
+					.map(|_| {
+						elements::FuncBody::new(
+							Vec::new(),
+							elements::Instructions::new(vec![
+								Instruction::Unreachable,
+								Instruction::End,
+							]),
+						)
+					})
+					.collect();
+				Some(elements::Section::Code(elements::CodeSection::with_bodies(bodies)))
+			},
+			elements::Section::Start(_) |
+			elements::Section::Custom(_) |
+			elements::Section::Name(_) |
+			elements::Section::Reloc(_) |
+			elements::Section::Unparsed { .. } => None,
+			section => Some(section.clone()),
+		})
+		.collect();
+	elements::Module::new(sections)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	#[test]
+	fn keeps_structure() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "ext" (func $ext (param i32) (result i32)))
+	(memory (export "memory") 1 2)
+	(table 1 funcref)
+	(global $counter (mut i32) (i32.const 0))
+	(elem (i32.const 0) $secret)
+	(func $secret (export "secret") (param i32) (result i32) (local i64)
+		(global.set $counter (call $ext (local.get 0)))
+		(i32.mul (local.get 0) (i32.const 42))
+	)
+	(func $init
+		(global.set $counter (i32.const 1))
+	)
+	(start $init)
+	(data (i32.const 8) "layout")
+)
+"#,
+		)
+		.parse_names()
+		.unwrap();
+
+		let skeleton = skeletonize(&module);
+
+		for body in skeleton.code_section().unwrap().bodies() {
+			assert!(body.locals().is_empty());
+			assert_eq!(body.code().elements(), &[Instruction::Unreachable, Instruction::End]);
+		}
+		assert_eq!(skeleton.code_section().unwrap().bodies().len(), 2);
+		assert_eq!(skeleton.type_section(), module.type_section());
+		assert_eq!(skeleton.import_section(), module.import_section());
+		assert_eq!(skeleton.function_section(), module.function_section());
+		assert_eq!(skeleton.export_section(), module.export_section());
+		assert_eq!(skeleton.memory_section(), module.memory_section());
+		assert_eq!(skeleton.elements_section(), module.elements_section());
+		assert_eq!(skeleton.data_section(), module.data_section());
+		assert_eq!(skeleton.start_section(), None);
+		assert!(skeleton.names_section().is_none());
+
+		let binary = parity_wasm::serialize(skeleton).expect("serialization failed");
+		wabt::Module::read_binary(&binary, &Default::default())
+			.unwrap()
+			.validate()
+			.expect("skeleton to be valid");
+	}
+}
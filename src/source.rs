@@ -0,0 +1,157 @@
+//! Location of the binaries produced by cargo, the input of [`crate::build`].
+
+use std::{
+	env, fs,
+	path::{Path, PathBuf},
+};
+
+use super::SourceTarget;
+
+pub const UNKNOWN_TRIPLET: &str = "wasm32-unknown-unknown";
+pub const EMSCRIPTEN_TRIPLET: &str = "wasm32-unknown-emscripten";
+
+/// Configuration of previous build step (cargo compilation)
+#[derive(Debug)]
+pub struct SourceInput<'a> {
+	target_dir: &'a str,
+	bin_name: &'a str,
+	final_name: &'a str,
+	target: SourceTarget,
+	artifact: Option<&'a str>,
+}
+
+impl<'a> SourceInput<'a> {
+	pub fn new<'b>(target_dir: &'b str, bin_name: &'b str) -> SourceInput<'b> {
+		SourceInput {
+			target_dir,
+			bin_name,
+			final_name: bin_name,
+			target: SourceTarget::Emscripten,
+			artifact: None,
+		}
+	}
+
+	pub fn unknown(mut self) -> Self {
+		self.target = SourceTarget::Unknown;
+		self
+	}
+
+	pub fn emscripten(mut self) -> Self {
+		self.target = SourceTarget::Emscripten;
+		self
+	}
+
+	pub fn with_final(mut self, final_name: &'a str) -> Self {
+		self.final_name = final_name;
+		self
+	}
+
+	/// Use the binary at `path` instead of looking it up in the target directory.
+	pub fn with_artifact(mut self, path: &'a str) -> Self {
+		self.artifact = Some(path);
+		self
+	}
+
+	pub fn target_dir(&self) -> &str {
+		self.target_dir
+	}
+
+	pub fn bin_name(&self) -> &str {
+		self.bin_name
+	}
+
+	pub fn final_name(&self) -> &str {
+		self.final_name
+	}
+
+	pub fn target(&self) -> SourceTarget {
+		self.target
+	}
+
+	/// Path of the binary produced by cargo.
+	///
+	/// Unless given explicitly, this is `<target_dir>/<triplet>/release/<bin_name>.wasm` with
+	/// dashes in the binary name replaced by underscores.
+	pub fn artifact_path(&self) -> PathBuf {
+		if let Some(artifact) = self.artifact {
+			return PathBuf::from(artifact)
+		}
+		let mut path = PathBuf::from(self.target_dir);
+		path.push(match self.target {
+			SourceTarget::Emscripten => EMSCRIPTEN_TRIPLET,
+			SourceTarget::Unknown => UNKNOWN_TRIPLET,
+		});
+		path.push("release");
+		path.push(format!("{}.wasm", self.bin_name.replace('-', "_")));
+		path
+	}
+
+	/// Path of the final binary, `<target_dir>/<final_name>.wasm`.
+	pub fn final_path(&self) -> PathBuf {
+		let mut path = PathBuf::from(self.target_dir);
+		path.push(format!("{}.wasm", self.final_name));
+		path
+	}
+}
+
+/// Target directory cargo uses for the package in `manifest_dir`.
+///
+/// This is `CARGO_TARGET_DIR` if it is set, otherwise the `target` directory next to the manifest
+/// of the closest workspace containing the package, or of the package itself.
+pub fn cargo_target_dir(manifest_dir: &Path) -> PathBuf {
+	if let Some(target_dir) = env::var_os("CARGO_TARGET_DIR") {
+		return PathBuf::from(target_dir)
+	}
+	let root = manifest_dir
+		.ancestors()
+		.find(|dir| is_workspace_root(dir))
+		.unwrap_or(manifest_dir);
+	root.join("target")
+}
+
+fn is_workspace_root(dir: &Path) -> bool {
+	fs::read_to_string(dir.join("Cargo.toml"))
+		.map(|manifest| manifest.lines().any(|line| line.trim() == "[workspace]"))
+		.unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tempdir::TempDir;
+
+	#[test]
+	fn artifact_path() {
+		let input = SourceInput::new("target", "example-wasm").unknown().with_final("final");
+		assert_eq!(
+			input.artifact_path(),
+			Path::new("target/wasm32-unknown-unknown/release/example_wasm.wasm")
+		);
+		assert_eq!(input.final_path(), Path::new("target/final.wasm"));
+
+		let input = input.with_artifact("out/example.wasm");
+		assert_eq!(input.artifact_path(), Path::new("out/example.wasm"));
+		assert_eq!(input.final_path(), Path::new("target/final.wasm"));
+	}
+
+	#[test]
+	fn target_dir_resolution() {
+		env::remove_var("CARGO_TARGET_DIR");
+		let tmp_dir = TempDir::new("workspace").expect("temp dir failed");
+		let member = tmp_dir.path().join("contracts").join("token");
+		fs::create_dir_all(&member).expect("create dir failed");
+		fs::write(member.join("Cargo.toml"), "[package]\nname = \"token\"\n")
+			.expect("write manifest failed");
+
+		assert_eq!(cargo_target_dir(&member), member.join("target"));
+
+		fs::write(tmp_dir.path().join("Cargo.toml"), "[workspace]\nmembers = [\"contracts/*\"]\n")
+			.expect("write manifest failed");
+		assert_eq!(cargo_target_dir(&member), tmp_dir.path().join("target"));
+
+		env::set_var("CARGO_TARGET_DIR", "/tmp/custom-target");
+		let target_dir = cargo_target_dir(&member);
+		env::remove_var("CARGO_TARGET_DIR");
+		assert_eq!(target_dir, Path::new("/tmp/custom-target"));
+	}
+}
@@ -0,0 +1,576 @@
+//! Stack effects of instructions.
+//!
+//! [`function_stack_effects`] iterates over the body of a function and yields every instruction
+//! along with the number of values it pops from and pushes onto the value stack. Types of callees
+//! and arities of branch targets are resolved along the way.
+//!
+//! Structured control instructions describe their effect on the enclosing frame: `if` pops the
+//! condition, while `block`, `loop`, `else` and `end` neither pop nor push. Instructions that
+//! never pass control further (`unreachable`, `br`, `br_table`, `return`) only pop their
+//! operands; the stack is polymorphic after them.
+
+use crate::std::{fmt, slice, string::String, vec::Vec};
+
+use parity_wasm::elements::{self, BlockType, Instruction, Type, ValueType};
+
+#[cfg(feature = "atomics")]
+use parity_wasm::elements::AtomicsInstruction;
+#[cfg(feature = "bulk")]
+use parity_wasm::elements::BulkInstruction;
+#[cfg(feature = "sign_ext")]
+use parity_wasm::elements::SignExtInstruction;
+#[cfg(feature = "simd")]
+use parity_wasm::elements::SimdInstruction;
+
+/// Error that occured while resolving stack effects.
+///
+/// This means that the module is invalid.
+#[derive(Debug)]
+pub struct Error(pub(crate) String);
+
+impl Error {
+	/// Description of the error.
+	pub fn message(&self) -> &str {
+		&self.0
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		write!(f, "{}", self.0)
+	}
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+/// Number of values an instruction pops from and pushes onto the value stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackEffect {
+	/// Number of values popped.
+	pub pops: u32,
+	/// Number of values pushed.
+	pub pushes: u32,
+}
+
+impl StackEffect {
+	fn new(pops: u32, pushes: u32) -> Self {
+		StackEffect { pops, pushes }
+	}
+}
+
+/// Iterator over the instructions of a function body with their stack effects.
+///
+/// Created by [`function_stack_effects`].
+pub struct FunctionStackEffects<'a> {
+	module: &'a elements::Module,
+	instructions: slice::Iter<'a, Instruction>,
+	func_arity: u32,
+	/// Branch arities of the open control frames, the implicit function frame comes first.
+	frames: Vec<u32>,
+}
+
+/// Iterate over the body of the defined function `func_idx` (that is, the index into the code
+/// section) yielding each instruction and its [`StackEffect`].
+///
+/// This function expects the module to be validated.
+pub fn function_stack_effects(
+	module: &elements::Module,
+	func_idx: u32,
+) -> Result<FunctionStackEffects<'_>, Error> {
+	let func_section =
+		module.function_section().ok_or_else(|| Error("No function section".into()))?;
+	let code_section = module.code_section().ok_or_else(|| Error("No code section".into()))?;
+	let type_section = module.type_section().ok_or_else(|| Error("No type section".into()))?;
+
+	// Get a signature and a body of the specified function.
+	let func_sig_idx = func_section
+		.entries()
+		.get(func_idx as usize)
+		.ok_or_else(|| Error("Function is not found in func section".into()))?
+		.type_ref();
+	let Type::Function(func_signature) = type_section
+		.types()
+		.get(func_sig_idx as usize)
+		.ok_or_else(|| Error("Function is not found in func section".into()))?;
+	let body = code_section
+		.bodies()
+		.get(func_idx as usize)
+		.ok_or_else(|| Error("Function body for the index isn't found".into()))?;
+
+	let func_arity = func_signature.results().len() as u32;
+	Ok(FunctionStackEffects {
+		module,
+		instructions: body.code().elements().iter(),
+		func_arity,
+		frames: vec![func_arity],
+	})
+}
+
+impl<'a> FunctionStackEffects<'a> {
+	/// Returns the branch arity of a frame by specified depth relative to the top of the
+	/// control stack.
+	fn branch_arity(&self, rel_depth: u32) -> Result<u32, Error> {
+		let last_idx = self
+			.frames
+			.len()
+			.checked_sub(1)
+			.ok_or_else(|| Error("control stack is empty".into()))?;
+		let idx = last_idx
+			.checked_sub(rel_depth as usize)
+			.ok_or_else(|| Error("control stack out-of-bounds".into()))?;
+		Ok(self.frames[idx])
+	}
+
+	fn effect(&mut self, instruction: &Instruction) -> Result<StackEffect, Error> {
+		use parity_wasm::elements::Instruction::*;
+
+		let effect = match instruction {
+			Nop | Unreachable | Else => StackEffect::new(0, 0),
+			Block(ty) | Loop(ty) | If(ty) => {
+				let end_arity = if *ty == BlockType::NoResult { 0 } else { 1 };
+				let branch_arity = if let Loop(_) = instruction { 0 } else { end_arity };
+				self.frames.push(branch_arity);
+				// `if` pops the condition.
+				StackEffect::new(if let If(_) = instruction { 1 } else { 0 }, 0)
+			},
+			End => {
+				self.frames.pop().ok_or_else(|| Error("control stack is empty".into()))?;
+				StackEffect::new(0, 0)
+			},
+			Br(target) => StackEffect::new(self.branch_arity(*target)?, 0),
+			BrIf(target) => {
+				// Pops the values for the destination block and the condition and pushes the
+				// values back, in case the branch is not taken.
+				let arity = self.branch_arity(*target)?;
+				StackEffect::new(arity + 1, arity)
+			},
+			BrTable(br_table_data) => {
+				let arity_of_default = self.branch_arity(br_table_data.default)?;
+
+				// Check that all jump targets have an equal arities.
+				for target in &*br_table_data.table {
+					if self.branch_arity(*target)? != arity_of_default {
+						return Err(Error("Arity of all jump-targets must be equal".into()))
+					}
+				}
+
+				// Pops the values for the destination block and the index.
+				StackEffect::new(arity_of_default + 1, 0)
+			},
+			Return => StackEffect::new(self.func_arity, 0),
+			Call(idx) => {
+				let ty = resolve_func_type(*idx, self.module)?;
+				StackEffect::new(ty.params().len() as u32, ty.results().len() as u32)
+			},
+			CallIndirect(x, _) => {
+				let Type::Function(ty) = self
+					.module
+					.type_section()
+					.and_then(|ts| ts.types().get(*x as usize))
+					.ok_or_else(|| Error("Type not found".into()))?;
+				// Also pops the offset into the function table.
+				StackEffect::new(ty.params().len() as u32 + 1, ty.results().len() as u32)
+			},
+			Drop => StackEffect::new(1, 0),
+			// Pops two values and one condition and pushes the selected value.
+			Select => StackEffect::new(3, 1),
+			GetLocal(_) | GetGlobal(_) => StackEffect::new(0, 1),
+			SetLocal(_) | SetGlobal(_) => StackEffect::new(1, 0),
+			TeeLocal(_) => StackEffect::new(1, 1),
+
+			I32Load(_, _) |
+			I64Load(_, _) |
+			F32Load(_, _) |
+			F64Load(_, _) |
+			I32Load8S(_, _) |
+			I32Load8U(_, _) |
+			I32Load16S(_, _) |
+			I32Load16U(_, _) |
+			I64Load8S(_, _) |
+			I64Load8U(_, _) |
+			I64Load16S(_, _) |
+			I64Load16U(_, _) |
+			I64Load32S(_, _) |
+			I64Load32U(_, _) => StackEffect::new(1, 1),
+
+			I32Store(_, _) |
+			I64Store(_, _) |
+			F32Store(_, _) |
+			F64Store(_, _) |
+			I32Store8(_, _) |
+			I32Store16(_, _) |
+			I64Store8(_, _) |
+			I64Store16(_, _) |
+			I64Store32(_, _) => StackEffect::new(2, 0),
+
+			CurrentMemory(_) => StackEffect::new(0, 1),
+			GrowMemory(_) => StackEffect::new(1, 1),
+
+			I32Const(_) | I64Const(_) | F32Const(_) | F64Const(_) => StackEffect::new(0, 1),
+
+			I32Eqz | I64Eqz => StackEffect::new(1, 1),
+
+			I32Eq | I32Ne | I32LtS | I32LtU | I32GtS | I32GtU | I32LeS | I32LeU | I32GeS |
+			I32GeU | I64Eq | I64Ne | I64LtS | I64LtU | I64GtS | I64GtU | I64LeS | I64LeU |
+			I64GeS | I64GeU | F32Eq | F32Ne | F32Lt | F32Gt | F32Le | F32Ge | F64Eq | F64Ne |
+			F64Lt | F64Gt | F64Le | F64Ge => StackEffect::new(2, 1),
+
+			I32Clz | I32Ctz | I32Popcnt | I64Clz | I64Ctz | I64Popcnt | F32Abs | F32Neg |
+			F32Ceil | F32Floor | F32Trunc | F32Nearest | F32Sqrt | F64Abs | F64Neg | F64Ceil |
+			F64Floor | F64Trunc | F64Nearest | F64Sqrt => StackEffect::new(1, 1),
+
+			I32Add | I32Sub | I32Mul | I32DivS | I32DivU | I32RemS | I32RemU | I32And | I32Or |
+			I32Xor | I32Shl | I32ShrS | I32ShrU | I32Rotl | I32Rotr | I64Add | I64Sub |
+			I64Mul | I64DivS | I64DivU | I64RemS | I64RemU | I64And | I64Or | I64Xor | I64Shl |
+			I64ShrS | I64ShrU | I64Rotl | I64Rotr | F32Add | F32Sub | F32Mul | F32Div |
+			F32Min | F32Max | F32Copysign | F64Add | F64Sub | F64Mul | F64Div | F64Min |
+			F64Max | F64Copysign => StackEffect::new(2, 1),
+
+			I32WrapI64 | I32TruncSF32 | I32TruncUF32 | I32TruncSF64 | I32TruncUF64 |
+			I64ExtendSI32 | I64ExtendUI32 | I64TruncSF32 | I64TruncUF32 | I64TruncSF64 |
+			I64TruncUF64 | F32ConvertSI32 | F32ConvertUI32 | F32ConvertSI64 | F32ConvertUI64 |
+			F32DemoteF64 | F64ConvertSI32 | F64ConvertUI32 | F64ConvertSI64 | F64ConvertUI64 |
+			F64PromoteF32 | I32ReinterpretF32 | I64ReinterpretF64 | F32ReinterpretI32 |
+			F64ReinterpretI64 => StackEffect::new(1, 1),
+
+			#[cfg(feature = "sign_ext")]
+			SignExt(SignExtInstruction::I32Extend8S) |
+			SignExt(SignExtInstruction::I32Extend16S) |
+			SignExt(SignExtInstruction::I64Extend8S) |
+			SignExt(SignExtInstruction::I64Extend16S) |
+			SignExt(SignExtInstruction::I64Extend32S) => StackEffect::new(1, 1),
+
+			#[cfg(feature = "bulk")]
+			Bulk(BulkInstruction::MemoryInit(_)) |
+			Bulk(BulkInstruction::MemoryCopy) |
+			Bulk(BulkInstruction::MemoryFill) |
+			Bulk(BulkInstruction::TableInit(_)) |
+			Bulk(BulkInstruction::TableCopy) => StackEffect::new(3, 0),
+			#[cfg(feature = "bulk")]
+			Bulk(BulkInstruction::MemoryDrop(_)) | Bulk(BulkInstruction::TableDrop(_)) =>
+				StackEffect::new(0, 0),
+
+			#[cfg(feature = "simd")]
+			Simd(simd) => simd_stack_effect(simd),
+
+			#[cfg(feature = "atomics")]
+			Atomics(atomic) => atomic_stack_effect(atomic),
+		};
+		Ok(effect)
+	}
+}
+
+impl<'a> Iterator for FunctionStackEffects<'a> {
+	type Item = Result<(&'a Instruction, StackEffect), Error>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let instruction = self.instructions.next()?;
+		Some(self.effect(instruction).map(|effect| (instruction, effect)))
+	}
+}
+
+#[cfg(feature = "simd")]
+fn simd_stack_effect(instruction: &SimdInstruction) -> StackEffect {
+	use SimdInstruction::*;
+
+	match instruction {
+		V128Const(_) => StackEffect::new(0, 1),
+		V128Store(_) => StackEffect::new(2, 0),
+		V128Bitselect => StackEffect::new(3, 1),
+
+		V128Load(_) | I8x16Splat | I16x8Splat | I32x4Splat | I64x2Splat | F32x4Splat |
+		F64x2Splat | I8x16ExtractLaneS(_) | I8x16ExtractLaneU(_) | I16x8ExtractLaneS(_) |
+		I16x8ExtractLaneU(_) | I32x4ExtractLane(_) | I64x2ExtractLane(_) |
+		F32x4ExtractLane(_) | F64x2ExtractLane(_) | I8x16Neg | I16x8Neg | I32x4Neg | I64x2Neg |
+		V128Not | I8x16AnyTrue | I16x8AnyTrue | I32x4AnyTrue | I64x2AnyTrue | I8x16AllTrue |
+		I16x8AllTrue | I32x4AllTrue | I64x2AllTrue | F32x4Neg | F64x2Neg | F32x4Abs |
+		F64x2Abs | F32x4Sqrt | F64x2Sqrt | F32x4ConvertSI32x4 | F32x4ConvertUI32x4 |
+		F64x2ConvertSI64x2 | F64x2ConvertUI64x2 | I32x4TruncSF32x4Sat | I32x4TruncUF32x4Sat |
+		I64x2TruncSF64x2Sat | I64x2TruncUF64x2Sat => StackEffect::new(1, 1),
+
+		// Lane replacements, shuffles, shifts, comparisons and the arithmetic operators.
+		_ => StackEffect::new(2, 1),
+	}
+}
+
+#[cfg(feature = "atomics")]
+fn atomic_stack_effect(instruction: &AtomicsInstruction) -> StackEffect {
+	use AtomicsInstruction::*;
+
+	match instruction {
+		I32AtomicLoad(_) | I64AtomicLoad(_) | I32AtomicLoad8u(_) | I32AtomicLoad16u(_) |
+		I64AtomicLoad8u(_) | I64AtomicLoad16u(_) | I64AtomicLoad32u(_) => StackEffect::new(1, 1),
+
+		I32AtomicStore(_) | I64AtomicStore(_) | I32AtomicStore8u(_) | I32AtomicStore16u(_) |
+		I64AtomicStore8u(_) | I64AtomicStore16u(_) | I64AtomicStore32u(_) => StackEffect::new(2, 0),
+
+		I32AtomicWait(_) |
+		I64AtomicWait(_) |
+		I32AtomicRmwCmpxchg(_) |
+		I64AtomicRmwCmpxchg(_) |
+		I32AtomicRmwCmpxchg8u(_) |
+		I32AtomicRmwCmpxchg16u(_) |
+		I64AtomicRmwCmpxchg8u(_) |
+		I64AtomicRmwCmpxchg16u(_) |
+		I64AtomicRmwCmpxchg32u(_) => StackEffect::new(3, 1),
+
+		// `atomic.notify` and the read-modify-write operators take an address and an operand.
+		_ => StackEffect::new(2, 1),
+	}
+}
+
+/// Type of the value an instruction pushes, if it pushes one whose type follows from the
+/// instruction alone.
+///
+/// `None` for instructions pushing no value and for the ones whose result depends on the
+/// context, e.g. `get_local`, `call` or `select`.
+pub(crate) fn result_type(instruction: &Instruction) -> Option<ValueType> {
+	use parity_wasm::elements::Instruction::*;
+
+	let value_type = match instruction {
+		I32Load(_, _) |
+		I32Load8S(_, _) |
+		I32Load8U(_, _) |
+		I32Load16S(_, _) |
+		I32Load16U(_, _) |
+		CurrentMemory(_) |
+		GrowMemory(_) |
+		I32Const(_) => ValueType::I32,
+		I64Load(_, _) |
+		I64Load8S(_, _) |
+		I64Load8U(_, _) |
+		I64Load16S(_, _) |
+		I64Load16U(_, _) |
+		I64Load32S(_, _) |
+		I64Load32U(_, _) |
+		I64Const(_) => ValueType::I64,
+		F32Load(_, _) | F32Const(_) => ValueType::F32,
+		F64Load(_, _) | F64Const(_) => ValueType::F64,
+
+		I32Eqz | I64Eqz | I32Eq | I32Ne | I32LtS | I32LtU | I32GtS | I32GtU | I32LeS | I32LeU |
+		I32GeS | I32GeU | I64Eq | I64Ne | I64LtS | I64LtU | I64GtS | I64GtU | I64LeS | I64LeU |
+		I64GeS | I64GeU | F32Eq | F32Ne | F32Lt | F32Gt | F32Le | F32Ge | F64Eq | F64Ne |
+		F64Lt | F64Gt | F64Le | F64Ge => ValueType::I32,
+
+		I32Clz | I32Ctz | I32Popcnt | I32Add | I32Sub | I32Mul | I32DivS | I32DivU | I32RemS |
+		I32RemU | I32And | I32Or | I32Xor | I32Shl | I32ShrS | I32ShrU | I32Rotl | I32Rotr |
+		I32WrapI64 | I32TruncSF32 | I32TruncUF32 | I32TruncSF64 | I32TruncUF64 |
+		I32ReinterpretF32 => ValueType::I32,
+		I64Clz | I64Ctz | I64Popcnt | I64Add | I64Sub | I64Mul | I64DivS | I64DivU | I64RemS |
+		I64RemU | I64And | I64Or | I64Xor | I64Shl | I64ShrS | I64ShrU | I64Rotl | I64Rotr |
+		I64ExtendSI32 | I64ExtendUI32 | I64TruncSF32 | I64TruncUF32 | I64TruncSF64 |
+		I64TruncUF64 | I64ReinterpretF64 => ValueType::I64,
+		F32Abs | F32Neg | F32Ceil | F32Floor | F32Trunc | F32Nearest | F32Sqrt | F32Add |
+		F32Sub | F32Mul | F32Div | F32Min | F32Max | F32Copysign | F32ConvertSI32 |
+		F32ConvertUI32 | F32ConvertSI64 | F32ConvertUI64 | F32DemoteF64 | F32ReinterpretI32 =>
+			ValueType::F32,
+		F64Abs | F64Neg | F64Ceil | F64Floor | F64Trunc | F64Nearest | F64Sqrt | F64Add |
+		F64Sub | F64Mul | F64Div | F64Min | F64Max | F64Copysign | F64ConvertSI32 |
+		F64ConvertUI32 | F64ConvertSI64 | F64ConvertUI64 | F64PromoteF32 | F64ReinterpretI64 =>
+			ValueType::F64,
+
+		#[cfg(feature = "sign_ext")]
+		SignExt(SignExtInstruction::I32Extend8S) | SignExt(SignExtInstruction::I32Extend16S) =>
+			ValueType::I32,
+		#[cfg(feature = "sign_ext")]
+		SignExt(_) => ValueType::I64,
+
+		#[cfg(feature = "simd")]
+		Simd(simd) => simd_result_type(simd)?,
+
+		#[cfg(feature = "atomics")]
+		Atomics(atomic) => atomic_result_type(atomic)?,
+
+		_ => return None,
+	};
+	Some(value_type)
+}
+
+#[cfg(feature = "simd")]
+fn simd_result_type(instruction: &SimdInstruction) -> Option<ValueType> {
+	use SimdInstruction::*;
+
+	Some(match instruction {
+		V128Store(_) => return None,
+		I8x16ExtractLaneS(_) | I8x16ExtractLaneU(_) | I16x8ExtractLaneS(_) |
+		I16x8ExtractLaneU(_) | I32x4ExtractLane(_) | I8x16AnyTrue | I16x8AnyTrue |
+		I32x4AnyTrue | I64x2AnyTrue | I8x16AllTrue | I16x8AllTrue | I32x4AllTrue | I64x2AllTrue =>
+			ValueType::I32,
+		I64x2ExtractLane(_) => ValueType::I64,
+		F32x4ExtractLane(_) => ValueType::F32,
+		F64x2ExtractLane(_) => ValueType::F64,
+		_ => ValueType::V128,
+	})
+}
+
+#[cfg(feature = "atomics")]
+fn atomic_result_type(instruction: &AtomicsInstruction) -> Option<ValueType> {
+	use AtomicsInstruction::*;
+
+	Some(match instruction {
+		I32AtomicStore(_) | I64AtomicStore(_) | I32AtomicStore8u(_) | I32AtomicStore16u(_) |
+		I64AtomicStore8u(_) | I64AtomicStore16u(_) | I64AtomicStore32u(_) => return None,
+
+		I64AtomicLoad(_) |
+		I64AtomicLoad8u(_) |
+		I64AtomicLoad16u(_) |
+		I64AtomicLoad32u(_) |
+		I64AtomicRmwAdd(_) |
+		I64AtomicRmwAdd8u(_) |
+		I64AtomicRmwAdd16u(_) |
+		I64AtomicRmwAdd32u(_) |
+		I64AtomicRmwSub(_) |
+		I64AtomicRmwSub8u(_) |
+		I64AtomicRmwSub16u(_) |
+		I64AtomicRmwSub32u(_) |
+		I64AtomicRmwAnd(_) |
+		I64AtomicRmwAnd8u(_) |
+		I64AtomicRmwAnd16u(_) |
+		I64AtomicRmwAnd32u(_) |
+		I64AtomicRmwOr(_) |
+		I64AtomicRmwOr8u(_) |
+		I64AtomicRmwOr16u(_) |
+		I64AtomicRmwOr32u(_) |
+		I64AtomicRmwXor(_) |
+		I64AtomicRmwXor8u(_) |
+		I64AtomicRmwXor16u(_) |
+		I64AtomicRmwXor32u(_) |
+		I64AtomicRmwXchg(_) |
+		I64AtomicRmwXchg8u(_) |
+		I64AtomicRmwXchg16u(_) |
+		I64AtomicRmwXchg32u(_) |
+		I64AtomicRmwCmpxchg(_) |
+		I64AtomicRmwCmpxchg8u(_) |
+		I64AtomicRmwCmpxchg16u(_) |
+		I64AtomicRmwCmpxchg32u(_) => ValueType::I64,
+
+		// `atomic.notify`, the waits and the 32-bit loads and read-modify-write operators.
+		_ => ValueType::I32,
+	})
+}
+
+/// Resolve the type of the function `func_idx` in the function index space.
+pub(crate) fn resolve_func_type(
+	func_idx: u32,
+	module: &elements::Module,
+) -> Result<&elements::FunctionType, Error> {
+	let types = module.type_section().map(|ts| ts.types()).unwrap_or(&[]);
+	let functions = module.function_section().map(|fs| fs.entries()).unwrap_or(&[]);
+
+	let func_imports = module.import_count(elements::ImportCountType::Function);
+	let sig_idx = if func_idx < func_imports as u32 {
+		module
+			.import_section()
+			.expect("function import count is not zero; import section must exists; qed")
+			.entries()
+			.iter()
+			.filter_map(|entry| match entry.external() {
+				elements::External::Function(idx) => Some(*idx),
+				_ => None,
+			})
+			.nth(func_idx as usize)
+			.expect(
+				"func_idx is less than function imports count;
+				nth function import must be `Some`;
+				qed",
+			)
+	} else {
+		functions
+			.get(func_idx as usize - func_imports)
+			.ok_or_else(|| Error(format!("Function at index {} is not defined", func_idx)))?
+			.type_ref()
+	};
+	let Type::Function(ty) = types.get(sig_idx as usize).ok_or_else(|| {
+		Error(format!("Signature {} (specified by func {}) isn't defined", sig_idx, func_idx))
+	})?;
+	Ok(ty)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	fn effects(module: &elements::Module, func_idx: u32) -> Vec<(u32, u32)> {
+		function_stack_effects(module, func_idx)
+			.unwrap()
+			.map(|item| item.map(|(_, effect)| (effect.pops, effect.pushes)))
+			.collect::<Result<_, _>>()
+			.unwrap()
+	}
+
+	#[test]
+	fn calls_and_select() {
+		let module = parse_wat(
+			r#"
+(module
+	(type $t (func (param i32 i64) (result i32)))
+	(import "env" "f" (func $f (type $t)))
+	(table 1 funcref)
+	(func (result i32)
+		i32.const 1
+		i64.const 2
+		call $f
+		i32.const 1
+		i64.const 2
+		i32.const 0
+		call_indirect (type $t)
+		i32.const 1
+		select
+	)
+)
+"#,
+		);
+
+		assert_eq!(
+			effects(&module, 0),
+			vec![(0, 1), (0, 1), (2, 1), (0, 1), (0, 1), (0, 1), (3, 1), (0, 1), (3, 1), (0, 0)]
+		);
+	}
+
+	#[test]
+	fn branches() {
+		let module = parse_wat(
+			r#"
+(module
+	(func (param i32) (result i32)
+		block (result i32)
+			loop
+				get_local 0
+				br_if 0
+			end
+			i32.const 1
+			get_local 0
+			br_if 0
+			get_local 0
+			br_table 0 1
+		end
+	)
+)
+"#,
+		);
+
+		assert_eq!(
+			effects(&module, 0),
+			vec![
+				(0, 0),
+				(0, 0),
+				(0, 1),
+				(1, 0),
+				(0, 0),
+				(0, 1),
+				(0, 1),
+				(2, 1),
+				(0, 1),
+				(2, 0),
+				(0, 0),
+				(0, 0)
+			]
+		);
+	}
+}
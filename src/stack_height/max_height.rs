@@ -1,11 +1,9 @@
 use crate::std::vec::Vec;
 
-use super::{resolve_func_type, Error};
+use super::{resolve_func_type, Error, ValueWeights};
+use crate::stack_effect::{function_stack_effects, result_type};
 use log::trace;
-use parity_wasm::elements::{self, BlockType, Type};
-
-#[cfg(feature = "sign_ext")]
-use parity_wasm::elements::SignExtInstruction;
+use parity_wasm::elements::{self, BlockType, ValueType};
 
 /// Control stack frame.
 #[derive(Debug)]
@@ -14,31 +12,27 @@ struct Frame {
 	/// never passes control further was executed.
 	is_polymorphic: bool,
 
-	/// Count of values which will be pushed after the exit
+	/// Weights of the values which will be pushed after the exit
 	/// from the current block.
-	end_arity: u32,
-
-	/// Count of values which should be poped upon a branch to
-	/// this frame.
-	///
-	/// This might be diffirent from `end_arity` since branch
-	/// to the loop header can't take any values.
-	branch_arity: u32,
+	end_weights: Vec<u32>,
 
-	/// Stack height before entering in the block.
-	start_height: u32,
+	/// Number of values on the value stack before entering in the block.
+	start_len: usize,
 }
 
 /// This is a compound stack that abstracts tracking height of the value stack
 /// and manipulation of the control stack.
 struct Stack {
+	/// Sum of the weights of the values on the value stack.
 	height: u32,
+	/// Weights of the values on the value stack.
+	values: Vec<u32>,
 	control_stack: Vec<Frame>,
 }
 
 impl Stack {
 	fn new() -> Stack {
-		Stack { height: 0, control_stack: Vec::new() }
+		Stack { height: 0, values: Vec::new(), control_stack: Vec::new() }
 	}
 
 	/// Returns current height of the value stack.
@@ -52,22 +46,28 @@ impl Stack {
 		let control_stack_height: usize = self.control_stack.len();
 		let last_idx = control_stack_height
 			.checked_sub(1)
-			.ok_or_else(|| Error("control stack is empty".into()))?;
+			.ok_or(Error::ControlStackUnderflow { depth: rel_depth })?;
 		let idx = last_idx
 			.checked_sub(rel_depth as usize)
-			.ok_or_else(|| Error("control stack out-of-bounds".into()))?;
+			.ok_or(Error::ControlStackUnderflow { depth: rel_depth })?;
 		Ok(&self.control_stack[idx])
 	}
 
+	/// Weight of the value at `depth` relative to the top of the value stack, if it was pushed
+	/// in the current frame.
+	fn peek(&self, depth: u32) -> Option<u32> {
+		let start_len = self.control_stack.last()?.start_len;
+		let idx = self.values.len().checked_sub(depth as usize + 1)?;
+		Some(self.values[idx]).filter(|_| idx >= start_len)
+	}
+
 	/// Mark successive instructions as unreachable.
 	///
 	/// This effectively makes stack polymorphic.
 	fn mark_unreachable(&mut self) -> Result<(), Error> {
 		trace!(target: "max_height", "unreachable");
-		let top_frame = self
-			.control_stack
-			.last_mut()
-			.ok_or_else(|| Error("stack must be non-empty".into()))?;
+		let top_frame =
+			self.control_stack.last_mut().ok_or(Error::ControlStackUnderflow { depth: 0 })?;
 		top_frame.is_polymorphic = true;
 		Ok(())
 	}
@@ -83,104 +83,126 @@ impl Stack {
 	/// Returns `Err` if the control stack is empty.
 	fn pop_frame(&mut self) -> Result<Frame, Error> {
 		trace!(target: "max_height", "pop_frame: {:?}", self.control_stack.last());
-		self.control_stack.pop().ok_or_else(|| Error("stack must be non-empty".into()))
+		self.control_stack.pop().ok_or(Error::ControlStackUnderflow { depth: 0 })
 	}
 
-	/// Truncate the height of value stack to the specified height.
-	fn trunc(&mut self, new_height: u32) {
-		trace!(target: "max_height", "trunc: {}", new_height);
-		self.height = new_height;
+	/// Truncate the value stack to the specified number of values.
+	fn trunc(&mut self, new_len: usize) {
+		trace!(target: "max_height", "trunc: {}", new_len);
+		while self.values.len() > new_len {
+			let weight = self.values.pop().expect("the stack is longer than `new_len`; qed");
+			self.height -= weight;
+		}
 	}
 
-	/// Push specified number of values into the value stack.
+	/// Push a value with the specified weight onto the value stack.
 	///
-	/// Returns `Err` if the height overflow usize value.
-	fn push_values(&mut self, value_count: u32) -> Result<(), Error> {
-		trace!(target: "max_height", "push: {}", value_count);
-		self.height = self
-			.height
-			.checked_add(value_count)
-			.ok_or_else(|| Error("stack overflow".into()))?;
+	/// Returns `Err` if the height overflow u32 value.
+	fn push_value(&mut self, weight: u32) -> Result<(), Error> {
+		trace!(target: "max_height", "push: {}", weight);
+		self.height = self.height.checked_add(weight).ok_or(Error::HeightOverflow)?;
+		self.values.push(weight);
 		Ok(())
 	}
 
 	/// Pop specified number of values from the value stack.
 	///
-	/// Returns `Err` if the stack happen to be negative value after
-	/// values popped.
+	/// Returns `Err` if more values are popped than were pushed in the current frame, unless
+	/// the frame became polymorphic.
 	fn pop_values(&mut self, value_count: u32) -> Result<(), Error> {
 		trace!(target: "max_height", "pop: {}", value_count);
-		if value_count == 0 {
-			return Ok(())
-		}
-		{
-			let top_frame = self.frame(0)?;
-			if self.height == top_frame.start_height {
-				// It is an error to pop more values than was pushed in the current frame
-				// (ie pop values pushed in the parent frame), unless the frame became
-				// polymorphic.
-				return if top_frame.is_polymorphic {
-					Ok(())
-				} else {
-					Err(Error("trying to pop more values than pushed".into()))
-				}
-			}
+		let top_frame = self.frame(0)?;
+		let pushed = self.values.len() - top_frame.start_len;
+		if value_count as usize > pushed && !top_frame.is_polymorphic {
+			// It is an error to pop more values than was pushed in the current frame
+			// (ie pop values pushed in the parent frame), unless the frame became
+			// polymorphic.
+			return Err(Error::ValueStackUnderflow)
 		}
+		let new_len = self.values.len() - pushed.min(value_count as usize);
+		self.trunc(new_len);
+		Ok(())
+	}
+}
 
-		self.height = self
-			.height
-			.checked_sub(value_count)
-			.ok_or_else(|| Error("stack underflow".into()))?;
+/// Types of the parameters and locals of a function.
+struct Locals<'a> {
+	params: &'a [ValueType],
+	locals: &'a [elements::Local],
+}
 
-		Ok(())
+impl Locals<'_> {
+	fn value_type(&self, local_idx: u32) -> Result<ValueType, Error> {
+		if let Some(value_type) = self.params.get(local_idx as usize) {
+			return Ok(*value_type)
+		}
+		let mut idx = local_idx as usize - self.params.len();
+		for group in self.locals {
+			if idx < group.count() as usize {
+				return Ok(group.value_type())
+			}
+			idx -= group.count() as usize;
+		}
+		Err(Error::LocalNotFound { local_idx })
 	}
 }
 
+/// Types of all globals of `module`, imported ones first.
+fn global_types(module: &elements::Module) -> Vec<ValueType> {
+	let imports = module.import_section().map(|section| section.entries()).unwrap_or(&[]);
+	let globals = module.global_section().map(|section| section.entries()).unwrap_or(&[]);
+	imports
+		.iter()
+		.filter_map(|entry| match entry.external() {
+			elements::External::Global(global_type) => Some(global_type.content_type()),
+			_ => None,
+		})
+		.chain(globals.iter().map(|global| global.global_type().content_type()))
+		.collect()
+}
+
 /// This function expects the function to be validated.
-pub(crate) fn compute(func_idx: u32, module: &elements::Module) -> Result<u32, Error> {
+///
+/// The values are counted with their `weights`. Functions may have several results, with the
+/// `multi_value` feature, but blocks push at most one value: `parity-wasm` can't decode block
+/// types referring to a type index.
+pub(crate) fn compute(
+	func_idx: u32,
+	module: &elements::Module,
+	weights: &ValueWeights,
+) -> Result<u32, Error> {
 	use parity_wasm::elements::Instruction::*;
 
-	let func_section =
-		module.function_section().ok_or_else(|| Error("No function section".into()))?;
-	let code_section = module.code_section().ok_or_else(|| Error("No code section".into()))?;
-	let type_section = module.type_section().ok_or_else(|| Error("No type section".into()))?;
-
 	trace!(target: "max_height", "func_idx: {}", func_idx);
 
-	// Get a signature and a body of the specified function.
-	let func_sig_idx = func_section
-		.entries()
-		.get(func_idx as usize)
-		.ok_or_else(|| Error("Function is not found in func section".into()))?
-		.type_ref();
-	let Type::Function(func_signature) = type_section
-		.types()
-		.get(func_sig_idx as usize)
-		.ok_or_else(|| Error("Function is not found in func section".into()))?;
-	let body = code_section
-		.bodies()
-		.get(func_idx as usize)
-		.ok_or_else(|| Error("Function body for the index isn't found".into()))?;
-	let instructions = body.code();
+	let instructions = function_stack_effects(module, func_idx)?;
+	let func_imports = module.import_count(elements::ImportCountType::Function) as u32;
+	let signature = resolve_func_type(func_imports + func_idx, module)?;
+	let locals = Locals {
+		params: signature.params(),
+		locals: module
+			.code_section()
+			.and_then(|section| section.bodies().get(func_idx as usize))
+			.map_or(&[], |body| body.locals()),
+	};
+	let global_types = global_types(module);
+	let weigh = |value_types: &[ValueType]| -> Vec<u32> {
+		value_types.iter().map(|value_type| weights.weight(*value_type)).collect()
+	};
 
 	let mut stack = Stack::new();
 	let mut max_height: u32 = 0;
-	let mut pc = 0;
 
 	// Add implicit frame for the function. Breaks to this frame and execution of
 	// the last end should deal with this frame.
-	let func_arity = func_signature.results().len() as u32;
 	stack.push_frame(Frame {
 		is_polymorphic: false,
-		end_arity: func_arity,
-		branch_arity: func_arity,
-		start_height: 0,
+		end_weights: weigh(signature.results()),
+		start_len: 0,
 	});
 
-	loop {
-		if pc >= instructions.elements().len() {
-			break
-		}
+	for instruction in instructions {
+		let (opcode, effect) = instruction?;
 
 		// If current value stack is higher than maximal height observed so far,
 		// save the new height.
@@ -189,24 +211,18 @@ pub(crate) fn compute(func_idx: u32, module: &elements::Module) -> Result<u32, E
 			max_height = stack.height();
 		}
 
-		let opcode = &instructions.elements()[pc];
 		trace!(target: "max_height", "{:?}", opcode);
 
 		match opcode {
-			Nop => {},
 			Block(ty) | Loop(ty) | If(ty) => {
-				let end_arity = if *ty == BlockType::NoResult { 0 } else { 1 };
-				let branch_arity = if let Loop(_) = *opcode { 0 } else { end_arity };
-				if let If(_) = *opcode {
-					stack.pop_values(1)?;
-				}
-				let height = stack.height();
-				stack.push_frame(Frame {
-					is_polymorphic: false,
-					end_arity,
-					branch_arity,
-					start_height: height,
-				});
+				let end_weights = match ty {
+					BlockType::NoResult => Vec::new(),
+					BlockType::Value(value_type) => weigh(&[*value_type]),
+				};
+				// Pops the condition of `if`.
+				stack.pop_values(effect.pops)?;
+				let start_len = stack.values.len();
+				stack.push_frame(Frame { is_polymorphic: false, end_weights, start_len });
 			},
 			Else => {
 				// The frame at the top should be pushed by `If`. So we leave
@@ -214,217 +230,52 @@ pub(crate) fn compute(func_idx: u32, module: &elements::Module) -> Result<u32, E
 			},
 			End => {
 				let frame = stack.pop_frame()?;
-				stack.trunc(frame.start_height);
-				stack.push_values(frame.end_arity)?;
-			},
-			Unreachable => {
-				stack.mark_unreachable()?;
-			},
-			Br(target) => {
-				// Pop values for the destination block result.
-				let target_arity = stack.frame(*target)?.branch_arity;
-				stack.pop_values(target_arity)?;
-
-				// This instruction unconditionally transfers control to the specified block,
-				// thus all instruction until the end of the current block is deemed unreachable
-				stack.mark_unreachable()?;
-			},
-			BrIf(target) => {
-				// Pop values for the destination block result.
-				let target_arity = stack.frame(*target)?.branch_arity;
-				stack.pop_values(target_arity)?;
-
-				// Pop condition value.
-				stack.pop_values(1)?;
-
-				// Push values back.
-				stack.push_values(target_arity)?;
-			},
-			BrTable(br_table_data) => {
-				let arity_of_default = stack.frame(br_table_data.default)?.branch_arity;
-
-				// Check that all jump targets have an equal arities.
-				for target in &*br_table_data.table {
-					let arity = stack.frame(*target)?.branch_arity;
-					if arity != arity_of_default {
-						return Err(Error("Arity of all jump-targets must be equal".into()))
-					}
+				stack.trunc(frame.start_len);
+				for weight in frame.end_weights {
+					stack.push_value(weight)?;
 				}
-
-				// Because all jump targets have an equal arities, we can just take arity of
-				// the default branch.
-				stack.pop_values(arity_of_default)?;
-
-				// This instruction doesn't let control flow to go further, since the control flow
-				// should take either one of branches depending on the value or the default branch.
-				stack.mark_unreachable()?;
-			},
-			Return => {
-				// Pop return values of the function. Mark successive instructions as unreachable
-				// since this instruction doesn't let control flow to go further.
-				stack.pop_values(func_arity)?;
-				stack.mark_unreachable()?;
-			},
-			Call(idx) => {
-				let ty = resolve_func_type(*idx, module)?;
-
-				// Pop values for arguments of the function.
-				stack.pop_values(ty.params().len() as u32)?;
-
-				// Push result of the function execution to the stack.
-				let callee_arity = ty.results().len() as u32;
-				stack.push_values(callee_arity)?;
-			},
-			CallIndirect(x, _) => {
-				let Type::Function(ty) = type_section
-					.types()
-					.get(*x as usize)
-					.ok_or_else(|| Error("Type not found".into()))?;
-
-				// Pop the offset into the function table.
-				stack.pop_values(1)?;
-
-				// Pop values for arguments of the function.
-				stack.pop_values(ty.params().len() as u32)?;
-
-				// Push result of the function execution to the stack.
-				let callee_arity = ty.results().len() as u32;
-				stack.push_values(callee_arity)?;
-			},
-			Drop => {
-				stack.pop_values(1)?;
-			},
-			Select => {
-				// Pop two values and one condition.
-				stack.pop_values(2)?;
-				stack.pop_values(1)?;
-
-				// Push the selected value.
-				stack.push_values(1)?;
-			},
-			GetLocal(_) => {
-				stack.push_values(1)?;
-			},
-			SetLocal(_) => {
-				stack.pop_values(1)?;
-			},
-			TeeLocal(_) => {
-				// This instruction pops and pushes the value, so
-				// effectively it doesn't modify the stack height.
-				stack.pop_values(1)?;
-				stack.push_values(1)?;
-			},
-			GetGlobal(_) => {
-				stack.push_values(1)?;
-			},
-			SetGlobal(_) => {
-				stack.pop_values(1)?;
-			},
-			I32Load(_, _) |
-			I64Load(_, _) |
-			F32Load(_, _) |
-			F64Load(_, _) |
-			I32Load8S(_, _) |
-			I32Load8U(_, _) |
-			I32Load16S(_, _) |
-			I32Load16U(_, _) |
-			I64Load8S(_, _) |
-			I64Load8U(_, _) |
-			I64Load16S(_, _) |
-			I64Load16U(_, _) |
-			I64Load32S(_, _) |
-			I64Load32U(_, _) => {
-				// These instructions pop the address and pushes the result,
-				// which effictively don't modify the stack height.
-				stack.pop_values(1)?;
-				stack.push_values(1)?;
-			},
-
-			I32Store(_, _) |
-			I64Store(_, _) |
-			F32Store(_, _) |
-			F64Store(_, _) |
-			I32Store8(_, _) |
-			I32Store16(_, _) |
-			I64Store8(_, _) |
-			I64Store16(_, _) |
-			I64Store32(_, _) => {
-				// These instructions pop the address and the value.
-				stack.pop_values(2)?;
-			},
-
-			CurrentMemory(_) => {
-				// Pushes current memory size
-				stack.push_values(1)?;
-			},
-			GrowMemory(_) => {
-				// Grow memory takes the value of pages to grow and pushes
-				stack.pop_values(1)?;
-				stack.push_values(1)?;
-			},
-
-			I32Const(_) | I64Const(_) | F32Const(_) | F64Const(_) => {
-				// These instructions just push the single literal value onto the stack.
-				stack.push_values(1)?;
-			},
-
-			I32Eqz | I64Eqz => {
-				// These instructions pop the value and compare it against zero, and pushes
-				// the result of the comparison.
-				stack.pop_values(1)?;
-				stack.push_values(1)?;
-			},
-
-			I32Eq | I32Ne | I32LtS | I32LtU | I32GtS | I32GtU | I32LeS | I32LeU | I32GeS |
-			I32GeU | I64Eq | I64Ne | I64LtS | I64LtU | I64GtS | I64GtU | I64LeS | I64LeU |
-			I64GeS | I64GeU | F32Eq | F32Ne | F32Lt | F32Gt | F32Le | F32Ge | F64Eq | F64Ne |
-			F64Lt | F64Gt | F64Le | F64Ge => {
-				// Comparison operations take two operands and produce one result.
-				stack.pop_values(2)?;
-				stack.push_values(1)?;
-			},
-
-			I32Clz | I32Ctz | I32Popcnt | I64Clz | I64Ctz | I64Popcnt | F32Abs | F32Neg |
-			F32Ceil | F32Floor | F32Trunc | F32Nearest | F32Sqrt | F64Abs | F64Neg | F64Ceil |
-			F64Floor | F64Trunc | F64Nearest | F64Sqrt => {
-				// Unary operators take one operand and produce one result.
-				stack.pop_values(1)?;
-				stack.push_values(1)?;
-			},
-
-			I32Add | I32Sub | I32Mul | I32DivS | I32DivU | I32RemS | I32RemU | I32And | I32Or |
-			I32Xor | I32Shl | I32ShrS | I32ShrU | I32Rotl | I32Rotr | I64Add | I64Sub |
-			I64Mul | I64DivS | I64DivU | I64RemS | I64RemU | I64And | I64Or | I64Xor | I64Shl |
-			I64ShrS | I64ShrU | I64Rotl | I64Rotr | F32Add | F32Sub | F32Mul | F32Div |
-			F32Min | F32Max | F32Copysign | F64Add | F64Sub | F64Mul | F64Div | F64Min |
-			F64Max | F64Copysign => {
-				// Binary operators take two operands and produce one result.
-				stack.pop_values(2)?;
-				stack.push_values(1)?;
 			},
+			_ => {
+				let pushed = match opcode {
+					GetLocal(idx) | TeeLocal(idx) => weigh(&[locals.value_type(*idx)?]),
+					GetGlobal(idx) => weigh(&[*global_types
+						.get(*idx as usize)
+						.ok_or(Error::GlobalNotFound { global_idx: *idx })?]),
+					Call(idx) => weigh(resolve_func_type(*idx, module)?.results()),
+					CallIndirect(type_idx, _) => {
+						let elements::Type::Function(ty) = module
+							.type_section()
+							.and_then(|section| section.types().get(*type_idx as usize))
+							.ok_or(Error::TypeNotFound { type_idx: *type_idx })?;
+						weigh(ty.results())
+					},
+					// The selected value and the values passed on by a branch which isn't taken
+					// keep their weights. They are unknown only in unreachable code.
+					Select | BrIf(_) => (1..=effect.pushes)
+						.rev()
+						.map(|depth| stack.peek(depth).unwrap_or(weights.i32))
+						.collect(),
+					_ => result_type(opcode)
+						.map(|value_type| weights.weight(value_type))
+						.into_iter()
+						.collect(),
+				};
+				if pushed.len() != effect.pushes as usize {
+					return Err(Error::UnknownResultType(opcode.clone()))
+				}
 
-			I32WrapI64 | I32TruncSF32 | I32TruncUF32 | I32TruncSF64 | I32TruncUF64 |
-			I64ExtendSI32 | I64ExtendUI32 | I64TruncSF32 | I64TruncUF32 | I64TruncSF64 |
-			I64TruncUF64 | F32ConvertSI32 | F32ConvertUI32 | F32ConvertSI64 | F32ConvertUI64 |
-			F32DemoteF64 | F64ConvertSI32 | F64ConvertUI32 | F64ConvertSI64 | F64ConvertUI64 |
-			F64PromoteF32 | I32ReinterpretF32 | I64ReinterpretF64 | F32ReinterpretI32 |
-			F64ReinterpretI64 => {
-				// Conversion operators take one value and produce one result.
-				stack.pop_values(1)?;
-				stack.push_values(1)?;
-			},
+				stack.pop_values(effect.pops)?;
+				for weight in pushed {
+					stack.push_value(weight)?;
+				}
 
-			#[cfg(feature = "sign_ext")]
-			SignExt(SignExtInstruction::I32Extend8S) |
-			SignExt(SignExtInstruction::I32Extend16S) |
-			SignExt(SignExtInstruction::I64Extend8S) |
-			SignExt(SignExtInstruction::I64Extend16S) |
-			SignExt(SignExtInstruction::I64Extend32S) => {
-				stack.pop_values(1)?;
-				stack.push_values(1)?;
+				// These instructions don't let control flow to go further, thus all
+				// instructions until the end of the current block are deemed unreachable.
+				if let Unreachable | Br(_) | BrTable(_) | Return = *opcode {
+					stack.mark_unreachable()?;
+				}
 			},
 		}
-		pc += 1;
 	}
 
 	Ok(max_height)
@@ -457,7 +308,7 @@ mod tests {
 "#,
 		);
 
-		let height = compute(0, &module).unwrap();
+		let height = compute(0, &module, &ValueWeights::default()).unwrap();
 		assert_eq!(height, 3);
 	}
 
@@ -474,7 +325,7 @@ mod tests {
 "#,
 		);
 
-		let height = compute(0, &module).unwrap();
+		let height = compute(0, &module, &ValueWeights::default()).unwrap();
 		assert_eq!(height, 1);
 	}
 
@@ -492,7 +343,7 @@ mod tests {
 "#,
 		);
 
-		let height = compute(0, &module).unwrap();
+		let height = compute(0, &module, &ValueWeights::default()).unwrap();
 		assert_eq!(height, 0);
 	}
 
@@ -527,7 +378,7 @@ mod tests {
 		)
 		.expect("Failed to deserialize the module");
 
-		let height = compute(0, &module).unwrap();
+		let height = compute(0, &module, &ValueWeights::default()).unwrap();
 		assert_eq!(height, 2);
 	}
 
@@ -551,7 +402,7 @@ mod tests {
 "#,
 		);
 
-		let height = compute(0, &module).unwrap();
+		let height = compute(0, &module, &ValueWeights::default()).unwrap();
 		assert_eq!(height, 1);
 	}
 
@@ -573,7 +424,7 @@ mod tests {
 "#,
 		);
 
-		let height = compute(0, &module).unwrap();
+		let height = compute(0, &module, &ValueWeights::default()).unwrap();
 		assert_eq!(height, 1);
 	}
 
@@ -599,7 +450,32 @@ mod tests {
 "#,
 		);
 
-		let height = compute(0, &module).unwrap();
+		let height = compute(0, &module, &ValueWeights::default()).unwrap();
 		assert_eq!(height, 3);
 	}
+
+	#[cfg(feature = "multi_value")]
+	#[test]
+	fn multiple_results_through_branches() {
+		let module = parse_wat(
+			r#"
+(module
+	(func (param i32) (result i64 i32)
+		i64.const 1
+		i32.const 2
+		local.get 0
+		br_if 0
+		i64.const 3
+		drop
+		return
+	)
+)
+"#,
+		);
+
+		// Both results keep their weights when the branch isn't taken.
+		let weights = ValueWeights { i64: 2, ..Default::default() };
+		let height = compute(0, &module, &weights).unwrap();
+		assert_eq!(height, 5);
+	}
 }
@@ -11,7 +11,8 @@
 //! the global stack height variable with statically determined "stack cost"
 //! of the callee. If after the increment the stack height exceeds
 //! the limit (specified by the `rules`) then execution traps.
-//! Otherwise, the call is executed.
+//! Otherwise, the call is executed. The host can be told about the overflow before the trap, see
+//! [`Config::with_overflow_handler`].
 //!
 //! The postamble is inserted after the call. The purpose of the postamble is to decrease
 //! the stack height by the "stack cost" of the callee function.
@@ -20,6 +21,11 @@
 //! example would be a trap issued by the host function.
 //! That means stack height global won't be equal to zero upon the next execution after such trap.
 //!
+//! Tail calls, i.e. `return_call` and `return_call_indirect`, aren't supported. They would have
+//! to charge the callee in place of the current frame instead of on top of it, but
+//! `parity-wasm` can't decode them, so modules using the tail-call proposal are refused before
+//! they reach this pass.
+//!
 //! # Thunks
 //!
 //! Because stack height is increased prior the call few problems arises:
@@ -32,6 +38,18 @@
 //! will increase before and decrease the stack height after the call to original function, and
 //! then make exported function and table entries, start section to point to a corresponding thunks.
 //!
+//! Tooling that caches compiled functions by index can ask for a mapping from original function
+//! indices to their thunks to be emitted as a custom section, see [`Config::with_thunk_map`].
+//! Thunks can also be given names in the name section, see [`Config::with_thunk_name_suffix`].
+//!
+//! A function gets a single thunk, however often it is exported or put into tables. Thunks of
+//! different functions differ in the function they call, so they can't be shared even if the
+//! functions have the same signature and stack cost.
+//!
+//! Calls which can't recurse needn't be checked one by one, see
+//! [`Config::with_recursive_calls_only`]. Their stack cost is charged by the checked call or the
+//! thunk leading to them then.
+//!
 //! # Stack cost
 //!
 //! Stack cost of the function is calculated as a sum of it's locals
@@ -48,14 +66,40 @@
 //! - arguments pushed by the caller are copied into callee stack rather than shared
 //!   between the frames.
 //! - upon entry into the function entire stack frame is allocated.
+//!
+//! The stack costs can be computed without instrumenting the module with [`analyze`], e.g. to
+//! report the stack profile of a module. [`dry_run`] also reports the overhead the
+//! instrumentation would add, e.g. to track it in CI.
 
-use crate::std::{mem, string::String, vec::Vec};
+use crate::{
+	check_limits,
+	sections::{
+		code_section_mut, get_or_insert_export_section, get_or_insert_global_section,
+		get_or_insert_type_section,
+	},
+	std::{
+		cmp::max,
+		collections::BTreeMap,
+		fmt, mem,
+		ops::Range,
+		string::{String, ToString},
+		vec::Vec,
+	},
+	IndexPlacement, LimitExceeded, Limits,
+};
 
+use byteorder::{ByteOrder, LittleEndian};
 use parity_wasm::{
 	builder,
-	elements::{self, Instruction, Instructions, Type},
+	elements::{self, Instruction, Instructions},
 };
 
+/// Number of instructions following the call in [`instrument_call!`].
+const POSTAMBLE_LEN: usize = 4;
+
+/// Number of instructions calling the overflow handler, see [`Config::with_overflow_handler`].
+const HANDLER_LEN: usize = 2;
+
 /// Macro to generate preamble and postamble.
 macro_rules! instrument_call {
 	($callee_idx: expr, $callee_stack_cost: expr, $stack_height_global_idx: expr, $stack_limit: expr) => {{
@@ -84,22 +128,590 @@ macro_rules! instrument_call {
 	}};
 }
 
+/// The sequence of [`instrument_call!`] with `call` as the call, calling `handler` with the
+/// given argument before trapping if there is one.
+fn checked_call(
+	call: Instruction,
+	cost: i32,
+	stack_height_global_idx: u32,
+	stack_limit: u32,
+	handler: Option<(u32, i32)>,
+) -> Vec<Instruction> {
+	let mut seq = instrument_call!(0, cost, stack_height_global_idx, stack_limit).to_vec();
+	let call_pos = seq.len() - POSTAMBLE_LEN - 1;
+	seq[call_pos] = call;
+	if let Some((handler_idx, reported)) = handler {
+		// Before the `unreachable` and the `end` of the check.
+		let unreachable_pos = call_pos - 2;
+		seq.splice(
+			unreachable_pos..unreachable_pos,
+			[Instruction::I32Const(reported), Instruction::Call(handler_idx)],
+		);
+	}
+	seq
+}
+
 mod max_height;
+mod static_bound;
 mod thunk;
+mod verify;
+
+pub use static_bound::max_stack_height;
+pub use verify::{verify, Mismatch};
 
 /// Error that occured during processing the module.
 ///
-/// This means that the module is invalid.
-#[derive(Debug)]
-pub struct Error(String);
+/// All variants but [`Error::Overflow`], [`Error::AlreadyInstrumented`],
+/// [`Error::LimitExceeded`] and [`Error::InvalidPlacement`] mean that the module is invalid.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Error {
+	/// The module is invalid, as described by the message.
+	Malformed(String),
+	/// The module has functions but no code section.
+	MissingCodeSection,
+	/// There is no body for the function `func_idx`, e.g. because a call refers to a function
+	/// out of bounds.
+	FunctionNotFound { func_idx: u32 },
+	/// The type `type_idx` isn't defined.
+	TypeNotFound { type_idx: u32 },
+	/// The global `global_idx` isn't defined.
+	GlobalNotFound { global_idx: u32 },
+	/// The local `local_idx` isn't declared.
+	LocalNotFound { local_idx: u32 },
+	/// A block ends or a branch targets a block at `depth` which isn't on the control stack.
+	ControlStackUnderflow { depth: u32 },
+	/// An instruction pops more values than were pushed in its block.
+	ValueStackUnderflow,
+	/// The height of the value stack overflows `u32`.
+	HeightOverflow,
+	/// The values pushed by the instruction can't be determined.
+	UnknownResultType(elements::Instruction),
+	/// The payload of the custom section with the thunk map isn't a sequence of index pairs.
+	MalformedThunkMap { section: String },
+	/// The calls in a body don't match the calls found when computing its stack cost.
+	UnusedCalls,
+	/// The stack cost of the function `func_idx` exceeds `i32::MAX` and can't be instrumented.
+	Overflow { func_idx: u32 },
+	/// The module records that its stack height is limited already, see
+	/// [`Config::with_mark`].
+	AlreadyInstrumented,
+	/// The module exceeds the limits set with [`Config::with_limits`].
+	LimitExceeded(LimitExceeded),
+	/// The stack height global can't be placed at the `index` set with
+	/// [`Config::with_global_placement`].
+	InvalidPlacement { index: u32 },
+	/// The global `global_idx` set with [`Config::with_counter`] isn't a mutable `i32`.
+	InvalidCounter { global_idx: u32 },
+	/// The module exports something under the `field` set with [`Config::with_exported_global`]
+	/// already.
+	ExportExists { field: String },
+	/// The stack height a call of the function `func_idx` can reach exceeds the stack limit, see
+	/// [`Config::with_static_bound`].
+	StaticBoundExceeded { func_idx: u32, bound: u32 },
+	/// The function `func_idx` imported under the overflow handler, see
+	/// [`Config::with_overflow_handler`], doesn't have the signature `(param i32)`.
+	OverflowHandlerSignature { func_idx: u32 },
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			Error::Malformed(message) => write!(f, "Malformed module: {}", message),
+			Error::MissingCodeSection => write!(f, "The module has no code section"),
+			Error::FunctionNotFound { func_idx } => write!(f, "Function {} has no body", func_idx),
+			Error::TypeNotFound { type_idx } => write!(f, "Type {} isn't defined", type_idx),
+			Error::GlobalNotFound { global_idx } =>
+				write!(f, "Global {} isn't defined", global_idx),
+			Error::LocalNotFound { local_idx } => write!(f, "Local {} isn't declared", local_idx),
+			Error::ControlStackUnderflow { depth } =>
+				write!(f, "There is no block at depth {} on the control stack", depth),
+			Error::ValueStackUnderflow =>
+				write!(f, "Trying to pop more values than were pushed in the block"),
+			Error::HeightOverflow => write!(f, "The height of the value stack overflows"),
+			Error::UnknownResultType(instruction) =>
+				write!(f, "Unknown result type of `{}`", instruction),
+			Error::MalformedThunkMap { section } =>
+				write!(f, "Malformed thunk map in section {}", section),
+			Error::UnusedCalls => write!(f, "Not all calls were used"),
+			Error::Overflow { func_idx } =>
+				write!(f, "Stack cost of function {} overflows the stack height", func_idx),
+			Error::AlreadyInstrumented => write!(f, "The stack height is limited already"),
+			Error::LimitExceeded(err) => write!(f, "{}", err),
+			Error::InvalidPlacement { index } =>
+				write!(f, "The stack height global can't be placed at index {}", index),
+			Error::StaticBoundExceeded { func_idx, bound } => write!(
+				f,
+				"A call of function {} can reach the stack height {}, exceeding the limit",
+				func_idx, bound
+			),
+			Error::ExportExists { field } => write!(f, "The module already exports `{}`", field),
+			Error::InvalidCounter { global_idx } => write!(
+				f,
+				"Global {} can't count the stack height, it isn't a mutable i32",
+				global_idx
+			),
+			Error::OverflowHandlerSignature { func_idx } => write!(
+				f,
+				"The overflow handler {} doesn't have the signature (param i32)",
+				func_idx
+			),
+		}
+	}
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			Error::LimitExceeded(err) => Some(err),
+			_ => None,
+		}
+	}
+}
+
+impl Error {
+	/// Description of the error, as formatted by `Display`.
+	pub fn message(&self) -> String {
+		self.to_string()
+	}
+}
+
+impl From<crate::stack_effect::Error> for Error {
+	fn from(err: crate::stack_effect::Error) -> Self {
+		Error::Malformed(err.0)
+	}
+}
+
+/// Name of the stack height limiter in the section [`crate::INSTRUMENTED_SECTION`].
+pub const PASS: &str = "stack_height";
+
+/// Mapping from the index of an original function to the index of the thunk generated for it.
+pub type ThunkMap = BTreeMap<u32, u32>;
+
+/// Number of stack slots taken by a value of each type, see [`Config::with_value_weights`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueWeights {
+	/// Weight of an `i32` value.
+	pub i32: u32,
+	/// Weight of an `i64` value.
+	pub i64: u32,
+	/// Weight of an `f32` value.
+	pub f32: u32,
+	/// Weight of an `f64` value.
+	pub f64: u32,
+	/// Weight of a `v128` value, only used with the `simd` feature.
+	pub v128: u32,
+}
+
+impl ValueWeights {
+	/// Weight of a value of the type `value_type`.
+	pub fn weight(&self, value_type: elements::ValueType) -> u32 {
+		match value_type {
+			elements::ValueType::I32 => self.i32,
+			elements::ValueType::I64 => self.i64,
+			elements::ValueType::F32 => self.f32,
+			elements::ValueType::F64 => self.f64,
+			#[cfg(feature = "simd")]
+			elements::ValueType::V128 => self.v128,
+		}
+	}
+}
+
+impl Default for ValueWeights {
+	/// Every value takes one slot.
+	fn default() -> Self {
+		ValueWeights { i32: 1, i64: 1, f32: 1, f64: 1, v128: 1 }
+	}
+}
+
+/// Global counting the stack height, see [`Config::with_counter`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Counter {
+	/// Add a new global, placed as set with [`Config::with_global_placement`].
+	Injected,
+	/// Use the global with the given index.
+	Global(u32),
+	/// Use the global imported from `module` under `field`, adding the import if the module
+	/// doesn't have it yet.
+	Import { module: String, field: String },
+}
+
+/// Functions which get thunks, see [`Config::with_thunk_selection`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ThunkSelection {
+	/// The exported functions, the start function and the functions in the tables, the default.
+	All,
+	/// The exported functions and the start function.
+	Exports,
+	/// The given functions, as far as they are exported, the start function or in a table.
+	Functions(Vec<u32>),
+}
+
+/// Configuration of the stack height limiter.
+#[derive(Debug, Clone)]
+pub struct Config {
+	stack_limit: u32,
+	frame_cost: u32,
+	value_weights: ValueWeights,
+	indirect_calls: bool,
+	static_bound: bool,
+	recursive_calls_only: bool,
+	overflow_handler: Option<(String, String)>,
+	counter: Counter,
+	global_placement: IndexPlacement,
+	export_name: Option<String>,
+	thunk_selection: ThunkSelection,
+	thunk_map_section: Option<String>,
+	thunk_name_suffix: Option<String>,
+	mark_internal: bool,
+	mark: bool,
+	instrumentation_map: bool,
+	limits: Limits,
+}
+
+impl Config {
+	/// New configuration with the given stack limit.
+	pub fn new(stack_limit: u32) -> Self {
+		Config {
+			stack_limit,
+			frame_cost: 0,
+			value_weights: ValueWeights::default(),
+			indirect_calls: false,
+			static_bound: false,
+			recursive_calls_only: false,
+			overflow_handler: None,
+			counter: Counter::Injected,
+			global_placement: IndexPlacement::Last,
+			export_name: None,
+			thunk_selection: ThunkSelection::All,
+			thunk_map_section: None,
+			thunk_name_suffix: None,
+			mark_internal: false,
+			mark: false,
+			instrumentation_map: false,
+			limits: Limits::new(),
+		}
+	}
+
+	/// Add `cost` to the stack cost of every defined function, to account for the activation
+	/// frame of each call, e.g. the return address, the frame pointer and the reference to the
+	/// module instance.
+	///
+	/// The cost is counted in values, like the rest of the stack cost. Engines differ in the
+	/// size of their frames, so it should be chosen conservatively.
+	pub fn with_frame_cost(mut self, cost: u32) -> Self {
+		self.frame_cost = cost;
+		self
+	}
+
+	/// Count the locals and the values on the stack with the weights of their types instead of
+	/// one slot each, e.g. for engines keeping 64-bit values in two 32-bit slots.
+	///
+	/// The parameters aren't counted, like with the default weights.
+	pub fn with_value_weights(mut self, weights: ValueWeights) -> Self {
+		self.value_weights = weights;
+		self
+	}
+
+	/// Also wrap every `call_indirect` with checks of the stack height, charging the maximal
+	/// stack cost of the functions of the called type.
+	///
+	/// Without this option only the functions in the element segments are checked when called
+	/// indirectly, by replacing them with thunks. With it, the calls are checked where they are
+	/// made, so functions put into the table by the host are accounted for too. The element
+	/// segments keep the original functions then.
+	pub fn with_indirect_calls(mut self) -> Self {
+		self.indirect_calls = true;
+		self
+	}
+
+	/// Only generate thunks for the functions in `selection`, all functions entered from outside
+	/// of the module by default.
+	///
+	/// The calls of the functions without a thunk from outside of the module aren't charged, so
+	/// this is only sound if they can't be made, e.g. if the functions in the tables are only
+	/// called indirectly by functions which are checked themselves. The module doesn't pass
+	/// [`verify`] then, unless the indirect calls are checked, see
+	/// [`Config::with_indirect_calls`].
+	pub fn with_thunk_selection(mut self, selection: ThunkSelection) -> Self {
+		self.thunk_selection = selection;
+		self
+	}
+
+	/// Emit the [`ThunkMap`] as a custom section with the given name.
+	///
+	/// The payload is a sequence of little endian `u32` pairs of
+	/// (original function index, thunk index). Use [`read_thunk_map`] to decode it.
+	pub fn with_thunk_map(mut self, section_name: &str) -> Self {
+		self.thunk_map_section = Some(section_name.into());
+		self
+	}
+
+	/// Name every thunk after its original function with the given suffix appended.
+	///
+	/// Only applies to functions that have a name in the name section.
+	pub fn with_thunk_name_suffix(mut self, suffix: &str) -> Self {
+		self.thunk_name_suffix = Some(suffix.into());
+		self
+	}
+
+	/// Don't instrument modules whose stack height is bounded statically, i.e. which contain no
+	/// recursion and no `call_indirect`, but check the bound against the stack limit instead.
+	///
+	/// The bound of every function called from outside of the module, see
+	/// [`max_stack_height`], has to be within the limit, otherwise the instrumentation fails.
+	/// The module is returned as is then, apart from the record of [`Config::with_mark`]. Calls
+	/// back into the module from the host aren't accounted for, so this is only sound if the host
+	/// doesn't make them.
+	pub fn with_static_bound(mut self) -> Self {
+		self.static_bound = true;
+		self
+	}
+
+	/// Only check the calls of functions which are part of a cycle of direct calls, and charge
+	/// the stack cost of the calls without a check to the checked call or the thunk leading to
+	/// them.
+	///
+	/// A checked call or a thunk charges the stack cost of the called function plus the largest
+	/// cost of the chains of unchecked calls starting from it. Most contracts don't recurse, so
+	/// only their thunks remain checked. Modules instrumented this way don't pass [`verify`].
+	pub fn with_recursive_calls_only(mut self) -> Self {
+		self.recursive_calls_only = true;
+		self
+	}
+
+	/// Call the function imported from `module` under `field` before trapping on a stack
+	/// overflow, adding the import if the module doesn't have it yet.
+	///
+	/// The handler has the signature `(param i32)` and is passed the index of the called
+	/// function, or `-1` for a `call_indirect`. It can log the overflow or trap itself with an
+	/// error of its own; if it returns, the execution traps with `unreachable` as without a
+	/// handler. The calls of the handler aren't checked.
+	pub fn with_overflow_handler(mut self, module: &str, field: &str) -> Self {
+		self.overflow_handler = Some((module.into(), field.into()));
+		self
+	}
+
+	/// Count the stack height with the given global, a new one by default.
+	///
+	/// Tools and runtimes sharing an existing counter agree on the stack height. The counter has
+	/// to be a mutable `i32`; an added import is one.
+	pub fn with_counter(mut self, counter: Counter) -> Self {
+		self.counter = counter;
+		self
+	}
+
+	/// Place the injected stack height global in the global index space, after all existing
+	/// globals by default.
+	///
+	/// The globals from the chosen index on are shifted.
+	pub fn with_global_placement(mut self, placement: IndexPlacement) -> Self {
+		self.global_placement = placement;
+		self
+	}
+
+	/// Export the stack height global under `name`, e.g. `__stack_height`.
+	///
+	/// A trap in a host function leaves the stack height of the pending calls on the counter, so
+	/// the embedder can reset it to zero before the next call.
+	pub fn with_exported_global(mut self, name: &str) -> Self {
+		self.export_name = Some(name.into());
+		self
+	}
+
+	/// Mark the stack height global as internal, so that it is not exported by
+	/// `export_mutable_globals`. See [`crate::mark_internal_global`].
+	pub fn with_internal_global(mut self) -> Self {
+		self.mark_internal = true;
+		self
+	}
+
+	/// Record the instrumentation in the section [`crate::INSTRUMENTED_SECTION`] as the pass
+	/// [`PASS`], with the stack limit as parameter.
+	///
+	/// Modules with this record are refused by the instrumentation, whether this option is set or
+	/// not.
+	pub fn with_mark(mut self) -> Self {
+		self.mark = true;
+		self
+	}
+
+	/// Refuse modules exceeding `limits` before instrumenting them.
+	pub fn with_limits(mut self, limits: Limits) -> Self {
+		self.limits = limits;
+		self
+	}
+
+	/// Record the checks around the calls and the thunks in the section
+	/// [`crate::INSTRUMENTATION_MAP_SECTION`].
+	pub fn with_instrumentation_map(mut self) -> Self {
+		self.instrumentation_map = true;
+		self
+	}
+
+	/// Stack limit that is enforced by the instrumentation.
+	pub fn stack_limit(&self) -> u32 {
+		self.stack_limit
+	}
+
+	/// Cost of the activation frame added to the stack cost of every defined function.
+	pub fn frame_cost(&self) -> u32 {
+		self.frame_cost
+	}
+
+	/// Weights of the values in the stack cost.
+	pub fn value_weights(&self) -> &ValueWeights {
+		&self.value_weights
+	}
+}
 
 pub(crate) struct Context {
 	stack_height_global_idx: u32,
 	func_stack_costs: Vec<u32>,
+	/// Whether the calls of each function are checked, if not all of them are.
+	checked_callees: Option<Vec<bool>>,
+	/// Stack costs of the calls of each type with `call_indirect`, if they are checked.
+	indirect_stack_costs: Option<Vec<u32>>,
 	stack_limit: u32,
+	/// Function called before trapping on a stack overflow, if any.
+	overflow_handler: Option<u32>,
+	/// Functions which get thunks if they have a stack cost.
+	thunk_entries: Vec<u32>,
+	/// Ranges of the checks injected into every body instrumented so far.
+	injected: Vec<Vec<Range<usize>>>,
 }
 
 impl Context {
+	/// Prepare the instrumentation of `module`: add the stack height global and compute the
+	/// stack costs of all functions.
+	pub(crate) fn new(module: &mut elements::Module, config: &Config) -> Result<Self, Error> {
+		check_module(module, config)?;
+		if let Some(field) = &config.export_name {
+			let exports = module.export_section().map(|section| section.entries()).unwrap_or(&[]);
+			if exports.iter().any(|entry| entry.field() == field) {
+				return Err(Error::ExportExists { field: field.clone() })
+			}
+		}
+		let (func_stack_costs, checked_callees) = charged_stack_costs(module, config)?;
+		let indirect_stack_costs = if config.indirect_calls {
+			Some(compute_indirect_stack_costs(module, &func_stack_costs))
+		} else {
+			None
+		};
+		let stack_height_global_idx = match &config.counter {
+			Counter::Injected => {
+				let imported_globals =
+					module.import_count(elements::ImportCountType::Global) as u32;
+				let global_idx = config
+					.global_placement
+					.index(imported_globals, module.globals_space() as u32)
+					.map_err(|index| Error::InvalidPlacement { index })?;
+				let appended = generate_stack_height_global(module);
+				crate::indices::move_global(module, appended, global_idx);
+				global_idx
+			},
+			Counter::Global(global_idx) => {
+				if !is_counter(module, *global_idx) {
+					return Err(Error::InvalidCounter { global_idx: *global_idx })
+				}
+				*global_idx
+			},
+			Counter::Import { module: import_module, field } =>
+				import_counter(module, import_module, field)?,
+		};
+		let mut ctx = Context {
+			stack_height_global_idx,
+			func_stack_costs,
+			checked_callees,
+			indirect_stack_costs,
+			stack_limit: config.stack_limit,
+			overflow_handler: None,
+			thunk_entries: thunk::entries(module, config.indirect_calls, &config.thunk_selection),
+			injected: Vec::new(),
+		};
+		if let Some((handler_module, field)) = &config.overflow_handler {
+			let (func_idx, inserted) = import_overflow_handler(module, handler_module, field)?;
+			if inserted {
+				ctx.insert_function(func_idx);
+			}
+			ctx.overflow_handler = Some(func_idx);
+		}
+		Ok(ctx)
+	}
+
+	/// Account for a function without stack cost inserted at `func_idx`, shifting the following
+	/// functions.
+	pub(crate) fn insert_function(&mut self, func_idx: u32) {
+		self.func_stack_costs.insert(func_idx as usize, 0);
+		if let Some(checked_callees) = &mut self.checked_callees {
+			checked_callees.insert(func_idx as usize, false);
+		}
+		for shifted in self.overflow_handler.iter_mut().chain(&mut self.thunk_entries) {
+			if *shifted >= func_idx {
+				*shifted += 1;
+			}
+		}
+	}
+
+	/// Account for a global inserted at `global_idx`, shifting the following globals.
+	pub(crate) fn insert_global(&mut self, global_idx: u32) {
+		if self.stack_height_global_idx >= global_idx {
+			self.stack_height_global_idx += 1;
+		}
+	}
+
+	/// Wrap the calls in `body` with checks of the stack height.
+	///
+	/// The bodies have to be instrumented in the order of the code section.
+	pub(crate) fn instrument_body(&mut self, body: &mut elements::FuncBody) -> Result<(), Error> {
+		let injected = instrument_function(self, body.code_mut())?;
+		self.injected.push(injected);
+		Ok(())
+	}
+
+	/// Generate the thunks once all bodies are instrumented and apply the remaining options of
+	/// `config`.
+	pub(crate) fn finish(
+		mut self,
+		module: elements::Module,
+		config: &Config,
+	) -> Result<elements::Module, Error> {
+		let bodies = module.code_section().map_or(0, |section| section.bodies().len());
+		let (mut module, thunks) = thunk::generate_thunks(&mut self, module)?;
+
+		if let Some(suffix) = &config.thunk_name_suffix {
+			name_thunks(&mut module, &thunks, suffix);
+		}
+		if config.mark_internal {
+			crate::mark_internal_global(&mut module, self.stack_height_global_idx());
+		}
+		if let Some(field) = &config.export_name {
+			get_or_insert_export_section(&mut module).entries_mut().push(
+				elements::ExportEntry::new(
+					field.clone(),
+					elements::Internal::Global(self.stack_height_global_idx()),
+				),
+			);
+		}
+		if let Some(section_name) = &config.thunk_map_section {
+			module.set_custom_section(section_name.as_str(), serialize_thunk_map(&thunks));
+		}
+		if config.mark {
+			crate::mark_instrumented(&mut module, PASS, &format!("limit={}", config.stack_limit));
+		}
+		if config.instrumentation_map {
+			crate::instrumentation_map::record_injected_code(
+				&mut module,
+				PASS,
+				&self.injected,
+				bodies,
+			);
+		}
+
+		Ok(module)
+	}
+
 	/// Returns index in a global index space of a stack_height global variable.
 	fn stack_height_global_idx(&self) -> u32 {
 		self.stack_height_global_idx
@@ -110,10 +722,65 @@ impl Context {
 		self.func_stack_costs.get(func_idx as usize).cloned()
 	}
 
-	/// Returns stack limit specified by the rules.
-	fn stack_limit(&self) -> u32 {
-		self.stack_limit
+	/// Returns the stack cost to check for a direct call of `func_idx`, `None` if the call isn't
+	/// checked.
+	fn call_stack_cost(&self, func_idx: u32) -> Option<u32> {
+		match &self.checked_callees {
+			Some(checked_callees) if !checked_callees.get(func_idx as usize).copied()? => None,
+			_ => self.stack_cost(func_idx),
+		}
+	}
+
+	/// Returns the stack cost of a `call_indirect` of the type `type_idx`, if these are checked.
+	fn indirect_stack_cost(&self, type_idx: u32) -> Option<u32> {
+		self.indirect_stack_costs.as_ref()?.get(type_idx as usize).cloned()
+	}
+
+	/// Whether the calls with `call_indirect` are checked where they are made.
+	fn checks_indirect_calls(&self) -> bool {
+		self.indirect_stack_costs.is_some()
+	}
+
+	/// Wrap `call` with a check of the stack height charging `cost`, see [`checked_call`].
+	fn checked_call(&self, call: Instruction, cost: u32) -> Vec<Instruction> {
+		let handler = self.overflow_handler.map(|handler| {
+			let reported = match call {
+				Instruction::Call(callee) => callee as i32,
+				_ => -1,
+			};
+			(handler, reported)
+		});
+		checked_call(call, cost as i32, self.stack_height_global_idx, self.stack_limit, handler)
+	}
+}
+
+/// Refuse modules which are instrumented already or exceed the limits of `config`.
+fn check_module(module: &elements::Module, config: &Config) -> Result<(), Error> {
+	if crate::is_instrumented(module, PASS) {
+		return Err(Error::AlreadyInstrumented)
+	}
+	check_limits(module, &config.limits).map_err(Error::LimitExceeded)
+}
+
+/// Check the static bound of the stack height if requested with [`Config::with_static_bound`].
+///
+/// Returns `Ok(true)` if `module` is bounded within the limit and needn't be instrumented. It is
+/// marked as instrumented then, if requested.
+pub(crate) fn check_static_bound(
+	module: &mut elements::Module,
+	config: &Config,
+) -> Result<bool, Error> {
+	if !config.static_bound {
+		return Ok(false)
 	}
+	check_module(module, config)?;
+	if !static_bound::check(module, config)? {
+		return Ok(false)
+	}
+	if config.mark {
+		crate::mark_instrumented(module, PASS, &format!("limit={}", config.stack_limit));
+	}
+	Ok(true)
 }
 
 /// Instrument a module with stack height limiter.
@@ -124,23 +791,77 @@ impl Context {
 ///
 /// Returns `Err` if module is invalid and can't be
 pub fn inject_limiter(
-	mut module: elements::Module,
+	module: elements::Module,
 	stack_limit: u32,
 ) -> Result<elements::Module, Error> {
-	let mut ctx = Context {
-		stack_height_global_idx: generate_stack_height_global(&mut module),
-		func_stack_costs: compute_stack_costs(&module)?,
-		stack_limit,
-	};
+	inject_limiter_with_config(module, &Config::new(stack_limit))
+}
 
+/// Instrument a module with stack height limiter using the given configuration.
+///
+/// See [`inject_limiter`] for more details.
+pub fn inject_limiter_with_config(
+	mut module: elements::Module,
+	config: &Config,
+) -> Result<elements::Module, Error> {
+	if check_static_bound(&mut module, config)? {
+		return Ok(module)
+	}
+	let mut ctx = Context::new(&mut module, config)?;
 	instrument_functions(&mut ctx, &mut module)?;
-	let module = thunk::generate_thunks(&mut ctx, module)?;
+	ctx.finish(module, config)
+}
+
+/// Read a [`ThunkMap`] from the custom section with the given name.
+///
+/// Returns `Ok(None)` if there is no such section.
+pub fn read_thunk_map(
+	module: &elements::Module,
+	section_name: &str,
+) -> Result<Option<ThunkMap>, Error> {
+	let payload = match module.custom_sections().find(|s| s.name() == section_name) {
+		Some(section) => section.payload(),
+		None => return Ok(None),
+	};
+
+	if payload.len() % 8 != 0 {
+		return Err(Error::MalformedThunkMap { section: section_name.into() })
+	}
+	let thunks = payload
+		.chunks(8)
+		.map(|pair| (LittleEndian::read_u32(&pair[0..4]), LittleEndian::read_u32(&pair[4..8])))
+		.collect();
+	Ok(Some(thunks))
+}
+
+fn serialize_thunk_map(thunks: &ThunkMap) -> Vec<u8> {
+	let mut payload = vec![0u8; thunks.len() * 8];
+	for ((original, thunk), pair) in thunks.iter().zip(payload.chunks_mut(8)) {
+		LittleEndian::write_u32(&mut pair[0..4], *original);
+		LittleEndian::write_u32(&mut pair[4..8], *thunk);
+	}
+	payload
+}
 
-	Ok(module)
+/// Give each thunk the name of its original function with `suffix` appended.
+fn name_thunks(module: &mut elements::Module, thunks: &ThunkMap, suffix: &str) {
+	let names = match module.names_section_mut().and_then(|ns| ns.functions_mut().as_mut()) {
+		Some(function_names) => function_names.names_mut(),
+		None => return,
+	};
+	for (original, thunk) in thunks {
+		if let Some(name) = names.get(*original) {
+			let thunk_name = format!("{}{}", name, suffix);
+			names.insert(*thunk, thunk_name);
+		}
+	}
 }
 
 /// Generate a new global that will be used for tracking current stack height.
+///
+/// Returns its index in the global index space.
 fn generate_stack_height_global(module: &mut elements::Module) -> u32 {
+	let imported_globals = module.import_count(elements::ImportCountType::Global) as u32;
 	let global_entry = builder::global()
 		.value_type()
 		.i32()
@@ -148,87 +869,304 @@ fn generate_stack_height_global(module: &mut elements::Module) -> u32 {
 		.init_expr(Instruction::I32Const(0))
 		.build();
 
-	// Try to find an existing global section.
-	for section in module.sections_mut() {
-		if let elements::Section::Global(gs) = section {
-			gs.entries_mut().push(global_entry);
-			return (gs.entries().len() as u32) - 1
+	let global_section = get_or_insert_global_section(module);
+	global_section.entries_mut().push(global_entry);
+	imported_globals + (global_section.entries().len() as u32) - 1
+}
+
+/// Stack usage of a defined function, see [`analyze`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FunctionStackCost {
+	/// Index of the function in the function space.
+	pub func_idx: u32,
+	/// Stack slots taken by the declared locals, not counting the parameters.
+	pub locals: u32,
+	/// Maximal height of the value stack.
+	pub max_height: u32,
+	/// Stack cost charged for a call of the function, i.e. the locals, the maximal height and
+	/// the frame cost.
+	pub cost: u32,
+}
+
+/// Compute the stack usage of all functions defined by `module`, in order, as the limiter
+/// configured with `config` would count it.
+///
+/// Fails like the instrumentation if the stack cost of a function can't be computed.
+pub fn analyze(
+	module: &elements::Module,
+	config: &Config,
+) -> Result<Vec<FunctionStackCost>, Error> {
+	let func_imports = module.import_count(elements::ImportCountType::Function) as u32;
+	(func_imports..module.functions_space() as u32)
+		.map(|func_idx| compute_stack_cost(func_idx, module, config))
+		.collect()
+}
+
+/// Overhead the stack height limiter would add to a module, see [`dry_run`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Report {
+	/// Stack usage of the functions defined by the module, in order.
+	pub functions: Vec<FunctionStackCost>,
+	/// Functions which would be replaced by thunks, in ascending order.
+	pub thunks: Vec<u32>,
+	/// Number of calls which would be wrapped with checks of the stack height.
+	pub checked_calls: usize,
+	/// Number of bytes the encoded module would grow by.
+	pub size_increase: usize,
+}
+
+/// Report what [`inject_limiter_with_config`] would do to `module` with `config`, without
+/// modifying it.
+///
+/// Fails like the instrumentation. The size increase accounts for all options of `config`, e.g.
+/// the custom sections. If the module is bounded statically, see [`Config::with_static_bound`],
+/// it only counts the record of [`Config::with_mark`].
+pub fn dry_run(module: &elements::Module, config: &Config) -> Result<Report, Error> {
+	let module_size = |module: &elements::Module| {
+		parity_wasm::serialize(module.clone()).map_or(0, |bytes| bytes.len())
+	};
+
+	let mut instrumented = module.clone();
+	let (instrumented, thunks, checked_calls) = if check_static_bound(&mut instrumented, config)? {
+		(instrumented, Vec::new(), 0)
+	} else {
+		let mut ctx = Context::new(&mut instrumented, config)?;
+		instrument_functions(&mut ctx, &mut instrumented)?;
+		let checked_calls = ctx.injected.iter().map(|ranges| ranges.len() / 2).sum();
+		// The instrumentation may shift the functions by an import, so the thunks are determined
+		// from the original module.
+		let (costs, _) = charged_stack_costs(module, config)?;
+		let thunks = thunk::entries(module, config.indirect_calls, &config.thunk_selection)
+			.into_iter()
+			.filter(|func_idx| costs.get(*func_idx as usize).map_or(false, |cost| *cost != 0))
+			.collect();
+		(ctx.finish(instrumented, config)?, thunks, checked_calls)
+	};
+
+	Ok(Report {
+		functions: analyze(module, config)?,
+		thunks,
+		checked_calls,
+		size_increase: module_size(&instrumented).saturating_sub(module_size(module)),
+	})
+}
+
+/// Whether the global `global_idx` of `module` is a mutable `i32`, as the stack height counter
+/// has to be.
+fn is_counter(module: &elements::Module, global_idx: u32) -> bool {
+	let imported = module
+		.import_section()
+		.map(|section| section.entries())
+		.unwrap_or(&[])
+		.iter()
+		.filter_map(|entry| match entry.external() {
+			elements::External::Global(global_type) => Some(global_type),
+			_ => None,
+		});
+	let defined = module
+		.global_section()
+		.map(|section| section.entries())
+		.unwrap_or(&[])
+		.iter()
+		.map(|entry| entry.global_type());
+	imported.chain(defined).nth(global_idx as usize).map_or(false, |global_type| {
+		global_type.is_mutable() && global_type.content_type() == elements::ValueType::I32
+	})
+}
+
+/// Index of the global imported from `import_module` under `field`, importing it as a mutable
+/// `i32` if `module` doesn't yet.
+fn import_counter(
+	module: &mut elements::Module,
+	import_module: &str,
+	field: &str,
+) -> Result<u32, Error> {
+	let existing = module
+		.import_section()
+		.map(|section| section.entries())
+		.unwrap_or(&[])
+		.iter()
+		.filter(|entry| matches!(entry.external(), elements::External::Global(_)))
+		.position(|entry| entry.module() == import_module && entry.field() == field);
+	match existing {
+		Some(global_idx) if is_counter(module, global_idx as u32) => Ok(global_idx as u32),
+		Some(global_idx) => Err(Error::InvalidCounter { global_idx: global_idx as u32 }),
+		None => Ok(crate::indices::insert_global_import(
+			module,
+			elements::ImportEntry::new(
+				import_module.into(),
+				field.into(),
+				elements::External::Global(elements::GlobalType::new(
+					elements::ValueType::I32,
+					true,
+				)),
+			),
+		)),
+	}
+}
+
+/// Index of the function imported from `import_module` under `field`, importing it with the
+/// signature `(param i32)` after the other imported functions if `module` doesn't yet.
+///
+/// Returns the index and whether the import was added.
+fn import_overflow_handler(
+	module: &mut elements::Module,
+	import_module: &str,
+	field: &str,
+) -> Result<(u32, bool), Error> {
+	let signature = elements::FunctionType::new(vec![elements::ValueType::I32], vec![]);
+	let existing = module
+		.import_section()
+		.map(|section| section.entries())
+		.unwrap_or(&[])
+		.iter()
+		.filter_map(|entry| match entry.external() {
+			elements::External::Function(type_idx) => Some((entry, *type_idx)),
+			_ => None,
+		})
+		.enumerate()
+		.find(|(_, (entry, _))| entry.module() == import_module && entry.field() == field);
+	let types = module.type_section().map(|section| section.types()).unwrap_or(&[]);
+	if let Some((func_idx, (_, type_idx))) = existing {
+		return match types.get(type_idx as usize) {
+			Some(elements::Type::Function(found)) if *found == signature =>
+				Ok((func_idx as u32, false)),
+			_ => Err(Error::OverflowHandlerSignature { func_idx: func_idx as u32 }),
 		}
 	}
 
-	// Existing section not found, create one!
-	module
-		.sections_mut()
-		.push(elements::Section::Global(elements::GlobalSection::with_entries(vec![global_entry])));
-	0
+	let type_idx = match types.iter().position(|elements::Type::Function(ty)| *ty == signature) {
+		Some(type_idx) => type_idx as u32,
+		None => {
+			let types = get_or_insert_type_section(module).types_mut();
+			types.push(elements::Type::Function(signature));
+			types.len() as u32 - 1
+		},
+	};
+	let func_idx = crate::indices::insert_function_import(
+		module,
+		elements::ImportEntry::new(
+			import_module.into(),
+			field.into(),
+			elements::External::Function(type_idx),
+		),
+	);
+	Ok((func_idx, true))
 }
 
 /// Calculate stack costs for all functions.
 ///
-/// Returns a vector with a stack cost for each function, including imports.
-fn compute_stack_costs(module: &elements::Module) -> Result<Vec<u32>, Error> {
+/// Returns a vector with a stack cost for each function, including imports. The frame cost of
+/// `config` is added to the stack costs of the defined functions.
+fn compute_stack_costs(module: &elements::Module, config: &Config) -> Result<Vec<u32>, Error> {
 	let func_imports = module.import_count(elements::ImportCountType::Function);
+	// We can't calculate stack_cost of the import functions.
+	let mut costs = vec![0; func_imports];
+	costs.extend(analyze(module, config)?.into_iter().map(|function| function.cost));
+	Ok(costs)
+}
 
-	// TODO: optimize!
-	(0..module.functions_space())
-		.map(|func_idx| {
-			if func_idx < func_imports {
-				// We can't calculate stack_cost of the import functions.
-				Ok(0)
-			} else {
-				compute_stack_cost(func_idx as u32, module)
-			}
+/// Calculate the stack costs charged for a call of each function, including imports, and whether
+/// the calls of each function are checked, if not all of them are.
+///
+/// These are the costs of [`compute_stack_costs`], unless only the calls of recursive functions
+/// are checked, see [`Config::with_recursive_calls_only`].
+fn charged_stack_costs(
+	module: &elements::Module,
+	config: &Config,
+) -> Result<(Vec<u32>, Option<Vec<bool>>), Error> {
+	let costs = compute_stack_costs(module, config)?;
+	if !config.recursive_calls_only {
+		return Ok((costs, None))
+	}
+	let (costs, recursive) = static_bound::recursive_call_costs(module, &costs)?;
+	Ok((costs, Some(recursive)))
+}
+
+/// Calculate the stack costs of the calls with `call_indirect` from the `stack_costs` of all
+/// functions.
+///
+/// Returns a vector with the maximal stack cost of the functions of each type, by type index.
+/// Like `call_indirect`, types with the same signature are considered the same.
+fn compute_indirect_stack_costs(module: &elements::Module, stack_costs: &[u32]) -> Vec<u32> {
+	let types = module.type_section().map(|section| section.types()).unwrap_or(&[]);
+	let func_imports = module.import_count(elements::ImportCountType::Function);
+	let functions = module.function_section().map(|section| section.entries()).unwrap_or(&[]);
+
+	let mut costs = vec![0; types.len()];
+	for (func, cost) in functions.iter().zip(stack_costs.iter().skip(func_imports)) {
+		if let Some(type_cost) = costs.get_mut(func.type_ref() as usize) {
+			*type_cost = max(*type_cost, *cost);
+		}
+	}
+	types
+		.iter()
+		.map(|elements::Type::Function(signature)| {
+			types
+				.iter()
+				.zip(&costs)
+				.filter(|(elements::Type::Function(other), _)| other == signature)
+				.map(|(_, cost)| *cost)
+				.max()
+				.unwrap_or(0)
 		})
 		.collect()
 }
 
 /// Stack cost of the given *defined* function is the sum of it's locals count (that is,
-/// number of arguments plus number of local variables) and the maximal stack
-/// height.
-fn compute_stack_cost(func_idx: u32, module: &elements::Module) -> Result<u32, Error> {
+/// number of arguments plus number of local variables), the maximal stack height and the frame
+/// cost, with the values counted by the weights of `config`.
+fn compute_stack_cost(
+	func_idx: u32,
+	module: &elements::Module,
+	config: &Config,
+) -> Result<FunctionStackCost, Error> {
+	let weights = &config.value_weights;
 	// To calculate the cost of a function we need to convert index from
 	// function index space to defined function spaces.
 	let func_imports = module.import_count(elements::ImportCountType::Function) as u32;
-	let defined_func_idx = func_idx
-		.checked_sub(func_imports)
-		.ok_or_else(|| Error("This should be a index of a defined function".into()))?;
+	let defined_func_idx =
+		func_idx.checked_sub(func_imports).ok_or(Error::FunctionNotFound { func_idx })?;
 
-	let code_section = module
-		.code_section()
-		.ok_or_else(|| Error("Due to validation code section should exists".into()))?;
+	let code_section = module.code_section().ok_or(Error::MissingCodeSection)?;
 	let body = &code_section
 		.bodies()
 		.get(defined_func_idx as usize)
-		.ok_or_else(|| Error("Function body is out of bounds".into()))?;
+		.ok_or(Error::FunctionNotFound { func_idx })?;
 
-	let mut locals_count: u32 = 0;
+	let mut locals_cost: u32 = 0;
 	for local_group in body.locals() {
-		locals_count = locals_count
-			.checked_add(local_group.count())
-			.ok_or_else(|| Error("Overflow in local count".into()))?;
+		locals_cost = local_group
+			.count()
+			.checked_mul(weights.weight(local_group.value_type()))
+			.and_then(|cost| locals_cost.checked_add(cost))
+			.ok_or(Error::Overflow { func_idx })?;
 	}
 
-	let max_stack_height = max_height::compute(defined_func_idx, module)?;
+	let max_stack_height = max_height::compute(defined_func_idx, module, weights)?;
 
-	locals_count
+	// The cost is added to the stack height with `i32.add`, it must be a positive `i32`.
+	let cost = locals_cost
 		.checked_add(max_stack_height)
-		.ok_or_else(|| Error("Overflow in adding locals_count and max_stack_height".into()))
+		.and_then(|cost| cost.checked_add(config.frame_cost))
+		.filter(|cost| *cost <= i32::MAX as u32)
+		.ok_or(Error::Overflow { func_idx })?;
+	Ok(FunctionStackCost { func_idx, locals: locals_cost, max_height: max_stack_height, cost })
 }
 
 fn instrument_functions(ctx: &mut Context, module: &mut elements::Module) -> Result<(), Error> {
-	for section in module.sections_mut() {
-		if let elements::Section::Code(code_section) = section {
-			for func_body in code_section.bodies_mut() {
-				let opcodes = func_body.code_mut();
-				instrument_function(ctx, opcodes)?;
-			}
+	if let Some(code_section) = code_section_mut(module) {
+		for func_body in code_section.bodies_mut() {
+			ctx.instrument_body(func_body)?;
 		}
 	}
 	Ok(())
 }
 
-/// This function searches `call` instructions and wrap each call
-/// with preamble and postamble.
+/// This function searches `call` instructions, and `call_indirect` instructions if these are
+/// checked, and wrap each call with preamble and postamble.
 ///
 /// Before:
 ///
@@ -253,12 +1191,16 @@ fn instrument_functions(ctx: &mut Context, module: &mut elements::Module) -> Res
 ///
 /// drop
 /// ```
-fn instrument_function(ctx: &mut Context, func: &mut Instructions) -> Result<(), Error> {
+///
+/// Returns the ranges of the preambles and postambles.
+fn instrument_function(
+	ctx: &mut Context,
+	func: &mut Instructions,
+) -> Result<Vec<Range<usize>>, Error> {
 	use Instruction::*;
 
 	struct InstrumentCall {
 		offset: usize,
-		callee: u32,
 		cost: u32,
 	}
 
@@ -267,93 +1209,55 @@ fn instrument_function(ctx: &mut Context, func: &mut Instructions) -> Result<(),
 		.iter()
 		.enumerate()
 		.filter_map(|(offset, instruction)| {
-			if let Call(callee) = instruction {
-				ctx.stack_cost(*callee).and_then(|cost| {
-					if cost > 0 {
-						Some(InstrumentCall { callee: *callee, offset, cost })
-					} else {
-						None
-					}
-				})
-			} else {
-				None
-			}
+			let cost = match instruction {
+				Call(callee) => ctx.call_stack_cost(*callee),
+				CallIndirect(type_idx, _) => ctx.indirect_stack_cost(*type_idx),
+				_ => None,
+			};
+			cost.filter(|cost| *cost > 0).map(|cost| InstrumentCall { offset, cost })
 		})
 		.collect();
 
-	// The `instrumented_call!` contains the call itself. This is why we need to subtract one.
-	let len = func.elements().len() + calls.len() * (instrument_call!(0, 0, 0, 0).len() - 1);
+	// The checked call contains the call itself. This is why we need to subtract one.
+	let len = func.elements().len() +
+		calls.len() * (instrument_call!(0, 0, 0, 0).len() + HANDLER_LEN - 1);
 	let original_instrs = mem::replace(func.elements_mut(), Vec::with_capacity(len));
 	let new_instrs = func.elements_mut();
 
+	let mut injected = Vec::with_capacity(calls.len() * 2);
 	let mut calls = calls.into_iter().peekable();
 	for (original_pos, instr) in original_instrs.into_iter().enumerate() {
 		// whether there is some call instruction at this position that needs to be instrumented
-		let did_instrument = if let Some(call) = calls.peek() {
-			if call.offset == original_pos {
-				let new_seq = instrument_call!(
-					call.callee,
-					call.cost as i32,
-					ctx.stack_height_global_idx(),
-					ctx.stack_limit()
-				);
+		match calls.peek() {
+			Some(call) if call.offset == original_pos => {
+				// The call in the sequence is the original one, either direct or indirect.
+				let new_seq = ctx.checked_call(instr, call.cost);
+				let seq_call_pos = new_seq.len() - POSTAMBLE_LEN - 1;
+				// Everything but the original call, which is followed by the postamble, is
+				// injected.
+				let start = new_instrs.len();
+				let call_pos = start + seq_call_pos;
 				new_instrs.extend(new_seq);
-				true
-			} else {
-				false
-			}
-		} else {
-			false
-		};
-
-		if did_instrument {
-			calls.next();
-		} else {
-			new_instrs.push(instr);
+				injected.push(start..call_pos);
+				injected.push(call_pos + 1..new_instrs.len());
+				calls.next();
+			},
+			_ => new_instrs.push(instr),
 		}
 	}
 
 	if calls.next().is_some() {
-		return Err(Error("Not all calls were used".into()))
+		return Err(Error::UnusedCalls)
 	}
 
-	Ok(())
+	Ok(injected)
 }
 
 fn resolve_func_type(
 	func_idx: u32,
 	module: &elements::Module,
 ) -> Result<&elements::FunctionType, Error> {
-	let types = module.type_section().map(|ts| ts.types()).unwrap_or(&[]);
-	let functions = module.function_section().map(|fs| fs.entries()).unwrap_or(&[]);
-
-	let func_imports = module.import_count(elements::ImportCountType::Function);
-	let sig_idx = if func_idx < func_imports as u32 {
-		module
-			.import_section()
-			.expect("function import count is not zero; import section must exists; qed")
-			.entries()
-			.iter()
-			.filter_map(|entry| match entry.external() {
-				elements::External::Function(idx) => Some(*idx),
-				_ => None,
-			})
-			.nth(func_idx as usize)
-			.expect(
-				"func_idx is less than function imports count;
-				nth function import must be `Some`;
-				qed",
-			)
-	} else {
-		functions
-			.get(func_idx as usize - func_imports)
-			.ok_or_else(|| Error(format!("Function at index {} is not defined", func_idx)))?
-			.type_ref()
-	};
-	let Type::Function(ty) = types.get(sig_idx as usize).ok_or_else(|| {
-		Error(format!("Signature {} (specified by func {}) isn't defined", sig_idx, func_idx))
-	})?;
-	Ok(ty)
+	Ok(crate::stack_effect::resolve_func_type(func_idx, module)?)
 }
 
 #[cfg(test)]
@@ -391,4 +1295,723 @@ mod tests {
 		let module = inject_limiter(module, 1024).expect("Failed to inject stack counter");
 		validate_module(module);
 	}
+
+	#[test]
+	fn single_thunk_per_function() {
+		let module = parse_wat(
+			r#"
+(module
+	(type $t (func (result i32)))
+	(func $a (type $t)
+		i32.const 1
+	)
+	(func $b (type $t)
+		i32.const 2
+	)
+	(table 4 funcref)
+	(elem (i32.const 0) $a $b $a)
+	(elem (i32.const 3) $a)
+	(export "a" (func $a))
+	(export "a_again" (func $a))
+)
+"#,
+		);
+
+		let config = Config::new(1024).with_thunk_map("thunks");
+		let module = inject_limiter_with_config(module, &config).expect("Failed to inject");
+
+		let thunks = read_thunk_map(&module, "thunks").unwrap().expect("Thunk map is emitted");
+		assert_eq!(thunks.into_iter().collect::<Vec<_>>(), vec![(0, 2), (1, 3)]);
+		assert_eq!(module.functions_space(), 4);
+		// No type is added for the thunks, they have the signatures of their functions.
+		assert_eq!(module.type_section().unwrap().types().len(), 1);
+		validate_module(module);
+	}
+
+	#[test]
+	fn start_function_thunk() {
+		let module = parse_wat(
+			r#"
+(module
+	(func $start
+		(local i32)
+	)
+	(start $start)
+)
+"#,
+		);
+
+		let module = inject_limiter(module, 1024).expect("Failed to inject stack counter");
+		// The start function isn't exported, but still is entered through its thunk.
+		assert_eq!(module.start_section(), Some(1));
+		assert_eq!(verify(&module, 1024), Ok(()));
+		validate_module(module);
+	}
+
+	#[test]
+	fn thunk_selection() {
+		let module = parse_wat(
+			r#"
+(module
+	(type $t (func (param i32) (result i32)))
+	(func $a (type $t)
+		local.get 0
+	)
+	(func $b (type $t)
+		local.get 0
+	)
+	(func $main (export "main") (param i32) (result i32)
+		(call_indirect (type $t) (local.get 0) (i32.const 0))
+	)
+	(table 2 funcref)
+	(elem (i32.const 0) $a $b)
+)
+"#,
+		);
+		let thunked = |selection| {
+			let config = Config::new(1024).with_thunk_selection(selection).with_thunk_map("thunks");
+			let module = inject_limiter_with_config(module.clone(), &config).unwrap();
+			let thunks = read_thunk_map(&module, "thunks").unwrap().unwrap();
+			thunks.into_keys().collect::<Vec<_>>()
+		};
+
+		assert_eq!(thunked(ThunkSelection::All), vec![0, 1, 2]);
+		assert_eq!(thunked(ThunkSelection::Exports), vec![2]);
+		assert_eq!(thunked(ThunkSelection::Functions(vec![1, 2, 5])), vec![1, 2]);
+
+		// The selected functions are shifted along with the rest by an added import.
+		let config = Config::new(1024)
+			.with_thunk_selection(ThunkSelection::Functions(vec![1]))
+			.with_overflow_handler("env", "stack_overflow")
+			.with_thunk_map("thunks");
+		let module = inject_limiter_with_config(module, &config).unwrap();
+		let thunks = read_thunk_map(&module, "thunks").unwrap().unwrap();
+		assert_eq!(thunks.into_iter().collect::<Vec<_>>(), vec![(2, 4)]);
+	}
+
+	#[test]
+	fn thunk_map_and_names() {
+		let mut module = parse_wat(
+			r#"
+(module
+	(func $leaf (result i32)
+		i32.const 1
+	)
+	(func $main (export "main") (result i32)
+		call $leaf
+	)
+)
+"#,
+		);
+		let mut function_names = elements::FunctionNameSubsection::default();
+		function_names.names_mut().insert(1, "main".into());
+		module.sections_mut().push(elements::Section::Name(elements::NameSection::new(
+			None,
+			Some(function_names),
+			None,
+		)));
+
+		let config = Config::new(1024).with_thunk_map("thunks").with_thunk_name_suffix("_thunk");
+		let module = inject_limiter_with_config(module, &config).expect("Failed to inject");
+
+		let thunks = read_thunk_map(&module, "thunks").unwrap().expect("Thunk map is emitted");
+		assert_eq!(thunks.into_iter().collect::<Vec<_>>(), vec![(1, 2)]);
+
+		let names = module.names_section().unwrap().functions().unwrap().names();
+		assert_eq!(names.get(2).map(String::as_str), Some("main_thunk"));
+
+		validate_module(module);
+	}
+
+	#[test]
+	fn internal_global_with_imported_globals() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "g" (global i32))
+	(global (mut i32) (i32.const 0))
+	(func (export "main") (result i32)
+		get_global 0
+	)
+)
+"#,
+		);
+
+		let config = Config::new(1024).with_internal_global();
+		let module = inject_limiter_with_config(module, &config).expect("Failed to inject");
+
+		assert_eq!(crate::internal_globals(&module), vec![2]);
+		validate_module(module);
+	}
+
+	#[test]
+	fn existing_counter() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "g" (global i32))
+	(global $sp (export "sp") (mut i32) (i32.const 0))
+	(func $leaf (result i32)
+		global.get $sp
+	)
+	(func (export "main") (result i32)
+		call $leaf
+	)
+)
+"#,
+		);
+
+		let counter = Counter::Import { module: "env".into(), field: "stack_height".into() };
+		let config = Config::new(1024).with_counter(counter);
+		let instrumented = inject_limiter_with_config(module.clone(), &config).unwrap();
+		let imports = instrumented.import_section().unwrap().entries();
+		assert_eq!(imports[1].field(), "stack_height");
+		// The defined global is shifted by the import.
+		assert_eq!(instrumented.globals_space(), 3);
+		let exports = instrumented.export_section().unwrap().entries();
+		assert_eq!(exports[0].internal(), &elements::Internal::Global(2));
+		let main = &instrumented.code_section().unwrap().bodies()[1];
+		assert!(main.code().elements().contains(&Instruction::SetGlobal(1)));
+		validate_module(instrumented);
+
+		// An existing import is reused.
+		let imported = parse_wat(
+			r#"
+(module
+	(import "env" "stack_height" (global (mut i32)))
+	(func (export "main"))
+)
+"#,
+		);
+		let instrumented = inject_limiter_with_config(imported, &config).unwrap();
+		assert_eq!(instrumented.import_section().unwrap().entries().len(), 1);
+		assert_eq!(instrumented.globals_space(), 1);
+
+		let config = Config::new(1024).with_counter(Counter::Global(1));
+		let instrumented = inject_limiter_with_config(module.clone(), &config).unwrap();
+		assert_eq!(instrumented.globals_space(), 2);
+
+		let config = Config::new(1024).with_counter(Counter::Global(0));
+		assert_eq!(
+			inject_limiter_with_config(module, &config).unwrap_err(),
+			Error::InvalidCounter { global_idx: 0 }
+		);
+	}
+
+	#[test]
+	fn exported_global() {
+		let module = parse_wat(
+			r#"
+(module
+	(global (mut i32) (i32.const 0))
+	(func (export "main"))
+)
+"#,
+		);
+
+		let config = Config::new(1024).with_exported_global("__stack_height");
+		let instrumented = inject_limiter_with_config(module, &config).unwrap();
+		let exports = instrumented.export_section().unwrap().entries();
+		assert_eq!(exports[1].field(), "__stack_height");
+		assert_eq!(exports[1].internal(), &elements::Internal::Global(1));
+		validate_module(instrumented);
+
+		let config = Config::new(1024).with_exported_global("main");
+		assert_eq!(
+			inject_limiter_with_config(parse_wat("(module (func (export \"main\")))"), &config)
+				.unwrap_err(),
+			Error::ExportExists { field: "main".into() }
+		);
+	}
+
+	#[test]
+	fn recursive_calls_only() {
+		let module = parse_wat(
+			r#"
+(module
+	(func $leaf (param i32) (result i32)
+		local.get 0
+	)
+	(func $middle (result i32)
+		(call $leaf (i32.const 1))
+		(call $leaf (i32.const 2))
+		i32.add
+	)
+	(func $countdown (param i32) (result i32)
+		local.get 0
+		if (result i32)
+			(call $countdown (i32.sub (local.get 0) (i32.const 1)))
+		else
+			call $middle
+		end
+	)
+	(func (export "main") (result i32)
+		(call $countdown (i32.const 3))
+	)
+)
+"#,
+		);
+
+		let config = Config::new(1024).with_recursive_calls_only();
+		let module = inject_limiter_with_config(module, &config).expect("Failed to inject");
+
+		// The costs checked in each body, the thunk of `main` last.
+		let checks: Vec<Vec<i32>> = module
+			.code_section()
+			.unwrap()
+			.bodies()
+			.iter()
+			.map(|body| {
+				body.code()
+					.elements()
+					.windows(3)
+					.filter_map(|window| match window {
+						[Instruction::GetGlobal(0), Instruction::I32Const(cost), Instruction::I32Add] =>
+							Some(*cost),
+						_ => None,
+					})
+					.collect()
+			})
+			.collect();
+		// Only the calls of `countdown` are checked, charging the cost of `middle` and `leaf` too.
+		// The thunk of `main` charges just its own cost, as `countdown` is checked.
+		assert_eq!(checks, vec![vec![], vec![], vec![5], vec![5], vec![1]]);
+		validate_module(module);
+	}
+
+	#[test]
+	fn overflow_handler() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "ext" (func $ext))
+	(func $callee (param i32) (result i32)
+		local.get 0
+	)
+	(func (export "call") (result i32)
+		call $ext
+		(call $callee (i32.const 1))
+	)
+)
+"#,
+		);
+
+		let config = Config::new(1024).with_overflow_handler("env", "stack_overflow");
+		let instrumented = inject_limiter_with_config(module.clone(), &config).unwrap();
+		let imports = instrumented.import_section().unwrap().entries();
+		assert_eq!(imports[1].field(), "stack_overflow");
+		// The handler is told the callee, shifted by the import of the handler.
+		let code = instrumented.code_section().unwrap().bodies()[1].code().elements();
+		assert_eq!(
+			&code[10..15],
+			&[
+				Instruction::I32Const(2),
+				Instruction::Call(1),
+				Instruction::Unreachable,
+				Instruction::End,
+				Instruction::Call(2)
+			]
+		);
+		assert_eq!(verify(&instrumented, 1024), Ok(()));
+		validate_module(instrumented);
+
+		// An imported handler is reused, so it has to have the right signature.
+		let config = Config::new(1024).with_overflow_handler("env", "ext");
+		assert_eq!(
+			inject_limiter_with_config(module, &config).unwrap_err(),
+			Error::OverflowHandlerSignature { func_idx: 0 }
+		);
+	}
+
+	#[test]
+	fn dry_run_report() {
+		let module = parse_wat(
+			r#"
+(module
+	(func $callee (param i32) (result i32)
+		local.get 0
+	)
+	(func $unused (result i32)
+		(call $callee (i32.const 1))
+	)
+	(func (export "main") (result i32)
+		(call $callee (i32.const 2))
+	)
+)
+"#,
+		);
+
+		let config = Config::new(1024);
+		let report = dry_run(&module, &config).unwrap();
+		assert_eq!(report.functions, analyze(&module, &config).unwrap());
+		assert_eq!(report.thunks, vec![2]);
+		assert_eq!(report.checked_calls, 2);
+
+		let instrumented = inject_limiter_with_config(module.clone(), &config).unwrap();
+		let size = |module: elements::Module| elements::serialize(module).unwrap().len();
+		assert_eq!(report.size_increase, size(instrumented) - size(module));
+	}
+
+	#[test]
+	fn static_bound() {
+		let module = parse_wat(
+			r#"
+(module
+	(func $leaf (param i32) (result i32)
+		local.get 0
+	)
+	(func $middle (result i32)
+		(call $leaf (i32.const 1))
+		(call $leaf (i32.const 2))
+		i32.add
+	)
+	(func (export "main") (result i32)
+		(call $middle)
+	)
+	(func $recursive (export "recursive")
+		call $recursive
+	)
+)
+"#,
+		);
+
+		assert_eq!(
+			max_stack_height(&module, &Config::new(1024)).unwrap(),
+			vec![("main".into(), Some(4)), ("recursive".into(), None)]
+		);
+		// The costs of `main`, `middle` and `leaf`, each with a frame cost.
+		assert_eq!(
+			max_stack_height(&module, &Config::new(1024).with_frame_cost(1)).unwrap()[0],
+			("main".into(), Some(7))
+		);
+
+		// Recursive modules are instrumented.
+		let config = Config::new(1024).with_static_bound();
+		let instrumented = inject_limiter_with_config(module.clone(), &config).unwrap();
+		assert_eq!(instrumented.globals_space(), 1);
+
+		let mut module = module;
+		module.export_section_mut().unwrap().entries_mut().pop();
+		let bounded =
+			inject_limiter_with_config(module.clone(), &config.clone().with_mark()).unwrap();
+		assert_eq!(bounded.globals_space(), 0);
+		assert!(crate::is_instrumented(&bounded, PASS));
+
+		let config = Config::new(3).with_static_bound();
+		assert_eq!(
+			inject_limiter_with_config(module, &config).unwrap_err(),
+			Error::StaticBoundExceeded { func_idx: 2, bound: 4 }
+		);
+	}
+
+	#[test]
+	fn deep_call_chain() {
+		let module = crate::testing::module_fixture()
+			.with_call_chain(200_000)
+			.with_export("call", 0)
+			.build();
+
+		let config = Config::new(1_000_000).with_frame_cost(1).with_static_bound();
+		let bounded = inject_limiter_with_config(module.clone(), &config).unwrap();
+		assert_eq!(bounded.globals_space(), 0);
+
+		// None of the calls is checked, the thunk of the export charges the whole chain.
+		let config = Config::new(1_000_000).with_frame_cost(1).with_recursive_calls_only();
+		let instrumented = inject_limiter_with_config(module, &config).unwrap();
+		let bodies = instrumented.code_section().unwrap().bodies();
+		assert_eq!(bodies.len(), 200_001);
+		assert!(bodies[..200_000]
+			.iter()
+			.all(|body| !body.code().elements().contains(&Instruction::GetGlobal(0))));
+		assert_eq!(
+			bodies[200_000].code().elements()[..2],
+			[Instruction::GetGlobal(0), Instruction::I32Const(200_000)]
+		);
+	}
+
+	#[test]
+	fn structured_errors() {
+		let module = elements::Module::new(vec![
+			elements::Section::Type(elements::TypeSection::with_types(vec![
+				elements::Type::Function(elements::FunctionType::new(vec![], vec![])),
+			])),
+			elements::Section::Function(elements::FunctionSection::with_entries(vec![
+				elements::Func::new(0),
+			])),
+		]);
+		let err = inject_limiter(module, 1024).unwrap_err();
+		assert_eq!(err, Error::MissingCodeSection);
+		assert_eq!(err.message(), "The module has no code section");
+
+		let mut module = parse_wat("(module)");
+		module.set_custom_section("thunks", vec![0; 5]);
+		assert_eq!(
+			read_thunk_map(&module, "thunks").unwrap_err(),
+			Error::MalformedThunkMap { section: "thunks".into() }
+		);
+	}
+
+	#[test]
+	fn global_placement() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "g" (global i32))
+	(global $sp (export "sp") (mut i32) (i32.const 0))
+	(func $leaf (result i32)
+		get_global $sp
+	)
+	(func (export "main") (result i32)
+		call $leaf
+	)
+)
+"#,
+		);
+
+		let config = Config::new(1024)
+			.with_global_placement(IndexPlacement::First)
+			.with_internal_global();
+		let instrumented = inject_limiter_with_config(module.clone(), &config).unwrap();
+		assert_eq!(crate::internal_globals(&instrumented), vec![1]);
+		let exports = instrumented.export_section().unwrap().entries();
+		assert_eq!(exports[0].internal(), &elements::Internal::Global(2));
+		let leaf = &instrumented.code_section().unwrap().bodies()[0];
+		assert_eq!(leaf.code().elements()[0], Instruction::GetGlobal(2));
+		let main = &instrumented.code_section().unwrap().bodies()[1];
+		assert!(main.code().elements().contains(&Instruction::SetGlobal(1)));
+		validate_module(instrumented);
+
+		let config = Config::new(1024).with_global_placement(IndexPlacement::At(0));
+		assert_eq!(
+			inject_limiter_with_config(module, &config).unwrap_err(),
+			Error::InvalidPlacement { index: 0 }
+		);
+	}
+	#[cfg(feature = "sign_ext")]
+	#[test]
+	fn sign_ext() {
+		let module = parse_wat(
+			r#"
+(module
+	(func $extend (param i32) (result i32)
+		(i32.extend16_s (local.get 0))
+	)
+	(func (export "main") (result i32)
+		(call $extend (i32.const 1))
+	)
+)
+"#,
+		);
+
+		let module = inject_limiter(module, 1024).expect("Failed to inject stack counter");
+		validate_module(module);
+	}
+
+	#[cfg(feature = "multi_value")]
+	#[test]
+	fn multiple_results() {
+		let module = parse_wat(
+			r#"
+(module
+	(func $pair (export "pair") (param i32) (result i32 i64)
+		(local.get 0)
+		(i64.const 1)
+	)
+	(func (export "main") (result i32)
+		(call $pair (i32.const 1))
+		(drop)
+	)
+)
+"#,
+		);
+
+		// Both results are on the stack at the end of `$pair`.
+		assert_eq!(analyze(&module, &Config::new(1024)).unwrap()[0].cost, 2);
+
+		let module = inject_limiter(module, 1024).expect("Failed to inject stack counter");
+		validate_module(module);
+	}
+
+	#[cfg(feature = "atomics")]
+	#[test]
+	fn atomics() {
+		use elements::{AtomicsInstruction::*, Instruction::*, MemArg};
+		use parity_wasm::builder;
+
+		let mem = MemArg { align: 2, offset: 0 };
+		let module = builder::module()
+			.memory()
+			.build()
+			.function()
+			.signature()
+			.with_param(elements::ValueType::I32)
+			.with_result(elements::ValueType::I32)
+			.build()
+			.body()
+			.with_instructions(elements::Instructions::new(vec![
+				GetLocal(0),
+				I32Const(1),
+				I32Const(2),
+				Atomics(I32AtomicRmwCmpxchg(mem.clone())),
+				GetLocal(0),
+				Atomics(I32AtomicLoad(mem)),
+				I32Add,
+				End,
+			]))
+			.build()
+			.build()
+			.export()
+			.field("main")
+			.internal()
+			.func(0)
+			.build()
+			.build();
+
+		// The compare-exchange needs three values on the stack.
+		assert_eq!(analyze(&module, &Config::new(1024)).unwrap()[0].cost, 3);
+		assert!(inject_limiter(module, 1024).is_ok());
+	}
+
+	#[test]
+	fn value_weights() {
+		let module = parse_wat(
+			r#"
+(module
+	(global $g i32 (i32.const 1))
+	(func (param i64) (result i64) (local f64 i32)
+		(select (local.get 0) (i64.const 1) (global.get $g))
+		(i64.const 2)
+		(i64.add)
+	)
+)
+"#,
+		);
+
+		assert_eq!(analyze(&module, &Config::new(1024)).unwrap()[0].cost, 5);
+		// The `f64` and `i32` locals, plus two `i64` values and the `i32` condition of `select`.
+		let weights = ValueWeights { i64: 2, f64: 3, ..ValueWeights::default() };
+		let config = Config::new(1024).with_value_weights(weights);
+		assert_eq!(
+			analyze(&module, &config).unwrap(),
+			vec![FunctionStackCost { func_idx: 0, locals: 4, max_height: 5, cost: 9 }]
+		);
+		// The selected `i64` and the second operand of the addition.
+		let weights = ValueWeights { i32: 0, ..weights };
+		let config = Config::new(1024).with_value_weights(weights).with_frame_cost(2);
+		assert_eq!(
+			analyze(&module, &config).unwrap(),
+			vec![FunctionStackCost { func_idx: 0, locals: 3, max_height: 4, cost: 9 }]
+		);
+	}
+
+	#[test]
+	fn frame_cost() {
+		let module = parse_wat(
+			r#"
+(module
+	(func $callee (param i32) (result i32)
+		(local.get 0)
+	)
+	(func (export "call") (result i32)
+		(call $callee (i32.const 1))
+	)
+)
+"#,
+		);
+
+		let config = Config::new(1024).with_frame_cost(3);
+		let module = inject_limiter_with_config(module, &config).unwrap();
+		let body = module.code_section().unwrap().bodies()[1].code().elements();
+		// The value on the stack, plus the frame.
+		assert_eq!(body[2], Instruction::I32Const(4));
+		validate_module(module);
+	}
+
+	#[test]
+	fn indirect_calls() {
+		let module = parse_wat(
+			r#"
+(module
+	(type $binary (func (param i32 i32) (result i32)))
+	(table 2 funcref)
+	(elem (i32.const 0) $add $mul)
+	(func $add (type $binary)
+		(i32.add (local.get 0) (local.get 1))
+	)
+	(func $mul (type $binary) (local i32 i32)
+		(i32.mul (local.get 0) (local.get 1))
+	)
+	(func (export "call") (param i32) (result i32)
+		(call_indirect (type $binary) (i32.const 2) (i32.const 3) (local.get 0))
+	)
+)
+"#,
+		);
+
+		let config = Config::new(1024).with_indirect_calls();
+		let module = inject_limiter_with_config(module, &config).unwrap();
+		let body = module.code_section().unwrap().bodies()[2].code().elements();
+		// The locals of `$mul` and its two values on the stack.
+		assert_eq!(&body[3..5], &[Instruction::GetGlobal(0), Instruction::I32Const(4)]);
+		assert!(matches!(body[13], Instruction::CallIndirect(..)));
+		// The table keeps the original functions.
+		let members = module.elements_section().unwrap().entries()[0].members();
+		assert_eq!(members, &[0, 1]);
+		assert_eq!(verify(&module, 1024), Ok(()));
+		validate_module(module);
+	}
+
+	#[test]
+	fn stack_cost_overflow() {
+		use parity_wasm::builder;
+
+		let module = |locals: Vec<elements::Local>| {
+			builder::module()
+				.import()
+				.module("env")
+				.field("f")
+				.external()
+				.func(0)
+				.build()
+				.function()
+				.signature()
+				.build()
+				.body()
+				.with_locals(locals)
+				.build()
+				.build()
+				.build()
+		};
+
+		let local = |count| elements::Local::new(count, elements::ValueType::I32);
+		assert!(matches!(
+			inject_limiter(module(vec![local(u32::MAX), local(1)]), 1024),
+			Err(Error::Overflow { func_idx: 1 })
+		));
+		assert!(matches!(
+			inject_limiter(module(vec![local(i32::MAX as u32 + 1)]), 1024),
+			Err(Error::Overflow { func_idx: 1 })
+		));
+		assert!(inject_limiter(module(vec![local(1024)]), 1024).is_ok());
+	}
+	#[test]
+	fn double_instrumentation_refused() {
+		let module = parse_wat(
+			r#"
+(module
+	(func (export "call")
+		(nop)
+	)
+)
+"#,
+		);
+
+		let config = Config::new(1024).with_mark();
+		let limited = inject_limiter_with_config(module, &config).unwrap();
+		assert_eq!(
+			crate::instrumentation_marks(&limited),
+			vec![(PASS.into(), "limit=1024".into())]
+		);
+		assert!(matches!(inject_limiter(limited, 1024), Err(Error::AlreadyInstrumented)));
+	}
 }
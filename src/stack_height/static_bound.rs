@@ -0,0 +1,324 @@
+//! Static bounds of the stack height along the calls which can't recurse.
+
+use crate::std::{
+	cmp::min,
+	collections::{BTreeMap as Map, BTreeSet as Set},
+	string::String,
+	vec::Vec,
+};
+
+use super::{compute_stack_costs, thunk, Config, Error, ThunkSelection};
+use parity_wasm::elements::{self, Instruction, Internal};
+
+struct Analysis<'a> {
+	module: &'a elements::Module,
+	func_imports: u32,
+	/// Stack costs of all functions, including imports.
+	costs: Vec<u32>,
+	bounds: Map<u32, Option<u32>>,
+	on_stack: Set<u32>,
+}
+
+/// A function whose calls are being visited by [`Analysis::bound`].
+struct Frame {
+	func_idx: u32,
+	/// Calls which aren't visited yet in reverse order, `None` for a `call_indirect`.
+	calls: Vec<Option<u32>>,
+	/// Bound of the callees visited so far.
+	callees_bound: Option<u32>,
+}
+
+impl Analysis<'_> {
+	/// The bound of `func_idx` if it is known without visiting its calls, or a new frame for it.
+	fn enter(&mut self, func_idx: u32) -> Result<Option<u32>, Frame> {
+		if let Some(bound) = self.bounds.get(&func_idx) {
+			return Ok(*bound)
+		}
+		// The host isn't limited.
+		let defined_idx = match func_idx.checked_sub(self.func_imports) {
+			Some(defined_idx) => defined_idx as usize,
+			None => return Ok(Some(0)),
+		};
+		if self.on_stack.contains(&func_idx) {
+			return Ok(None)
+		}
+		let code = match self.module.code_section().and_then(|s| s.bodies().get(defined_idx)) {
+			Some(body) => body.code().elements(),
+			None => return Ok(None),
+		};
+
+		self.on_stack.insert(func_idx);
+		let mut calls: Vec<Option<u32>> = code
+			.iter()
+			.filter_map(|instruction| match instruction {
+				Instruction::Call(callee) => Some(Some(*callee)),
+				Instruction::CallIndirect(..) => Some(None),
+				_ => None,
+			})
+			.collect();
+		calls.reverse();
+		Err(Frame { func_idx, calls, callees_bound: Some(0) })
+	}
+
+	/// Maximal stack height reached by a call of `func_idx`, `None` if a recursion or an indirect
+	/// call is reachable.
+	///
+	/// The call graph is walked with an explicit stack, since it may be arbitrarily deep.
+	fn bound(&mut self, func_idx: u32) -> Option<u32> {
+		let mut frames = match self.enter(func_idx) {
+			Ok(bound) => return bound,
+			Err(frame) => vec![frame],
+		};
+		// Bound of the function which was finished last.
+		let mut returned = None;
+		while let Some(frame) = frames.last_mut() {
+			if let Some(callee_bound) = returned.take() {
+				frame.callees_bound =
+					frame.callees_bound.zip(callee_bound).map(|(a, b): (u32, u32)| a.max(b));
+			}
+			// The remaining calls don't matter once the function is unbounded.
+			let call = if frame.callees_bound.is_some() { frame.calls.pop() } else { None };
+			match call {
+				Some(Some(callee)) => match self.enter(callee) {
+					Ok(bound) => returned = Some(bound),
+					Err(callee_frame) => frames.push(callee_frame),
+				},
+				Some(None) => frame.callees_bound = None,
+				None => {
+					let Frame { func_idx, callees_bound, .. } = *frame;
+					frames.pop();
+					self.on_stack.remove(&func_idx);
+					let bound = callees_bound.map(|callees_bound| {
+						self.costs
+							.get(func_idx as usize)
+							.copied()
+							.unwrap_or(0)
+							.saturating_add(callees_bound)
+					});
+					self.bounds.insert(func_idx, bound);
+					returned = Some(bound);
+				},
+			}
+		}
+		returned.expect("the outermost frame returns its bound; qed")
+	}
+}
+
+fn analysis<'a>(module: &'a elements::Module, config: &Config) -> Result<Analysis<'a>, Error> {
+	Ok(Analysis {
+		module,
+		func_imports: module.import_count(elements::ImportCountType::Function) as u32,
+		costs: compute_stack_costs(module, config)?,
+		bounds: Map::new(),
+		on_stack: Set::new(),
+	})
+}
+
+/// Compute the maximal stack height each exported function can reach, counted like the limiter
+/// configured with `config` counts it.
+///
+/// The module isn't modified. The height is the sum of the stack costs along the deepest chain
+/// of calls. It is `None` if a recursion or a `call_indirect` is reachable, since then the depth
+/// of the calls isn't known statically. Calls of imported functions count nothing, and calls
+/// back into the module from the host aren't accounted for.
+///
+/// Returns the height for each exported function by export name, in export order.
+pub fn max_stack_height(
+	module: &elements::Module,
+	config: &Config,
+) -> Result<Vec<(String, Option<u32>)>, Error> {
+	let mut analysis = analysis(module, config)?;
+	Ok(module
+		.export_section()
+		.map(|section| section.entries())
+		.unwrap_or(&[])
+		.iter()
+		.filter_map(|entry| match *entry.internal() {
+			Internal::Function(func_idx) => Some((entry.field().into(), analysis.bound(func_idx))),
+			_ => None,
+		})
+		.collect())
+}
+
+/// Check the static bound of the stack height of all functions called from outside of the
+/// module against the stack limit of `config`, see [`Config::with_static_bound`].
+///
+/// Returns `Ok(false)` if some function isn't bounded statically.
+pub(crate) fn check(module: &elements::Module, config: &Config) -> Result<bool, Error> {
+	let mut analysis = analysis(module, config)?;
+	for func_idx in thunk::entries(module, false, &ThunkSelection::All) {
+		match analysis.bound(func_idx) {
+			Some(bound) if bound > config.stack_limit =>
+				return Err(Error::StaticBoundExceeded { func_idx, bound }),
+			Some(_) => {},
+			None => return Ok(false),
+		}
+	}
+	Ok(true)
+}
+
+/// Direct calls between the functions of a module.
+struct CallGraph {
+	/// Functions called by each function, including imports, which call nothing.
+	callees: Vec<Vec<u32>>,
+}
+
+impl CallGraph {
+	fn new(module: &elements::Module) -> Self {
+		let func_imports = module.import_count(elements::ImportCountType::Function);
+		let bodies = module.code_section().map(|section| section.bodies()).unwrap_or(&[]);
+		let mut callees = vec![Vec::new(); func_imports];
+		callees.extend(bodies.iter().map(|body| {
+			let mut callees: Vec<u32> = body
+				.code()
+				.elements()
+				.iter()
+				.filter_map(|instruction| match instruction {
+					Instruction::Call(callee) => Some(*callee),
+					_ => None,
+				})
+				.collect();
+			callees.sort_unstable();
+			callees.dedup();
+			callees
+		}));
+		CallGraph { callees }
+	}
+
+	/// Whether each function is part of a cycle of direct calls, including calling itself, and
+	/// all functions in reverse topological order of their components: the functions a function
+	/// calls come before it unless they are in its cycle.
+	fn components(&self) -> (Vec<bool>, Vec<u32>) {
+		let len = self.callees.len();
+		let mut components = Components {
+			callees: &self.callees,
+			index: vec![None; len],
+			low_link: vec![0; len],
+			stack: Vec::new(),
+			on_stack: vec![false; len],
+			next_index: 0,
+			recursive: vec![false; len],
+			order: Vec::with_capacity(len),
+		};
+		for func_idx in 0..len as u32 {
+			if components.index[func_idx as usize].is_none() {
+				components.visit(func_idx);
+			}
+		}
+		(components.recursive, components.order)
+	}
+}
+
+/// Tarjan's search for the strongly connected components of the call graph.
+struct Components<'a> {
+	callees: &'a [Vec<u32>],
+	index: Vec<Option<u32>>,
+	low_link: Vec<u32>,
+	stack: Vec<u32>,
+	on_stack: Vec<bool>,
+	next_index: u32,
+	recursive: Vec<bool>,
+	/// The members of the components found so far, in the order they were completed.
+	order: Vec<u32>,
+}
+
+impl Components<'_> {
+	fn open(&mut self, func_idx: u32) {
+		let idx = func_idx as usize;
+		self.index[idx] = Some(self.next_index);
+		self.low_link[idx] = self.next_index;
+		self.next_index += 1;
+		self.stack.push(func_idx);
+		self.on_stack[idx] = true;
+	}
+
+	/// Visit the functions reachable from `root` which aren't visited yet.
+	///
+	/// The search keeps its own stack of the functions being visited together with the position
+	/// of the next callee to visit, since the call graph may be arbitrarily deep.
+	fn visit(&mut self, root: u32) {
+		let callees = self.callees;
+		self.open(root);
+		let mut visiting = vec![(root, 0)];
+		while let Some((func_idx, next)) = visiting.last_mut() {
+			let (func_idx, idx) = (*func_idx, *func_idx as usize);
+			if let Some(&callee) = callees[idx].get(*next) {
+				*next += 1;
+				if callee == func_idx {
+					self.recursive[idx] = true;
+				}
+				let callee_idx = callee as usize;
+				match self.index.get(callee_idx) {
+					// Invalid calls are reported by the computation of the stack costs.
+					None => {},
+					Some(None) => {
+						self.open(callee);
+						visiting.push((callee, 0));
+					},
+					Some(Some(callee_index)) if self.on_stack[callee_idx] =>
+						self.low_link[idx] = min(self.low_link[idx], *callee_index),
+					Some(Some(_)) => {},
+				}
+				continue
+			}
+
+			visiting.pop();
+			if let Some((caller, _)) = visiting.last() {
+				let caller_idx = *caller as usize;
+				self.low_link[caller_idx] = min(self.low_link[caller_idx], self.low_link[idx]);
+			}
+			if Some(self.low_link[idx]) == self.index[idx] {
+				let first = self.order.len();
+				while let Some(member) = self.stack.pop() {
+					self.on_stack[member as usize] = false;
+					self.order.push(member);
+					if member == func_idx {
+						break
+					}
+				}
+				if self.order.len() - first > 1 {
+					for member in &self.order[first..] {
+						self.recursive[*member as usize] = true;
+					}
+				}
+			}
+		}
+	}
+}
+
+/// Stack costs to charge for calls when only the calls of recursive functions are checked, see
+/// [`Config::with_recursive_calls_only`].
+///
+/// The cost of a function is its own stack cost in `costs` plus the largest cost of the functions
+/// it calls without a check. Returns the costs of all functions, including imports, and whether
+/// each function is recursive, i.e. whether calls of it have to be checked.
+pub(crate) fn recursive_call_costs(
+	module: &elements::Module,
+	costs: &[u32],
+) -> Result<(Vec<u32>, Vec<bool>), Error> {
+	let graph = CallGraph::new(module);
+	let (recursive, order) = graph.components();
+	let mut charged = vec![0; costs.len()];
+	// The functions called without a check aren't recursive, so they are in components of their
+	// own which come before their callers.
+	for func_idx in order {
+		let deepest = graph.callees[func_idx as usize]
+			.iter()
+			.filter(|callee| !recursive.get(**callee as usize).copied().unwrap_or(true))
+			.map(|callee| charged.get(*callee as usize).copied().unwrap_or(0))
+			.max()
+			.unwrap_or(0);
+		// The cost is added to the stack height with `i32.add`, it must be a positive `i32`.
+		let cost = costs
+			.get(func_idx as usize)
+			.copied()
+			.unwrap_or(0)
+			.checked_add(deepest)
+			.filter(|cost| *cost <= i32::MAX as u32)
+			.ok_or(Error::Overflow { func_idx })?;
+		if let Some(slot) = charged.get_mut(func_idx as usize) {
+			*slot = cost;
+		}
+	}
+	Ok((charged, recursive))
+}
@@ -9,7 +9,8 @@ use parity_wasm::{
 	elements::{self, FunctionType, Internal},
 };
 
-use super::{resolve_func_type, Context, Error};
+use super::{resolve_func_type, Context, Error, ThunkMap, ThunkSelection};
+use crate::{visit_function_indices, IndexSite};
 
 struct Thunk {
 	signature: FunctionType,
@@ -18,34 +19,54 @@ struct Thunk {
 	callee_stack_cost: u32,
 }
 
+/// Functions entered from outside of the module, which get thunks if they have a stack cost: the
+/// exported functions, the start function and the members of the tables, as far as they are in
+/// `selection`.
+///
+/// The functions called indirectly don't need thunks if the calls are checked, see
+/// [`super::Config::with_indirect_calls`]. Returns the function indices in ascending order.
+pub(crate) fn entries(
+	module: &elements::Module,
+	checks_indirect_calls: bool,
+	selection: &ThunkSelection,
+) -> Vec<u32> {
+	let exports = module.export_section().map(|es| es.entries()).unwrap_or(&[]);
+	let elem_segments = module.elements_section().map(|es| es.entries()).unwrap_or(&[]);
+
+	let exported_func_indices = exports.iter().filter_map(|entry| match entry.internal() {
+		Internal::Function(function_idx) => Some(*function_idx),
+		_ => None,
+	});
+	let table_func_indices = elem_segments
+		.iter()
+		.filter(|_| !checks_indirect_calls && *selection != ThunkSelection::Exports)
+		.flat_map(|segment| segment.members())
+		.cloned();
+
+	let mut entries: Vec<u32> = exported_func_indices
+		.chain(table_func_indices)
+		.chain(module.start_section())
+		.collect();
+	if let ThunkSelection::Functions(selected) = selection {
+		entries.retain(|func_idx| selected.contains(func_idx));
+	}
+	entries.sort_unstable();
+	entries.dedup();
+	entries
+}
+
 pub(crate) fn generate_thunks(
 	ctx: &mut Context,
 	module: elements::Module,
-) -> Result<elements::Module, Error> {
+) -> Result<(elements::Module, ThunkMap), Error> {
 	// First, we need to collect all function indices that should be replaced by thunks
 
 	let mut replacement_map: Map<u32, Thunk> = {
-		let exports = module.export_section().map(|es| es.entries()).unwrap_or(&[]);
-		let elem_segments = module.elements_section().map(|es| es.entries()).unwrap_or(&[]);
-		let start_func_idx = module.start_section();
-
-		let exported_func_indices = exports.iter().filter_map(|entry| match entry.internal() {
-			Internal::Function(function_idx) => Some(*function_idx),
-			_ => None,
-		});
-		let table_func_indices =
-			elem_segments.iter().flat_map(|segment| segment.members()).cloned();
-
-		// Replacement map is at least export section size.
 		let mut replacement_map: Map<u32, Thunk> = Map::new();
 
-		for func_idx in exported_func_indices
-			.chain(table_func_indices)
-			.chain(start_func_idx.into_iter())
-		{
-			let callee_stack_cost = ctx
-				.stack_cost(func_idx)
-				.ok_or_else(|| Error(format!("function with idx {} isn't found", func_idx)))?;
+		for &func_idx in &ctx.thunk_entries {
+			let callee_stack_cost =
+				ctx.stack_cost(func_idx).ok_or(Error::FunctionNotFound { func_idx })?;
 
 			// Don't generate a thunk if stack_cost of a callee is zero.
 			if callee_stack_cost != 0 {
@@ -70,12 +91,8 @@ pub(crate) fn generate_thunks(
 
 	let mut mbuilder = builder::from_module(module);
 	for (func_idx, thunk) in replacement_map.iter_mut() {
-		let instrumented_call = instrument_call!(
-			*func_idx,
-			thunk.callee_stack_cost as i32,
-			ctx.stack_height_global_idx(),
-			ctx.stack_limit()
-		);
+		let instrumented_call =
+			ctx.checked_call(elements::Instruction::Call(*func_idx), thunk.callee_stack_cost);
 		// Thunk body consist of:
 		//  - argument pushing
 		//  - instrumented call
@@ -86,11 +103,10 @@ pub(crate) fn generate_thunks(
 		for (arg_idx, _) in thunk.signature.params().iter().enumerate() {
 			thunk_body.push(elements::Instruction::GetLocal(arg_idx as u32));
 		}
-		thunk_body.extend(instrumented_call.iter().cloned());
+		thunk_body.extend(instrumented_call);
 		thunk_body.push(elements::Instruction::End);
 
-		// TODO: Don't generate a signature, but find an existing one.
-
+		// The builder reuses the type of the signature if the module has it already.
 		mbuilder = mbuilder
 			.function()
 			// Signature of the thunk should match the original function signature.
@@ -120,24 +136,21 @@ pub(crate) fn generate_thunks(
 		}
 	};
 
-	for section in module.sections_mut() {
-		match section {
-			elements::Section::Export(export_section) =>
-				for entry in export_section.entries_mut() {
-					if let Internal::Function(function_idx) = entry.internal_mut() {
-						fixup(function_idx)
-					}
-				},
-			elements::Section::Element(elem_section) =>
-				for segment in elem_section.entries_mut() {
-					for function_idx in segment.members_mut() {
-						fixup(function_idx)
-					}
-				},
-			elements::Section::Start(start_idx) => fixup(start_idx),
-			_ => {},
-		}
-	}
-
-	Ok(module)
+	// Calls keep referring to the original functions, and so do the element segments if the
+	// indirect calls are checked.
+	let thunk_elements = !ctx.checks_indirect_calls();
+	visit_function_indices(&mut module, |function_idx, site| match site {
+		IndexSite::Export | IndexSite::Start => fixup(function_idx),
+		IndexSite::Element if thunk_elements => fixup(function_idx),
+		_ => {},
+	});
+
+	let thunks = replacement_map
+		.iter()
+		.map(|(func_idx, thunk)| {
+			(*func_idx, thunk.idx.expect("At this point an index must be assigned to each thunk"))
+		})
+		.collect();
+
+	Ok((module, thunks))
 }
@@ -0,0 +1,219 @@
+//! Checking that a module is instrumented by the stack height limiter.
+
+use super::{checked_call, HANDLER_LEN};
+use crate::{
+	stack_effect::resolve_func_type,
+	std::{collections::BTreeSet, fmt, vec::Vec},
+};
+use parity_wasm::elements::{self, Instruction, Internal};
+
+/// A discrepancy between a module and the instrumentation of the stack height limiter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+	/// The call at `offset` in the body of the function `func_idx` isn't preceded by a check of
+	/// the stack height.
+	UnguardedCall { func_idx: u32, offset: usize },
+	/// The check before the call at `offset` in the body of the function `func_idx` enforces the
+	/// stack limit `found`.
+	Limit { func_idx: u32, offset: usize, found: u32 },
+	/// The function `func_idx` is exported, in the table or the start function, but isn't a thunk
+	/// checking the stack height.
+	UnguardedEntry { func_idx: u32 },
+}
+
+impl fmt::Display for Mismatch {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			Mismatch::UnguardedCall { func_idx, offset } => write!(
+				f,
+				"Function {} calls at offset {} without checking the stack height",
+				func_idx, offset
+			),
+			Mismatch::Limit { func_idx, offset, found } => write!(
+				f,
+				"Function {} checks the stack limit {} before the call at offset {}",
+				func_idx, found, offset
+			),
+			Mismatch::UnguardedEntry { func_idx } =>
+				write!(f, "Function {} is called from outside without a thunk", func_idx),
+		}
+	}
+}
+
+/// Number of instructions checking the stack height before a call.
+const GUARD_LEN: usize = 10;
+
+/// The stack limit of the check preceding the call at `offset` of `code`, if any.
+///
+/// The check may call an overflow handler, see [`super::Config::with_overflow_handler`].
+fn guard_limit(code: &[Instruction], offset: usize) -> Option<u32> {
+	use Instruction::*;
+	let len = guard_len(code, offset)?;
+	let guard = &code[offset - len..offset];
+	let (global, cost, limit) = match guard {
+		[GetGlobal(global), I32Const(cost), _, _, _, I32Const(limit), ..] =>
+			(*global, *cost, *limit),
+		_ => return None,
+	};
+	let handler = match guard {
+		[.., I32Const(reported), Call(handler), _, _] if len > GUARD_LEN =>
+			Some((*handler, *reported)),
+		_ => None,
+	};
+	let expected = checked_call(Nop, cost, global, limit as u32, handler);
+	if guard == &expected[..len] {
+		Some(limit as u32)
+	} else {
+		None
+	}
+}
+
+/// Length of the check preceding the call at `offset` of `code`, judging by its last
+/// instructions.
+fn guard_len(code: &[Instruction], offset: usize) -> Option<usize> {
+	use Instruction::*;
+	match code.get(..offset)? {
+		[.., Call(_), Unreachable, End] => Some(GUARD_LEN + HANDLER_LEN),
+		[.., Unreachable, End] => Some(GUARD_LEN),
+		_ => None,
+	}
+	.filter(|len| *len <= offset)
+}
+
+/// Checks that the module was instrumented by [`super::inject_limiter`] with `stack_limit`.
+///
+/// The stack costs of the functions aren't recomputed. Instead, the functions certainly having
+/// a stack cost, i.e. the ones with parameters or locals, are checked: every call of them has to
+/// be preceded by a check of the stack height against `stack_limit`, and if they are exported,
+/// in the table or the start function, they have to be replaced by a thunk. The functions in the
+/// table needn't be if every `call_indirect` is preceded by a check instead. This detects modules
+/// which aren't instrumented or instrumented with a different limit, but not every tampering with
+/// the instrumentation.
+pub fn verify(module: &elements::Module, stack_limit: u32) -> Result<(), Vec<Mismatch>> {
+	let func_imports = module.import_count(elements::ImportCountType::Function) as u32;
+	let bodies = module.code_section().map(|section| section.bodies()).unwrap_or(&[]);
+	let has_stack_cost = |func_idx: u32| {
+		let body = match func_idx.checked_sub(func_imports) {
+			Some(defined_idx) => bodies.get(defined_idx as usize),
+			None => return false,
+		};
+		let params = resolve_func_type(func_idx, module).map_or(0, |ty| ty.params().len());
+		params > 0 || body.map_or(false, |body| !body.locals().is_empty())
+	};
+
+	let mut mismatches = Vec::new();
+	let mut unguarded_indirect_calls = false;
+	for (idx, body) in bodies.iter().enumerate() {
+		let func_idx = func_imports + idx as u32;
+		let code = body.code().elements();
+		for (offset, instruction) in code.iter().enumerate() {
+			match instruction {
+				Instruction::Call(callee) if has_stack_cost(*callee) =>
+					match guard_limit(code, offset) {
+						Some(found) if found == stack_limit => {},
+						Some(found) => mismatches.push(Mismatch::Limit { func_idx, offset, found }),
+						None => mismatches.push(Mismatch::UnguardedCall { func_idx, offset }),
+					},
+				Instruction::CallIndirect(..) => match guard_limit(code, offset) {
+					Some(found) if found == stack_limit => {},
+					Some(found) => mismatches.push(Mismatch::Limit { func_idx, offset, found }),
+					None => unguarded_indirect_calls = true,
+				},
+				_ => {},
+			}
+		}
+	}
+
+	let mut entries = BTreeSet::new();
+	for entry in module.export_section().map(|section| section.entries()).unwrap_or(&[]) {
+		if let Internal::Function(func_idx) = *entry.internal() {
+			entries.insert(func_idx);
+		}
+	}
+	entries.extend(module.start_section());
+	// The functions in the table don't need thunks if every indirect call is guarded.
+	if unguarded_indirect_calls {
+		for segment in module.elements_section().map(|section| section.entries()).unwrap_or(&[]) {
+			entries.extend(segment.members().iter().copied());
+		}
+	}
+	for func_idx in entries {
+		if !has_stack_cost(func_idx) {
+			continue
+		}
+		let code = match bodies.get((func_idx - func_imports) as usize) {
+			Some(body) => body.code().elements(),
+			None => continue,
+		};
+		// A thunk forwards its parameters to the guarded call of the original function.
+		let params = resolve_func_type(func_idx, module).map_or(0, |ty| ty.params().len());
+		let forwards = code
+			.iter()
+			.take(params)
+			.enumerate()
+			.all(|(idx, instruction)| *instruction == Instruction::GetLocal(idx as u32));
+		let is_thunk = forwards &&
+			[GUARD_LEN, GUARD_LEN + HANDLER_LEN].iter().any(|len| {
+				let call = params + len;
+				matches!(code.get(call), Some(Instruction::Call(_))) &&
+					guard_len(code, call) == Some(*len) &&
+					guard_limit(code, call).is_some()
+			});
+		if !is_thunk {
+			mismatches.push(Mismatch::UnguardedEntry { func_idx });
+		}
+	}
+
+	if mismatches.is_empty() {
+		Ok(())
+	} else {
+		Err(mismatches)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::stack_height::inject_limiter;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	const SOURCE: &str = r#"
+(module
+	(import "env" "ext" (func $ext (param i32)))
+	(func $callee (param i32) (result i32)
+		(local.get 0)
+	)
+	(func (export "call") (param i32)
+		(call $ext (call $callee (local.get 0)))
+	)
+)
+"#;
+
+	#[test]
+	fn accepts_limited() {
+		let module = inject_limiter(parse_wat(SOURCE), 1024).unwrap();
+		assert_eq!(verify(&module, 1024), Ok(()));
+		assert_eq!(
+			verify(&module, 2048),
+			Err(vec![
+				Mismatch::Limit { func_idx: 2, offset: 11, found: 1024 },
+				Mismatch::Limit { func_idx: 3, offset: 11, found: 1024 },
+			])
+		);
+	}
+
+	#[test]
+	fn detects_unlimited() {
+		assert_eq!(
+			verify(&parse_wat(SOURCE), 1024),
+			Err(vec![
+				Mismatch::UnguardedCall { func_idx: 2, offset: 1 },
+				Mismatch::UnguardedEntry { func_idx: 2 },
+			])
+		);
+	}
+}
@@ -0,0 +1,413 @@
+//! Locating the shadow stack in the linear memory and the data segments it overlaps.
+//!
+//! Code compiled from Rust or C keeps the parts of its stack frames which don't fit into locals
+//! on a shadow stack in the linear memory. The stack grows downwards from its initial top, which
+//! the module stores in a stack pointer. Data segments within the region of the stack are
+//! overwritten once the stack grows that far, e.g. after [`crate::shrink_unknown_stack`] moved the
+//! top of the stack below them.
+
+use crate::std::{fmt, ops::Range, str, string::String, vec::Vec};
+
+use byteorder::{ByteOrder, LittleEndian};
+use parity_wasm::elements::{self, Instruction};
+
+/// Name of the global holding the stack pointer in the modules linked by lld.
+const STACK_POINTER: &str = "__stack_pointer";
+
+/// Name of the global holding the start of the heap in the modules linked by lld.
+const HEAP_BASE: &str = "__heap_base";
+
+/// Id of the subsection of the name section naming the globals.
+const GLOBAL_NAMES_SUBSECTION: u8 = 7;
+
+/// Alignment of the top of the stack.
+const STACK_ALIGN: u64 = 16;
+
+/// Where the module keeps the top of its shadow stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackPointer {
+	/// The global with the given index, named or exported as `__stack_pointer`.
+	Global(u32),
+	/// The data segment with the given index initializing the 4 bytes at the address 4, as in
+	/// the modules of the old `wasm32-unknown-unknown` target.
+	DataSegment(usize),
+}
+
+/// The shadow stack of a module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShadowStack {
+	pub pointer: StackPointer,
+	/// Initial top of the stack. The stack occupies the addresses below it.
+	pub top: u32,
+}
+
+impl ShadowStack {
+	/// Addresses occupied by the stack if it grows to `size` bytes.
+	pub fn region(&self, size: u32) -> Range<u32> {
+		self.top.saturating_sub(size)..self.top
+	}
+}
+
+/// A data segment overlapping the shadow stack, see [`stack_overlaps`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataOverlap {
+	/// Index of the segment in the data section.
+	pub segment: usize,
+	/// Addresses initialized by the segment.
+	pub range: Range<u32>,
+}
+
+impl fmt::Display for DataOverlap {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		write!(
+			f,
+			"Data segment {} at {:#x}..{:#x} overlaps the stack",
+			self.segment, self.range.start, self.range.end
+		)
+	}
+}
+
+/// Failure of [`relocate_stack`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+	/// The module has no memory.
+	NoMemory,
+	/// The relocated stack requires `required` pages, but the memory is limited to `maximum`.
+	MemoryTooSmall { required: u32, maximum: u32 },
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			Error::NoMemory => write!(f, "The module has no memory to place the stack in"),
+			Error::MemoryTooSmall { required, maximum } => write!(
+				f,
+				"The relocated stack requires {} pages of memory, but at most {} are allowed",
+				required, maximum
+			),
+		}
+	}
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+/// Value of a constant `i32` expression.
+fn const_i32(init: &elements::InitExpr) -> Option<u32> {
+	match init.code() {
+		[Instruction::I32Const(value), Instruction::End] => Some(*value as u32),
+		_ => None,
+	}
+}
+
+/// Index of the global named `name` in the name section or exported as `name`.
+///
+/// parity-wasm doesn't decode the names of globals, so they are read from the unparsed section.
+fn find_global(module: &elements::Module, name: &str) -> Option<u32> {
+	let exported = module.export_section().and_then(|section| {
+		section.entries().iter().find_map(|entry| match entry.internal() {
+			elements::Internal::Global(global_idx) if entry.field() == name => Some(*global_idx),
+			_ => None,
+		})
+	});
+	exported.or_else(|| {
+		let section = module.custom_sections().find(|section| section.name() == "name")?;
+		global_names(section.payload())?
+			.into_iter()
+			.find_map(|(global_idx, global_name)| (global_name == name).then(|| global_idx))
+	})
+}
+
+/// Read an unsigned LEB128 encoded number from the front of `bytes`.
+fn read_var_u32(bytes: &mut &[u8]) -> Option<u32> {
+	let mut value = 0u32;
+	for shift in (0..35).step_by(7) {
+		let (&byte, rest) = bytes.split_first()?;
+		*bytes = rest;
+		value |= u32::from(byte & 0x7f).checked_shl(shift)?;
+		if byte & 0x80 == 0 {
+			return Some(value)
+		}
+	}
+	None
+}
+
+/// Names of the globals in the payload of a name section.
+fn global_names(mut payload: &[u8]) -> Option<Vec<(u32, String)>> {
+	while let Some((&id, rest)) = payload.split_first() {
+		payload = rest;
+		let size = read_var_u32(&mut payload)? as usize;
+		let mut subsection = payload.get(..size)?;
+		payload = &payload[size..];
+		if id != GLOBAL_NAMES_SUBSECTION {
+			continue
+		}
+		let count = read_var_u32(&mut subsection)?;
+		return (0..count)
+			.map(|_| {
+				let global_idx = read_var_u32(&mut subsection)?;
+				let len = read_var_u32(&mut subsection)? as usize;
+				let name = str::from_utf8(subsection.get(..len)?).ok()?;
+				subsection = &subsection[len..];
+				Some((global_idx, name.into()))
+			})
+			.collect()
+	}
+	None
+}
+
+/// Entry of the defined global `global_idx`.
+fn global_entry(module: &elements::Module, global_idx: u32) -> Option<&elements::GlobalEntry> {
+	let imported = module.import_count(elements::ImportCountType::Global) as u32;
+	module
+		.global_section()?
+		.entries()
+		.get(global_idx.checked_sub(imported)? as usize)
+}
+
+/// Initialize the defined global `global_idx` with `value`.
+fn set_global(module: &mut elements::Module, global_idx: u32, value: u32) {
+	let imported = module.import_count(elements::ImportCountType::Global) as u32;
+	let entry = global_idx
+		.checked_sub(imported)
+		.and_then(|idx| module.global_section_mut()?.entries_mut().get_mut(idx as usize));
+	if let Some(entry) = entry {
+		*entry.init_expr_mut() =
+			elements::InitExpr::new(vec![Instruction::I32Const(value as i32), Instruction::End]);
+	}
+}
+
+/// Locate the shadow stack of `module`.
+///
+/// The stack pointer is the global named or exported as `__stack_pointer`, as in the modules
+/// linked by lld, or otherwise the 4 bytes at the address 4 if a data segment initializes them,
+/// as in the modules of the old `wasm32-unknown-unknown` target.
+pub fn find_shadow_stack(module: &elements::Module) -> Option<ShadowStack> {
+	if let Some(global_idx) = find_global(module, STACK_POINTER) {
+		let entry = global_entry(module, global_idx)?;
+		let global_type = entry.global_type();
+		if !global_type.is_mutable() || global_type.content_type() != elements::ValueType::I32 {
+			return None
+		}
+		let top = const_i32(entry.init_expr())?;
+		return Some(ShadowStack { pointer: StackPointer::Global(global_idx), top })
+	}
+	let segments = module.data_section()?.entries();
+	segments.iter().enumerate().find_map(|(segment, entry)| {
+		let offset = entry.offset().as_ref().and_then(const_i32);
+		(offset == Some(4) && entry.value().len() == 4).then(|| ShadowStack {
+			pointer: StackPointer::DataSegment(segment),
+			top: LittleEndian::read_u32(entry.value()),
+		})
+	})
+}
+
+/// Addresses initialized by the active data segments with a constant offset, by segment index.
+///
+/// The segment holding the stack pointer is left out.
+fn data_ranges(module: &elements::Module, stack: &ShadowStack) -> Vec<(usize, Range<u32>)> {
+	let segments = module.data_section().map(|section| section.entries()).unwrap_or(&[]);
+	segments
+		.iter()
+		.enumerate()
+		.filter(|(segment, _)| stack.pointer != StackPointer::DataSegment(*segment))
+		.filter_map(|(segment, entry)| {
+			let start = entry.offset().as_ref().and_then(const_i32)?;
+			let end = start.saturating_add(entry.value().len() as u32);
+			Some((segment, start..end))
+		})
+		.filter(|(_, range)| !range.is_empty())
+		.collect()
+}
+
+/// Data segments of `module` overlapping the region of `stack` if it grows to `size` bytes,
+/// sorted by address.
+///
+/// Segments with an offset that isn't constant aren't checked.
+pub fn stack_overlaps(
+	module: &elements::Module,
+	stack: &ShadowStack,
+	size: u32,
+) -> Vec<DataOverlap> {
+	let region = stack.region(size);
+	let mut overlaps: Vec<_> = data_ranges(module, stack)
+		.into_iter()
+		.filter(|(_, range)| range.start < region.end && region.start < range.end)
+		.map(|(segment, range)| DataOverlap { segment, range })
+		.collect();
+	overlaps.sort_by_key(|overlap| (overlap.range.start, overlap.segment));
+	overlaps
+}
+
+/// Move `stack` of `module` above all data segments, so that a stack of `size` bytes overlaps
+/// none of them.
+///
+/// The data can't be moved, since the code refers to it by address. Instead the stack pointer
+/// is initialized with the new top, and the initial memory is grown to hold the stack if
+/// necessary. A global named or exported as `__heap_base` below the new top is moved along, so
+/// that the heap starts after the stack.
+///
+/// Returns the relocated stack.
+pub fn relocate_stack(
+	module: &mut elements::Module,
+	stack: &ShadowStack,
+	size: u32,
+) -> Result<ShadowStack, Error> {
+	let data_end = data_ranges(module, stack).into_iter().map(|(_, range)| range.end).max();
+	let data_end = u64::from(data_end.unwrap_or(0));
+	let top = (data_end + STACK_ALIGN - 1) / STACK_ALIGN * STACK_ALIGN + u64::from(size);
+	let pages = (top + 65535) / 65536;
+	grow_memory(module, pages)?;
+	// The memory may be 4 GiB big, but the stack pointer can't point past its end.
+	let top = u32::try_from(top).map_err(|_| Error::MemoryTooSmall {
+		required: pages as u32,
+		maximum: u32::from(u16::MAX),
+	})?;
+
+	let heap_base = find_global(module, HEAP_BASE).filter(|global_idx| {
+		global_entry(module, *global_idx)
+			.and_then(|entry| const_i32(entry.init_expr()))
+			.map_or(false, |base| base < top)
+	});
+	match stack.pointer {
+		StackPointer::Global(global_idx) => set_global(module, global_idx, top),
+		StackPointer::DataSegment(segment) => {
+			let section = module.data_section_mut().expect("the pointer is in a segment; qed");
+			LittleEndian::write_u32(section.entries_mut()[segment].value_mut(), top);
+		},
+	}
+	if let Some(global_idx) = heap_base {
+		set_global(module, global_idx, top);
+	}
+
+	Ok(ShadowStack { pointer: stack.pointer, top })
+}
+
+/// Make the initial memory at least `pages` big.
+fn grow_memory(module: &mut elements::Module, pages: u64) -> Result<(), Error> {
+	let imported = module.import_section_mut().and_then(|section| {
+		section.entries_mut().iter_mut().find_map(|entry| match entry.external_mut() {
+			elements::External::Memory(memory) => Some(memory),
+			_ => None,
+		})
+	});
+	let memory = match imported {
+		Some(memory) => memory,
+		None => module
+			.memory_section_mut()
+			.and_then(|section| section.entries_mut().first_mut())
+			.ok_or(Error::NoMemory)?,
+	};
+
+	let limits = memory.limits();
+	let maximum = limits.maximum().map_or(u64::from(u16::MAX) + 1, u64::from);
+	if pages > maximum {
+		return Err(Error::MemoryTooSmall { required: pages as u32, maximum: maximum as u32 })
+	}
+	if pages > u64::from(limits.initial()) {
+		*memory = elements::MemoryType::new(pages as u32, limits.maximum());
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	#[test]
+	fn pointer_in_memory() {
+		let mut module = parse_wat(
+			r#"
+(module
+	(memory 1)
+	(data (i32.const 4) "\00\00\01\00")
+	(data (i32.const 0x12000) "late")
+	(data (i32.const 0x8000) "overlaps")
+)
+"#,
+		);
+
+		let stack = find_shadow_stack(&module).unwrap();
+		assert_eq!(stack, ShadowStack { pointer: StackPointer::DataSegment(0), top: 0x10000 });
+		assert_eq!(stack.region(0x20000), 0..0x10000);
+		assert_eq!(
+			stack_overlaps(&module, &stack, 0x10000),
+			vec![DataOverlap { segment: 2, range: 0x8000..0x8008 }]
+		);
+		assert!(stack_overlaps(&module, &stack, 0x7ff8).is_empty());
+
+		let relocated = relocate_stack(&mut module, &stack, 0x10000).unwrap();
+		assert_eq!(relocated, ShadowStack { pointer: StackPointer::DataSegment(0), top: 0x22010 });
+		assert_eq!(find_shadow_stack(&module), Some(relocated));
+		assert!(stack_overlaps(&module, &relocated, 0x10000).is_empty());
+		assert_eq!(module.memory_section().unwrap().entries()[0].limits().initial(), 3);
+	}
+
+	#[test]
+	fn stack_pointer_global() {
+		let mut module = parse_wat(
+			r#"
+(module
+	(memory 2 2)
+	(global (mut i32) (i32.const 0x10000))
+	(global i32 (i32.const 0x10000))
+	(export "__heap_base" (global 1))
+	(data (i32.const 0x400) "overlaps")
+)
+"#,
+		);
+		assert_eq!(find_shadow_stack(&module), None);
+
+		// The global names subsection, naming the global 0.
+		let mut payload = vec![GLOBAL_NAMES_SUBSECTION, 18, 1, 0, 15];
+		payload.extend_from_slice(STACK_POINTER.as_bytes());
+		module.set_custom_section("name", payload);
+		let stack = find_shadow_stack(&module).unwrap();
+		assert_eq!(stack, ShadowStack { pointer: StackPointer::Global(0), top: 0x10000 });
+		assert_eq!(
+			stack_overlaps(&module, &stack, 0x10000),
+			vec![DataOverlap { segment: 0, range: 0x400..0x408 }]
+		);
+
+		assert_eq!(
+			relocate_stack(&mut module.clone(), &stack, 0x20000),
+			Err(Error::MemoryTooSmall { required: 3, maximum: 2 })
+		);
+		let relocated = relocate_stack(&mut module, &stack, 0x10000).unwrap();
+		assert_eq!(relocated.top, 0x10410);
+		assert_eq!(find_shadow_stack(&module), Some(relocated));
+		// The heap starts after the stack.
+		let heap_base = global_entry(&module, 1).unwrap().init_expr();
+		assert_eq!(const_i32(heap_base), Some(0x10410));
+	}
+
+	#[test]
+	fn top_at_4_gib() {
+		let mut module = parse_wat(
+			r#"
+(module
+	(memory 1)
+	(global (mut i32) (i32.const 0x10000))
+	(export "__stack_pointer" (global 0))
+	(data (i32.const 0x400) "overlaps")
+)
+"#,
+		);
+		let stack = find_shadow_stack(&module).unwrap();
+
+		// The top would be exactly 4 GiB, which doesn't fit the stack pointer.
+		assert_eq!(
+			relocate_stack(&mut module.clone(), &stack, 0xffff_fbf0),
+			Err(Error::MemoryTooSmall { required: 65536, maximum: 65535 })
+		);
+		let relocated = relocate_stack(&mut module, &stack, 0xffff_fbe0).unwrap();
+		assert_eq!(relocated.top, 0xffff_fff0);
+		assert_eq!(module.memory_section().unwrap().entries()[0].limits().initial(), 65536);
+	}
+}
@@ -0,0 +1,127 @@
+//! Serializing modules without building the whole output in memory.
+//!
+//! [`parity_wasm::serialize`] returns the module as a single buffer, and even
+//! [`parity_wasm::serialize_to_file`] encodes every section into a buffer before writing it. For
+//! big modules, e.g. instrumented debug builds, the code section dominates the memory usage. The
+//! functions here write the function bodies one by one instead, so at most one body and the
+//! other sections are buffered at a time.
+
+use parity_wasm::elements::{self, Error, Serialize, Uint32, VarUint32, VarUint7};
+use std::{
+	fs,
+	io::{self, BufWriter, Write},
+	path::Path,
+};
+
+/// Id of the code section.
+const CODE_SECTION_ID: u8 = 10;
+
+fn io_error(err: io::Error) -> Error {
+	Error::HeapOther(format!("I/O Error: {:?}", err))
+}
+
+/// Writer counting the bytes written through it.
+struct CountingWriter<W> {
+	inner: W,
+	written: usize,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let written = self.inner.write(buf)?;
+		self.written += written;
+		Ok(written)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+/// Serialize `module` into `writer`, returning the number of bytes written.
+///
+/// The output is the same as the one of [`parity_wasm::serialize`]. The function bodies are
+/// encoded twice, once to compute the size of the code section and once to write them.
+pub fn serialize_to_writer<W: Write>(module: elements::Module, writer: W) -> Result<usize, Error> {
+	let mut writer = CountingWriter { inner: writer, written: 0 };
+	writer.write_all(b"\0asm").map_err(io_error)?;
+	Uint32::from(module.version()).serialize(&mut writer)?;
+
+	let mut scratch = Vec::new();
+	for section in module.into_sections() {
+		let bodies = match section {
+			elements::Section::Code(mut code) => std::mem::take(code.bodies_mut()),
+			section => {
+				section.serialize(&mut writer)?;
+				continue
+			},
+		};
+
+		let mut size = Vec::new();
+		VarUint32::from(bodies.len()).serialize(&mut size)?;
+		let mut section_size = size.len();
+		for body in &bodies {
+			scratch.clear();
+			body.clone().serialize(&mut scratch)?;
+			section_size += scratch.len();
+		}
+
+		VarUint7::from(CODE_SECTION_ID).serialize(&mut writer)?;
+		VarUint32::from(section_size).serialize(&mut writer)?;
+		writer.write_all(&size).map_err(io_error)?;
+		for body in bodies {
+			scratch.clear();
+			body.serialize(&mut scratch)?;
+			writer.write_all(&scratch).map_err(io_error)?;
+		}
+	}
+	writer.flush().map_err(io_error)?;
+	Ok(writer.written)
+}
+
+/// Serialize `module` into the file at `path`, returning the size of the file.
+///
+/// Like [`serialize_to_writer`], the module isn't encoded as a whole in memory.
+pub fn serialize_to_file<P: AsRef<Path>>(
+	path: P,
+	module: elements::Module,
+) -> Result<usize, Error> {
+	let file = fs::File::create(path)
+		.map_err(|e| Error::HeapOther(format!("Can't create the file: {:?}", e)))?;
+	serialize_to_writer(module, BufWriter::new(file))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	#[test]
+	fn same_as_serialize() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "f" (func))
+	(memory 1)
+	(func (export "call") (param i32) (result i32) (local i64)
+		(call 0)
+		(i32.add (local.get 0) (i32.const 1))
+	)
+	(func
+		(nop)
+	)
+	(data (i32.const 0) "abc")
+)
+"#,
+		);
+
+		let mut streamed = Vec::new();
+		let written = serialize_to_writer(module.clone(), &mut streamed).unwrap();
+		assert_eq!(streamed, parity_wasm::serialize(module).unwrap());
+		assert_eq!(written, streamed.len());
+	}
+}
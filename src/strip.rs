@@ -0,0 +1,63 @@
+//! Removal of the custom sections of a module.
+
+use crate::std::{string::String, vec::Vec};
+
+use crate::optimizer::glob_match;
+use parity_wasm::elements;
+
+/// Remove the custom sections of `module` whose names match none of the patterns in `keep`.
+///
+/// This covers the `name` section, the relocations and all other custom sections, e.g. the
+/// `producers` section and the debug information. The patterns are globs like the ones of
+/// [`crate::optimize`], so `.debug_*` keeps the DWARF sections.
+///
+/// Returns the names of the removed sections, in order.
+pub fn strip_custom_sections(module: &mut elements::Module, keep: &[&str]) -> Vec<String> {
+	let mut stripped = Vec::new();
+	module.sections_mut().retain(|section| {
+		let name = match section {
+			elements::Section::Custom(custom) => custom.name(),
+			elements::Section::Name(_) => "name",
+			elements::Section::Reloc(reloc) => reloc.name(),
+			_ => return true,
+		};
+		let kept = keep.iter().any(|pattern| glob_match(pattern, name));
+		if !kept {
+			stripped.push(name.into());
+		}
+		kept
+	});
+	stripped
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn allowlist() {
+		let mut module = elements::Module::new(
+			["name", "producers", ".debug_info", ".debug_line", "linking", "sourceMappingURL"]
+				.iter()
+				.map(|name| {
+					elements::Section::Custom(elements::CustomSection::new(
+						(*name).into(),
+						Vec::new(),
+					))
+				})
+				.collect(),
+		);
+
+		assert_eq!(
+			strip_custom_sections(&mut module, &[".debug_*", "sourceMappingURL"]),
+			vec!["name", "producers", "linking"]
+		);
+		assert_eq!(
+			module.custom_sections().map(|section| section.name()).collect::<Vec<_>>(),
+			vec![".debug_info", ".debug_line", "sourceMappingURL"]
+		);
+
+		assert_eq!(strip_custom_sections(&mut module, &[]).len(), 3);
+		assert!(module.sections().is_empty());
+	}
+}
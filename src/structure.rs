@@ -0,0 +1,362 @@
+//! Structural validation of a module.
+
+use crate::std::{collections::BTreeSet as Set, fmt, string::String, vec::Vec};
+
+use crate::data_segments::memory_offset;
+use parity_wasm::elements::{self, External, Instruction, Internal, Type};
+
+/// A part of a module which doesn't fit its structure, see [`check_structure`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StructureError {
+	/// The function section declares `functions` functions, but the code section has `bodies`.
+	BodyCount { functions: usize, bodies: usize },
+	/// The type `type_idx` is referred to, but the module has no such type.
+	UnknownType { type_idx: u32 },
+	/// The function `func_idx` is referred to, but the module has no such function.
+	UnknownFunction { func_idx: u32 },
+	/// The global `global_idx` is referred to, but the module has no such global.
+	UnknownGlobal { global_idx: u32 },
+	/// The function `func_idx` sets the global `global_idx`, which is immutable.
+	ImmutableGlobal { func_idx: u32, global_idx: u32 },
+	/// The function `func_idx` refers to the local `local_idx`, which it doesn't declare.
+	UnknownLocal { func_idx: u32, local_idx: u32 },
+	/// The table is used, but the module has none.
+	MissingTable,
+	/// The memory is used, but the module has none.
+	MissingMemory,
+	/// The start function takes parameters or returns results.
+	InvalidStart { func_idx: u32 },
+	/// Several exports are named `field`.
+	DuplicateExport { field: String },
+	/// The control blocks of the function `func_idx` aren't nested properly, or it branches to a
+	/// label which doesn't exist.
+	MalformedBody { func_idx: u32 },
+}
+
+impl fmt::Display for StructureError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+		match self {
+			StructureError::BodyCount { functions, bodies } =>
+				write!(f, "{} functions are declared, but {} bodies defined", functions, bodies),
+			StructureError::UnknownType { type_idx } => write!(f, "Unknown type {}", type_idx),
+			StructureError::UnknownFunction { func_idx } =>
+				write!(f, "Unknown function {}", func_idx),
+			StructureError::UnknownGlobal { global_idx } =>
+				write!(f, "Unknown global {}", global_idx),
+			StructureError::ImmutableGlobal { func_idx, global_idx } =>
+				write!(f, "Function {} sets the immutable global {}", func_idx, global_idx),
+			StructureError::UnknownLocal { func_idx, local_idx } =>
+				write!(f, "Function {} refers to the unknown local {}", func_idx, local_idx),
+			StructureError::MissingTable => write!(f, "The table is used, but there is none"),
+			StructureError::MissingMemory => write!(f, "The memory is used, but there is none"),
+			StructureError::InvalidStart { func_idx } =>
+				write!(f, "The start function {} takes parameters or returns results", func_idx),
+			StructureError::DuplicateExport { field } =>
+				write!(f, "Several exports are named `{}`", field),
+			StructureError::MalformedBody { func_idx } => write!(
+				f,
+				"The control blocks of function {} are malformed or it branches to an unknown \
+				 label",
+				func_idx
+			),
+		}
+	}
+}
+
+/// Check that the indices a module refers to exist and that its sections fit together.
+///
+/// The checks cover what the passes of this crate rely on: the types of the functions and of
+/// `call_indirect`, the functions, globals and locals referred to, the number of function bodies,
+/// the presence of the table and the memory, the start function, the names of the exports and
+/// the nesting of the control blocks. Unlike a full validator, the types of the values on the
+/// stack aren't checked.
+///
+/// Returns every error found.
+pub fn check_structure(module: &elements::Module) -> Result<(), Vec<StructureError>> {
+	let mut errors = Vec::new();
+	let mut push = |error: StructureError| {
+		if !errors.contains(&error) {
+			errors.push(error);
+		}
+	};
+
+	let types = module.type_section().map_or(&[][..], |section| section.types());
+	let imports = module.import_section().map_or(&[][..], |section| section.entries());
+	let functions = module.function_section().map_or(&[][..], |section| section.entries());
+	let bodies = module.code_section().map_or(&[][..], |section| section.bodies());
+	let globals = module.global_section().map_or(&[][..], |section| section.entries());
+
+	if functions.len() != bodies.len() {
+		push(StructureError::BodyCount { functions: functions.len(), bodies: bodies.len() });
+	}
+
+	// The type of every function and the mutability of every global in the index spaces.
+	let mut func_types = Vec::new();
+	let mut global_mutability = Vec::new();
+	let mut has_table =
+		module.table_section().map_or(false, |section| !section.entries().is_empty());
+	let mut has_memory =
+		module.memory_section().map_or(false, |section| !section.entries().is_empty());
+	for entry in imports {
+		match entry.external() {
+			External::Function(type_idx) => func_types.push(*type_idx),
+			External::Global(global_type) => global_mutability.push(global_type.is_mutable()),
+			External::Table(_) => has_table = true,
+			External::Memory(_) => has_memory = true,
+		}
+	}
+	func_types.extend(functions.iter().map(|func| func.type_ref()));
+	global_mutability.extend(globals.iter().map(|global| global.global_type().is_mutable()));
+	let func_count = func_types.len() as u32;
+	let global_count = global_mutability.len() as u32;
+	for type_idx in &func_types {
+		if *type_idx as usize >= types.len() {
+			push(StructureError::UnknownType { type_idx: *type_idx });
+		}
+	}
+
+	let check_init = |code: &[Instruction], push: &mut dyn FnMut(StructureError)| {
+		for instruction in code {
+			if let Instruction::GetGlobal(global_idx) = instruction {
+				if *global_idx >= global_count {
+					push(StructureError::UnknownGlobal { global_idx: *global_idx });
+				}
+			}
+		}
+	};
+	for global in globals {
+		check_init(global.init_expr().code(), &mut push);
+	}
+
+	let func_imports = module.import_count(elements::ImportCountType::Function) as u32;
+	for (idx, body) in bodies.iter().enumerate() {
+		let func_idx = func_imports + idx as u32;
+		let params = func_types
+			.get(func_idx as usize)
+			.and_then(|type_idx| types.get(*type_idx as usize))
+			.map_or(0, |Type::Function(ty)| ty.params().len() as u64);
+		let locals = body.locals().iter().map(|local| u64::from(local.count())).sum::<u64>();
+		// For each open control block whether it is an `if`.
+		let mut blocks = vec![false];
+		let mut malformed = false;
+		for instruction in body.code().elements() {
+			if blocks.is_empty() {
+				// Instructions after the final `end`.
+				malformed = true;
+				break
+			}
+			let depth = blocks.len() as u32;
+			match instruction {
+				Instruction::Block(_) | Instruction::Loop(_) => blocks.push(false),
+				Instruction::If(_) => blocks.push(true),
+				Instruction::Else => malformed |= blocks.last() != Some(&true),
+				Instruction::End => {
+					blocks.pop();
+				},
+				Instruction::Br(label) | Instruction::BrIf(label) => malformed |= *label >= depth,
+				Instruction::BrTable(table) =>
+					malformed |= table.table.iter().chain([&table.default]).any(|l| *l >= depth),
+				Instruction::Call(callee) =>
+					if *callee >= func_count {
+						push(StructureError::UnknownFunction { func_idx: *callee });
+					},
+				Instruction::CallIndirect(type_idx, _) => {
+					if *type_idx as usize >= types.len() {
+						push(StructureError::UnknownType { type_idx: *type_idx });
+					}
+					if !has_table {
+						push(StructureError::MissingTable);
+					}
+				},
+				Instruction::GetLocal(local_idx) |
+				Instruction::SetLocal(local_idx) |
+				Instruction::TeeLocal(local_idx) =>
+					if u64::from(*local_idx) >= params + locals {
+						push(StructureError::UnknownLocal { func_idx, local_idx: *local_idx });
+					},
+				Instruction::GetGlobal(global_idx) =>
+					if *global_idx >= global_count {
+						push(StructureError::UnknownGlobal { global_idx: *global_idx });
+					},
+				Instruction::SetGlobal(global_idx) => match global_mutability
+					.get(*global_idx as usize)
+				{
+					Some(true) => {},
+					Some(false) =>
+						push(StructureError::ImmutableGlobal { func_idx, global_idx: *global_idx }),
+					None => push(StructureError::UnknownGlobal { global_idx: *global_idx }),
+				},
+				Instruction::CurrentMemory(_) | Instruction::GrowMemory(_) =>
+					if !has_memory {
+						push(StructureError::MissingMemory);
+					},
+				instruction =>
+					if memory_offset(instruction).is_some() && !has_memory {
+						push(StructureError::MissingMemory);
+					},
+			}
+		}
+		if malformed || !blocks.is_empty() {
+			push(StructureError::MalformedBody { func_idx });
+		}
+	}
+
+	let mut fields = Set::new();
+	for entry in module.export_section().map_or(&[][..], |section| section.entries()) {
+		if !fields.insert(entry.field()) {
+			push(StructureError::DuplicateExport { field: entry.field().into() });
+		}
+		match *entry.internal() {
+			Internal::Function(func_idx) if func_idx >= func_count =>
+				push(StructureError::UnknownFunction { func_idx }),
+			Internal::Global(global_idx) if global_idx >= global_count =>
+				push(StructureError::UnknownGlobal { global_idx }),
+			Internal::Table(_) if !has_table => push(StructureError::MissingTable),
+			Internal::Memory(_) if !has_memory => push(StructureError::MissingMemory),
+			_ => {},
+		}
+	}
+
+	if let Some(func_idx) = module.start_section() {
+		let signature = func_types
+			.get(func_idx as usize)
+			.and_then(|type_idx| types.get(*type_idx as usize));
+		match signature {
+			Some(Type::Function(ty)) if ty.params().is_empty() && ty.results().is_empty() => {},
+			Some(_) => push(StructureError::InvalidStart { func_idx }),
+			None if func_idx >= func_count => push(StructureError::UnknownFunction { func_idx }),
+			// The type is unknown, which is reported above.
+			None => {},
+		}
+	}
+
+	for segment in module.elements_section().map_or(&[][..], |section| section.entries()) {
+		if !has_table {
+			push(StructureError::MissingTable);
+		}
+		if let Some(offset) = segment.offset() {
+			check_init(offset.code(), &mut push);
+		}
+		for func_idx in segment.members() {
+			if *func_idx >= func_count {
+				push(StructureError::UnknownFunction { func_idx: *func_idx });
+			}
+		}
+	}
+	for segment in module.data_section().map_or(&[][..], |section| section.entries()) {
+		if !has_memory {
+			push(StructureError::MissingMemory);
+		}
+		if let Some(offset) = segment.offset() {
+			check_init(offset.code(), &mut push);
+		}
+	}
+
+	if errors.is_empty() {
+		Ok(())
+	} else {
+		Err(errors)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use parity_wasm::builder;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	#[test]
+	fn valid_module() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "counter" (global (mut i32)))
+	(memory 1)
+	(table 1 funcref)
+	(elem (i32.const 0) $f)
+	(data (i32.const 0) "abcd")
+	(func $f (param i32) (result i32) (local i64)
+		(block
+			(br_if 0 (local.get 0))
+			(global.set 0 (i32.load (i32.const 0)))
+		)
+		(call_indirect (type 0) (local.get 0) (i32.const 0))
+	)
+	(func $start)
+	(start $start)
+	(export "f" (func $f))
+)
+"#,
+		);
+
+		assert_eq!(check_structure(&module), Ok(()));
+	}
+
+	#[test]
+	fn invalid_indices() {
+		use Instruction::*;
+
+		let mut module = builder::module()
+			.global()
+			.value_type()
+			.i32()
+			.init_expr(I32Const(0))
+			.build()
+			.function()
+			.signature()
+			.param()
+			.i32()
+			.build()
+			.body()
+			.with_instructions(elements::Instructions::new(vec![
+				GetLocal(1),
+				SetGlobal(0),
+				GetGlobal(1),
+				Drop,
+				Call(2),
+				I32Load(2, 0),
+				Drop,
+				Br(1),
+				End,
+			]))
+			.build()
+			.build()
+			.export()
+			.field("f")
+			.internal()
+			.func(0)
+			.build()
+			.export()
+			.field("f")
+			.internal()
+			.func(3)
+			.build()
+			.build();
+		module.sections_mut().push(elements::Section::Start(0));
+
+		assert_eq!(
+			check_structure(&module),
+			Err(vec![
+				StructureError::UnknownLocal { func_idx: 0, local_idx: 1 },
+				StructureError::ImmutableGlobal { func_idx: 0, global_idx: 0 },
+				StructureError::UnknownGlobal { global_idx: 1 },
+				StructureError::UnknownFunction { func_idx: 2 },
+				StructureError::MissingMemory,
+				StructureError::MalformedBody { func_idx: 0 },
+				StructureError::DuplicateExport { field: "f".into() },
+				StructureError::UnknownFunction { func_idx: 3 },
+				StructureError::InvalidStart { func_idx: 0 },
+			])
+		);
+
+		let bodies = module.code_section_mut().unwrap().bodies_mut();
+		bodies.clear();
+		assert!(check_structure(&module)
+			.unwrap_err()
+			.contains(&StructureError::BodyCount { functions: 1, bodies: 0 }));
+	}
+}
@@ -2,9 +2,8 @@
 use crate::std::collections::BTreeSet as Set;
 #[cfg(features = "std")]
 use crate::std::collections::HashSet as Set;
-use crate::std::vec::Vec;
+use crate::std::{collections::BTreeMap as Map, vec::Vec};
 
-use log::trace;
 use parity_wasm::elements;
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Copy, Clone, Debug)]
@@ -14,6 +13,8 @@ pub enum Symbol {
 	Global(usize),
 	Function(usize),
 	Export(usize),
+	Table(usize),
+	Memory(usize),
 }
 
 pub fn resolve_function(module: &elements::Module, index: u32) -> Symbol {
@@ -48,6 +49,85 @@ pub fn resolve_global(module: &elements::Module, index: u32) -> Symbol {
 	Symbol::Global(index as usize - globals as usize)
 }
 
+pub fn resolve_table(module: &elements::Module, index: u32) -> Symbol {
+	let mut tables = 0;
+	if let Some(import_section) = module.import_section() {
+		for (item_index, item) in import_section.entries().iter().enumerate() {
+			if let elements::External::Table(_) = item.external() {
+				if tables == index {
+					return Symbol::Import(item_index)
+				}
+				tables += 1;
+			}
+		}
+	}
+
+	Symbol::Table(index as usize - tables as usize)
+}
+
+pub fn resolve_memory(module: &elements::Module, index: u32) -> Symbol {
+	let mut memories = 0;
+	if let Some(import_section) = module.import_section() {
+		for (item_index, item) in import_section.entries().iter().enumerate() {
+			if let elements::External::Memory(_) = item.external() {
+				if memories == index {
+					return Symbol::Import(item_index)
+				}
+				memories += 1;
+			}
+		}
+	}
+
+	Symbol::Memory(index as usize - memories as usize)
+}
+
+/// Push symbols used by element segments that target the table `index` (in table index space).
+pub fn push_table_symbols(module: &elements::Module, index: u32, dest: &mut Vec<Symbol>) {
+	let segments = module.elements_section().map(|es| es.entries()).unwrap_or(&[]);
+	for segment in segments.iter().filter(|segment| segment.index() == index) {
+		push_code_symbols(
+			module,
+			segment
+				.offset()
+				.as_ref()
+				.expect("parity-wasm is compiled without bulk-memory operations")
+				.code(),
+			dest,
+		);
+		for func_index in segment.members() {
+			dest.push(resolve_function(module, *func_index));
+		}
+	}
+}
+
+/// Push symbols used by data segments that target the memory `index` (in memory index space).
+pub fn push_memory_symbols(module: &elements::Module, index: u32, dest: &mut Vec<Symbol>) {
+	let segments = module.data_section().map(|ds| ds.entries()).unwrap_or(&[]);
+	for segment in segments.iter().filter(|segment| segment.index() == index) {
+		push_code_symbols(
+			module,
+			segment
+				.offset()
+				.as_ref()
+				.expect("parity-wasm is compiled without bulk-memory operations")
+				.code(),
+			dest,
+		);
+	}
+}
+
+/// Number of imports of the given kind that precede the import entry `import_idx`.
+fn imports_before(
+	module: &elements::Module,
+	import_idx: usize,
+	is_kind: impl Fn(&elements::External) -> bool,
+) -> u32 {
+	module.import_section().expect("Import section to exist").entries()[..import_idx]
+		.iter()
+		.filter(|entry| is_kind(entry.external()))
+		.count() as u32
+}
+
 pub fn push_code_symbols(
 	module: &elements::Module,
 	instructions: &[elements::Instruction],
@@ -69,87 +149,82 @@ pub fn push_code_symbols(
 	}
 }
 
-pub fn expand_symbols(module: &elements::Module, set: &mut Set<Symbol>) {
+/// Symbols directly kept alive by `symbol`.
+fn referenced_symbols(module: &elements::Module, symbol: Symbol) -> Vec<Symbol> {
 	use self::Symbol::*;
 
-	// symbols that were already processed
-	let mut stop: Set<Symbol> = Set::new();
-	let mut fringe = set.iter().cloned().collect::<Vec<Symbol>>();
-	loop {
-		let next = match fringe.pop() {
-			Some(s) if stop.contains(&s) => continue,
-			Some(s) => s,
-			_ => break,
-		};
-		trace!("Processing symbol {:?}", next);
-
-		match next {
-			Export(idx) => {
-				let entry =
-					&module.export_section().expect("Export section to exist").entries()[idx];
-				match entry.internal() {
-					elements::Internal::Function(func_idx) => {
-						let symbol = resolve_function(module, *func_idx);
-						if !stop.contains(&symbol) {
-							fringe.push(symbol);
-						}
-						set.insert(symbol);
-					},
-					elements::Internal::Global(global_idx) => {
-						let symbol = resolve_global(module, *global_idx);
-						if !stop.contains(&symbol) {
-							fringe.push(symbol);
-						}
-						set.insert(symbol);
-					},
-					_ => {},
-				}
-			},
-			Import(idx) => {
-				let entry =
-					&module.import_section().expect("Import section to exist").entries()[idx];
-				if let elements::External::Function(type_idx) = entry.external() {
-					let type_symbol = Symbol::Type(*type_idx as usize);
-					if !stop.contains(&type_symbol) {
-						fringe.push(type_symbol);
-					}
-					set.insert(type_symbol);
-				}
-			},
-			Function(idx) => {
-				let body = &module.code_section().expect("Code section to exist").bodies()[idx];
-				let mut code_symbols = Vec::new();
-				push_code_symbols(module, body.code().elements(), &mut code_symbols);
-				for symbol in code_symbols.drain(..) {
-					if !stop.contains(&symbol) {
-						fringe.push(symbol);
-					}
-					set.insert(symbol);
-				}
+	let mut symbols = Vec::new();
+	match symbol {
+		Export(idx) => {
+			let entry = &module.export_section().expect("Export section to exist").entries()[idx];
+			symbols.push(match entry.internal() {
+				elements::Internal::Function(func_idx) => resolve_function(module, *func_idx),
+				elements::Internal::Global(global_idx) => resolve_global(module, *global_idx),
+				elements::Internal::Table(table_idx) => resolve_table(module, *table_idx),
+				elements::Internal::Memory(memory_idx) => resolve_memory(module, *memory_idx),
+			});
+		},
+		Import(idx) => {
+			let entry = &module.import_section().expect("Import section to exist").entries()[idx];
+			match entry.external() {
+				elements::External::Function(type_idx) =>
+					symbols.push(Symbol::Type(*type_idx as usize)),
+				elements::External::Table(_) => {
+					let table_idx =
+						imports_before(module, idx, |e| matches!(e, elements::External::Table(_)));
+					push_table_symbols(module, table_idx, &mut symbols);
+				},
+				elements::External::Memory(_) => {
+					let memory_idx =
+						imports_before(module, idx, |e| matches!(e, elements::External::Memory(_)));
+					push_memory_symbols(module, memory_idx, &mut symbols);
+				},
+				elements::External::Global(_) => {},
+			}
+		},
+		Function(idx) => {
+			let body = &module.code_section().expect("Code section to exist").bodies()[idx];
+			push_code_symbols(module, body.code().elements(), &mut symbols);
 
-				let signature =
-					&module.function_section().expect("Functions section to exist").entries()[idx];
-				let type_symbol = Symbol::Type(signature.type_ref() as usize);
-				if !stop.contains(&type_symbol) {
-					fringe.push(type_symbol);
-				}
-				set.insert(type_symbol);
-			},
-			Global(idx) => {
-				let entry =
-					&module.global_section().expect("Global section to exist").entries()[idx];
-				let mut code_symbols = Vec::new();
-				push_code_symbols(module, entry.init_expr().code(), &mut code_symbols);
-				for symbol in code_symbols.drain(..) {
-					if !stop.contains(&symbol) {
-						fringe.push(symbol);
-					}
-					set.insert(symbol);
+			let signature =
+				&module.function_section().expect("Functions section to exist").entries()[idx];
+			symbols.push(Symbol::Type(signature.type_ref() as usize));
+		},
+		Global(idx) => {
+			let entry = &module.global_section().expect("Global section to exist").entries()[idx];
+			push_code_symbols(module, entry.init_expr().code(), &mut symbols);
+		},
+		Table(idx) => {
+			let table_idx = module.import_count(elements::ImportCountType::Table) + idx;
+			push_table_symbols(module, table_idx as u32, &mut symbols);
+		},
+		Memory(idx) => {
+			let memory_idx = module.import_count(elements::ImportCountType::Memory) + idx;
+			push_memory_symbols(module, memory_idx as u32, &mut symbols);
+		},
+		Type(_) => {},
+	}
+	symbols
+}
+
+/// Find the shortest chain of references from `roots` to every symbol they keep alive.
+///
+/// Maps each kept symbol which isn't a root to the symbol referencing it on such a chain.
+pub fn retention_parents(module: &elements::Module, roots: &[Symbol]) -> Map<Symbol, Symbol> {
+	let mut parents = Map::new();
+	let mut visited = roots.iter().copied().collect::<Set<_>>();
+	let mut level = roots.to_vec();
+	while !level.is_empty() {
+		let mut next_level = Vec::new();
+		for symbol in level {
+			for referenced in referenced_symbols(module, symbol) {
+				if visited.insert(referenced) {
+					parents.insert(referenced, symbol);
+					next_level.push(referenced);
 				}
-			},
-			_ => {},
+			}
 		}
-
-		stop.insert(next);
+		level = next_level;
 	}
+	parents
 }
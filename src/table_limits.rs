@@ -0,0 +1,337 @@
+//! Setting the limits of the tables defined by a module.
+
+use crate::std::{cmp::max, vec::Vec};
+
+use crate::{
+	sections::{elements_section_mut, table_section, table_section_mut},
+	stack_effect::resolve_func_type,
+};
+use parity_wasm::elements::{self, ElementSegment, InitExpr, Instruction, TableType};
+
+/// How [`limit_tables`] sets the maximum of a table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableLimit {
+	/// Set the maximum to the initial size.
+	///
+	/// Runtimes reject tables without a maximum, which LLD often omits. Fixing the size is always
+	/// safe: parity-wasm doesn't support the reference types proposal, so modules it handles
+	/// can't grow their tables with `table.grow`.
+	Tighten,
+	/// Raise the maximum to the given number of elements, or to the initial size if it is
+	/// larger. Tables without a maximum get one, higher maxima are kept.
+	Raise(u32),
+}
+
+/// Set the maximum of every table defined by `module` according to `limit`.
+///
+/// Imported tables are left unchanged, their limits have to match the table provided by the host.
+///
+/// Returns the indices of the changed tables in the table index space.
+pub fn limit_tables(module: &mut elements::Module, limit: TableLimit) -> Vec<u32> {
+	let table_imports = module.import_count(elements::ImportCountType::Table) as u32;
+	let mut changed = Vec::new();
+	let tables = table_section_mut(module).map_or(&mut [][..], |section| section.entries_mut());
+	for (idx, table) in tables.iter_mut().enumerate() {
+		let initial = table.limits().initial();
+		let maximum = match (limit, table.limits().maximum()) {
+			(TableLimit::Tighten, _) => initial,
+			(TableLimit::Raise(policy), Some(maximum)) => max(max(policy, initial), maximum),
+			(TableLimit::Raise(policy), None) => max(policy, initial),
+		};
+		if table.limits().maximum() != Some(maximum) {
+			*table = TableType::new(initial, Some(maximum));
+			changed.push(table_imports + idx as u32);
+		}
+	}
+	changed
+}
+
+/// Outcome of [`compact_tables`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TableCompaction {
+	/// Number of element entries which were dropped.
+	pub dropped_elements: usize,
+	/// Initial size of the table before and after the compaction, unless it was left unchanged.
+	pub resized: Option<(u32, u32)>,
+	/// Whether the table was removed.
+	pub removed_table: bool,
+}
+
+/// Drop the element entries which no `call_indirect` can call and shrink the table to the
+/// remaining entries, removing it if nothing uses it.
+///
+/// `call_indirect` compares the signature of the callee with the expected one, so an entry whose
+/// signature isn't expected by any `call_indirect` can only trap. Clearing it keeps the trap, and
+/// the entries after it keep their slots: segments are split around the cleared entries, and the
+/// table ends after the last remaining entry. Without `call_indirect` the table and its element
+/// segments are removed. Run [`crate::optimize`] afterwards to remove the functions which were
+/// only referenced by the dropped entries.
+///
+/// The module is left unchanged unless the only table is defined by the module and not exported,
+/// and all element segments are active with constant offsets, so that all uses are known. It is
+/// also left unchanged if element segments overlap and `call_indirect` is used: an entry
+/// overwritten by a later segment would become callable again if the later one was cleared.
+pub fn compact_tables(module: &mut elements::Module) -> TableCompaction {
+	let mut compaction = TableCompaction::default();
+	let initial = match table_section(module).map(|section| section.entries()) {
+		Some([table]) if module.import_count(elements::ImportCountType::Table) == 0 =>
+			table.limits().initial(),
+		_ => return compaction,
+	};
+	let exports = module.export_section().map_or(&[][..], |section| section.entries());
+	if exports
+		.iter()
+		.any(|entry| matches!(entry.internal(), elements::Internal::Table(_)))
+	{
+		return compaction
+	}
+
+	let mut segments = Vec::new();
+	for segment in module.elements_section().map_or(&[][..], |section| section.entries()) {
+		let code = segment.offset().as_ref().map(|offset| offset.code());
+		match code {
+			Some([Instruction::I32Const(offset), Instruction::End]) =>
+				segments.push((*offset as u32, segment.members())),
+			_ => return compaction,
+		}
+	}
+
+	let types = module.type_section().map_or(&[][..], |section| section.types());
+	let mut signatures = Vec::new();
+	let bodies = module.code_section().map_or(&[][..], |section| section.bodies());
+	for instruction in bodies.iter().flat_map(|body| body.code().elements()) {
+		match instruction {
+			Instruction::CallIndirect(type_idx, _) => match types.get(*type_idx as usize) {
+				Some(elements::Type::Function(signature)) => signatures.push(signature),
+				None => return compaction,
+			},
+			// The table is accessed without `call_indirect`.
+			#[cfg(feature = "bulk")]
+			Instruction::Bulk(
+				elements::BulkInstruction::TableInit(_) |
+				elements::BulkInstruction::TableDrop(_) |
+				elements::BulkInstruction::TableCopy,
+			) => return compaction,
+			_ => {},
+		}
+	}
+
+	if signatures.is_empty() {
+		compaction.dropped_elements = segments.iter().map(|(_, members)| members.len()).sum();
+		compaction.removed_table = true;
+		module.sections_mut().retain(|section| {
+			!matches!(section, elements::Section::Table(_) | elements::Section::Element(_))
+		});
+		return compaction
+	}
+
+	let mut ranges: Vec<(u64, u64)> = segments
+		.iter()
+		.map(|(offset, members)| (u64::from(*offset), u64::from(*offset) + members.len() as u64))
+		.filter(|(start, end)| start < end)
+		.collect();
+	ranges.sort_unstable();
+	if ranges.windows(2).any(|pair| pair[1].0 < pair[0].1) {
+		return compaction
+	}
+
+	// An invalid function is kept, validation reports it.
+	let callable = |func_idx: u32| {
+		resolve_func_type(func_idx, module)
+			.map_or(true, |signature| signatures.contains(&signature))
+	};
+	let mut compacted = Vec::new();
+	let mut size = 0;
+	for (offset, members) in segments {
+		let mut run: Option<(u32, Vec<u32>)> = None;
+		for (slot, func_idx) in (offset..).zip(members.iter().copied()) {
+			if callable(func_idx) {
+				run.get_or_insert_with(|| (slot, Vec::new())).1.push(func_idx);
+				size = max(size, slot + 1);
+			} else {
+				compaction.dropped_elements += 1;
+				compacted.extend(run.take());
+			}
+		}
+		compacted.extend(run);
+	}
+
+	if compaction.dropped_elements > 0 {
+		*elements_section_mut(module)
+			.expect("there are element entries; qed")
+			.entries_mut() = compacted
+			.into_iter()
+			.map(|(offset, members)| {
+				let offset =
+					InitExpr::new(vec![Instruction::I32Const(offset as i32), Instruction::End]);
+				ElementSegment::new(0, Some(offset), members)
+			})
+			.collect();
+	}
+	if size < initial {
+		let table = &mut table_section_mut(module).expect("the table exists; qed").entries_mut()[0];
+		*table = TableType::new(size, table.limits().maximum().map(|_| size));
+		compaction.resized = Some((initial, size));
+	}
+	compaction
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		elements::deserialize_buffer(&wabt::wat2wasm(source).expect("Failed to wat2wasm"))
+			.expect("Failed to deserialize the module")
+	}
+
+	fn maximum(module: &elements::Module) -> Option<u32> {
+		table_section(module).unwrap().entries()[0].limits().maximum()
+	}
+
+	#[test]
+	fn tighten_and_raise() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "table" (table 1 funcref))
+	(table 4 funcref)
+)
+"#,
+		);
+
+		let mut tightened = module.clone();
+		assert_eq!(limit_tables(&mut tightened, TableLimit::Tighten), vec![1]);
+		assert_eq!(maximum(&tightened), Some(4));
+		assert!(limit_tables(&mut tightened, TableLimit::Tighten).is_empty());
+
+		assert_eq!(limit_tables(&mut tightened, TableLimit::Raise(16)), vec![1]);
+		assert_eq!(maximum(&tightened), Some(16));
+		assert!(limit_tables(&mut tightened, TableLimit::Raise(8)).is_empty());
+		assert_eq!(maximum(&tightened), Some(16));
+
+		let mut raised = module;
+		assert_eq!(limit_tables(&mut raised, TableLimit::Raise(2)), vec![1]);
+		assert_eq!(maximum(&raised), Some(4));
+	}
+
+	fn validate_module(module: &elements::Module) {
+		let binary = elements::serialize(module.clone()).expect("Failed to serialize");
+		wabt::Module::read_binary(&binary, &Default::default())
+			.expect("Wabt failed to read final binary")
+			.validate()
+			.expect("Invalid module");
+	}
+
+	#[test]
+	fn compact() {
+		let mut module = parse_wat(
+			r#"
+(module
+	(type $binary (func (param i32 i32) (result i32)))
+	(table 8 16 funcref)
+	(elem (i32.const 1) $add $log $sub $log $log)
+	(func $add (type $binary) (i32.add (local.get 0) (local.get 1)))
+	(func $sub (param i32 i32) (result i32) (i32.sub (local.get 0) (local.get 1)))
+	(func $log (param i32))
+	(func (export "call") (param i32) (result i32)
+		(call_indirect (type $binary) (i32.const 1) (i32.const 2) (local.get 0))
+	)
+)
+"#,
+		);
+
+		assert_eq!(
+			compact_tables(&mut module),
+			TableCompaction { dropped_elements: 3, resized: Some((8, 4)), removed_table: false }
+		);
+		let segments = module.elements_section().unwrap().entries();
+		let segments = segments
+			.iter()
+			.map(|segment| {
+				(segment.offset().as_ref().unwrap().code()[0].clone(), segment.members())
+			})
+			.collect::<Vec<_>>();
+		assert_eq!(
+			segments,
+			vec![(Instruction::I32Const(1), &[0][..]), (Instruction::I32Const(3), &[1][..])]
+		);
+		assert_eq!(table_section(&module).unwrap().entries()[0], TableType::new(4, Some(4)));
+		validate_module(&module);
+
+		assert_eq!(compact_tables(&mut module), TableCompaction::default());
+	}
+
+	#[test]
+	fn overlapping_segments() {
+		let source = r#"
+(module
+	(type $binary (func (param i32 i32) (result i32)))
+	(table 4 funcref)
+	(elem (i32.const 0) $add $add $add)
+	(elem (i32.const 1) $log)
+	(func $add (type $binary) (i32.add (local.get 0) (local.get 1)))
+	(func $log (param i32))
+	(func (export "call") (param i32) (result i32)
+		(call_indirect (type $binary) (i32.const 1) (i32.const 2) (local.get 0))
+	)
+)
+"#;
+		// Clearing the entry of `$log` would make the slot 1 call `$add` instead of trapping.
+		let mut module = parse_wat(source);
+		let original = module.clone();
+		assert_eq!(compact_tables(&mut module), TableCompaction::default());
+		assert_eq!(module, original);
+
+		// Adjacent segments don't overlap.
+		let mut module = parse_wat(&source.replace("$add $add $add", "$add"));
+		assert_eq!(
+			compact_tables(&mut module),
+			TableCompaction { dropped_elements: 1, resized: Some((4, 1)), removed_table: false }
+		);
+		validate_module(&module);
+	}
+
+	#[test]
+	fn remove_unused_table() {
+		let mut module = parse_wat(
+			r#"
+(module
+	(table 2 funcref)
+	(elem (i32.const 0) $f $f)
+	(func $f)
+	(func (export "call") (call $f))
+)
+"#,
+		);
+
+		assert_eq!(
+			compact_tables(&mut module),
+			TableCompaction { dropped_elements: 2, resized: None, removed_table: true }
+		);
+		assert!(table_section(&module).is_none());
+		assert!(module.elements_section().is_none());
+		validate_module(&module);
+	}
+
+	#[test]
+	fn unknown_uses() {
+		for source in [
+			r#"(module (table (export "table") 1 funcref) (elem (i32.const 0) $f) (func $f))"#,
+			r#"(module (import "env" "table" (table 1 funcref)) (elem (i32.const 0) $f) (func $f))"#,
+			r#"
+(module
+	(import "env" "base" (global i32))
+	(table 1 funcref)
+	(elem (global.get 0) $f)
+	(func $f)
+)
+"#,
+		] {
+			let mut module = parse_wat(source);
+			let original = module.clone();
+			assert_eq!(compact_tables(&mut module), TableCompaction::default());
+			assert_eq!(module, original);
+		}
+	}
+}
@@ -0,0 +1,229 @@
+//! Helpers for building small valid modules, e.g. to test instrumentation passes.
+//!
+//! ```
+//! use pwasm_utils::testing::module_fixture;
+//!
+//! let module = module_fixture()
+//!     .with_call_chain(3)
+//!     .with_table(4)
+//!     .with_data(16, vec![1, 2, 3])
+//!     .with_export("call", 0)
+//!     .build();
+//!
+//! assert_eq!(module.functions_space(), 3);
+//! ```
+
+use crate::std::{string::String, vec::Vec};
+
+use parity_wasm::{builder, elements};
+
+/// Builder of a module fixture, see [`module_fixture`].
+///
+/// The functions of the fixture have the signature `[] -> []` unless given parameters with
+/// [`ModuleFixture::with_params`].
+#[derive(Debug, Default)]
+pub struct ModuleFixture {
+	functions: u32,
+	call_chain: bool,
+	params: Vec<(u32, Vec<elements::ValueType>)>,
+	table_entries: Option<u32>,
+	// `Some(true)` if the memory is imported.
+	memory: Option<bool>,
+	memory_max: Option<u32>,
+	data: Vec<(u32, Vec<u8>)>,
+	exports: Vec<(String, u32)>,
+}
+
+/// Start building a module fixture.
+pub fn module_fixture() -> ModuleFixture {
+	ModuleFixture::default()
+}
+
+impl ModuleFixture {
+	/// Add `count` functions with empty bodies.
+	pub fn with_functions(mut self, count: u32) -> Self {
+		self.functions = count;
+		self.call_chain = false;
+		self
+	}
+
+	/// Add `count` functions where each one calls the next one and the last one does nothing.
+	pub fn with_call_chain(mut self, count: u32) -> Self {
+		self.functions = count;
+		self.call_chain = true;
+		self
+	}
+
+	/// Add a table with `entries` elements placed at offset 0.
+	///
+	/// Element `i` refers to the function `i % functions`.
+	pub fn with_table(mut self, entries: u32) -> Self {
+		self.table_entries = Some(entries);
+		self
+	}
+
+	/// Add a memory of one page defined by the module.
+	pub fn with_memory(mut self) -> Self {
+		self.memory = Some(false);
+		self
+	}
+
+	/// Add a memory of one page imported as `env.memory`.
+	pub fn with_imported_memory(mut self) -> Self {
+		self.memory = Some(true);
+		self
+	}
+
+	/// Limit the memory to `pages` pages. Adds a memory if there is none yet.
+	pub fn with_memory_max(mut self, pages: u32) -> Self {
+		self.memory.get_or_insert(false);
+		self.memory_max = Some(pages);
+		self
+	}
+
+	/// Give the function `func_idx` the parameters `params`.
+	///
+	/// The calls of a call chain don't pass arguments, so the called functions must not take
+	/// any.
+	pub fn with_params(mut self, func_idx: u32, params: Vec<elements::ValueType>) -> Self {
+		self.params.push((func_idx, params));
+		self
+	}
+
+	/// Add a data segment with `value` at `offset`. Adds a memory if there is none yet.
+	pub fn with_data(mut self, offset: u32, value: Vec<u8>) -> Self {
+		self.memory.get_or_insert(false);
+		self.data.push((offset, value));
+		self
+	}
+
+	/// Export the function `func_idx` under `field`.
+	pub fn with_export(mut self, field: &str, func_idx: u32) -> Self {
+		self.exports.push((field.into(), func_idx));
+		self
+	}
+
+	/// Build the module.
+	///
+	/// # Panics
+	///
+	/// Panics if a table is requested for a module without functions.
+	pub fn build(self) -> elements::Module {
+		let mut module = builder::module();
+
+		if self.memory == Some(true) {
+			module = module
+				.import()
+				.module("env")
+				.field("memory")
+				.external()
+				.memory(1, self.memory_max)
+				.build();
+		}
+
+		for func_idx in 0..self.functions {
+			let mut body = Vec::new();
+			if self.call_chain && func_idx + 1 < self.functions {
+				body.push(elements::Instruction::Call(func_idx + 1));
+			}
+			body.push(elements::Instruction::End);
+
+			let params = self
+				.params
+				.iter()
+				.rev()
+				.find(|(idx, _)| *idx == func_idx)
+				.map_or_else(Vec::new, |(_, params)| params.clone());
+			module = module
+				.function()
+				.signature()
+				.with_params(params)
+				.build()
+				.body()
+				.with_instructions(elements::Instructions::new(body))
+				.build()
+				.build();
+		}
+
+		if let Some(entries) = self.table_entries {
+			assert!(self.functions > 0, "table elements need functions to refer to");
+			let members = (0..entries).map(|i| i % self.functions).collect();
+			module = module.table().with_min(entries).with_element(0, members).build();
+		}
+
+		if self.memory == Some(false) {
+			module = module.memory().with_min(1).with_max(self.memory_max).build();
+		}
+
+		for (offset, value) in self.data {
+			module = module
+				.data()
+				.offset(elements::Instruction::I32Const(offset as i32))
+				.value(value)
+				.build();
+		}
+
+		for (field, func_idx) in self.exports {
+			module = module.export().field(&field).internal().func(func_idx).build();
+		}
+
+		module.build()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn call_chain() {
+		let module = module_fixture().with_call_chain(3).with_export("call", 0).build();
+
+		let bodies = module.code_section().expect("code section to exist").bodies();
+		assert_eq!(bodies.len(), 3);
+		assert_eq!(bodies[1].code().elements()[0], elements::Instruction::Call(2));
+		assert_eq!(bodies[2].code().elements(), &[elements::Instruction::End]);
+
+		let binary = elements::serialize(module).expect("serialization to succeed");
+		wabt::Module::read_binary(&binary, &Default::default())
+			.expect("module to be read")
+			.validate()
+			.expect("module to be valid");
+	}
+
+	#[test]
+	fn memory_max_and_params() {
+		let module = module_fixture()
+			.with_imported_memory()
+			.with_memory_max(2)
+			.with_functions(2)
+			.with_params(1, vec![elements::ValueType::I64])
+			.build();
+
+		let import = &module.import_section().expect("import section to exist").entries()[0];
+		assert!(matches!(
+			import.external(),
+			elements::External::Memory(memory) if memory.limits().maximum() == Some(2)
+		));
+		let types = module.type_section().expect("type section to exist").types();
+		let elements::Type::Function(signature) = &types[1];
+		assert_eq!(signature.params(), &[elements::ValueType::I64]);
+	}
+
+	#[test]
+	fn table_and_data() {
+		let module =
+			module_fixture().with_functions(2).with_table(3).with_data(8, vec![42]).build();
+
+		let segment = &module.elements_section().expect("element section to exist").entries()[0];
+		assert_eq!(segment.members(), &[0, 1, 0]);
+		assert_eq!(module.memory_section().expect("memory section to exist").entries().len(), 1);
+		assert_eq!(module.data_section().expect("data section to exist").entries().len(), 1);
+
+		let binary = elements::serialize(module).expect("serialization to succeed");
+		wabt::Module::read_binary(&binary, &Default::default())
+			.expect("module to be read")
+			.validate()
+			.expect("module to be valid");
+	}
+}
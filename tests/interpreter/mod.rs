@@ -0,0 +1,469 @@
+//! Interpreter for the modules produced by the pipeline tests.
+//!
+//! It runs the integer subset of MVP Wasm that small contracts and the injected code use, with
+//! the host functions of the pwasm runtime: `gas` sums up the charges and `ret` records the
+//! returned data. Anything else is refused with a panic, since a test relying on it is broken.
+
+use parity_wasm::elements::{self, BlockType, External, Instruction, Internal};
+use std::collections::BTreeMap;
+
+const PAGE_SIZE: usize = 65536;
+/// Depth of nested calls at which the interpreter gives up, like an engine with a native stack.
+const MAX_CALL_DEPTH: usize = 1024;
+
+/// Reason the execution stopped early.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Trap {
+	Unreachable,
+	MemoryOutOfBounds,
+	DivisionByZero,
+	CallStackExhausted,
+}
+
+/// State of the host, as seen after the execution.
+#[derive(Debug, Default)]
+pub struct Host {
+	/// Sum of the charges passed to the gas function.
+	pub gas: u64,
+	/// Data passed to `ret`.
+	pub returned: Option<Vec<u8>>,
+	/// Function passed to `stack_overflow`.
+	pub overflowed: Option<i32>,
+}
+
+enum HostFunction {
+	Gas,
+	Ret,
+	StackOverflow,
+}
+
+/// Position of the matching `else` and `end` of a block starting instruction.
+#[derive(Clone, Copy)]
+struct BlockEnds {
+	else_pc: Option<usize>,
+	end_pc: usize,
+}
+
+struct Label {
+	is_loop: bool,
+	start_pc: usize,
+	end_pc: usize,
+	height: usize,
+	arity: usize,
+}
+
+struct Function<'a> {
+	params: usize,
+	results: usize,
+	locals: Vec<elements::ValueType>,
+	code: &'a [Instruction],
+	blocks: BTreeMap<usize, BlockEnds>,
+}
+
+/// Instantiated module.
+pub struct Instance<'a> {
+	module: &'a elements::Module,
+	imports: Vec<HostFunction>,
+	functions: Vec<Function<'a>>,
+	globals: Vec<u64>,
+	memory: Vec<u8>,
+	pub host: Host,
+}
+
+impl<'a> Instance<'a> {
+	/// Instantiate `module`, providing the memory it imports with its initial size.
+	pub fn new(module: &'a elements::Module) -> Self {
+		let imports = module.import_section().map_or(&[][..], |s| s.entries());
+		let mut pages = 0;
+		let mut host_functions = Vec::new();
+		for entry in imports {
+			match entry.external() {
+				External::Function(_) => host_functions.push(match entry.field() {
+					"gas" => HostFunction::Gas,
+					"ret" => HostFunction::Ret,
+					"stack_overflow" => HostFunction::StackOverflow,
+					field => panic!("unknown host function `{}`", field),
+				}),
+				External::Memory(memory) => pages = memory.limits().initial(),
+				_ => panic!("only functions and the memory can be imported"),
+			}
+		}
+		if let Some(memory) = module.memory_section().and_then(|s| s.entries().first()) {
+			pages = memory.limits().initial();
+		}
+
+		let types = module.type_section().map_or(&[][..], |s| s.types());
+		let entries = module.function_section().map_or(&[][..], |s| s.entries());
+		let bodies = module.code_section().map_or(&[][..], |s| s.bodies());
+		let functions = entries
+			.iter()
+			.zip(bodies)
+			.map(|(func, body)| {
+				let elements::Type::Function(ty) = &types[func.type_ref() as usize];
+				let code = body.code().elements();
+				Function {
+					params: ty.params().len(),
+					results: ty.results().len(),
+					locals: body
+						.locals()
+						.iter()
+						.flat_map(|local| (0..local.count()).map(move |_| local.value_type()))
+						.collect(),
+					code,
+					blocks: block_ends(code),
+				}
+			})
+			.collect();
+
+		let globals = module
+			.global_section()
+			.map_or(&[][..], |s| s.entries())
+			.iter()
+			.map(|global| const_value(global.init_expr()))
+			.collect();
+
+		let mut memory = vec![0; pages as usize * PAGE_SIZE];
+		for segment in module.data_section().map_or(&[][..], |s| s.entries()) {
+			let offset = const_value(segment.offset().as_ref().expect("active segment")) as usize;
+			memory[offset..offset + segment.value().len()].copy_from_slice(segment.value());
+		}
+
+		Instance {
+			module,
+			imports: host_functions,
+			functions,
+			globals,
+			memory,
+			host: Host::default(),
+		}
+	}
+
+	/// Call the exported function `name` with `args`, returning its results.
+	pub fn invoke_export(&mut self, name: &str, args: &[u64]) -> Result<Vec<u64>, Trap> {
+		let func_idx = self
+			.module
+			.export_section()
+			.and_then(|s| s.entries().iter().find(|e| e.field() == name))
+			.and_then(|e| match e.internal() {
+				Internal::Function(func_idx) => Some(*func_idx),
+				_ => None,
+			})
+			.unwrap_or_else(|| panic!("no exported function `{}`", name));
+		let mut stack = args.to_vec();
+		self.call(func_idx, &mut stack, 0)?;
+		Ok(stack)
+	}
+
+	fn call(&mut self, func_idx: u32, stack: &mut Vec<u64>, call_depth: usize) -> Result<(), Trap> {
+		if call_depth == MAX_CALL_DEPTH {
+			return Err(Trap::CallStackExhausted)
+		}
+		let func_idx = func_idx as usize;
+		if let Some(host_function) = self.imports.get(func_idx) {
+			match host_function {
+				HostFunction::Gas => self.host.gas += stack.pop().expect("charge") as u32 as u64,
+				HostFunction::Ret => {
+					let len = stack.pop().expect("length") as u32 as usize;
+					let ptr = stack.pop().expect("pointer") as u32 as usize;
+					self.host.returned = Some(self.memory(ptr, len)?.to_vec());
+				},
+				HostFunction::StackOverflow =>
+					self.host.overflowed = Some(stack.pop().expect("function") as i32),
+			}
+			return Ok(())
+		}
+
+		let func = &self.functions[func_idx - self.imports.len()];
+		let (params, results, code) = (func.params, func.results, func.code);
+		let mut locals = stack.split_off(stack.len() - params);
+		locals.resize(params + func.locals.len(), 0);
+		let mut values = Vec::new();
+		let mut labels = vec![Label {
+			is_loop: false,
+			start_pc: 0,
+			end_pc: code.len() - 1,
+			height: 0,
+			arity: results,
+		}];
+		let mut pc = 0;
+
+		while pc < code.len() {
+			use Instruction::*;
+
+			match &code[pc] {
+				Unreachable => return Err(Trap::Unreachable),
+				Nop => {},
+				Block(ty) | Loop(ty) => {
+					let ends = self.block_ends(func_idx, pc);
+					labels.push(Label {
+						is_loop: matches!(code[pc], Loop(_)),
+						start_pc: pc,
+						end_pc: ends.end_pc,
+						height: values.len(),
+						arity: arity(*ty),
+					});
+				},
+				If(ty) => {
+					let ends = self.block_ends(func_idx, pc);
+					let condition = pop(&mut values) as u32;
+					labels.push(Label {
+						is_loop: false,
+						start_pc: pc,
+						end_pc: ends.end_pc,
+						height: values.len(),
+						arity: arity(*ty),
+					});
+					if condition == 0 {
+						match ends.else_pc {
+							Some(else_pc) => pc = else_pc,
+							None => {
+								labels.pop();
+								pc = ends.end_pc;
+							},
+						}
+					}
+				},
+				Else => {
+					// The end of the taken branch.
+					let label = labels.pop().expect("else in a block");
+					pc = label.end_pc;
+				},
+				End => {
+					labels.pop();
+				},
+				Br(depth) => pc = branch(&mut labels, &mut values, *depth),
+				BrIf(depth) =>
+					if pop(&mut values) as u32 != 0 {
+						pc = branch(&mut labels, &mut values, *depth);
+					},
+				BrTable(table) => {
+					let idx = pop(&mut values) as u32 as usize;
+					let depth = *table.table.get(idx).unwrap_or(&table.default);
+					pc = branch(&mut labels, &mut values, depth);
+				},
+				Return => {
+					let depth = labels.len() as u32 - 1;
+					pc = branch(&mut labels, &mut values, depth);
+				},
+				Call(callee) => self.call(*callee, &mut values, call_depth + 1)?,
+				Drop => {
+					pop(&mut values);
+				},
+				Select => {
+					let condition = pop(&mut values) as u32;
+					let second = pop(&mut values);
+					let first = pop(&mut values);
+					values.push(if condition != 0 { first } else { second });
+				},
+				GetLocal(idx) => values.push(locals[*idx as usize]),
+				SetLocal(idx) => locals[*idx as usize] = pop(&mut values),
+				TeeLocal(idx) => locals[*idx as usize] = *values.last().expect("operand"),
+				GetGlobal(idx) => values.push(self.globals[*idx as usize]),
+				SetGlobal(idx) => self.globals[*idx as usize] = pop(&mut values),
+				I32Load(_, offset) => {
+					let bytes = self.load(&mut values, *offset, 4)?;
+					values.push(u32::from_le_bytes(bytes.try_into().unwrap()) as u64);
+				},
+				I64Load(_, offset) => {
+					let bytes = self.load(&mut values, *offset, 8)?;
+					values.push(u64::from_le_bytes(bytes.try_into().unwrap()));
+				},
+				I32Load8U(_, offset) => {
+					let bytes = self.load(&mut values, *offset, 1)?;
+					values.push(bytes[0] as u64);
+				},
+				I32Store(_, offset) => {
+					let value = pop(&mut values) as u32;
+					self.store(&mut values, *offset, &value.to_le_bytes())?;
+				},
+				I64Store(_, offset) => {
+					let value = pop(&mut values);
+					self.store(&mut values, *offset, &value.to_le_bytes())?;
+				},
+				I32Store8(_, offset) => {
+					let value = pop(&mut values) as u8;
+					self.store(&mut values, *offset, &[value])?;
+				},
+				CurrentMemory(_) => values.push((self.memory.len() / PAGE_SIZE) as u64),
+				GrowMemory(_) => {
+					let pages = pop(&mut values) as u32 as usize;
+					values.push((self.memory.len() / PAGE_SIZE) as u64);
+					self.memory.resize(self.memory.len() + pages * PAGE_SIZE, 0);
+				},
+				I32Const(value) => values.push(*value as u32 as u64),
+				I64Const(value) => values.push(*value as u64),
+				I32Eqz => {
+					let value = pop(&mut values) as u32;
+					values.push((value == 0) as u64);
+				},
+				I64Eqz => {
+					let value = pop(&mut values);
+					values.push((value == 0) as u64);
+				},
+				I32WrapI64 => {
+					let value = pop(&mut values) as u32;
+					values.push(value as u64);
+				},
+				I64ExtendUI32 => {},
+				I32Eq | I32Ne | I32LtS | I32LtU | I32GtS | I32GtU | I32LeS | I32LeU | I32GeS |
+				I32GeU | I32Add | I32Sub | I32Mul | I32DivU | I32RemU | I32And | I32Or |
+				I32Xor | I32Shl | I32ShrU | I32ShrS => {
+					let rhs = pop(&mut values) as u32;
+					let lhs = pop(&mut values) as u32;
+					values.push(i32_binary(&code[pc], lhs, rhs)? as u64);
+				},
+				I64Eq | I64Ne | I64LtU | I64GtU | I64LeU | I64GeU => {
+					let rhs = pop(&mut values);
+					let lhs = pop(&mut values);
+					values.push(i64_comparison(&code[pc], lhs, rhs) as u64);
+				},
+				I64Add => {
+					let rhs = pop(&mut values);
+					let lhs = pop(&mut values);
+					values.push(lhs.wrapping_add(rhs));
+				},
+				I64Sub => {
+					let rhs = pop(&mut values);
+					let lhs = pop(&mut values);
+					values.push(lhs.wrapping_sub(rhs));
+				},
+				I64Mul => {
+					let rhs = pop(&mut values);
+					let lhs = pop(&mut values);
+					values.push(lhs.wrapping_mul(rhs));
+				},
+				instruction => panic!("unsupported instruction `{}`", instruction),
+			}
+			pc += 1;
+		}
+
+		stack.extend_from_slice(&values[values.len() - results..]);
+		Ok(())
+	}
+
+	fn block_ends(&self, func_idx: usize, pc: usize) -> BlockEnds {
+		self.functions[func_idx - self.imports.len()].blocks[&pc]
+	}
+
+	fn memory(&self, address: usize, len: usize) -> Result<&[u8], Trap> {
+		address
+			.checked_add(len)
+			.and_then(|end| self.memory.get(address..end))
+			.ok_or(Trap::MemoryOutOfBounds)
+	}
+
+	fn load(&self, values: &mut Vec<u64>, offset: u32, len: usize) -> Result<Vec<u8>, Trap> {
+		let address = pop(values) as u32 as usize + offset as usize;
+		Ok(self.memory(address, len)?.to_vec())
+	}
+
+	fn store(&mut self, values: &mut Vec<u64>, offset: u32, bytes: &[u8]) -> Result<(), Trap> {
+		let address = pop(values) as u32 as usize + offset as usize;
+		self.memory(address, bytes.len())?;
+		self.memory[address..address + bytes.len()].copy_from_slice(bytes);
+		Ok(())
+	}
+}
+
+fn pop(values: &mut Vec<u64>) -> u64 {
+	values.pop().expect("the module is validated, so operands are on the stack")
+}
+
+fn arity(ty: BlockType) -> usize {
+	match ty {
+		BlockType::NoResult => 0,
+		BlockType::Value(_) => 1,
+	}
+}
+
+/// Unwind to the label at `depth`, returning the position of the instruction to continue
+/// after.
+fn branch(labels: &mut Vec<Label>, values: &mut Vec<u64>, depth: u32) -> usize {
+	let target = labels.len() - 1 - depth as usize;
+	let label = &labels[target];
+	// Blocks don't take parameters, so a loop is entered with no values.
+	let arity = if label.is_loop { 0 } else { label.arity };
+	let kept = values.split_off(values.len() - arity);
+	values.truncate(label.height);
+	values.extend(kept);
+	if label.is_loop {
+		let start_pc = label.start_pc;
+		labels.truncate(target + 1);
+		start_pc
+	} else {
+		let end_pc = label.end_pc;
+		labels.truncate(target);
+		end_pc
+	}
+}
+
+/// Matching `else` and `end` of all block starting instructions of `code`.
+fn block_ends(code: &[Instruction]) -> BTreeMap<usize, BlockEnds> {
+	let mut blocks = BTreeMap::new();
+	let mut open = Vec::new();
+	for (pc, instruction) in code.iter().enumerate() {
+		match instruction {
+			Instruction::Block(_) | Instruction::Loop(_) | Instruction::If(_) =>
+				open.push((pc, None)),
+			Instruction::Else => open.last_mut().expect("else in a block").1 = Some(pc),
+			Instruction::End =>
+				if let Some((start_pc, else_pc)) = open.pop() {
+					blocks.insert(start_pc, BlockEnds { else_pc, end_pc: pc });
+				},
+			_ => {},
+		}
+	}
+	blocks
+}
+
+fn const_value(init_expr: &elements::InitExpr) -> u64 {
+	match init_expr.code().first() {
+		Some(Instruction::I32Const(value)) => *value as u32 as u64,
+		Some(Instruction::I64Const(value)) => *value as u64,
+		other => panic!("unsupported init expression {:?}", other),
+	}
+}
+
+fn i32_binary(instruction: &Instruction, lhs: u32, rhs: u32) -> Result<u32, Trap> {
+	use Instruction::*;
+
+	let (signed_lhs, signed_rhs) = (lhs as i32, rhs as i32);
+	Ok(match instruction {
+		I32Eq => (lhs == rhs) as u32,
+		I32Ne => (lhs != rhs) as u32,
+		I32LtS => (signed_lhs < signed_rhs) as u32,
+		I32LtU => (lhs < rhs) as u32,
+		I32GtS => (signed_lhs > signed_rhs) as u32,
+		I32GtU => (lhs > rhs) as u32,
+		I32LeS => (signed_lhs <= signed_rhs) as u32,
+		I32LeU => (lhs <= rhs) as u32,
+		I32GeS => (signed_lhs >= signed_rhs) as u32,
+		I32GeU => (lhs >= rhs) as u32,
+		I32Add => lhs.wrapping_add(rhs),
+		I32Sub => lhs.wrapping_sub(rhs),
+		I32Mul => lhs.wrapping_mul(rhs),
+		I32DivU => lhs.checked_div(rhs).ok_or(Trap::DivisionByZero)?,
+		I32RemU => lhs.checked_rem(rhs).ok_or(Trap::DivisionByZero)?,
+		I32And => lhs & rhs,
+		I32Or => lhs | rhs,
+		I32Xor => lhs ^ rhs,
+		I32Shl => lhs.wrapping_shl(rhs),
+		I32ShrU => lhs.wrapping_shr(rhs),
+		I32ShrS => signed_lhs.wrapping_shr(rhs) as u32,
+		_ => unreachable!("only i32 binary operators are passed"),
+	})
+}
+
+fn i64_comparison(instruction: &Instruction, lhs: u64, rhs: u64) -> bool {
+	use Instruction::*;
+
+	match instruction {
+		I64Eq => lhs == rhs,
+		I64Ne => lhs != rhs,
+		I64LtU => lhs < rhs,
+		I64GtU => lhs > rhs,
+		I64LeU => lhs <= rhs,
+		I64GeU => lhs >= rhs,
+		_ => unreachable!("only i64 comparisons are passed"),
+	}
+}
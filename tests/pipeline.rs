@@ -0,0 +1,118 @@
+//! Runs the example contracts through the whole pipeline, i.e. the instrumentation, the build and
+//! the packing, and executes the result.
+//!
+//! The per-pass diffs only check the output of a single pass, these tests catch passes breaking
+//! each other. The gas consumed is checked against golden values, so a change of the metering
+//! shows up here as well.
+
+mod interpreter;
+
+use interpreter::{Instance, Trap};
+use parity_wasm::elements;
+use pwasm_utils::{self as utils, rules, stack_height, GasConfig, SourceTarget, TargetRuntime};
+use std::{fs, path::PathBuf};
+
+fn contract(name: &str) -> elements::Module {
+	let mut path = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/examples/contracts/"));
+	path.push(format!("{}.wat", name));
+	let wat = fs::read(&path).expect("Failed to read contract");
+	let wasm = wabt::wat2wasm(wat).expect("Failed to compile contract");
+	elements::deserialize_buffer(&wasm).expect("Failed to deserialize contract")
+}
+
+/// Outcome of the execution of an export.
+#[derive(Debug)]
+struct Outcome {
+	result: Result<(), Trap>,
+	gas: u64,
+	returned: Option<Vec<u8>>,
+	overflowed: Option<i32>,
+}
+
+fn execute(module: &elements::Module, export: &str) -> Outcome {
+	let mut instance = Instance::new(module);
+	let result = instance.invoke_export(export, &[]).map(|results| assert!(results.is_empty()));
+	Outcome {
+		result,
+		gas: instance.host.gas,
+		returned: instance.host.returned,
+		overflowed: instance.host.overflowed,
+	}
+}
+
+/// Instrument and build the contract `name`, deploy it and call the deployed code.
+fn deploy_and_call(name: &str, stack_config: &stack_height::Config) -> (Outcome, Outcome) {
+	let module = utils::inject_gas_and_stack_limiter(
+		contract(name),
+		&rules::Set::default(),
+		&GasConfig::new("env"),
+		stack_config,
+	)
+	.expect("Failed to instrument contract");
+	let (code, ctor) = utils::build(
+		module,
+		SourceTarget::Unknown,
+		None,
+		&[],
+		false,
+		0,
+		false,
+		&TargetRuntime::pwasm(),
+	)
+	.expect("Failed to build contract");
+	let ctor = ctor.expect("The contract has a constructor");
+
+	let deploy = execute(&ctor, "call");
+	assert_eq!(deploy.result, Ok(()));
+	let deployed = deploy.returned.as_ref().expect("The constructor returns the code");
+	assert_eq!(deployed, &elements::serialize(code).expect("Failed to serialize code"));
+
+	let deployed = elements::deserialize_buffer(deployed).expect("Failed to deserialize code");
+	let call = execute(&deployed, "call");
+	(deploy, call)
+}
+
+#[test]
+fn sum() {
+	let (deploy, call) = deploy_and_call("sum", &stack_height::Config::new(1024));
+	assert_eq!(deploy.gas, 0);
+	assert_eq!(call.result, Ok(()));
+	assert_eq!(call.returned, Some(55u32.to_le_bytes().to_vec()));
+	assert_eq!(call.gas, 133);
+}
+
+#[test]
+fn fib() {
+	let (deploy, call) = deploy_and_call("fib", &stack_height::Config::new(1024));
+	assert_eq!(deploy.gas, 0);
+	assert_eq!(call.result, Ok(()));
+	assert_eq!(call.returned, Some(610u32.to_le_bytes().to_vec()));
+	assert_eq!(call.gas, 17760);
+}
+
+#[test]
+fn fib_exceeding_stack_limit() {
+	let (_, call) = deploy_and_call("fib", &stack_height::Config::new(32));
+	assert_eq!(call.result, Err(Trap::Unreachable));
+	assert_eq!(call.returned, None);
+	assert_eq!(call.gas, 98);
+}
+
+#[test]
+fn fib_reporting_stack_overflow() {
+	let stack_config = stack_height::Config::new(32).with_overflow_handler("env", "stack_overflow");
+	let (_, call) = deploy_and_call("fib", &stack_config);
+	assert_eq!(call.result, Err(Trap::Unreachable));
+	// `fib` follows the imports of `ret`, the handler and the gas function.
+	assert_eq!(call.overflowed, Some(3));
+	assert_eq!(call.gas, 98);
+}
+
+#[test]
+fn greeting() {
+	let (deploy, call) = deploy_and_call("greeting", &stack_height::Config::new(1024));
+	assert_eq!(deploy.gas, 5);
+	assert_eq!(call.result, Ok(()));
+	assert_eq!(call.returned.as_deref(), Some(&b"Hello, world!"[..]));
+	assert_eq!(call.gas, 3);
+}